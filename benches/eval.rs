@@ -0,0 +1,81 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use sieve::{Compiler, Input, Runtime, Sieve};
+
+const MESSAGE: &[u8] = b"From: Sales <sales@example.org>\r\nSubject: TPS Reports\r\n\r\nbody\r\n";
+
+// Runs a script that only produces a keep to completion
+fn run(runtime: &Runtime<()>, script: &Sieve) {
+    let mut ctx = runtime.filter(MESSAGE);
+    let mut input = Input::script("bench", script.clone());
+    while let Some(result) = ctx.run(input) {
+        result.unwrap();
+        input = Input::True;
+    }
+}
+
+// The same tests with constant and variable arguments, to compare the
+// cost of evaluating constants against expanding variables
+fn script(constant: bool) -> Sieve {
+    let mut script = String::from("require [\"variables\", \"fileinto\"];\n");
+    script.push_str("set \"name\" \"subject\";\nset \"value\" \"no match\";\n");
+    for n in 0..200 {
+        if constant {
+            script.push_str(&format!(
+                "if string :is \"no match\" [\"a{n}\", \"b{n}\", \"c{n}\"] {{ fileinto \"{n}\"; }}\n"
+            ));
+            script.push_str(&format!(
+                "if header :contains \"subject\" \"q{n}\" {{ fileinto \"{n}\"; }}\n"
+            ));
+        } else {
+            script.push_str(&format!(
+                "if string :is \"${{value}}\" [\"a{n}\", \"b{n}\", \"c{n}\"] {{ fileinto \"{n}\"; }}\n"
+            ));
+            script.push_str(&format!(
+                "if header :contains \"${{name}}\" \"q{n}\" {{ fileinto \"{n}\"; }}\n"
+            ));
+        }
+    }
+    Compiler::new()
+        .with_max_instructions(usize::MAX)
+        .compile(script.as_bytes())
+        .unwrap()
+}
+
+fn eval_constants(c: &mut Criterion) {
+    let runtime = Runtime::new().with_cpu_limit(usize::MAX);
+    let constant = script(true);
+    let variable = script(false);
+
+    c.bench_function("string tests, constant arguments", |b| {
+        b.iter(|| run(&runtime, &constant))
+    });
+    c.bench_function("string tests, variable arguments", |b| {
+        b.iter(|| run(&runtime, &variable))
+    });
+}
+
+criterion_group!(benches, eval_constants);
+criterion_main!(benches);
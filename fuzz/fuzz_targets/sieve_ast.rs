@@ -0,0 +1,59 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use sieve::{compiler::fuzz::Script, Compiler, Input, Runtime};
+
+static MESSAGE: &[u8] = b"From: \"Joe\" <joe+fuzz@example.org>\r
+To: user@example.org, other@example.net\r
+Subject: =?utf-8?q?Hello?=\r
+X-Custom: 42\r
+Content-Type: multipart/mixed; boundary=\"b\"\r
+\r
+--b\r
+Content-Type: text/plain\r
+\r
+Hello world\r
+--b\r
+Content-Type: text/html\r
+\r
+<p>Hello</p>\r
+--b--\r
+";
+
+fuzz_target!(|script: Script| {
+    let compiler = Compiler::new();
+    if let Ok(sieve) = compiler.compile(script.to_string().as_bytes()) {
+        let runtime = Runtime::new();
+        let mut instance = runtime.filter(MESSAGE);
+        let mut input = Input::script("fuzz", sieve);
+        while let Some(result) = instance.run(input) {
+            if result.is_err() {
+                break;
+            }
+            input = Input::True;
+        }
+    }
+});
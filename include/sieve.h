@@ -0,0 +1,103 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+#ifndef SIEVE_H
+#define SIEVE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct SieveScript SieveScript;
+typedef struct SieveRuntime SieveRuntime;
+
+typedef enum SieveEventKind {
+    SIEVE_INCLUDE_SCRIPT = 0,  /* name: script, flag: optional */
+    SIEVE_MAILBOX_EXISTS = 1,  /* values: mailboxes, lists: special use attributes */
+    SIEVE_LIST_CONTAINS = 2,   /* values: values, lists: lists */
+    SIEVE_DUPLICATE_ID = 3,    /* name: id, number: expiry, flag: last */
+    SIEVE_SET_ENVELOPE = 4,    /* name: envelope part, value: new value */
+    SIEVE_KEEP = 5,            /* values: flags, message_id */
+    SIEVE_DISCARD = 6,
+    SIEVE_REJECT = 7,          /* value: reason, flag: extended */
+    SIEVE_FILE_INTO = 8,       /* name: folder, value: mailbox id, values: flags,
+                                  lists: special use, flag: create, message_id */
+    SIEVE_SEND_MESSAGE = 9,    /* name: address or value: list or values: group, message_id */
+    SIEVE_NOTIFY = 10,         /* name: method, value: message, values: options, lists: from */
+    SIEVE_CREATED_MESSAGE = 11 /* message, message_len, message_id */
+} SieveEventKind;
+
+/* Fields not used by an event are NULL or zero. Pointers are only valid
+ * until the callback returns. */
+typedef struct SieveEvent {
+    SieveEventKind kind;
+    const char *name;
+    const char *value;
+    const char *const *values;
+    size_t num_values;
+    const char *const *lists;
+    size_t num_lists;
+    const uint8_t *message;
+    size_t message_len;
+    size_t message_id;
+    uint64_t number;
+    bool flag;
+} SieveEvent;
+
+/* Returns non-zero to answer true to SIEVE_MAILBOX_EXISTS, SIEVE_LIST_CONTAINS
+ * and SIEVE_DUPLICATE_ID, the value is ignored for actions. */
+typedef int (*SieveEventCallback)(const SieveEvent *event, void *user_data);
+
+/* Returns NULL on failure and sets *error, if error is not NULL. */
+SieveScript *sieve_compile(const uint8_t *source, size_t source_len, char **error);
+
+/* Runtimes start with the default limits, an empty envelope and no user address. */
+SieveRuntime *sieve_runtime_new(void);
+void sieve_runtime_set_cpu_limit(SieveRuntime *runtime, size_t limit);
+void sieve_runtime_set_max_redirects(SieveRuntime *runtime, size_t max);
+void sieve_runtime_set_max_out_messages(SieveRuntime *runtime, size_t max);
+void sieve_runtime_set_max_variable_size(SieveRuntime *runtime, size_t size);
+
+/* Return 0 on success or -1 for an unknown envelope part or invalid UTF-8. */
+int sieve_runtime_set_envelope(SieveRuntime *runtime, const char *name, const char *value);
+int sieve_runtime_set_user_address(SieveRuntime *runtime, const char *address);
+
+void sieve_runtime_free(SieveRuntime *runtime);
+
+/* Returns 0 on success or -1 on failure, setting *error if error is not NULL.
+ * A NULL runtime runs the script with the default limits. */
+int sieve_run(const SieveScript *script, const SieveRuntime *runtime, const uint8_t *message,
+              size_t message_len, SieveEventCallback callback, void *user_data, char **error);
+
+void sieve_script_free(SieveScript *script);
+void sieve_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SIEVE_H */
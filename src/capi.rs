@@ -0,0 +1,661 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! C bindings for embedding the interpreter in MTAs and milters.
+//!
+//! Enabled with the `capi` feature, the declarations are in `include/sieve.h`.
+//! Scripts are compiled with `sieve_compile` and executed with `sieve_run`, which
+//! reports every event to a callback. Strings and arrays passed to the callback
+//! are only valid until it returns. Limits, the envelope and the user address
+//! are configured on a `SieveRuntime` created with `sieve_runtime_new`.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr, slice,
+    sync::Arc,
+};
+
+use crate::{Compiler, Envelope, Event, Input, Mailbox, Recipient, Runtime, Sieve};
+
+pub struct SieveScript {
+    sieve: Arc<Sieve>,
+}
+
+pub struct SieveRuntime {
+    runtime: Runtime<()>,
+    envelope: Vec<(Envelope, String)>,
+    user_address: Option<String>,
+}
+
+impl Default for SieveRuntime {
+    fn default() -> Self {
+        SieveRuntime {
+            runtime: Runtime::new(),
+            envelope: Vec::new(),
+            user_address: None,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SieveEventKind {
+    IncludeScript = 0,
+    MailboxExists = 1,
+    ListContains = 2,
+    DuplicateId = 3,
+    SetEnvelope = 4,
+    Keep = 5,
+    Discard = 6,
+    Reject = 7,
+    FileInto = 8,
+    SendMessage = 9,
+    Notify = 10,
+    CreatedMessage = 11,
+}
+
+#[repr(C)]
+pub struct SieveEvent {
+    pub kind: SieveEventKind,
+    pub name: *const c_char,
+    pub value: *const c_char,
+    pub values: *const *const c_char,
+    pub num_values: usize,
+    pub lists: *const *const c_char,
+    pub num_lists: usize,
+    pub message: *const u8,
+    pub message_len: usize,
+    pub message_id: usize,
+    pub number: u64,
+    pub flag: bool,
+}
+
+/// Called for every event, the return value is the answer to tests such as
+/// `MailboxExists`, `ListContains` or `DuplicateId` (non-zero means true).
+pub type SieveEventCallback =
+    Option<unsafe extern "C" fn(event: *const SieveEvent, user_data: *mut c_void) -> c_int>;
+
+/// Compiles a script. Returns NULL on failure, in which case `error`, if not NULL,
+/// is set to a message that must be released with `sieve_string_free`.
+///
+/// # Safety
+///
+/// `source` must point to `source_len` readable bytes and `error` must be
+/// NULL or a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_compile(
+    source: *const u8,
+    source_len: usize,
+    error: *mut *mut c_char,
+) -> *mut SieveScript {
+    let source = bytes(source, source_len);
+    match catch_unwind(|| Compiler::new().compile(source)) {
+        Ok(Ok(sieve)) => Box::into_raw(Box::new(SieveScript {
+            sieve: Arc::new(sieve),
+        })),
+        Ok(Err(err)) => {
+            set_error(error, err.to_string());
+            ptr::null_mut()
+        }
+        Err(_) => {
+            set_error(error, "Compiler panicked".to_string());
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Creates a runtime with the default limits, an empty envelope and no user
+/// address. Release it with `sieve_runtime_free`.
+#[no_mangle]
+pub extern "C" fn sieve_runtime_new() -> *mut SieveRuntime {
+    Box::into_raw(Box::default())
+}
+
+/// Sets the maximum number of instructions a script can execute.
+///
+/// # Safety
+///
+/// `runtime` must have been returned by `sieve_runtime_new`.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_runtime_set_cpu_limit(runtime: *mut SieveRuntime, limit: usize) {
+    if let Some(runtime) = runtime.as_mut() {
+        runtime.runtime.set_cpu_limit(limit);
+    }
+}
+
+/// Sets the maximum number of redirects a script can perform.
+///
+/// # Safety
+///
+/// `runtime` must have been returned by `sieve_runtime_new`.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_runtime_set_max_redirects(runtime: *mut SieveRuntime, max: usize) {
+    if let Some(runtime) = runtime.as_mut() {
+        runtime.runtime.set_max_redirects(max);
+    }
+}
+
+/// Sets the maximum number of messages a script can send.
+///
+/// # Safety
+///
+/// `runtime` must have been returned by `sieve_runtime_new`.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_runtime_set_max_out_messages(
+    runtime: *mut SieveRuntime,
+    max: usize,
+) {
+    if let Some(runtime) = runtime.as_mut() {
+        runtime.runtime.set_max_out_messages(max);
+    }
+}
+
+/// Sets the maximum size of a variable.
+///
+/// # Safety
+///
+/// `runtime` must have been returned by `sieve_runtime_new`.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_runtime_set_max_variable_size(
+    runtime: *mut SieveRuntime,
+    size: usize,
+) {
+    if let Some(runtime) = runtime.as_mut() {
+        runtime.runtime.set_max_variable_size(size);
+    }
+}
+
+/// Sets an envelope part such as `from` or `to` for every run. Returns 0 on
+/// success or -1 if the part is unknown or a string is not valid UTF-8.
+///
+/// # Safety
+///
+/// `runtime` must have been returned by `sieve_runtime_new` and `name` and
+/// `value` must be NULL or valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_runtime_set_envelope(
+    runtime: *mut SieveRuntime,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    match (runtime.as_mut(), str_arg(name), str_arg(value)) {
+        (Some(runtime), Some(name), Some(value)) => match Envelope::try_from(name) {
+            Ok(envelope) => {
+                runtime.envelope.push((envelope, value.to_string()));
+                0
+            }
+            Err(_) => -1,
+        },
+        _ => -1,
+    }
+}
+
+/// Sets the address of the user the script runs for. Returns 0 on success or
+/// -1 if the address is not valid UTF-8.
+///
+/// # Safety
+///
+/// `runtime` must have been returned by `sieve_runtime_new` and `address` must
+/// be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_runtime_set_user_address(
+    runtime: *mut SieveRuntime,
+    address: *const c_char,
+) -> c_int {
+    match (runtime.as_mut(), str_arg(address)) {
+        (Some(runtime), Some(address)) => {
+            runtime.user_address = Some(address.to_string());
+            0
+        }
+        _ => -1,
+    }
+}
+
+/// Releases a runtime returned by `sieve_runtime_new`.
+///
+/// # Safety
+///
+/// `runtime` must be NULL or have been returned by `sieve_runtime_new`, and
+/// must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_runtime_free(runtime: *mut SieveRuntime) {
+    if !runtime.is_null() {
+        drop(Box::from_raw(runtime));
+    }
+}
+
+/// Runs a compiled script against a raw message. Returns 0 on success, or -1
+/// with `error` set as in `sieve_compile`. A NULL `runtime` runs the script
+/// with the default limits.
+///
+/// Included scripts are not supported: optional includes are skipped and
+/// required ones fail the run.
+///
+/// # Safety
+///
+/// `script` must have been returned by `sieve_compile`, `runtime` must be NULL
+/// or have been returned by `sieve_runtime_new`, `message` must point to
+/// `message_len` readable bytes and `error` must be NULL or a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_run(
+    script: *const SieveScript,
+    runtime: *const SieveRuntime,
+    message: *const u8,
+    message_len: usize,
+    callback: SieveEventCallback,
+    user_data: *mut c_void,
+    error: *mut *mut c_char,
+) -> c_int {
+    let Some(script) = script.as_ref() else {
+        set_error(error, "Invalid script".to_string());
+        return -1;
+    };
+    let message = bytes(message, message_len);
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let default_runtime;
+        let runtime = match runtime.as_ref() {
+            Some(runtime) => runtime,
+            None => {
+                default_runtime = SieveRuntime::default();
+                &default_runtime
+            }
+        };
+        let mut instance = runtime.runtime.filter(message);
+        for (envelope, value) in &runtime.envelope {
+            instance.set_envelope(*envelope, value.as_str());
+        }
+        if let Some(address) = &runtime.user_address {
+            instance.set_user_address(address.as_str());
+        }
+        let mut input = Input::script("script", script.sieve.clone());
+
+        while let Some(result) = instance.run(input) {
+            let event = result.map_err(|err| err.to_string())?;
+            if let Event::IncludeScript { name, optional } = &event {
+                if !*optional {
+                    return Err(format!("Script {name} not found"));
+                }
+            }
+            let is_function = matches!(&event, Event::Function { .. });
+            let answer = EventData::new(event)
+                .map(|data| data.dispatch(callback, user_data))
+                .unwrap_or(false);
+            input = if is_function {
+                Input::FncResult(Default::default())
+            } else {
+                answer.into()
+            };
+        }
+
+        Ok(())
+    }));
+
+    match result {
+        Ok(Ok(())) => 0,
+        Ok(Err(err)) => {
+            set_error(error, err);
+            -1
+        }
+        Err(_) => {
+            set_error(error, "Runtime panicked".to_string());
+            -1
+        }
+    }
+}
+
+/// Releases a script returned by `sieve_compile`.
+///
+/// # Safety
+///
+/// `script` must be NULL or have been returned by `sieve_compile`, and must
+/// not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_script_free(script: *mut SieveScript) {
+    if !script.is_null() {
+        drop(Box::from_raw(script));
+    }
+}
+
+/// Releases an error message.
+///
+/// # Safety
+///
+/// `string` must be NULL or have been returned by this library, and must not
+/// be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn sieve_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+// Owned copies of the event fields, kept alive while the callback runs
+struct EventData {
+    kind: SieveEventKind,
+    name: Option<CString>,
+    value: Option<CString>,
+    values: Vec<CString>,
+    lists: Vec<CString>,
+    message: Vec<u8>,
+    message_id: usize,
+    number: u64,
+    flag: bool,
+}
+
+impl EventData {
+    fn new(event: Event) -> Option<Self> {
+        let mut data = EventData {
+            kind: SieveEventKind::Discard,
+            name: None,
+            value: None,
+            values: Vec::new(),
+            lists: Vec::new(),
+            message: Vec::new(),
+            message_id: 0,
+            number: 0,
+            flag: false,
+        };
+
+        match event {
+            Event::IncludeScript { name, optional } => {
+                data.kind = SieveEventKind::IncludeScript;
+                data.name = c_string(name.as_str());
+                data.flag = optional;
+            }
+            Event::MailboxExists {
+                mailboxes,
+                special_use,
+            } => {
+                data.kind = SieveEventKind::MailboxExists;
+                data.values = mailboxes
+                    .iter()
+                    .filter_map(|mailbox| match mailbox {
+                        Mailbox::Name(name) | Mailbox::Id(name) => c_string(name),
+                    })
+                    .collect();
+                data.lists = c_strings(&special_use);
+            }
+            Event::ListContains { lists, values, .. } => {
+                data.kind = SieveEventKind::ListContains;
+                data.values = c_strings(&values);
+                data.lists = c_strings(&lists);
+            }
+            Event::DuplicateId { id, expiry, last } => {
+                data.kind = SieveEventKind::DuplicateId;
+                data.name = c_string(&id);
+                data.number = expiry;
+                data.flag = last;
+            }
+            Event::SetEnvelope { envelope, value } => {
+                data.kind = SieveEventKind::SetEnvelope;
+                data.name = c_string(&format!("{envelope:?}").to_lowercase());
+                data.value = c_string(&value);
+            }
+            Event::Keep { flags, message_id } => {
+                data.kind = SieveEventKind::Keep;
+                data.values = c_strings(&flags);
+                data.message_id = message_id;
+            }
+            Event::Discard => {
+                data.kind = SieveEventKind::Discard;
+            }
+            Event::Reject {
+                extended, reason, ..
+            } => {
+                data.kind = SieveEventKind::Reject;
+                data.value = c_string(&reason);
+                data.flag = extended;
+            }
+            Event::FileInto {
+                folder,
+                flags,
+                mailbox_id,
+                special_use,
+                create,
+                message_id,
+            } => {
+                data.kind = SieveEventKind::FileInto;
+                data.name = c_string(&folder);
+                data.value = mailbox_id.as_deref().and_then(c_string);
+                data.values = c_strings(&flags);
+                data.lists = special_use
+                    .as_deref()
+                    .and_then(c_string)
+                    .into_iter()
+                    .collect();
+                data.flag = create;
+                data.message_id = message_id;
+            }
+            Event::SendMessage {
+                recipient,
+                message_id,
+                ..
+            } => {
+                data.kind = SieveEventKind::SendMessage;
+                match recipient {
+                    Recipient::Address(address) => data.name = c_string(&address),
+                    Recipient::List(list) => data.value = c_string(&list),
+                    Recipient::Group(group) => data.values = c_strings(&group),
+                }
+                data.message_id = message_id;
+            }
+            Event::Notify {
+                from,
+                importance_level,
+                options,
+                message,
+                method,
+                ..
+            } => {
+                data.kind = SieveEventKind::Notify;
+                data.name = c_string(&method);
+                data.value = c_string(&message);
+                data.values = c_strings(&options);
+                data.lists = from.as_deref().and_then(c_string).into_iter().collect();
+                data.number = importance_level as u64;
+            }
+            Event::CreatedMessage {
+                message_id,
+                message,
+            } => {
+                data.kind = SieveEventKind::CreatedMessage;
+                data.message = message;
+                data.message_id = message_id;
+            }
+            Event::Function { .. } | Event::PreviewModification { .. } => return None,
+        }
+
+        Some(data)
+    }
+
+    fn dispatch(&self, callback: SieveEventCallback, user_data: *mut c_void) -> bool {
+        let Some(callback) = callback else {
+            return false;
+        };
+        let values = self.values.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
+        let lists = self.lists.iter().map(|v| v.as_ptr()).collect::<Vec<_>>();
+        let event = SieveEvent {
+            kind: self.kind,
+            name: self.name.as_ref().map_or(ptr::null(), |v| v.as_ptr()),
+            value: self.value.as_ref().map_or(ptr::null(), |v| v.as_ptr()),
+            values: values.as_ptr(),
+            num_values: values.len(),
+            lists: lists.as_ptr(),
+            num_lists: lists.len(),
+            message: self.message.as_ptr(),
+            message_len: self.message.len(),
+            message_id: self.message_id,
+            number: self.number,
+            flag: self.flag,
+        };
+
+        unsafe { callback(&event, user_data) != 0 }
+    }
+}
+
+unsafe fn bytes<'x>(data: *const u8, len: usize) -> &'x [u8] {
+    if data.is_null() || len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+unsafe fn str_arg<'x>(value: *const c_char) -> Option<&'x str> {
+    if value.is_null() {
+        None
+    } else {
+        CStr::from_ptr(value).to_str().ok()
+    }
+}
+
+unsafe fn set_error(error: *mut *mut c_char, message: String) {
+    if !error.is_null() {
+        *error = c_string(&message).map_or(ptr::null_mut(), CString::into_raw);
+    }
+}
+
+fn c_string(value: &str) -> Option<CString> {
+    CString::new(value.replace('\0', "")).ok()
+}
+
+fn c_strings(values: &[String]) -> Vec<CString> {
+    values.iter().filter_map(|v| c_string(v)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_int, c_void, CStr},
+        ptr,
+    };
+
+    use super::{
+        sieve_compile, sieve_run, sieve_runtime_free, sieve_runtime_new,
+        sieve_runtime_set_cpu_limit, sieve_runtime_set_envelope, sieve_script_free,
+        sieve_string_free, SieveEvent, SieveEventKind,
+    };
+
+    unsafe extern "C" fn collect(event: *const SieveEvent, user_data: *mut c_void) -> c_int {
+        let event = &*event;
+        let folders = &mut *(user_data as *mut Vec<String>);
+        if event.kind == SieveEventKind::FileInto {
+            folders.push(CStr::from_ptr(event.name).to_string_lossy().into_owned());
+        }
+        1
+    }
+
+    #[test]
+    fn compile_and_run() {
+        let script = b"require \"fileinto\"; fileinto \"Junk\";";
+        let message = b"Subject: test\r\n\r\nbody";
+        let mut folders: Vec<String> = Vec::new();
+
+        unsafe {
+            let compiled = sieve_compile(script.as_ptr(), script.len(), ptr::null_mut());
+            assert!(!compiled.is_null());
+            assert_eq!(
+                sieve_run(
+                    compiled,
+                    ptr::null(),
+                    message.as_ptr(),
+                    message.len(),
+                    Some(collect),
+                    &mut folders as *mut Vec<String> as *mut c_void,
+                    ptr::null_mut(),
+                ),
+                0
+            );
+            sieve_script_free(compiled);
+
+            assert!(sieve_compile(b"if {".as_ptr(), 4, ptr::null_mut()).is_null());
+        }
+
+        assert_eq!(folders, vec!["Junk".to_string()]);
+    }
+
+    #[test]
+    fn run_with_runtime() {
+        let script = b"require [\"envelope\", \"fileinto\"];
+            if envelope :is \"from\" \"sender@example.org\" { fileinto \"Sender\"; }";
+        let message = b"Subject: test\r\n\r\nbody";
+        let mut folders: Vec<String> = Vec::new();
+
+        unsafe {
+            let compiled = sieve_compile(script.as_ptr(), script.len(), ptr::null_mut());
+            assert!(!compiled.is_null());
+            let runtime = sieve_runtime_new();
+            assert_eq!(
+                sieve_runtime_set_envelope(
+                    runtime,
+                    b"from\0".as_ptr() as *const _,
+                    b"sender@example.org\0".as_ptr() as *const _,
+                ),
+                0
+            );
+            assert_eq!(
+                sieve_runtime_set_envelope(
+                    runtime,
+                    b"unknown\0".as_ptr() as *const _,
+                    b"value\0".as_ptr() as *const _,
+                ),
+                -1
+            );
+            assert_eq!(
+                sieve_run(
+                    compiled,
+                    runtime,
+                    message.as_ptr(),
+                    message.len(),
+                    Some(collect),
+                    &mut folders as *mut Vec<String> as *mut c_void,
+                    ptr::null_mut(),
+                ),
+                0
+            );
+            assert_eq!(folders, vec!["Sender".to_string()]);
+
+            sieve_runtime_set_cpu_limit(runtime, 1);
+            let mut error = ptr::null_mut();
+            assert_eq!(
+                sieve_run(
+                    compiled,
+                    runtime,
+                    message.as_ptr(),
+                    message.len(),
+                    None,
+                    ptr::null_mut(),
+                    &mut error,
+                ),
+                -1
+            );
+            assert!(!error.is_null());
+            sieve_string_free(error);
+
+            sieve_runtime_free(runtime);
+            sieve_script_free(compiled);
+        }
+    }
+}
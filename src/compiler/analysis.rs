@@ -0,0 +1,380 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::ops::Range;
+
+use crate::Sieve;
+
+use super::{
+    grammar::{expr::Expression, instruction::Instruction, test::Test, Capability},
+    Value, VariableType,
+};
+
+/// Effects of a compiled script, collected without executing it. Only
+/// constant arguments are listed, `has_dynamic_targets` is set when a
+/// mailbox, redirect address or notification URI is built from variables.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Analysis {
+    pub mailboxes: Vec<String>,
+    pub redirects: Vec<String>,
+    pub notify_uris: Vec<String>,
+    pub includes: Vec<String>,
+    pub headers_tested: Vec<String>,
+    pub headers_modified: Vec<String>,
+    pub capabilities: Vec<Capability>,
+    pub has_dynamic_targets: bool,
+    pub discard_reachable: bool,
+    pub reject_reachable: bool,
+}
+
+impl Sieve {
+    pub fn analyze(&self) -> Analysis {
+        let mut analysis = Analysis::default();
+        let reachable = self.reachable_instructions();
+
+        for (instruction, _) in self
+            .instructions
+            .iter()
+            .zip(reachable)
+            .filter(|(_, is_reachable)| *is_reachable)
+        {
+            match instruction {
+                Instruction::Require(capabilities) => {
+                    for capability in capabilities {
+                        if !analysis.capabilities.contains(capability) {
+                            analysis.capabilities.push(capability.clone());
+                        }
+                    }
+                }
+                Instruction::FileInto(fileinto) => {
+                    analysis.add_target(&fileinto.folder, Target::Mailbox);
+                }
+                Instruction::Redirect(redirect) => {
+                    analysis.add_target(&redirect.address, Target::Redirect);
+                }
+                Instruction::Notify(notify) => {
+                    analysis.add_target(&notify.method, Target::NotifyUri);
+                    if let Some(fcc) = &notify.fcc {
+                        analysis.add_target(&fcc.mailbox, Target::Mailbox);
+                    }
+                }
+                Instruction::Vacation(vacation) => {
+                    if let Some(fcc) = &vacation.fcc {
+                        analysis.add_target(&fcc.mailbox, Target::Mailbox);
+                    }
+                }
+                Instruction::Include(include) => {
+                    analysis.add_target(&include.value, Target::Include);
+                }
+                Instruction::Discard => {
+                    analysis.discard_reachable = true;
+                }
+                Instruction::Reject(_) => {
+                    analysis.reject_reachable = true;
+                }
+                Instruction::AddHeader(add_header) => {
+                    add_header_name(&mut analysis.headers_modified, &add_header.field_name);
+                }
+                Instruction::DeleteHeader(delete_header) => {
+                    add_header_name(&mut analysis.headers_modified, &delete_header.field_name);
+                }
+                Instruction::Test(test) => analysis.add_test(test),
+                Instruction::Eval(expr) => analysis.add_expression(expr),
+                Instruction::Let(let_) => analysis.add_expression(&let_.expr),
+                Instruction::While(while_) => analysis.add_expression(&while_.expr),
+                _ => {}
+            }
+        }
+
+        for list in [
+            &mut analysis.mailboxes,
+            &mut analysis.redirects,
+            &mut analysis.notify_uris,
+            &mut analysis.includes,
+            &mut analysis.headers_tested,
+            &mut analysis.headers_modified,
+        ] {
+            list.sort_unstable();
+            list.dedup();
+        }
+
+        analysis
+    }
+
+    // Splits the script into top-level rules
+    pub(crate) fn rule_ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+
+        while start < self.instructions.len() {
+            let end = self.rule_end(start);
+            ranges.push(start..end);
+            start = end;
+        }
+
+        ranges
+    }
+
+    // A rule extends until the furthest position any of its jumps lands on
+    pub(crate) fn rule_end(&self, start: usize) -> usize {
+        let mut end = start + 1;
+        let mut pos = start;
+        while pos < end && pos < self.instructions.len() {
+            if let Some(jmp_pos) = self.instructions[pos].jump_target() {
+                end = end.max(jmp_pos.min(self.instructions.len()));
+            }
+            pos += 1;
+        }
+        end
+    }
+
+    // Position of the conditional jump of the `if` starting at `pos` and
+    // its target. It is the first jump that does not land on another jump
+    // of an `anyof` or `allof` list.
+    pub(crate) fn if_jump(&self, pos: usize, end: usize) -> Option<(usize, usize)> {
+        for (jmp_pos, instruction) in self.instructions.get(pos..end)?.iter().enumerate() {
+            match instruction {
+                Instruction::Jz(target)
+                    if !matches!(
+                        self.instructions.get(*target as usize),
+                        Some(Instruction::Jz(_) | Instruction::Jnz(_))
+                    ) =>
+                {
+                    return Some((pos + jmp_pos, *target as usize));
+                }
+                Instruction::Test(_)
+                | Instruction::Eval(_)
+                | Instruction::Jz(_)
+                | Instruction::Jnz(_) => (),
+                _ => break,
+            }
+        }
+        None
+    }
+
+    // Instructions that can be reached from the start of the script,
+    // assuming every conditional jump can be taken.
+    pub(crate) fn reachable_instructions(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.instructions.len()];
+        let mut pending = vec![0];
+
+        while let Some(pos) = pending.pop() {
+            match reachable.get_mut(pos) {
+                Some(is_reachable) if !*is_reachable => {
+                    *is_reachable = true;
+                }
+                _ => continue,
+            }
+
+            match &self.instructions[pos] {
+                Instruction::Jmp(jmp_pos) => {
+                    pending.push(*jmp_pos as usize);
+                }
+                Instruction::Jz(jmp_pos) | Instruction::Jnz(jmp_pos) => {
+                    pending.push(*jmp_pos as usize);
+                    pending.push(pos + 1);
+                }
+                Instruction::ForEveryPart(fep) => {
+                    pending.push(fep.jz_pos as usize);
+                    pending.push(pos + 1);
+                }
+                Instruction::While(while_) => {
+                    pending.push(while_.jz_pos as usize);
+                    pending.push(pos + 1);
+                }
+                Instruction::Stop
+                | Instruction::Return
+                | Instruction::Error(_)
+                | Instruction::Invalid(_) => {}
+                _ => {
+                    pending.push(pos + 1);
+                }
+            }
+        }
+
+        reachable
+    }
+}
+
+impl Instruction {
+    pub(crate) fn jump_target(&self) -> Option<usize> {
+        match self {
+            Instruction::Jmp(jmp_pos) | Instruction::Jz(jmp_pos) | Instruction::Jnz(jmp_pos) => {
+                Some(*jmp_pos as usize)
+            }
+            Instruction::ForEveryPart(fep) => Some(fep.jz_pos as usize),
+            Instruction::While(while_) => Some(while_.jz_pos as usize),
+            _ => None,
+        }
+    }
+}
+
+enum Target {
+    Mailbox,
+    Redirect,
+    NotifyUri,
+    Include,
+}
+
+impl Analysis {
+    fn add_target(&mut self, value: &Value, target: Target) {
+        if let Some(value) = value.to_constant() {
+            match target {
+                Target::Mailbox => &mut self.mailboxes,
+                Target::Redirect => &mut self.redirects,
+                Target::NotifyUri => &mut self.notify_uris,
+                Target::Include => &mut self.includes,
+            }
+            .push(value);
+        } else {
+            self.has_dynamic_targets = true;
+        }
+    }
+
+    fn add_test(&mut self, test: &Test) {
+        let headers_tested = &mut self.headers_tested;
+        match test {
+            Test::Header(test) => {
+                for header in &test.header_list {
+                    add_header_name(headers_tested, header);
+                }
+            }
+            Test::Address(test) => {
+                for header in &test.header_list {
+                    add_header_name(headers_tested, header);
+                }
+            }
+            Test::Exists(test) => {
+                for header in &test.header_names {
+                    add_header_name(headers_tested, header);
+                }
+            }
+            Test::Date(test) => {
+                add_header_name(headers_tested, &test.header_name);
+            }
+            Test::String(test) => {
+                for value in &test.source {
+                    add_header_variables(headers_tested, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add_expression(&mut self, expr: &[Expression]) {
+        for item in expr {
+            if let Expression::Variable(VariableType::Header(header)) = item {
+                for name in &header.name {
+                    self.headers_tested.push(name.as_str().to_ascii_lowercase());
+                }
+            }
+        }
+    }
+}
+
+impl Value {
+    pub(crate) fn to_constant(&self) -> Option<String> {
+        match self {
+            Value::Text(text) => Some(text.as_str().to_string()),
+            Value::Number(number) => Some(number.to_string()),
+            Value::Regex(regex) => Some(regex.expr.clone()),
+            Value::Glob(glob) => Some(glob.expr.clone()),
+            Value::Header(header) => Some(header.expr.clone()),
+            Value::List(list) => {
+                let mut result = String::new();
+                for item in list {
+                    result.push_str(&item.to_constant()?);
+                }
+                Some(result)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn add_header_name(headers: &mut Vec<String>, header: &Value) {
+    if let Some(name) = header.to_constant() {
+        headers.push(name.to_ascii_lowercase());
+    }
+}
+
+fn add_header_variables(headers: &mut Vec<String>, value: &Value) {
+    match value {
+        Value::Variable(VariableType::Header(header)) => {
+            for name in &header.name {
+                headers.push(name.as_str().to_ascii_lowercase());
+            }
+        }
+        Value::List(list) => {
+            for item in list {
+                add_header_variables(headers, item);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::grammar::Capability, Compiler};
+
+    #[test]
+    fn analyze() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "editheader", "reject", "variables", "enotify"];
+
+if header :contains "Subject" "invoice" {
+    fileinto "Billing";
+    stop;
+} elsif address :domain "From" "example.org" {
+    fileinto "Work";
+    redirect "boss@example.org";
+} elsif exists "X-Spam" {
+    addheader "X-Filtered" "yes";
+    deleteheader "X-Spam";
+    notify "mailto:admin@example.org";
+} else {
+    set "folder" "Archive/${header.list-id}";
+    fileinto "${folder}";
+}
+
+stop;
+discard;
+"#,
+            )
+            .unwrap();
+
+        let analysis = script.analyze();
+        assert_eq!(analysis.mailboxes, vec!["Billing", "Work"]);
+        assert_eq!(analysis.redirects, vec!["boss@example.org"]);
+        assert_eq!(analysis.notify_uris, vec!["mailto:admin@example.org"]);
+        assert_eq!(analysis.headers_tested, vec!["from", "subject", "x-spam"]);
+        assert_eq!(analysis.headers_modified, vec!["x-filtered", "x-spam"]);
+        assert!(analysis.capabilities.contains(&Capability::FileInto));
+        assert!(analysis.capabilities.contains(&Capability::Reject));
+        assert!(analysis.has_dynamic_targets);
+        assert!(!analysis.discard_reachable);
+        assert!(!analysis.reject_reachable);
+    }
+}
@@ -0,0 +1,221 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::Sieve;
+
+use super::{
+    grammar::{actions::action_set::Let, expr::Expression, instruction::Instruction, test::Test},
+    Span, Value,
+};
+
+/// Externally visible effects a script can cause, in script order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Audit {
+    pub effects: Vec<Effect>,
+}
+
+/// An effect and the command causing it. `targets` lists the constant
+/// addresses, mailboxes, URIs or header names the effect applies to and
+/// `is_dynamic` is set when a target is built from variables.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Effect {
+    pub kind: EffectKind,
+    pub targets: Vec<String>,
+    pub is_dynamic: bool,
+    pub reachable: bool,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EffectKind {
+    Deliver,
+    Discard,
+    CreateMailbox,
+    SendMessage,
+    Reject,
+    AutoReply,
+    Notify,
+    AddHeader,
+    DeleteHeader,
+    ModifyMessage,
+    TrackDuplicate,
+    IncludeScript,
+    CallFunction,
+}
+
+impl Sieve {
+    pub fn audit(&self) -> Audit {
+        let mut audit = Audit::default();
+        let reachable = self.reachable_instructions();
+
+        for (pos, instruction) in self.instructions.iter().enumerate() {
+            let mut effects = EffectList {
+                audit: &mut audit,
+                reachable: reachable.get(pos).copied().unwrap_or_default(),
+                span: self.spans.get(pos).copied().unwrap_or_default(),
+            };
+
+            match instruction {
+                Instruction::Keep(_) => effects.add(EffectKind::Deliver, []),
+                Instruction::FileInto(fi) => {
+                    effects.add(EffectKind::Deliver, [&fi.folder]);
+                    if fi.create {
+                        effects.add(EffectKind::CreateMailbox, [&fi.folder]);
+                    }
+                }
+                Instruction::Redirect(redirect) => {
+                    effects.add(EffectKind::SendMessage, [&redirect.address])
+                }
+                Instruction::Discard => effects.add(EffectKind::Discard, []),
+                Instruction::Reject(_) => effects.add(EffectKind::Reject, []),
+                Instruction::Vacation(vacation) => {
+                    effects.add(EffectKind::AutoReply, []);
+                    if let Some(fcc) = &vacation.fcc {
+                        effects.add(EffectKind::Deliver, [&fcc.mailbox]);
+                        if fcc.create {
+                            effects.add(EffectKind::CreateMailbox, [&fcc.mailbox]);
+                        }
+                    }
+                }
+                Instruction::Notify(notify) => {
+                    effects.add(EffectKind::Notify, [&notify.method]);
+                    if let Some(fcc) = &notify.fcc {
+                        effects.add(EffectKind::Deliver, [&fcc.mailbox]);
+                        if fcc.create {
+                            effects.add(EffectKind::CreateMailbox, [&fcc.mailbox]);
+                        }
+                    }
+                }
+                Instruction::AddHeader(add) => {
+                    effects.add(EffectKind::AddHeader, [&add.field_name])
+                }
+                Instruction::DeleteHeader(delete) => {
+                    effects.add(EffectKind::DeleteHeader, [&delete.field_name])
+                }
+                Instruction::Replace(_) | Instruction::Enclose(_) | Instruction::Convert(_) => {
+                    effects.add(EffectKind::ModifyMessage, [])
+                }
+                Instruction::Test(test) => match test.as_ref() {
+                    Test::Convert(_) => effects.add(EffectKind::ModifyMessage, []),
+                    Test::Duplicate(_) => effects.add(EffectKind::TrackDuplicate, []),
+                    _ => (),
+                },
+                Instruction::Include(include) => {
+                    effects.add(EffectKind::IncludeScript, [&include.value])
+                }
+                Instruction::Eval(expr) | Instruction::Let(Let { expr, .. }) => {
+                    effects.functions(expr)
+                }
+                Instruction::While(while_) => effects.functions(&while_.expr),
+                _ => (),
+            }
+        }
+
+        audit
+    }
+}
+
+struct EffectList<'x> {
+    audit: &'x mut Audit,
+    reachable: bool,
+    span: Span,
+}
+
+impl EffectList<'_> {
+    fn add<'y>(&mut self, kind: EffectKind, targets: impl IntoIterator<Item = &'y Value>) {
+        let mut effect = Effect {
+            kind,
+            targets: Vec::new(),
+            is_dynamic: false,
+            reachable: self.reachable,
+            span: self.span,
+        };
+        for target in targets {
+            if let Some(target) = target.to_constant() {
+                effect.targets.push(target);
+            } else {
+                effect.is_dynamic = true;
+            }
+        }
+        self.audit.effects.push(effect);
+    }
+
+    // Functions missing from the runtime are handed over to the host
+    fn functions(&mut self, expr: &[Expression]) {
+        for item in expr {
+            if let Expression::Function { id, .. } = item {
+                self.audit.effects.push(Effect {
+                    kind: EffectKind::CallFunction,
+                    targets: vec![id.to_string()],
+                    is_dynamic: false,
+                    reachable: self.reachable,
+                    span: self.span,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    use super::EffectKind;
+
+    #[test]
+    fn audit() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "mailbox", "editheader", "variables", "vacation"];
+
+if header :contains "Subject" "invoice" {
+    fileinto :create "Invoices";
+    addheader "X-Filed" "yes";
+} else {
+    redirect "${1}@example.org";
+}
+vacation "Out of office";
+"#,
+            )
+            .unwrap();
+
+        let effects = script.audit().effects;
+        assert_eq!(
+            effects
+                .iter()
+                .map(|effect| (effect.kind, effect.targets.join(","), effect.span.line_num))
+                .collect::<Vec<_>>(),
+            [
+                (EffectKind::Deliver, "Invoices".to_string(), 4),
+                (EffectKind::CreateMailbox, "Invoices".to_string(), 4),
+                (EffectKind::AddHeader, "X-Filed".to_string(), 5),
+                (EffectKind::SendMessage, "".to_string(), 7),
+                (EffectKind::AutoReply, "".to_string(), 9),
+            ]
+        );
+        assert!(effects[3].is_dynamic);
+        assert!(effects.iter().all(|effect| effect.reachable));
+    }
+}
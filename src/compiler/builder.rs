@@ -0,0 +1,691 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! Builds scripts programmatically, without generating and parsing Sieve
+//! text. Strings are used as is, so they never need to be escaped and
+//! `${...}` is not expanded.
+//!
+//! ```
+//! use sieve::{
+//!     compiler::builder::{fileinto, header_contains, stop, Rule, ScriptBuilder},
+//!     Compiler,
+//! };
+//!
+//! let script = ScriptBuilder::new()
+//!     .rule(
+//!         Rule::new()
+//!             .test(header_contains("from", "@example.com"))
+//!             .action(fileinto("Work"))
+//!             .action(stop()),
+//!     )
+//!     .compile(&Compiler::new())
+//!     .unwrap();
+//! ```
+
+use std::sync::Arc;
+
+use crate::{
+    compiler::{
+        grammar::{
+            actions::{
+                action_fileinto::FileInto,
+                action_flags::{Action as FlagAction, EditFlags},
+                action_keep::Keep,
+                action_mime::MimeOpts,
+                action_redirect::{is_domain_allowed, ByTime, Notify, Redirect, Ret},
+                action_reject::Reject,
+            },
+            instruction::Instruction,
+            test::Test,
+            tests::{
+                test_address::TestAddress,
+                test_exists::TestExists,
+                test_header::TestHeader,
+                test_size::{SizeMode, TestSize},
+            },
+            AddressPart, Capability, Comparator, MatchType,
+        },
+        header_key::HeaderKey,
+        CompileError, ErrorType, Glob, Regex, Span, Value,
+    },
+    Compiler, Sieve,
+};
+
+/// An ordered list of rules, compiled with [`ScriptBuilder::compile`].
+#[derive(Debug, Clone, Default)]
+pub struct ScriptBuilder {
+    rules: Vec<Rule>,
+}
+
+/// Actions that run when all the tests of the rule match, or always when
+/// the rule has no tests.
+#[derive(Debug, Clone, Default)]
+pub struct Rule {
+    tests: Vec<Condition>,
+    actions: Vec<Action>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Condition(ConditionType);
+
+#[derive(Debug, Clone)]
+enum ConditionType {
+    Test {
+        test: Test,
+        capability: Option<Capability>,
+    },
+    AllOf(Vec<Condition>),
+    AnyOf(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Action {
+    instruction: Instruction,
+    capability: Option<Capability>,
+}
+
+impl ScriptBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_rule(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.add_rule(rule);
+        self
+    }
+
+    /// Builds the script, applying the instruction and size limits of
+    /// `compiler`. Errors have no position as there is no source text.
+    pub fn compile(&self, compiler: &Compiler) -> Result<Sieve, CompileError> {
+        // Jump targets are absolute, so the require has to be emitted first
+        let mut capabilities = Vec::new();
+        for rule in &self.rules {
+            for test in &rule.tests {
+                test.add_capabilities(&mut capabilities);
+            }
+            for action in &rule.actions {
+                add_capability(&mut capabilities, &action.capability);
+            }
+        }
+        let mut instructions = Vec::new();
+        if !capabilities.is_empty() {
+            instructions.push(Instruction::Require(capabilities.clone()));
+        }
+
+        for rule in &self.rules {
+            let mut jz_pos = None;
+            if !rule.tests.is_empty() {
+                let condition = if rule.tests.len() == 1 {
+                    rule.tests[0].clone()
+                } else {
+                    all_of(rule.tests.iter().cloned())
+                };
+                condition.compile(false, &mut instructions)?;
+                jz_pos = instructions.len().into();
+                instructions.push(Instruction::Jz(u32::MAX));
+            }
+
+            for action in &rule.actions {
+                if let Instruction::Redirect(Redirect {
+                    address: Value::Text(address),
+                    list: false,
+                    ..
+                }) = &action.instruction
+                {
+                    if !compiler.redirect_domains.is_empty()
+                        && !is_domain_allowed(address, &compiler.redirect_domains)
+                    {
+                        return Err(error(ErrorType::RedirectNotAllowed(address.to_string())));
+                    }
+                }
+                instructions.push(action.instruction.clone());
+            }
+
+            if let Some(jz_pos) = jz_pos {
+                instructions[jz_pos] = Instruction::Jz(instructions.len() as u32);
+            }
+
+            if instructions.len() > compiler.max_instructions.min(u32::MAX as usize) {
+                return Err(error(ErrorType::TooManyInstructions));
+            }
+        }
+
+        let sieve = Sieve {
+            uses_body: instructions.iter().any(|i| i.uses_body()),
+            spans: vec![Span::default(); instructions.len()],
+            instructions,
+            num_vars: 0,
+            num_match_vars: 0,
+            unknown_tags: Vec::new(),
+            capabilities,
+            warnings: Vec::new(),
+        };
+
+        if compiler.max_compiled_size > 0
+            && sieve
+                .serialized_size()
+                .map_or(true, |size| size > compiler.max_compiled_size)
+        {
+            return Err(error(ErrorType::CompiledScriptTooLarge));
+        }
+
+        Ok(sieve)
+    }
+}
+
+impl Rule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a test, a rule with several tests matches when all of them do.
+    pub fn add_test(&mut self, test: Condition) {
+        self.tests.push(test);
+    }
+
+    pub fn test(mut self, test: Condition) -> Self {
+        self.add_test(test);
+        self
+    }
+
+    pub fn add_action(&mut self, action: Action) {
+        self.actions.push(action);
+    }
+
+    pub fn action(mut self, action: Action) -> Self {
+        self.add_action(action);
+        self
+    }
+}
+
+impl Condition {
+    fn test(test: Test, capability: Option<Capability>) -> Self {
+        Condition(ConditionType::Test { test, capability })
+    }
+
+    fn add_capabilities(&self, capabilities: &mut Vec<Capability>) {
+        match &self.0 {
+            ConditionType::Test { capability, .. } => add_capability(capabilities, capability),
+            ConditionType::Not(condition) => condition.add_capabilities(capabilities),
+            ConditionType::AllOf(conditions) | ConditionType::AnyOf(conditions) => {
+                for condition in conditions {
+                    condition.add_capabilities(capabilities);
+                }
+            }
+        }
+    }
+
+    // Emits the same instructions as the parser does for the equivalent
+    // test, negations are pushed down to the tests and allof/anyof blocks
+    // jump to the position after their last test.
+    fn compile(
+        &self,
+        is_not: bool,
+        instructions: &mut Vec<Instruction>,
+    ) -> Result<(), CompileError> {
+        match &self.0 {
+            ConditionType::Test { test, .. } => {
+                let mut test = test.clone();
+                test.compile_values()?;
+                let test: Instruction = test.into();
+                instructions.push(if !is_not { test } else { test.set_not() });
+            }
+            ConditionType::Not(condition) => {
+                condition.compile(!is_not, instructions)?;
+            }
+            ConditionType::AllOf(conditions) | ConditionType::AnyOf(conditions) => {
+                let is_all = matches!(&self.0, ConditionType::AllOf(_)) ^ is_not;
+                if conditions.is_empty() {
+                    instructions.push(if is_all { Test::True } else { Test::False }.into());
+                    return Ok(());
+                }
+
+                let mut jmps = Vec::with_capacity(conditions.len() - 1);
+                for (pos, condition) in conditions.iter().enumerate() {
+                    if pos > 0 {
+                        jmps.push(instructions.len());
+                        instructions.push(if is_all {
+                            Instruction::Jz(u32::MAX)
+                        } else {
+                            Instruction::Jnz(u32::MAX)
+                        });
+                    }
+                    condition.compile(is_not, instructions)?;
+                }
+
+                let cur_pos = instructions.len() as u32;
+                for jmp_pos in jmps {
+                    if let Instruction::Jnz(jmp_pos) | Instruction::Jz(jmp_pos) =
+                        &mut instructions[jmp_pos]
+                    {
+                        *jmp_pos = cur_pos;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Test {
+    // Parses header names and compiles patterns, as the parser does for
+    // constant strings
+    fn compile_values(&mut self) -> Result<(), CompileError> {
+        let (header_list, key_list) = match self {
+            Test::Header(test) => (
+                &mut test.header_list,
+                Some((&test.match_type, &mut test.key_list)),
+            ),
+            Test::Address(test) => (
+                &mut test.header_list,
+                Some((&test.match_type, &mut test.key_list)),
+            ),
+            Test::Exists(test) => (&mut test.header_names, None),
+            _ => return Ok(()),
+        };
+
+        for header_name in header_list.iter_mut() {
+            if let Value::Text(name) = header_name {
+                *header_name = Value::Header(
+                    HeaderKey::new(name.as_str())
+                        .ok_or_else(|| error(ErrorType::InvalidHeaderName))?,
+                );
+            }
+        }
+
+        if let Some((match_type, key_list)) = key_list {
+            for key in key_list.iter_mut() {
+                if let Value::Text(expr) = key {
+                    match match_type {
+                        MatchType::Regex(_) => {
+                            *key = Value::Regex(Regex {
+                                regex: fancy_regex::Regex::new(expr).map_err(|err| {
+                                    error(ErrorType::InvalidRegex(format!("{expr}: {err}")))
+                                })?,
+                                expr: expr.to_string(),
+                            });
+                        }
+                        MatchType::Matches(_) => {
+                            *key = Value::Glob(Glob::new(expr.to_string()));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Matches when the header is equal to `value`, ignoring case.
+pub fn header_is(name: impl Into<String>, value: impl Into<String>) -> Condition {
+    header(name, value, MatchType::Is)
+}
+
+/// Matches when the header contains `value`, ignoring case.
+pub fn header_contains(name: impl Into<String>, value: impl Into<String>) -> Condition {
+    header(name, value, MatchType::Contains)
+}
+
+/// Matches the header against a wildcard pattern, ignoring case.
+pub fn header_matches(name: impl Into<String>, pattern: impl Into<String>) -> Condition {
+    header(name, pattern, MatchType::Matches(0))
+}
+
+/// Matches the header against a regular expression, requires `regex`.
+pub fn header_regex(name: impl Into<String>, pattern: impl Into<String>) -> Condition {
+    let mut condition = header(name, pattern, MatchType::Regex(0));
+    if let ConditionType::Test { capability, .. } = &mut condition.0 {
+        *capability = Some(Capability::Regex);
+    }
+    condition
+}
+
+/// Matches when an address in the header is equal to `address`.
+pub fn address_is(name: impl Into<String>, address: impl Into<String>) -> Condition {
+    self::address(name, address, AddressPart::All, MatchType::Is)
+}
+
+/// Matches when an address in the header contains `value`.
+pub fn address_contains(name: impl Into<String>, value: impl Into<String>) -> Condition {
+    self::address(name, value, AddressPart::All, MatchType::Contains)
+}
+
+/// Matches when the domain of an address in the header is equal to `domain`.
+pub fn domain_is(name: impl Into<String>, domain: impl Into<String>) -> Condition {
+    self::address(name, domain, AddressPart::Domain, MatchType::Is)
+}
+
+/// Matches when the message contains the header.
+pub fn exists(name: impl Into<String>) -> Condition {
+    Condition::test(
+        Test::Exists(TestExists {
+            header_names: vec![text(name)],
+            mime: false,
+            mime_anychild: false,
+            is_not: false,
+        }),
+        None,
+    )
+}
+
+/// Matches when the message is larger than `size` bytes.
+pub fn size_over(size: usize) -> Condition {
+    self::size(size, true)
+}
+
+/// Matches when the message is smaller than `size` bytes.
+pub fn size_under(size: usize) -> Condition {
+    self::size(size, false)
+}
+
+pub fn all_of(conditions: impl IntoIterator<Item = Condition>) -> Condition {
+    Condition(ConditionType::AllOf(conditions.into_iter().collect()))
+}
+
+pub fn any_of(conditions: impl IntoIterator<Item = Condition>) -> Condition {
+    Condition(ConditionType::AnyOf(conditions.into_iter().collect()))
+}
+
+pub fn not(condition: Condition) -> Condition {
+    Condition(ConditionType::Not(Box::new(condition)))
+}
+
+pub fn keep() -> Action {
+    action(Instruction::Keep(Keep { flags: Vec::new() }), None)
+}
+
+pub fn discard() -> Action {
+    action(Instruction::Discard, None)
+}
+
+pub fn stop() -> Action {
+    action(Instruction::Stop, None)
+}
+
+/// Files the message into `folder`, requires `fileinto`.
+pub fn fileinto(folder: impl Into<String>) -> Action {
+    action(
+        Instruction::FileInto(FileInto {
+            copy: false,
+            create: false,
+            folder: text(folder),
+            flags: Vec::new(),
+            mailbox_id: None,
+            special_use: None,
+        }),
+        Capability::FileInto.into(),
+    )
+}
+
+pub fn redirect(address: impl Into<String>) -> Action {
+    action(
+        Instruction::Redirect(Redirect {
+            copy: false,
+            address: text(address),
+            notify: Notify::Default,
+            return_of_content: Ret::Default,
+            by_time: ByTime::None,
+            list: false,
+        }),
+        None,
+    )
+}
+
+/// Rejects the message with `reason`, requires `reject`.
+pub fn reject(reason: impl Into<String>) -> Action {
+    action(
+        Instruction::Reject(Reject {
+            ereject: false,
+            reason: text(reason),
+        }),
+        Capability::Reject.into(),
+    )
+}
+
+/// Adds `flag` to the internal flags, requires `imap4flags`.
+pub fn add_flag(flag: impl Into<String>) -> Action {
+    action(
+        Instruction::EditFlags(EditFlags {
+            action: FlagAction::Add,
+            name: None,
+            flags: vec![text(flag)],
+        }),
+        Capability::Imap4Flags.into(),
+    )
+}
+
+fn header(name: impl Into<String>, value: impl Into<String>, match_type: MatchType) -> Condition {
+    Condition::test(
+        Test::Header(TestHeader {
+            header_list: vec![text(name)],
+            key_list: vec![text(value)],
+            match_type,
+            comparator: Comparator::AsciiCaseMap,
+            index: None,
+            mime_opts: MimeOpts::None,
+            mime: false,
+            mime_anychild: false,
+            is_not: false,
+        }),
+        None,
+    )
+}
+
+fn address(
+    name: impl Into<String>,
+    value: impl Into<String>,
+    address_part: AddressPart,
+    match_type: MatchType,
+) -> Condition {
+    Condition::test(
+        Test::Address(TestAddress {
+            header_list: vec![text(name)],
+            key_list: vec![text(value)],
+            address_part,
+            match_type,
+            comparator: Comparator::AsciiCaseMap,
+            index: None,
+            mime: false,
+            mime_anychild: false,
+            is_not: false,
+        }),
+        None,
+    )
+}
+
+fn size(limit: usize, over: bool) -> Condition {
+    Condition::test(
+        Test::Size(TestSize {
+            over,
+            limit,
+            mode: SizeMode::Raw,
+            is_not: false,
+        }),
+        None,
+    )
+}
+
+fn action(instruction: Instruction, capability: Option<Capability>) -> Action {
+    Action {
+        instruction,
+        capability,
+    }
+}
+
+fn text(value: impl Into<String>) -> Value {
+    Value::Text(Arc::new(value.into()))
+}
+
+fn add_capability(capabilities: &mut Vec<Capability>, capability: &Option<Capability>) {
+    if let Some(capability) = capability {
+        if !capabilities.contains(capability) {
+            capabilities.push(capability.clone());
+        }
+    }
+}
+
+fn error(error_type: ErrorType) -> CompileError {
+    CompileError {
+        line_num: 0,
+        line_pos: 0,
+        error_type,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::ErrorType, Compiler, Event, Input, Recipient, Runtime};
+
+    use super::{
+        any_of, discard, exists, fileinto, header_contains, header_is, not, redirect, size_over,
+        stop, Rule, ScriptBuilder,
+    };
+
+    #[test]
+    fn script_builder() {
+        let compiler = Compiler::new();
+        let script = ScriptBuilder::new()
+            .rule(
+                Rule::new()
+                    .test(header_contains("from", "@example.com"))
+                    .test(not(any_of([exists("x-spam"), size_over(100)])))
+                    .action(fileinto("Work"))
+                    .action(stop()),
+            )
+            .rule(Rule::new().action(redirect("bob@example.org")))
+            .compile(&compiler)
+            .unwrap();
+        let expected = compiler
+            .compile(
+                br#"require "fileinto";
+if allof(header :contains "from" "@example.com", not anyof(exists "x-spam", size :over 100)) {
+    fileinto "Work";
+    stop;
+}
+redirect "bob@example.org";
+"#,
+            )
+            .unwrap();
+        assert_eq!(script.instructions, expected.instructions);
+        assert_eq!(script.capabilities, expected.capabilities);
+
+        // Strings are not parsed, so quotes and variables are matched as is
+        let script = ScriptBuilder::new()
+            .rule(
+                Rule::new()
+                    .test(header_is("subject", "a \"quoted\" ${x}"))
+                    .action(fileinto("${x}")),
+            )
+            .compile(&compiler)
+            .unwrap();
+        let mut instance = Runtime::new().filter(b"Subject: a \"quoted\" ${x}\r\n\r\nHi");
+        let mut input = Input::script("", script);
+        let mut events = Vec::new();
+        while let Some(event) = instance.run(input) {
+            events.push(event.unwrap());
+            input = true.into();
+        }
+        assert!(
+            matches!(
+                events.as_slice(),
+                [Event::FileInto { folder, .. }] if folder == "${x}"
+            ),
+            "{events:?}"
+        );
+
+        let err = ScriptBuilder::new()
+            .rule(
+                Rule::new()
+                    .test(header_is("bad header", "x"))
+                    .action(stop()),
+            )
+            .compile(&compiler)
+            .unwrap_err();
+        assert!(matches!(err.error_type(), ErrorType::InvalidHeaderName));
+    }
+
+    #[test]
+    fn script_builder_without_capabilities() {
+        let compiler = Compiler::new();
+        let script = ScriptBuilder::new()
+            .rule(
+                Rule::new()
+                    .test(header_is("subject", "spam"))
+                    .action(discard()),
+            )
+            .rule(
+                Rule::new()
+                    .test(header_contains("to", "bob"))
+                    .action(redirect("bob@example.org")),
+            )
+            .compile(&compiler)
+            .unwrap();
+        let expected = compiler
+            .compile(
+                br#"if header "subject" "spam" {
+    discard;
+}
+if header :contains "to" "bob" {
+    redirect "bob@example.org";
+}
+"#,
+            )
+            .unwrap();
+        assert_eq!(script.instructions, expected.instructions);
+
+        let run = |raw_message: &[u8]| {
+            let runtime = Runtime::new();
+            let mut instance = runtime.filter(raw_message);
+            let mut input = Input::script("", script.clone());
+            let mut events = Vec::new();
+            while let Some(event) = instance.run(input) {
+                events.push(event.unwrap());
+                input = true.into();
+            }
+            events
+        };
+
+        let events = run(b"Subject: hello\r\nTo: bob@example.org\r\n\r\nHi");
+        assert!(
+            matches!(
+                events.as_slice(),
+                [Event::SendMessage { recipient: Recipient::Address(address), .. }]
+                    if address == "bob@example.org"
+            ),
+            "{events:?}"
+        );
+        let events = run(b"Subject: hello\r\nTo: jane@example.org\r\n\r\nHi");
+        assert!(
+            matches!(events.as_slice(), [Event::Keep { .. }]),
+            "{events:?}"
+        );
+        assert!(run(b"Subject: spam\r\nTo: jane@example.org\r\n\r\nHi").is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::Sieve;
+
+use super::grammar::{
+    expr::{functions::BuiltIn, Expression},
+    instruction::Instruction,
+    test::Test,
+    MatchType,
+};
+
+/// Cost of executing any instruction.
+pub const INSTRUCTION_COST: u64 = 1;
+/// Cost of comparing one key with `:is`, `:contains`, `:matches` or a relational match.
+pub const KEY_COST: u64 = 2;
+/// Cost of matching one key, or evaluating one `regex_capture`, with a regular expression.
+pub const REGEX_COST: u64 = 20;
+/// Cost of decoding and scanning the message body.
+pub const BODY_SCAN_COST: u64 = 100;
+/// Multiplier applied to instructions inside each `foreverypart` or `while` loop.
+pub const LOOP_FACTOR: u64 = 10;
+
+/// Deterministic complexity metrics of a compiled script.
+///
+/// `cost` is an upper bound estimate computed assuming every instruction
+/// is executed: each instruction costs `INSTRUCTION_COST`, tests add
+/// `KEY_COST` per key (`REGEX_COST` for regular expressions), body scans
+/// add `BODY_SCAN_COST` and everything inside a loop is multiplied by
+/// `LOOP_FACTOR` for each enclosing loop. Included scripts are not
+/// followed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Complexity {
+    pub instructions: usize,
+    pub regexes: usize,
+    pub body_scans: usize,
+    pub nesting_depth: usize,
+    pub cost: u64,
+}
+
+impl Sieve {
+    pub fn complexity(&self) -> Complexity {
+        let mut complexity = Complexity {
+            instructions: self.instructions.len(),
+            ..Default::default()
+        };
+
+        // Blocks are delimited by the conditional jump of an `if` (which
+        // never lands on another jump, unlike the ones inside `anyof` and
+        // `allof`) or by a loop.
+        let mut block_depth = vec![0i64; self.instructions.len() + 1];
+        let mut loop_depth = vec![0i64; self.instructions.len() + 1];
+        for (pos, instruction) in self.instructions.iter().enumerate() {
+            let (end, is_loop) = match instruction {
+                Instruction::Jz(jmp_pos)
+                    if *jmp_pos as usize > pos
+                        && !matches!(
+                            self.instructions.get(*jmp_pos as usize),
+                            Some(Instruction::Jz(_) | Instruction::Jnz(_))
+                        ) =>
+                {
+                    (*jmp_pos as usize, false)
+                }
+                Instruction::ForEveryPart(fep) if fep.jz_pos as usize > pos => {
+                    (fep.jz_pos as usize, true)
+                }
+                Instruction::While(while_) if while_.jz_pos as usize > pos => {
+                    (while_.jz_pos as usize, true)
+                }
+                _ => continue,
+            };
+            let end = end.min(self.instructions.len());
+            block_depth[pos + 1] += 1;
+            block_depth[end] -= 1;
+            if is_loop {
+                loop_depth[pos + 1] += 1;
+                loop_depth[end] -= 1;
+            }
+        }
+
+        let mut depth = 0;
+        let mut loops = 0;
+        for (pos, instruction) in self.instructions.iter().enumerate() {
+            depth += block_depth[pos];
+            loops += loop_depth[pos];
+            complexity.nesting_depth = complexity.nesting_depth.max(depth as usize);
+
+            let mut cost = INSTRUCTION_COST;
+            match instruction {
+                Instruction::Test(test) => {
+                    if matches!(test.as_ref(), Test::Body(_)) {
+                        complexity.body_scans += 1;
+                        cost += BODY_SCAN_COST;
+                    }
+                    if let Some((match_type, num_keys)) = test.match_keys() {
+                        if matches!(match_type, MatchType::Regex(_)) {
+                            complexity.regexes += 1;
+                            cost += REGEX_COST * num_keys as u64;
+                        } else {
+                            cost += KEY_COST * num_keys as u64;
+                        }
+                    }
+                }
+                Instruction::DeleteHeader(delete_header) => {
+                    if matches!(delete_header.match_type, MatchType::Regex(_)) {
+                        complexity.regexes += 1;
+                        cost += REGEX_COST * delete_header.value_patterns.len() as u64;
+                    } else {
+                        cost += KEY_COST * delete_header.value_patterns.len() as u64;
+                    }
+                }
+                Instruction::ExtractText(_) => {
+                    complexity.body_scans += 1;
+                    cost += BODY_SCAN_COST;
+                }
+                Instruction::Eval(expr) => cost += complexity.add_expression(expr),
+                Instruction::Let(let_) => cost += complexity.add_expression(&let_.expr),
+                Instruction::While(while_) => cost += complexity.add_expression(&while_.expr),
+                _ => {}
+            }
+
+            complexity.cost = complexity
+                .cost
+                .saturating_add(cost.saturating_mul(LOOP_FACTOR.saturating_pow(loops as u32)));
+        }
+
+        complexity
+    }
+}
+
+impl Complexity {
+    fn add_expression(&mut self, expr: &[Expression]) -> u64 {
+        let mut cost = 0;
+        for item in expr {
+            match item {
+                Expression::BuiltIn {
+                    function: BuiltIn::RegexCapture,
+                    ..
+                } => {
+                    self.regexes += 1;
+                    cost += REGEX_COST;
+                }
+                Expression::BuiltIn {
+                    function: BuiltIn::BodyText,
+                    ..
+                } => {
+                    self.body_scans += 1;
+                    cost += BODY_SCAN_COST;
+                }
+                _ => {}
+            }
+        }
+        cost
+    }
+}
+
+impl Test {
+    fn match_keys(&self) -> Option<(&MatchType, usize)> {
+        match self {
+            Test::Address(test) => Some((&test.match_type, test.key_list.len())),
+            Test::Envelope(test) => Some((&test.match_type, test.key_list.len())),
+            Test::Header(test) => Some((&test.match_type, test.key_list.len())),
+            Test::Body(test) => Some((&test.match_type, test.key_list.len())),
+            Test::Date(test) => Some((&test.match_type, test.key_list.len())),
+            Test::CurrentDate(test) => Some((&test.match_type, test.key_list.len())),
+            Test::String(test) | Test::Environment(test) => {
+                Some((&test.match_type, test.key_list.len()))
+            }
+            Test::NotifyMethodCapability(test) => Some((&test.match_type, test.key_list.len())),
+            Test::HasFlag(test) => Some((&test.match_type, test.flags.len())),
+            Test::Metadata(test) => Some((&test.match_type, test.key_list.len())),
+            Test::SpamTest(test) => Some((&test.match_type, 1)),
+            Test::VirusTest(test) => Some((&test.match_type, 1)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    use super::{Complexity, BODY_SCAN_COST, KEY_COST, REGEX_COST};
+
+    #[test]
+    fn complexity() {
+        let compiler = Compiler::new();
+        let script = compiler.compile(b"keep;").unwrap();
+        assert_eq!(
+            script.complexity(),
+            Complexity {
+                instructions: script.instructions.len(),
+                regexes: 0,
+                body_scans: 0,
+                nesting_depth: 0,
+                cost: script.instructions.len() as u64,
+            }
+        );
+
+        let script = compiler
+            .compile(
+                br#"require ["regex", "body", "fileinto"];
+if anyof (header :regex "Subject" ["^a", "^b"], header :is "From" "x") {
+    if allof (body :contains "foo", true) {
+        if true { fileinto "A"; }
+    }
+} elsif header :contains "To" "bar" {
+    discard;
+}
+"#,
+            )
+            .unwrap();
+        let complexity = script.complexity();
+        assert_eq!(complexity.regexes, 1);
+        assert_eq!(complexity.body_scans, 1);
+        assert_eq!(complexity.nesting_depth, 3);
+        assert_eq!(
+            complexity.cost,
+            script.instructions.len() as u64 + 2 * REGEX_COST + 3 * KEY_COST + BODY_SCAN_COST
+        );
+
+        // Loops multiply the cost of their contents
+        let plain = compiler
+            .compile(br#"require "body"; if body :contains "x" { keep; }"#)
+            .unwrap()
+            .complexity();
+        let looped = compiler
+            .compile(
+                br#"require ["body", "foreverypart"];
+foreverypart { if body :contains "x" { keep; } }"#,
+            )
+            .unwrap()
+            .complexity();
+        assert_eq!(looped.nesting_depth, 2);
+        assert!(looped.cost > plain.cost * 9);
+    }
+}
@@ -0,0 +1,374 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::Sieve;
+
+use super::grammar::{
+    actions::action_flags::Action, instruction::Instruction, test::Test, Capability,
+};
+
+/// Semantic differences between two compiled scripts. Scripts are split
+/// into top-level rules (an `if`/`elsif`/`else` chain or a single command)
+/// which are matched regardless of their position, so formatting,
+/// comments and reordering of `require` lists do not show up as changes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScriptDiff {
+    pub capabilities_added: Vec<Capability>,
+    pub capabilities_removed: Vec<Capability>,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    RuleAdded(Rule),
+    RuleRemoved(Rule),
+    RuleChanged {
+        old: Rule,
+        new: Rule,
+        targets: Vec<TargetChange>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    /// Position of the rule among the top-level rules of its script.
+    pub index: usize,
+    pub actions: Vec<RuleAction>,
+}
+
+/// An action performed by a rule. `target` is the constant mailbox,
+/// address, URI, header or flag list the action applies to, or `None`
+/// when the action has no target or it is built from variables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleAction {
+    pub name: &'static str,
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetChange {
+    pub action: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+struct RuleBody {
+    index: usize,
+    instructions: Vec<Instruction>,
+    condition: Vec<Test>,
+    actions: Vec<RuleAction>,
+}
+
+impl Sieve {
+    pub fn diff(&self, other: &Sieve) -> ScriptDiff {
+        let old_caps = self.required_capabilities();
+        let new_caps = other.required_capabilities();
+        let mut diff = ScriptDiff {
+            capabilities_added: new_caps
+                .iter()
+                .filter(|c| !old_caps.contains(c))
+                .cloned()
+                .collect(),
+            capabilities_removed: old_caps
+                .iter()
+                .filter(|c| !new_caps.contains(c))
+                .cloned()
+                .collect(),
+            changes: Vec::new(),
+        };
+
+        let old_rules = self.rules();
+        let new_rules = other.rules();
+        let mut old_matched = vec![false; old_rules.len()];
+        let mut new_matched = vec![false; new_rules.len()];
+        let mut changed = Vec::new();
+
+        // Identical rules, wherever they are placed
+        for (old_pos, old_rule) in old_rules.iter().enumerate() {
+            if let Some(new_pos) = (0..new_rules.len()).find(|&new_pos| {
+                !new_matched[new_pos] && new_rules[new_pos].instructions == old_rule.instructions
+            }) {
+                old_matched[old_pos] = true;
+                new_matched[new_pos] = true;
+            }
+        }
+
+        // Rules with the same condition, or unconditional rules performing
+        // the same kind of actions, are reported as changed
+        for (old_pos, old_rule) in old_rules.iter().enumerate() {
+            if old_matched[old_pos] {
+                continue;
+            }
+            if let Some(new_pos) = (0..new_rules.len()).find(|&new_pos| {
+                let new_rule = &new_rules[new_pos];
+                !new_matched[new_pos]
+                    && new_rule.condition == old_rule.condition
+                    && (!old_rule.condition.is_empty()
+                        || new_rule
+                            .actions
+                            .iter()
+                            .map(|a| a.name)
+                            .eq(old_rule.actions.iter().map(|a| a.name)))
+            }) {
+                old_matched[old_pos] = true;
+                new_matched[new_pos] = true;
+                changed.push((old_pos, new_pos));
+            }
+        }
+
+        for (old_pos, old_rule) in old_rules.iter().enumerate() {
+            if let Some((_, new_pos)) = changed.iter().find(|(pos, _)| *pos == old_pos) {
+                let new_rule = &new_rules[*new_pos];
+                diff.changes.push(Change::RuleChanged {
+                    old: old_rule.to_rule(),
+                    new: new_rule.to_rule(),
+                    targets: target_changes(&old_rule.actions, &new_rule.actions),
+                });
+            } else if !old_matched[old_pos] {
+                diff.changes.push(Change::RuleRemoved(old_rule.to_rule()));
+            }
+        }
+        for (new_pos, new_rule) in new_rules.iter().enumerate() {
+            if !new_matched[new_pos] {
+                diff.changes.push(Change::RuleAdded(new_rule.to_rule()));
+            }
+        }
+
+        diff
+    }
+
+    pub(crate) fn required_capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        for instruction in &self.instructions {
+            if let Instruction::Require(required) = instruction {
+                for capability in required {
+                    if !capabilities.contains(capability) {
+                        capabilities.push(capability.clone());
+                    }
+                }
+            }
+        }
+        capabilities
+    }
+
+    fn rules(&self) -> Vec<RuleBody> {
+        self.rule_ranges()
+            .into_iter()
+            .filter(|range| !matches!(&self.instructions[range.start], Instruction::Require(_)))
+            .enumerate()
+            .map(|(index, range)| {
+                let start = range.start;
+                let instructions = self.instructions[range]
+                    .iter()
+                    .map(|instruction| instruction.relative_to(start))
+                    .collect::<Vec<_>>();
+                let condition = instructions
+                    .iter()
+                    .take_while(|instruction| {
+                        matches!(
+                            instruction,
+                            Instruction::Test(_) | Instruction::Jz(_) | Instruction::Jnz(_)
+                        )
+                    })
+                    .filter_map(|instruction| match instruction {
+                        Instruction::Test(test) => Some(test.as_ref().clone()),
+                        _ => None,
+                    })
+                    .collect();
+                let actions = instructions
+                    .iter()
+                    .filter_map(Instruction::to_rule_action)
+                    .collect();
+
+                RuleBody {
+                    index,
+                    instructions,
+                    condition,
+                    actions,
+                }
+            })
+            .collect()
+    }
+}
+
+impl RuleBody {
+    fn to_rule(&self) -> Rule {
+        Rule {
+            index: self.index,
+            actions: self.actions.clone(),
+        }
+    }
+}
+
+impl Instruction {
+    fn relative_to(&self, start: usize) -> Instruction {
+        let mut instruction = self.clone();
+        match &mut instruction {
+            Instruction::Jmp(jmp_pos) | Instruction::Jz(jmp_pos) | Instruction::Jnz(jmp_pos) => {
+                *jmp_pos = jmp_pos.wrapping_sub(start as u32);
+            }
+            Instruction::ForEveryPart(fep) => {
+                fep.jz_pos = fep.jz_pos.wrapping_sub(start as u32);
+            }
+            Instruction::While(while_) => {
+                while_.jz_pos = while_.jz_pos.wrapping_sub(start as u32);
+            }
+            _ => {}
+        }
+        instruction
+    }
+
+    fn to_rule_action(&self) -> Option<RuleAction> {
+        let (name, target) = match self {
+            Instruction::Keep(_) => ("keep", None),
+            Instruction::FileInto(fileinto) => ("fileinto", fileinto.folder.to_constant()),
+            Instruction::Redirect(redirect) => ("redirect", redirect.address.to_constant()),
+            Instruction::Discard => ("discard", None),
+            Instruction::Stop => ("stop", None),
+            Instruction::Reject(reject) => {
+                (if reject.ereject { "ereject" } else { "reject" }, None)
+            }
+            Instruction::Vacation(_) => ("vacation", None),
+            Instruction::Notify(notify) => ("notify", notify.method.to_constant()),
+            Instruction::AddHeader(add_header) => {
+                ("addheader", add_header.field_name.to_constant())
+            }
+            Instruction::DeleteHeader(delete_header) => {
+                ("deleteheader", delete_header.field_name.to_constant())
+            }
+            Instruction::EditFlags(edit_flags) => (
+                match edit_flags.action {
+                    Action::Set => "setflag",
+                    Action::Add => "addflag",
+                    Action::Remove => "removeflag",
+                },
+                edit_flags
+                    .flags
+                    .iter()
+                    .map(|flag| flag.to_constant())
+                    .collect::<Option<Vec<_>>>()
+                    .map(|flags| flags.join(" ")),
+            ),
+            Instruction::Include(include) => ("include", include.value.to_constant()),
+            Instruction::Error(_) => ("error", None),
+            _ => return None,
+        };
+
+        Some(RuleAction { name, target })
+    }
+}
+
+// Pairs actions of the same kind by their order within the rule
+fn target_changes(old: &[RuleAction], new: &[RuleAction]) -> Vec<TargetChange> {
+    let mut changes = Vec::new();
+    let mut new_used = vec![false; new.len()];
+
+    for old_action in old {
+        if let Some(new_pos) =
+            (0..new.len()).find(|&pos| !new_used[pos] && new[pos].name == old_action.name)
+        {
+            new_used[new_pos] = true;
+            if new[new_pos].target != old_action.target {
+                changes.push(TargetChange {
+                    action: old_action.name,
+                    old: old_action.target.clone(),
+                    new: new[new_pos].target.clone(),
+                });
+            }
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::grammar::Capability, Compiler};
+
+    use super::{Change, TargetChange};
+
+    #[test]
+    fn diff() {
+        let compiler = Compiler::new();
+        let old = compiler
+            .compile(
+                br#"require ["fileinto", "reject"];
+if header :contains "Subject" "invoice" { fileinto "Billing"; stop; }
+if address :domain "From" "spam.org" { reject "No thanks"; }
+fileinto "Archive";
+"#,
+            )
+            .unwrap();
+        let new = compiler
+            .compile(
+                br#"# Reformatted and reordered
+require ["fileinto",
+         "imap4flags"];
+
+fileinto "Old";
+
+if header :contains "Subject" "invoice"
+{
+    fileinto "Invoices";
+    stop;
+}
+
+if exists "X-Spam" { addflag "\\Junk"; }
+"#,
+            )
+            .unwrap();
+
+        assert!(old.diff(&old).changes.is_empty());
+
+        let diff = old.diff(&new);
+        assert_eq!(diff.capabilities_added, vec![Capability::Imap4Flags]);
+        assert_eq!(diff.capabilities_removed, vec![Capability::Reject]);
+        assert_eq!(diff.changes.len(), 4, "{:#?}", diff.changes);
+
+        match &diff.changes[0] {
+            Change::RuleChanged { old, new, targets } => {
+                assert_eq!((old.index, new.index), (0, 1));
+                assert_eq!(
+                    targets,
+                    &vec![TargetChange {
+                        action: "fileinto",
+                        old: Some("Billing".into()),
+                        new: Some("Invoices".into()),
+                    }]
+                );
+            }
+            change => panic!("Unexpected change {change:?}"),
+        }
+        assert!(matches!(&diff.changes[1], Change::RuleRemoved(rule) if rule.index == 1));
+        match &diff.changes[2] {
+            Change::RuleChanged { targets, .. } => {
+                assert_eq!(targets[0].old.as_deref(), Some("Archive"));
+                assert_eq!(targets[0].new.as_deref(), Some("Old"));
+            }
+            change => panic!("Unexpected change {change:?}"),
+        }
+        assert!(
+            matches!(&diff.changes[3], Change::RuleAdded(rule) if rule.actions[0].name == "addflag")
+        );
+    }
+}
@@ -0,0 +1,1191 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! Structured script generation for fuzzing. [`Script`] implements
+//! [`Arbitrary`] and renders to well-formed Sieve source using unusual but
+//! valid combinations of extensions, match types and string contents, so
+//! fuzz targets exercise the compiler and interpreter past the lexer.
+
+use std::fmt::{self, Display, Write};
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+const MAX_DEPTH: usize = 4;
+const MAX_COMMANDS: usize = 6;
+const MAX_TESTS: usize = 4;
+const MAX_VALUES: usize = 3;
+const MAX_FRAGMENTS: usize = 6;
+
+static LITERALS: &[&str] = &[
+    "",
+    "a",
+    "INBOX",
+    "Hello World",
+    "0",
+    "42",
+    "-1",
+    "18446744073709551616",
+    " ",
+    "\t",
+    "\r\n",
+    "\"",
+    "\\",
+    "*",
+    "?",
+    "\\*",
+    "$",
+    "${",
+    "}",
+    "@",
+    "user+detail@example.org",
+    "é",
+    "€uro",
+    "日本語",
+    "😀",
+    "=?utf-8?q?caf=C3=A9?=",
+];
+static ENCODED: &[&str] = &[
+    "${hex:41}",
+    "${hex: 20 7e }",
+    "${unicode:1F600}",
+    "${unicode: 65 301}",
+    "${hex:}",
+];
+static VARIABLES: &[&str] = &["a", "b", "var", "x_1", "_", "Long_Variable_Name"];
+static HEADERS: &[&str] = &[
+    "Subject",
+    "From",
+    "To",
+    "Cc",
+    "Bcc",
+    "Reply-To",
+    "Sender",
+    "Received",
+    "Message-ID",
+    "Content-Type",
+    "List-Id",
+    "X-Spam-Status",
+    "X-Custom",
+];
+static ADDRESS_HEADERS: &[&str] = &["From", "To", "Cc", "Bcc", "Sender", "Reply-To"];
+static ADDRESSES: &[&str] = &[
+    "user@example.org",
+    "user+detail@example.org",
+    "\"quoted user\"@example.org",
+    "user@[127.0.0.1]",
+];
+static MAILBOXES: &[&str] = &["INBOX", "Junk", "a/b/c", "Ünïcödé", "INBOX.Sub Folder"];
+static FLAGS: &[&str] = &["\\Seen", "\\Flagged", "\\Deleted", "$Junk", "custom"];
+static REGEX_META: &str = "\\.+*?()|[]{}^$";
+
+/// A script made of the commands and tests supported by the generator.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Script {
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Keep,
+    Discard,
+    Stop,
+    FileInto {
+        copy: bool,
+        create: bool,
+        mailbox: Text,
+    },
+    Redirect {
+        copy: bool,
+        address: Text,
+    },
+    Reject(Text),
+    Set {
+        modifier: Option<Modifier>,
+        name: Name,
+        value: Text,
+    },
+    Flags {
+        action: FlagAction,
+        flags: Vec<Text>,
+    },
+    AddHeader {
+        last: bool,
+        name: Name,
+        value: Text,
+    },
+    DeleteHeader {
+        name: Name,
+        values: Vec<Text>,
+    },
+    Vacation {
+        days: u8,
+        subject: Option<Text>,
+        reason: Text,
+    },
+    If {
+        branches: Vec<(Test, Vec<Command>)>,
+        otherwise: Option<Vec<Command>>,
+    },
+    ForEveryPart(Vec<Command>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Test {
+    True,
+    False,
+    Not(Box<Test>),
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Header {
+        match_: Match,
+        headers: Vec<Name>,
+        keys: Vec<Text>,
+    },
+    Address {
+        part: AddressPart,
+        match_: Match,
+        headers: Vec<Name>,
+        keys: Vec<Text>,
+    },
+    Envelope {
+        part: AddressPart,
+        match_: Match,
+        from: bool,
+        keys: Vec<Text>,
+    },
+    Exists(Vec<Name>),
+    Size {
+        over: bool,
+        limit: u32,
+        unit: Option<char>,
+    },
+    Body {
+        transform: BodyTransform,
+        match_: Match,
+        keys: Vec<Text>,
+    },
+    String {
+        match_: Match,
+        sources: Vec<Text>,
+        keys: Vec<Text>,
+    },
+    HasFlag(Vec<Text>),
+}
+
+/// A string built from fragments, rendered into a quoted string.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Text(pub Vec<Fragment>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fragment {
+    Literal(&'static str),
+    Char(char),
+    Encoded(&'static str),
+    Variable(&'static str),
+    MatchVariable(u8),
+}
+
+/// A header, variable or other identifier taken from a fixed list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Name(pub &'static str);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    pub comparator: Comparator,
+    pub match_type: MatchType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Arbitrary)]
+pub enum Comparator {
+    Default,
+    Octet,
+    AsciiCaseMap,
+    AsciiNumeric,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Arbitrary)]
+pub enum MatchType {
+    Is,
+    Contains,
+    Matches,
+    Regex,
+    Value(Relation),
+    Count(Relation),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Arbitrary)]
+pub enum Relation {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Arbitrary)]
+pub enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+    User,
+    Detail,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodyTransform {
+    Default,
+    Raw,
+    Text,
+    Content(Vec<Text>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Arbitrary)]
+pub enum Modifier {
+    Lower,
+    Upper,
+    LowerFirst,
+    UpperFirst,
+    QuoteWildcard,
+    Length,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Arbitrary)]
+pub enum FlagAction {
+    Set,
+    Add,
+    Remove,
+}
+
+impl<'a> Arbitrary<'a> for Script {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Script {
+            commands: block(u, 0)?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Text {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let len = u.int_in_range(0..=MAX_FRAGMENTS)?;
+        let mut fragments = Vec::with_capacity(len);
+        for _ in 0..len {
+            fragments.push(match u.int_in_range(0..=9)? {
+                0..=4 => Fragment::Literal(*u.choose(LITERALS)?),
+                5 | 6 => Fragment::Char(u.arbitrary()?),
+                7 => Fragment::Encoded(*u.choose(ENCODED)?),
+                8 => Fragment::Variable(*u.choose(VARIABLES)?),
+                _ => Fragment::MatchVariable(u.int_in_range(0..=9)?),
+            });
+        }
+        Ok(Text(fragments))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Match {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let match_type: MatchType = u.arbitrary()?;
+        let mut comparator: Comparator = u.arbitrary()?;
+        // i;ascii-numeric does not support substring matching
+        if comparator == Comparator::AsciiNumeric
+            && matches!(
+                match_type,
+                MatchType::Contains | MatchType::Matches | MatchType::Regex
+            )
+        {
+            comparator = Comparator::Default;
+        }
+        Ok(Match {
+            comparator,
+            match_type,
+        })
+    }
+}
+
+impl Text {
+    pub fn literal(text: &'static str) -> Self {
+        Text(vec![Fragment::Literal(text)])
+    }
+}
+
+fn block(u: &mut Unstructured, depth: usize) -> Result<Vec<Command>> {
+    let len = u.int_in_range(0..=MAX_COMMANDS)?;
+    let mut commands = Vec::with_capacity(len);
+    for _ in 0..len {
+        if u.is_empty() {
+            break;
+        }
+        commands.push(command(u, depth)?);
+    }
+    Ok(commands)
+}
+
+fn command(u: &mut Unstructured, depth: usize) -> Result<Command> {
+    let max = if depth < MAX_DEPTH { 13 } else { 11 };
+    Ok(match u.int_in_range(0..=max)? {
+        0 => Command::Keep,
+        1 => Command::Discard,
+        2 => Command::Stop,
+        3 => Command::FileInto {
+            copy: u.arbitrary()?,
+            create: u.arbitrary()?,
+            mailbox: choose_or_text(u, MAILBOXES)?,
+        },
+        4 => Command::Redirect {
+            copy: u.arbitrary()?,
+            address: Text::literal(*u.choose(ADDRESSES)?),
+        },
+        5 => Command::Reject(u.arbitrary()?),
+        6 => Command::Set {
+            modifier: u.arbitrary()?,
+            name: Name(*u.choose(VARIABLES)?),
+            value: u.arbitrary()?,
+        },
+        7 => Command::Flags {
+            action: u.arbitrary()?,
+            flags: values(u, |u| choose_or_text(u, FLAGS))?,
+        },
+        8 => Command::AddHeader {
+            last: u.arbitrary()?,
+            name: Name(*u.choose(HEADERS)?),
+            value: u.arbitrary()?,
+        },
+        9 => Command::DeleteHeader {
+            name: Name(*u.choose(HEADERS)?),
+            values: values(u, |u| u.arbitrary())?,
+        },
+        10 | 11 => Command::Vacation {
+            days: u.int_in_range(1..=30)?,
+            subject: u.arbitrary()?,
+            reason: u.arbitrary()?,
+        },
+        12 => {
+            let num_branches = u.int_in_range(1..=3)?;
+            let mut branches = Vec::with_capacity(num_branches);
+            for _ in 0..num_branches {
+                branches.push((test(u, depth + 1)?, block(u, depth + 1)?));
+            }
+            Command::If {
+                branches,
+                otherwise: if u.arbitrary()? {
+                    Some(block(u, depth + 1)?)
+                } else {
+                    None
+                },
+            }
+        }
+        _ => Command::ForEveryPart(block(u, depth + 1)?),
+    })
+}
+
+fn test(u: &mut Unstructured, depth: usize) -> Result<Test> {
+    let max = if depth < MAX_DEPTH { 13 } else { 10 };
+    Ok(match u.int_in_range(0..=max)? {
+        0 => Test::True,
+        1 => Test::False,
+        2 => Test::Header {
+            match_: u.arbitrary()?,
+            headers: values(u, |u| Ok(Name(*u.choose(HEADERS)?)))?,
+            keys: values(u, |u| u.arbitrary())?,
+        },
+        3 => Test::Address {
+            part: u.arbitrary()?,
+            match_: u.arbitrary()?,
+            headers: values(u, |u| Ok(Name(*u.choose(ADDRESS_HEADERS)?)))?,
+            keys: values(u, |u| u.arbitrary())?,
+        },
+        4 => Test::Envelope {
+            part: u.arbitrary()?,
+            match_: u.arbitrary()?,
+            from: u.arbitrary()?,
+            keys: values(u, |u| u.arbitrary())?,
+        },
+        5 => Test::Exists(values(u, |u| Ok(Name(*u.choose(HEADERS)?)))?),
+        6 => Test::Size {
+            over: u.arbitrary()?,
+            limit: u.int_in_range(0..=1000)?,
+            unit: *u.choose(&[None, Some('K'), Some('M'), Some('G')])?,
+        },
+        7 => Test::Body {
+            transform: match u.int_in_range(0..=3)? {
+                0 => BodyTransform::Default,
+                1 => BodyTransform::Raw,
+                2 => BodyTransform::Text,
+                _ => BodyTransform::Content(values(u, |u| {
+                    choose_or_text(u, &["text", "text/plain", "multipart", ""])
+                })?),
+            },
+            match_: u.arbitrary()?,
+            keys: values(u, |u| u.arbitrary())?,
+        },
+        8 => Test::String {
+            match_: u.arbitrary()?,
+            sources: values(u, |u| u.arbitrary())?,
+            keys: values(u, |u| u.arbitrary())?,
+        },
+        9 | 10 => Test::HasFlag(values(u, |u| choose_or_text(u, FLAGS))?),
+        11 => Test::Not(Box::new(test(u, depth + 1)?)),
+        12 => Test::AllOf(tests(u, depth + 1)?),
+        _ => Test::AnyOf(tests(u, depth + 1)?),
+    })
+}
+
+fn tests(u: &mut Unstructured, depth: usize) -> Result<Vec<Test>> {
+    let len = u.int_in_range(1..=MAX_TESTS)?;
+    let mut tests = Vec::with_capacity(len);
+    for _ in 0..len {
+        tests.push(test(u, depth)?);
+    }
+    Ok(tests)
+}
+
+fn values<'a, T>(
+    u: &mut Unstructured<'a>,
+    mut item: impl FnMut(&mut Unstructured<'a>) -> Result<T>,
+) -> Result<Vec<T>> {
+    let len = u.int_in_range(1..=MAX_VALUES)?;
+    let mut values = Vec::with_capacity(len);
+    for _ in 0..len {
+        values.push(item(u)?);
+    }
+    Ok(values)
+}
+
+fn choose_or_text<'a>(u: &mut Unstructured<'a>, choices: &[&'static str]) -> Result<Text> {
+    if u.ratio(3, 4)? {
+        Ok(Text::literal(*u.choose(choices)?))
+    } else {
+        u.arbitrary()
+    }
+}
+
+impl Script {
+    /// Returns variants of the script that are one step smaller, by
+    /// removing a command or argument, inlining a block or replacing a test
+    /// with a constant.
+    pub fn shrink(&self) -> Vec<Script> {
+        shrink_block(&self.commands)
+            .into_iter()
+            .map(|commands| Script { commands })
+            .collect()
+    }
+
+    /// Shrinks the script for as long as `is_interesting` holds for any
+    /// of its smaller variants, returning the smallest script found.
+    pub fn reduce(self, mut is_interesting: impl FnMut(&Script) -> bool) -> Script {
+        let mut script = self;
+        'outer: loop {
+            for candidate in script.shrink() {
+                if is_interesting(&candidate) {
+                    script = candidate;
+                    continue 'outer;
+                }
+            }
+            return script;
+        }
+    }
+}
+
+fn shrink_block(commands: &[Command]) -> Vec<Vec<Command>> {
+    let mut result = Vec::new();
+    for pos in 0..commands.len() {
+        let mut block = commands.to_vec();
+        block.remove(pos);
+        result.push(block);
+    }
+    for (pos, command) in commands.iter().enumerate() {
+        for replacement in command.shrink() {
+            let mut block = commands[..pos].to_vec();
+            block.extend(replacement);
+            block.extend_from_slice(&commands[pos + 1..]);
+            result.push(block);
+        }
+    }
+    result
+}
+
+impl Command {
+    // Each result replaces the command with zero or more commands
+    fn shrink(&self) -> Vec<Vec<Command>> {
+        match self {
+            Command::If {
+                branches,
+                otherwise,
+            } => {
+                let mut result = Vec::new();
+                for (_, block) in branches {
+                    result.push(block.clone());
+                }
+                if let Some(block) = otherwise {
+                    result.push(block.clone());
+                    result.push(vec![Command::If {
+                        branches: branches.clone(),
+                        otherwise: None,
+                    }]);
+                }
+                if branches.len() > 1 {
+                    for pos in 0..branches.len() {
+                        let mut branches = branches.clone();
+                        branches.remove(pos);
+                        result.push(vec![Command::If {
+                            branches,
+                            otherwise: otherwise.clone(),
+                        }]);
+                    }
+                }
+                for (pos, (test, block)) in branches.iter().enumerate() {
+                    for test in test.shrink() {
+                        let mut branches = branches.clone();
+                        branches[pos].0 = test;
+                        result.push(vec![Command::If {
+                            branches,
+                            otherwise: otherwise.clone(),
+                        }]);
+                    }
+                    for block in shrink_block(block) {
+                        let mut branches = branches.clone();
+                        branches[pos].1 = block;
+                        result.push(vec![Command::If {
+                            branches,
+                            otherwise: otherwise.clone(),
+                        }]);
+                    }
+                }
+                if let Some(block) = otherwise {
+                    for block in shrink_block(block) {
+                        result.push(vec![Command::If {
+                            branches: branches.clone(),
+                            otherwise: Some(block),
+                        }]);
+                    }
+                }
+                result
+            }
+            Command::ForEveryPart(block) => {
+                let mut result = vec![block.clone()];
+                result.extend(
+                    shrink_block(block)
+                        .into_iter()
+                        .map(|block| vec![Command::ForEveryPart(block)]),
+                );
+                result
+            }
+            Command::Flags { action, flags } if flags.len() > 1 => shrink_list(flags)
+                .into_iter()
+                .map(|flags| {
+                    vec![Command::Flags {
+                        action: *action,
+                        flags,
+                    }]
+                })
+                .collect(),
+            Command::DeleteHeader { name, values } if values.len() > 1 => shrink_list(values)
+                .into_iter()
+                .map(|values| {
+                    vec![Command::DeleteHeader {
+                        name: *name,
+                        values,
+                    }]
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Test {
+    fn shrink(&self) -> Vec<Test> {
+        let mut result = match self {
+            Test::True => return Vec::new(),
+            Test::False => return vec![Test::True],
+            _ => vec![Test::True, Test::False],
+        };
+        match self {
+            Test::Not(test) => {
+                result.push(test.as_ref().clone());
+                result.extend(test.shrink().into_iter().map(|t| Test::Not(Box::new(t))));
+            }
+            Test::AllOf(tests) | Test::AnyOf(tests) => {
+                let is_all_of = matches!(self, Test::AllOf(_));
+                result.extend(tests.iter().cloned());
+                for pos in 0..tests.len() {
+                    let shrunk = if tests.len() > 1 {
+                        let mut tests = tests.clone();
+                        tests.remove(pos);
+                        vec![tests]
+                    } else {
+                        vec![]
+                    };
+                    let nested = tests[pos].shrink().into_iter().map(|test| {
+                        let mut tests = tests.clone();
+                        tests[pos] = test;
+                        tests
+                    });
+                    result.extend(shrunk.into_iter().chain(nested).map(|tests| {
+                        if is_all_of {
+                            Test::AllOf(tests)
+                        } else {
+                            Test::AnyOf(tests)
+                        }
+                    }));
+                }
+            }
+            Test::Header {
+                match_,
+                headers,
+                keys,
+            } => {
+                result.extend(
+                    shrink_list(headers)
+                        .into_iter()
+                        .map(|headers| Test::Header {
+                            match_: *match_,
+                            headers,
+                            keys: keys.clone(),
+                        }),
+                );
+                result.extend(shrink_list(keys).into_iter().map(|keys| Test::Header {
+                    match_: *match_,
+                    headers: headers.clone(),
+                    keys,
+                }));
+            }
+            Test::String {
+                match_,
+                sources,
+                keys,
+            } => {
+                result.extend(
+                    shrink_list(sources)
+                        .into_iter()
+                        .map(|sources| Test::String {
+                            match_: *match_,
+                            sources,
+                            keys: keys.clone(),
+                        }),
+                );
+                result.extend(shrink_list(keys).into_iter().map(|keys| Test::String {
+                    match_: *match_,
+                    sources: sources.clone(),
+                    keys,
+                }));
+            }
+            _ => (),
+        }
+        result
+    }
+}
+
+fn shrink_list<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+    if items.len() > 1 {
+        (0..items.len())
+            .map(|pos| {
+                let mut items = items.to_vec();
+                items.remove(pos);
+                items
+            })
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+#[derive(Default)]
+struct Renderer {
+    capabilities: Vec<&'static str>,
+    script: String,
+    indent: usize,
+}
+
+impl Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut renderer = Renderer::default();
+        renderer.block(&self.commands);
+        if !renderer.capabilities.is_empty() {
+            f.write_str("require [")?;
+            for (pos, capability) in renderer.capabilities.iter().enumerate() {
+                if pos > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "\"{capability}\"")?;
+            }
+            f.write_str("];\n")?;
+        }
+        f.write_str(&renderer.script)
+    }
+}
+
+impl Renderer {
+    fn require(&mut self, capability: &'static str) {
+        if !self.capabilities.contains(&capability) {
+            self.capabilities.push(capability);
+        }
+    }
+
+    fn block(&mut self, commands: &[Command]) {
+        for command in commands {
+            self.command(command);
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        for _ in 0..self.indent {
+            self.script.push_str("    ");
+        }
+        self.script.push_str(line);
+        self.script.push('\n');
+    }
+
+    fn nested(&mut self, header: &str, commands: &[Command]) {
+        self.line(&format!("{header} {{"));
+        self.indent += 1;
+        self.block(commands);
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    fn command(&mut self, command: &Command) {
+        let line = match command {
+            Command::Keep => "keep;".to_string(),
+            Command::Discard => "discard;".to_string(),
+            Command::Stop => "stop;".to_string(),
+            Command::FileInto {
+                copy,
+                create,
+                mailbox,
+            } => {
+                self.require("fileinto");
+                let mut line = "fileinto".to_string();
+                if *copy {
+                    self.require("copy");
+                    line.push_str(" :copy");
+                }
+                if *create {
+                    self.require("mailbox");
+                    line.push_str(" :create");
+                }
+                format!("{line} {};", self.text(mailbox, false))
+            }
+            Command::Redirect { copy, address } => {
+                if *copy {
+                    self.require("copy");
+                    format!("redirect :copy {};", self.text(address, false))
+                } else {
+                    format!("redirect {};", self.text(address, false))
+                }
+            }
+            Command::Reject(reason) => {
+                self.require("reject");
+                format!("reject {};", self.text(reason, false))
+            }
+            Command::Set {
+                modifier,
+                name,
+                value,
+            } => {
+                self.require("variables");
+                let modifier = match modifier {
+                    Some(Modifier::Lower) => ":lower ",
+                    Some(Modifier::Upper) => ":upper ",
+                    Some(Modifier::LowerFirst) => ":lowerfirst ",
+                    Some(Modifier::UpperFirst) => ":upperfirst ",
+                    Some(Modifier::QuoteWildcard) => ":quotewildcard ",
+                    Some(Modifier::Length) => ":length ",
+                    None => "",
+                };
+                format!("set {modifier}\"{}\" {};", name.0, self.text(value, false))
+            }
+            Command::Flags { action, flags } => {
+                self.require("imap4flags");
+                let action = match action {
+                    FlagAction::Set => "setflag",
+                    FlagAction::Add => "addflag",
+                    FlagAction::Remove => "removeflag",
+                };
+                format!("{action} {};", self.text_list(flags, false))
+            }
+            Command::AddHeader { last, name, value } => {
+                self.require("editheader");
+                format!(
+                    "addheader{} \"{}\" {};",
+                    if *last { " :last" } else { "" },
+                    name.0,
+                    self.text(value, false)
+                )
+            }
+            Command::DeleteHeader { name, values } => {
+                self.require("editheader");
+                format!(
+                    "deleteheader \"{}\" {};",
+                    name.0,
+                    self.text_list(values, false)
+                )
+            }
+            Command::Vacation {
+                days,
+                subject,
+                reason,
+            } => {
+                self.require("vacation");
+                let mut line = format!("vacation :days {days}");
+                if let Some(subject) = subject {
+                    line.push_str(&format!(" :subject {}", self.text(subject, false)));
+                }
+                format!("{line} {};", self.text(reason, false))
+            }
+            Command::If {
+                branches,
+                otherwise,
+            } => {
+                for (pos, (test, commands)) in branches.iter().enumerate() {
+                    let test = self.test(test);
+                    if pos == 0 {
+                        self.nested(&format!("if {test}"), commands);
+                    } else {
+                        self.nested(&format!("elsif {test}"), commands);
+                    }
+                }
+                if let Some(commands) = otherwise {
+                    self.nested("else", commands);
+                }
+                return;
+            }
+            Command::ForEveryPart(commands) => {
+                self.require("foreverypart");
+                self.nested("foreverypart", commands);
+                return;
+            }
+        };
+        self.line(&line);
+    }
+
+    fn test(&mut self, test: &Test) -> String {
+        match test {
+            Test::True => "true".to_string(),
+            Test::False => "false".to_string(),
+            Test::Not(test) => format!("not {}", self.test(test)),
+            Test::AllOf(tests) | Test::AnyOf(tests) => {
+                let mut result = if matches!(test, Test::AllOf(_)) {
+                    "allof(".to_string()
+                } else {
+                    "anyof(".to_string()
+                };
+                for (pos, test) in tests.iter().enumerate() {
+                    if pos > 0 {
+                        result.push_str(", ");
+                    }
+                    result.push_str(&self.test(test));
+                }
+                result.push(')');
+                result
+            }
+            Test::Header {
+                match_,
+                headers,
+                keys,
+            } => {
+                let (args, is_regex) = self.match_args(match_);
+                format!(
+                    "header{args} {} {}",
+                    names(headers),
+                    self.text_list(keys, is_regex)
+                )
+            }
+            Test::Address {
+                part,
+                match_,
+                headers,
+                keys,
+            } => {
+                let part = self.address_part(part);
+                let (args, is_regex) = self.match_args(match_);
+                format!(
+                    "address{part}{args} {} {}",
+                    names(headers),
+                    self.text_list(keys, is_regex)
+                )
+            }
+            Test::Envelope {
+                part,
+                match_,
+                from,
+                keys,
+            } => {
+                self.require("envelope");
+                let part = self.address_part(part);
+                let (args, is_regex) = self.match_args(match_);
+                format!(
+                    "envelope{part}{args} \"{}\" {}",
+                    if *from { "from" } else { "to" },
+                    self.text_list(keys, is_regex)
+                )
+            }
+            Test::Exists(headers) => format!("exists {}", names(headers)),
+            Test::Size { over, limit, unit } => format!(
+                "size {} {limit}{}",
+                if *over { ":over" } else { ":under" },
+                unit.map(String::from).unwrap_or_default()
+            ),
+            Test::Body {
+                transform,
+                match_,
+                keys,
+            } => {
+                self.require("body");
+                let transform = match transform {
+                    BodyTransform::Default => String::new(),
+                    BodyTransform::Raw => " :raw".to_string(),
+                    BodyTransform::Text => " :text".to_string(),
+                    BodyTransform::Content(types) => {
+                        format!(" :content {}", self.text_list(types, false))
+                    }
+                };
+                let (args, is_regex) = self.match_args(match_);
+                format!("body{transform}{args} {}", self.text_list(keys, is_regex))
+            }
+            Test::String {
+                match_,
+                sources,
+                keys,
+            } => {
+                self.require("variables");
+                let (args, is_regex) = self.match_args(match_);
+                format!(
+                    "string{args} {} {}",
+                    self.text_list(sources, false),
+                    self.text_list(keys, is_regex)
+                )
+            }
+            Test::HasFlag(flags) => {
+                self.require("imap4flags");
+                format!("hasflag {}", self.text_list(flags, false))
+            }
+        }
+    }
+
+    fn match_args(&mut self, match_: &Match) -> (String, bool) {
+        let mut result = String::new();
+        match match_.comparator {
+            Comparator::Default => (),
+            Comparator::Octet => result.push_str(" :comparator \"i;octet\""),
+            Comparator::AsciiCaseMap => result.push_str(" :comparator \"i;ascii-casemap\""),
+            Comparator::AsciiNumeric => {
+                self.require("comparator-i;ascii-numeric");
+                result.push_str(" :comparator \"i;ascii-numeric\"");
+            }
+        }
+        match match_.match_type {
+            MatchType::Is => result.push_str(" :is"),
+            MatchType::Contains => result.push_str(" :contains"),
+            MatchType::Matches => result.push_str(" :matches"),
+            MatchType::Regex => {
+                self.require("regex");
+                result.push_str(" :regex");
+            }
+            MatchType::Value(relation) | MatchType::Count(relation) => {
+                self.require("relational");
+                let relation = match relation {
+                    Relation::Gt => "gt",
+                    Relation::Ge => "ge",
+                    Relation::Lt => "lt",
+                    Relation::Le => "le",
+                    Relation::Eq => "eq",
+                    Relation::Ne => "ne",
+                };
+                if matches!(match_.match_type, MatchType::Value(_)) {
+                    write!(result, " :value \"{relation}\"").ok();
+                } else {
+                    write!(result, " :count \"{relation}\"").ok();
+                }
+            }
+        }
+        (result, match_.match_type == MatchType::Regex)
+    }
+
+    fn address_part(&mut self, part: &AddressPart) -> &'static str {
+        match part {
+            AddressPart::All => "",
+            AddressPart::LocalPart => " :localpart",
+            AddressPart::Domain => " :domain",
+            AddressPart::User => {
+                self.require("subaddress");
+                " :user"
+            }
+            AddressPart::Detail => {
+                self.require("subaddress");
+                " :detail"
+            }
+        }
+    }
+
+    fn text_list(&mut self, items: &[Text], is_regex: bool) -> String {
+        if items.len() == 1 {
+            self.text(&items[0], is_regex)
+        } else {
+            let mut result = "[".to_string();
+            for (pos, item) in items.iter().enumerate() {
+                if pos > 0 {
+                    result.push_str(", ");
+                }
+                result.push_str(&self.text(item, is_regex));
+            }
+            result.push(']');
+            result
+        }
+    }
+
+    // Regular expressions are escaped so that constant keys always compile
+    fn text(&mut self, text: &Text, is_regex: bool) -> String {
+        let mut value = String::new();
+        for fragment in &text.0 {
+            match fragment {
+                Fragment::Literal(literal) => push_text(&mut value, literal, is_regex),
+                Fragment::Char(ch) => push_text(&mut value, ch.encode_utf8(&mut [0; 4]), is_regex),
+                Fragment::Encoded(_) | Fragment::Variable(_) | Fragment::MatchVariable(_)
+                    if is_regex =>
+                {
+                    value.push_str(".*");
+                }
+                Fragment::Encoded(encoded) => {
+                    self.require("encoded-character");
+                    value.push_str(encoded);
+                }
+                Fragment::Variable(name) => {
+                    self.require("variables");
+                    write!(value, "${{{name}}}").ok();
+                }
+                Fragment::MatchVariable(num) => {
+                    self.require("variables");
+                    write!(value, "${{{num}}}").ok();
+                }
+            }
+        }
+
+        let mut result = String::with_capacity(value.len() + 2);
+        result.push('"');
+        for ch in value.chars() {
+            if matches!(ch, '"' | '\\') {
+                result.push('\\');
+            }
+            result.push(ch);
+        }
+        result.push('"');
+        result
+    }
+}
+
+fn names(names: &[Name]) -> String {
+    if names.len() == 1 {
+        format!("\"{}\"", names[0].0)
+    } else {
+        let mut result = "[".to_string();
+        for (pos, name) in names.iter().enumerate() {
+            if pos > 0 {
+                result.push_str(", ");
+            }
+            write!(result, "\"{}\"", name.0).ok();
+        }
+        result.push(']');
+        result
+    }
+}
+
+fn push_text(value: &mut String, text: &str, is_regex: bool) {
+    for ch in text.chars() {
+        if is_regex && REGEX_META.contains(ch) {
+            value.push('\\');
+        }
+        value.push(ch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arbitrary::{Arbitrary, Unstructured};
+
+    use crate::Compiler;
+
+    use super::{Command, Comparator, Match, MatchType, Name, Script, Test, Text};
+
+    #[test]
+    fn render_and_reduce() {
+        let script = Script {
+            commands: vec![
+                Command::Keep,
+                Command::If {
+                    branches: vec![(
+                        Test::AnyOf(vec![
+                            Test::Exists(vec![Name("X-Custom")]),
+                            Test::Header {
+                                match_: Match {
+                                    comparator: Comparator::Default,
+                                    match_type: MatchType::Regex,
+                                },
+                                headers: vec![Name("Subject"), Name("From")],
+                                keys: vec![Text::literal("a+b"), Text::literal("\"*\"")],
+                            },
+                        ]),
+                        vec![Command::FileInto {
+                            copy: true,
+                            create: false,
+                            mailbox: Text::literal("Junk"),
+                        }],
+                    )],
+                    otherwise: None,
+                },
+            ],
+        };
+        let source = script.to_string();
+        assert_eq!(
+            source,
+            concat!(
+                "require [\"regex\", \"fileinto\", \"copy\"];\n",
+                "keep;\n",
+                "if anyof(exists \"X-Custom\", header :regex [\"Subject\", \"From\"] ",
+                "[\"a\\\\+b\", \"\\\"\\\\*\\\"\"]) {\n",
+                "    fileinto :copy \"Junk\";\n",
+                "}\n"
+            )
+        );
+        Compiler::new().compile(source.as_bytes()).unwrap();
+
+        assert_eq!(
+            script
+                .reduce(|script| script.to_string().contains("fileinto"))
+                .to_string(),
+            "require [\"fileinto\", \"copy\"];\nfileinto :copy \"Junk\";\n"
+        );
+
+        // Generated scripts are rendered and shrunk without panicking
+        let data = (0..4096u32)
+            .map(|n| (n.wrapping_mul(2654435761) >> 13) as u8)
+            .collect::<Vec<_>>();
+        for offset in 0..64 {
+            let mut u = Unstructured::new(&data[offset * 7..]);
+            if let Ok(script) = Script::arbitrary(&mut u) {
+                Compiler::new().compile(script.to_string().as_bytes()).ok();
+                script.reduce(|script| !script.commands.is_empty());
+            }
+        }
+    }
+}
@@ -26,14 +26,14 @@ use serde::{Deserialize, Serialize};
 use crate::compiler::{
     grammar::instruction::{CompilerState, Instruction},
     lexer::{word::Word, Token},
-    CompileError, Value, VariableType,
+    CompileError, Span, Value, VariableType,
 };
 
 use super::action_set::Modifier;
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub(crate) struct ForEveryPart {
-    pub jz_pos: usize,
+    pub jz_pos: u32,
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
@@ -96,12 +96,15 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        self.instructions.push(Instruction::Replace(Replace {
-            subject,
-            from,
-            replacement,
-            mime,
-        }));
+        self.instructions.push(Instruction::Replace(
+            Replace {
+                subject,
+                from,
+                replacement,
+                mime,
+            }
+            .into(),
+        ));
         Ok(())
     }
 
@@ -128,11 +131,14 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        self.instructions.push(Instruction::Enclose(Enclose {
-            subject,
-            headers,
-            value,
-        }));
+        self.instructions.push(Instruction::Enclose(
+            Enclose {
+                subject,
+                headers,
+                value,
+            }
+            .into(),
+        ));
         Ok(())
     }
 
@@ -158,10 +164,11 @@ impl<'x> CompilerState<'x> {
                     | Word::QuoteRegex
                     | Word::Length),
                 ) => {
-                    let modifier = word.into();
-                    if !modifiers.contains(&modifier) {
-                        modifiers.push(modifier);
-                    }
+                    let span = Span {
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                    };
+                    self.add_modifier(&mut modifiers, word.into(), span)?;
                 }
                 Token::Tag(Word::Replace) => {
                     let find = self.tokens.unwrap_next()?;
@@ -181,7 +188,7 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        modifiers.sort_unstable_by_key(|m| std::cmp::Reverse(m.order()));
+        modifiers.sort_by_key(|m| std::cmp::Reverse(m.order()));
 
         self.instructions
             .push(Instruction::ExtractText(ExtractText {
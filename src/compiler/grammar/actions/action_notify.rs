@@ -32,7 +32,7 @@ use crate::{
         lexer::{word::Word, Token},
         CompileError, ErrorType, Value,
     },
-    runtime::actions::action_notify::{validate_from, validate_uri},
+    runtime::actions::action_notify::{parse_uri, validate_from, validate_uri},
     FileCarbonCopy,
 };
 
@@ -141,7 +141,18 @@ impl<'x> CompilerState<'x> {
                 }
                 _ => {
                     if let Token::StringConstant(uri) = &token_info.token {
-                        if validate_uri(uri.to_string().as_ref()).is_none() {
+                        let uri = uri.to_string().into_owned();
+                        if let Some(validator) = parse_uri(&uri).and_then(|(scheme, _)| {
+                            self.compiler
+                                .notify_uri_validators
+                                .get(&scheme.to_ascii_lowercase())
+                        }) {
+                            if let Err(reason) = validator(&uri) {
+                                return Err(
+                                    token_info.custom(ErrorType::InvalidNotifyUri { uri, reason })
+                                );
+                            }
+                        } else if validate_uri(&uri).is_none() {
                             return Err(token_info.custom(ErrorType::InvalidURI));
                         }
                     }
@@ -158,25 +169,28 @@ impl<'x> CompilerState<'x> {
             return Err(self.tokens.unwrap_next()?.missing_tag(":fcc"));
         }
 
-        self.instructions.push(Instruction::Notify(Notify {
-            method,
-            from,
-            importance,
-            options,
-            message,
-            fcc: if let Some(fcc) = fcc {
-                FileCarbonCopy {
-                    mailbox: fcc,
-                    create,
-                    flags,
-                    special_use,
-                    mailbox_id,
-                }
-                .into()
-            } else {
-                None
-            },
-        }));
+        self.instructions.push(Instruction::Notify(
+            Notify {
+                method,
+                from,
+                importance,
+                options,
+                message,
+                fcc: if let Some(fcc) = fcc {
+                    FileCarbonCopy {
+                        mailbox: fcc,
+                        create,
+                        flags,
+                        special_use,
+                        mailbox_id,
+                    }
+                    .into()
+                } else {
+                    None
+                },
+            }
+            .into(),
+        ));
         Ok(())
     }
 }
@@ -21,6 +21,7 @@
  * for more details.
 */
 
+use ahash::AHashSet;
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
@@ -29,7 +30,7 @@ use crate::compiler::{
         Capability,
     },
     lexer::{word::Word, Token},
-    CompileError, Value,
+    CompileError, ErrorType, Value,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -218,7 +219,20 @@ impl<'x> CompilerState<'x> {
                     }
                 }
                 _ => {
+                    let line_num = token_info.line_num;
+                    let line_pos = token_info.line_pos;
                     address = self.parse_string_token(token_info)?;
+                    if !list && !self.compiler.redirect_domains.is_empty() {
+                        if let Some(address) = address.to_constant() {
+                            if !is_domain_allowed(&address, &self.compiler.redirect_domains) {
+                                return Err(CompileError {
+                                    line_num,
+                                    line_pos,
+                                    error_type: ErrorType::RedirectNotAllowed(address),
+                                });
+                            }
+                        }
+                    }
                     break;
                 }
             }
@@ -250,6 +264,18 @@ impl<'x> CompilerState<'x> {
     }
 }
 
+pub(crate) fn is_domain_allowed(address: &str, domains: &AHashSet<String>) -> bool {
+    let Some((_, domain)) = address.trim_end_matches('>').rsplit_once('@') else {
+        return false;
+    };
+    let domain = domain.to_ascii_lowercase();
+    domains.iter().any(|allowed| {
+        domain
+            .strip_suffix(allowed.as_str())
+            .map_or(false, |prefix| prefix.is_empty() || prefix.ends_with('.'))
+    })
+}
+
 impl MapLocalVars for ByTime<Value> {
     fn map_local_vars(&mut self, last_id: usize) {
         if let ByTime::Absolute { alimit, .. } = self {
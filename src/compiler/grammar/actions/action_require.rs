@@ -27,11 +27,32 @@ use crate::compiler::{
         Capability,
     },
     lexer::Token,
-    CompileError,
+    CompileError, ErrorType,
 };
 
 impl<'x> CompilerState<'x> {
-    fn add_capability(&mut self, capabilities: &mut Vec<Capability>, capability: Capability) {
+    fn add_capability(
+        &mut self,
+        capabilities: &mut Vec<Capability>,
+        capability: Capability,
+        line_num: usize,
+        line_pos: usize,
+    ) -> Result<(), CompileError> {
+        match &capability {
+            // Comparators that may be unavailable are only allowed behind ihave
+            Capability::Comparator(comparator)
+                if !self.compiler.comparators.contains(comparator)
+                    && !self.has_capability(&Capability::Ihave) =>
+            {
+                return Err(CompileError {
+                    line_num,
+                    line_pos,
+                    error_type: ErrorType::UnsupportedComparator(comparator.as_str().to_string()),
+                });
+            }
+            _ => (),
+        }
+
         if !self.has_capability(&capability) {
             let parent_capability = if matches!(&capability, Capability::SpamTestPlus) {
                 Some(Capability::SpamTest)
@@ -48,6 +69,8 @@ impl<'x> CompilerState<'x> {
                 }
             }
         }
+
+        Ok(())
     }
 
     pub(crate) fn parse_require(&mut self) -> Result<(), CompileError> {
@@ -62,7 +85,9 @@ impl<'x> CompilerState<'x> {
                         self.add_capability(
                             &mut capabilities,
                             Capability::parse(value.to_string().as_ref()),
-                        );
+                            token_info.line_num,
+                            token_info.line_pos,
+                        )?;
                         let token_info = self.tokens.unwrap_next()?;
                         match token_info.token {
                             Token::Comma => (),
@@ -81,7 +106,9 @@ impl<'x> CompilerState<'x> {
                 self.add_capability(
                     &mut capabilities,
                     Capability::parse(value.to_string().as_ref()),
-                );
+                    token_info.line_num,
+                    token_info.line_pos,
+                )?;
             }
             _ => {
                 return Err(token_info.expected("'[' or string"));
@@ -30,9 +30,9 @@ use crate::{
             instruction::{CompilerState, Instruction},
         },
         lexer::{tokenizer::TokenInfo, word::Word, Token},
-        CompileError, ErrorType, Value, VariableType,
+        CompileError, CompileWarning, CompileWarningKind, ErrorType, Span, Value, VariableType,
     },
-    Envelope,
+    Envelope, VariableNameRules,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -49,6 +49,9 @@ pub(crate) enum Modifier {
 }
 
 impl Modifier {
+    // Modifiers are applied from the highest to the lowest order, following the
+    // precedence defined in RFC 5229 (40 case, 30 first character case,
+    // 20 quoting, 15 url encoding and 10 length).
     pub fn order(&self) -> usize {
         match self {
             Modifier::Lower => 41,
@@ -62,6 +65,52 @@ impl Modifier {
             Modifier::Replace { .. } => 40,
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Modifier::Lower => ":lower",
+            Modifier::Upper => ":upper",
+            Modifier::LowerFirst => ":lowerfirst",
+            Modifier::UpperFirst => ":upperfirst",
+            Modifier::QuoteWildcard => ":quotewildcard",
+            Modifier::QuoteRegex => ":quoteregex",
+            Modifier::EncodeUrl => ":encodeurl",
+            Modifier::Length => ":length",
+            Modifier::Replace { .. } => ":replace",
+        }
+    }
+
+    // RFC 5229 does not allow two modifiers of the same precedence
+    fn conflicts_with(&self, other: &Modifier) -> bool {
+        matches!(
+            (self, other),
+            (Modifier::Lower, Modifier::Upper)
+                | (Modifier::Upper, Modifier::Lower)
+                | (Modifier::LowerFirst, Modifier::UpperFirst)
+                | (Modifier::UpperFirst, Modifier::LowerFirst)
+                | (Modifier::QuoteWildcard, Modifier::QuoteRegex)
+                | (Modifier::QuoteRegex, Modifier::QuoteWildcard)
+        )
+    }
+
+    // Returns the modifier of the pair that has no effect on the result
+    fn redundant_with<'a>(&'a self, other: &'a Modifier) -> Option<&'a Modifier> {
+        match (self, other) {
+            (Modifier::LowerFirst, Modifier::Lower)
+            | (Modifier::UpperFirst, Modifier::Upper)
+            | (
+                Modifier::Lower | Modifier::Upper | Modifier::LowerFirst | Modifier::UpperFirst,
+                Modifier::Length,
+            ) => Some(self),
+            (Modifier::Lower, Modifier::LowerFirst)
+            | (Modifier::Upper, Modifier::UpperFirst)
+            | (
+                Modifier::Length,
+                Modifier::Lower | Modifier::Upper | Modifier::LowerFirst | Modifier::UpperFirst,
+            ) => Some(other),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -97,10 +146,11 @@ impl<'x> CompilerState<'x> {
                     | Word::Length
                     | Word::EncodeUrl),
                 ) => {
-                    let modifier = word.into();
-                    if !modifiers.contains(&modifier) {
-                        modifiers.push(modifier);
-                    }
+                    let span = Span {
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                    };
+                    self.add_modifier(&mut modifiers, word.into(), span)?;
                 }
                 Token::Tag(Word::Replace) => {
                     let find = self.tokens.unwrap_next()?;
@@ -124,7 +174,7 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        modifiers.sort_unstable_by_key(|m| std::cmp::Reverse(m.order()));
+        modifiers.sort_by_key(|m| std::cmp::Reverse(m.order()));
 
         self.instructions.push(Instruction::Set(Set {
             modifiers,
@@ -134,6 +184,47 @@ impl<'x> CompilerState<'x> {
         Ok(())
     }
 
+    pub(crate) fn add_modifier(
+        &mut self,
+        modifiers: &mut Vec<Modifier>,
+        modifier: Modifier,
+        span: Span,
+    ) -> Result<(), CompileError> {
+        if modifiers.contains(&modifier) {
+            self.warn_redundant_modifier(&modifier, &modifier, span);
+            return Ok(());
+        }
+
+        for other in modifiers.iter() {
+            if modifier.conflicts_with(other) {
+                return Err(CompileError {
+                    line_num: span.line_num,
+                    line_pos: span.line_pos,
+                    error_type: ErrorType::ConflictingModifiers(
+                        other.as_str().to_string(),
+                        modifier.as_str().to_string(),
+                    ),
+                });
+            } else if let Some(redundant) = modifier.redundant_with(other) {
+                let with = if redundant == other { &modifier } else { other };
+                self.warn_redundant_modifier(redundant, with, span);
+            }
+        }
+
+        modifiers.push(modifier);
+        Ok(())
+    }
+
+    fn warn_redundant_modifier(&mut self, modifier: &Modifier, with: &Modifier, span: Span) {
+        self.warnings.push(CompileWarning {
+            kind: CompileWarningKind::RedundantModifier {
+                modifier: modifier.as_str().to_string(),
+                with: with.as_str().to_string(),
+            },
+            span,
+        });
+    }
+
     pub(crate) fn parse_let(&mut self) -> Result<(), CompileError> {
         let name = self.tokens.unwrap_next()?;
         let name = self.parse_variable_name(name, false)?;
@@ -166,15 +257,21 @@ impl<'x> CompilerState<'x> {
         register_as_local: bool,
     ) -> Result<VariableType, ErrorType> {
         let name = name.to_lowercase();
+        let rules = &self.compiler.variable_names;
         if let Some((namespace, part)) = name.split_once('.') {
+            rules.check_namespace(namespace)?;
             match namespace {
-                "global" | "t" => Ok(VariableType::Global(part.to_string())),
+                "global" | "t" => {
+                    rules.check_name(part)?;
+                    Ok(VariableType::Global(part.to_string()))
+                }
                 "envelope" => Envelope::try_from(part)
                     .map(VariableType::Envelope)
                     .map_err(|_| ErrorType::InvalidNamespace(namespace.to_string())),
                 _ => Err(ErrorType::InvalidNamespace(namespace.to_string())),
             }
         } else {
+            rules.check_name(&name)?;
             Ok(if !self.is_var_global(&name) {
                 VariableType::Local(self.register_local_var(name, register_as_local))
             } else {
@@ -184,6 +281,80 @@ impl<'x> CompilerState<'x> {
     }
 }
 
+impl VariableNameRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maximum length of the variables assigned or declared by scripts, zero
+    /// for no limit.
+    pub fn set_max_length(&mut self, max_length: usize) {
+        self.max_length = max_length;
+    }
+
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.set_max_length(max_length);
+        self
+    }
+
+    /// Namespaces such as `global`, `env` or `envelope` that scripts may use,
+    /// all of them are allowed by default.
+    pub fn set_allowed_namespaces(
+        &mut self,
+        namespaces: impl IntoIterator<Item = impl Into<String>>,
+    ) {
+        self.allowed_namespaces = Some(
+            namespaces
+                .into_iter()
+                .map(|namespace| namespace.into().to_lowercase())
+                .collect(),
+        );
+    }
+
+    pub fn with_allowed_namespaces(
+        mut self,
+        namespaces: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.set_allowed_namespaces(namespaces);
+        self
+    }
+
+    /// Variables starting with `prefix`, such as `admin.`, can be read but
+    /// not assigned or declared as global by scripts.
+    pub fn set_reserved_prefix(&mut self, prefix: impl Into<String>) {
+        self.reserved_prefixes.push(prefix.into().to_lowercase());
+    }
+
+    pub fn with_reserved_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.set_reserved_prefix(prefix);
+        self
+    }
+
+    pub(crate) fn check_namespace(&self, namespace: &str) -> Result<(), ErrorType> {
+        match &self.allowed_namespaces {
+            Some(namespaces) if !namespaces.iter().any(|n| n.eq_ignore_ascii_case(namespace)) => {
+                Err(ErrorType::NamespaceNotAllowed(namespace.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_name(&self, name: &str) -> Result<(), ErrorType> {
+        let name = name.to_lowercase();
+        if self.max_length > 0 && name.len() > self.max_length {
+            Err(ErrorType::VariableTooLong)
+        } else if self
+            .reserved_prefixes
+            .iter()
+            .any(|prefix| name.starts_with(prefix.as_str()))
+        {
+            Err(ErrorType::VariableNameReserved(name))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl From<Word> for Modifier {
     fn from(word: Word) -> Self {
         match word {
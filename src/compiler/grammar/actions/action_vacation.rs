@@ -200,35 +200,40 @@ impl<'x> CompilerState<'x> {
             return Err(self.tokens.unwrap_next()?.missing_tag(":fcc"));
         }
 
-        self.instructions
-            .push(Instruction::Test(Test::Vacation(TestVacation {
+        self.instructions.push(Instruction::Test(
+            Test::Vacation(TestVacation {
                 period,
                 handle,
                 reason: reason.clone(),
                 addresses,
-            })));
+            })
+            .into(),
+        ));
 
         self.instructions
-            .push(Instruction::Jz(self.instructions.len() + 2));
-
-        self.instructions.push(Instruction::Vacation(Vacation {
-            reason,
-            subject,
-            from,
-            mime,
-            fcc: if let Some(fcc) = fcc {
-                FileCarbonCopy {
-                    mailbox: fcc,
-                    create,
-                    flags,
-                    special_use,
-                    mailbox_id,
-                }
-                .into()
-            } else {
-                None
-            },
-        }));
+            .push(Instruction::Jz(self.instructions.len() as u32 + 2));
+
+        self.instructions.push(Instruction::Vacation(
+            Vacation {
+                reason,
+                subject,
+                from,
+                mime,
+                fcc: if let Some(fcc) = fcc {
+                    FileCarbonCopy {
+                        mailbox: fcc,
+                        create,
+                        flags,
+                        special_use,
+                        mailbox_id,
+                    }
+                    .into()
+                } else {
+                    None
+                },
+            }
+            .into(),
+        ));
 
         Ok(())
     }
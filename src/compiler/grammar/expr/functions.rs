@@ -0,0 +1,132 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum BuiltIn {
+    Split,
+    Join,
+    Substring,
+    Replace,
+    Trim,
+    Len,
+    Lower,
+    Upper,
+    StartsWith,
+    EndsWith,
+    Now,
+    ParseDate,
+    FormatDate,
+    Any,
+    All,
+    Count,
+    Header,
+    Address,
+    Envelope,
+    Env,
+    BodyText,
+    RegexCapture,
+    Sha256,
+    Sha1,
+    Md5,
+    Crc32,
+    Base64Encode,
+    Base64Decode,
+    Hex,
+    UrlEncode,
+    InList,
+}
+
+impl BuiltIn {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "split" => BuiltIn::Split,
+            "join" => BuiltIn::Join,
+            "substring" => BuiltIn::Substring,
+            "replace" => BuiltIn::Replace,
+            "trim" => BuiltIn::Trim,
+            "len" => BuiltIn::Len,
+            "lower" => BuiltIn::Lower,
+            "upper" => BuiltIn::Upper,
+            "starts_with" => BuiltIn::StartsWith,
+            "ends_with" => BuiltIn::EndsWith,
+            "now" => BuiltIn::Now,
+            "parse_date" => BuiltIn::ParseDate,
+            "format_date" => BuiltIn::FormatDate,
+            "any" => BuiltIn::Any,
+            "all" => BuiltIn::All,
+            "count" => BuiltIn::Count,
+            "header" => BuiltIn::Header,
+            "address" => BuiltIn::Address,
+            "envelope" => BuiltIn::Envelope,
+            "env" => BuiltIn::Env,
+            "body_text" => BuiltIn::BodyText,
+            "regex_capture" => BuiltIn::RegexCapture,
+            "sha256" => BuiltIn::Sha256,
+            "sha1" => BuiltIn::Sha1,
+            "md5" => BuiltIn::Md5,
+            "crc32" => BuiltIn::Crc32,
+            "base64_encode" => BuiltIn::Base64Encode,
+            "base64_decode" => BuiltIn::Base64Decode,
+            "hex" => BuiltIn::Hex,
+            "url_encode" => BuiltIn::UrlEncode,
+            "in_list" => BuiltIn::InList,
+            _ => return None,
+        })
+    }
+
+    pub fn num_args(&self) -> RangeInclusive<u32> {
+        match self {
+            BuiltIn::Now | BuiltIn::BodyText => 0..=0,
+            BuiltIn::Trim
+            | BuiltIn::Len
+            | BuiltIn::Lower
+            | BuiltIn::Upper
+            | BuiltIn::ParseDate
+            | BuiltIn::Header
+            | BuiltIn::Envelope
+            | BuiltIn::Env
+            | BuiltIn::Sha256
+            | BuiltIn::Sha1
+            | BuiltIn::Md5
+            | BuiltIn::Crc32
+            | BuiltIn::Base64Encode
+            | BuiltIn::Base64Decode
+            | BuiltIn::Hex
+            | BuiltIn::UrlEncode => 1..=1,
+            BuiltIn::Join | BuiltIn::Any | BuiltIn::All | BuiltIn::Count | BuiltIn::Address => {
+                1..=2
+            }
+            BuiltIn::Split
+            | BuiltIn::StartsWith
+            | BuiltIn::EndsWith
+            | BuiltIn::RegexCapture
+            | BuiltIn::InList => 2..=2,
+            BuiltIn::Substring | BuiltIn::FormatDate => 2..=3,
+            BuiltIn::Replace => 3..=3,
+        }
+    }
+}
@@ -27,6 +27,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::compiler::{Number, VariableType};
 
+use self::functions::BuiltIn;
+
+pub mod functions;
 pub mod parser;
 pub mod tokenizer;
 
@@ -38,8 +41,11 @@ pub(crate) enum Expression {
     UnaryOperator(UnaryOperator),
     JmpIf { val: bool, pos: u32 },
     Function { id: u32, num_args: u32 },
+    BuiltIn { function: BuiltIn, num_args: u32 },
     ArrayAccess,
     ArrayBuild(u32),
+    Branch { pos: u32 },
+    Jmp { pos: u32 },
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -83,6 +89,15 @@ pub(crate) enum BinaryOperator {
     Le,
     Gt,
     Ge,
+
+    Modulo,
+    ShiftLeft,
+    ShiftRight,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+
+    In,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
@@ -108,4 +123,6 @@ pub(crate) enum Token {
     OpenBracket,
     CloseBracket,
     Comma,
+    QuestionMark,
+    Colon,
 }
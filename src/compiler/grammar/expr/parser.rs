@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use super::{tokenizer::Tokenizer, BinaryOperator, Expression, Token};
+use super::{functions::BuiltIn, tokenizer::Tokenizer, BinaryOperator, Expression, Token};
 
 pub(crate) struct ExpressionParser<'x, F>
 where
@@ -31,11 +31,16 @@ where
     pub(crate) output: Vec<Expression>,
     operator_stack: Vec<(Token, Option<usize>)>,
     arg_count: Vec<i32>,
+    max_depth: usize,
+    max_nodes: usize,
 }
 
 pub(crate) const ID_ARRAY_ACCESS: u32 = u32::MAX;
 pub(crate) const ID_ARRAY_BUILD: u32 = u32::MAX - 1;
-pub(crate) const ID_EXTERNAL: u32 = u32::MAX - 2;
+pub(crate) const ID_BUILTIN: u32 = u32::MAX - 2;
+pub(crate) const ID_EXTERNAL: u32 = u32::MAX - 3;
+
+pub(crate) const VARIADIC_ARGS: u32 = u32::MAX;
 
 impl<'x, F> ExpressionParser<'x, F>
 where
@@ -47,9 +52,19 @@ where
             output: Vec::new(),
             operator_stack: Vec::new(),
             arg_count: Vec::new(),
+            max_depth: usize::MAX,
+            max_nodes: usize::MAX,
         }
     }
 
+    /// Limits the nesting of operators, parentheses and function calls, and
+    /// the number of nodes in the parsed expression.
+    pub fn with_limits(mut self, max_depth: usize, max_nodes: usize) -> Self {
+        self.max_depth = max_depth;
+        self.max_nodes = max_nodes;
+        self
+    }
+
     pub fn parse(mut self) -> Result<Self, String> {
         let mut last_is_var_or_fnc = false;
 
@@ -91,6 +106,7 @@ where
                             Some((Token::UnaryOperator(uop), _)) => {
                                 self.output.push(Expression::UnaryOperator(uop))
                             }
+                            Some((Token::Colon, jmp_pos)) => self.update_jmp_pos(jmp_pos),
                             _ => return Err("Mismatched parentheses".to_string()),
                         }
                     }
@@ -99,24 +115,57 @@ where
                         self.operator_stack.last()
                     {
                         let got_args = self.arg_count.pop().unwrap();
-                        if got_args != *num_args as i32 {
-                            return Err(if *id != u32::MAX {
-                                format!(
-                                    "Expression function {:?} expected {} arguments, got {}",
-                                    name, num_args, got_args
-                                )
+                        let expr = if *id == ID_BUILTIN {
+                            let function = BuiltIn::parse(name).unwrap();
+                            let expected = function.num_args();
+                            if got_args < 0 || !expected.contains(&(got_args as u32)) {
+                                return Err(if expected.start() == expected.end() {
+                                    format!(
+                                        "Expression function {:?} expected {} arguments, got {}",
+                                        name,
+                                        expected.start(),
+                                        got_args
+                                    )
+                                } else {
+                                    format!(
+                                        "Expression function {:?} expected {} to {} arguments, got {}",
+                                        name,
+                                        expected.start(),
+                                        expected.end(),
+                                        got_args
+                                    )
+                                });
+                            }
+                            if function == BuiltIn::InList {
+                                Expression::BinaryOperator(BinaryOperator::In)
                             } else {
-                                "Missing array index".to_string()
-                            });
-                        }
+                                Expression::BuiltIn {
+                                    function,
+                                    num_args: got_args as u32,
+                                }
+                            }
+                        } else {
+                            let num_args = if *num_args == VARIADIC_ARGS {
+                                got_args as u32
+                            } else {
+                                *num_args
+                            };
+                            if got_args != num_args as i32 {
+                                return Err(if *id != u32::MAX {
+                                    format!(
+                                        "Expression function {:?} expected {} arguments, got {}",
+                                        name, num_args, got_args
+                                    )
+                                } else {
+                                    "Missing array index".to_string()
+                                });
+                            }
 
-                        let expr = match *id {
-                            ID_ARRAY_ACCESS => Expression::ArrayAccess,
-                            ID_ARRAY_BUILD => Expression::ArrayBuild(*num_args),
-                            id => Expression::Function {
-                                id,
-                                num_args: *num_args,
-                            },
+                            match *id {
+                                ID_ARRAY_ACCESS => Expression::ArrayAccess,
+                                ID_ARRAY_BUILD => Expression::ArrayBuild(num_args),
+                                id => Expression::Function { id, num_args },
+                            }
                         };
 
                         self.operator_stack.pop();
@@ -205,12 +254,72 @@ where
                                 self.output.push(Expression::UnaryOperator(*uop));
                                 self.operator_stack.pop();
                             }
+                            Token::Colon => {
+                                let jmp_pos = *jmp_pos;
+                                self.update_jmp_pos(jmp_pos);
+                                self.operator_stack.pop();
+                            }
                             _ => break,
                         }
                     }
                 }
+                Token::QuestionMark | Token::Colon => {
+                    // Conditionals have the lowest precedence and are right-associative,
+                    // only the branches are evaluated lazily.
+                    self.dec_arg_count();
+                    let is_colon = matches!(token, Token::Colon);
+                    let mut jmp_pos_colon = None;
+                    while let Some((top_token, jmp_pos)) = self.operator_stack.pop() {
+                        match top_token {
+                            Token::BinaryOperator(bop) => {
+                                self.update_jmp_pos(jmp_pos);
+                                self.output.push(Expression::BinaryOperator(bop));
+                            }
+                            Token::UnaryOperator(uop) => {
+                                self.output.push(Expression::UnaryOperator(uop));
+                            }
+                            Token::Colon if is_colon => {
+                                self.update_jmp_pos(jmp_pos);
+                            }
+                            Token::QuestionMark if is_colon => {
+                                self.output.push(Expression::Jmp { pos: 0 });
+                                self.update_jmp_pos(jmp_pos);
+                                jmp_pos_colon = Some(self.output.len() - 1);
+                                break;
+                            }
+                            top_token => {
+                                self.operator_stack.push((top_token, jmp_pos));
+                                break;
+                            }
+                        }
+                    }
+
+                    if is_colon {
+                        if let Some(jmp_pos) = jmp_pos_colon {
+                            self.operator_stack.push((Token::Colon, Some(jmp_pos)));
+                        } else {
+                            return Err("Missing '?' in conditional expression".to_string());
+                        }
+                    } else {
+                        self.output.push(Expression::Branch { pos: 0 });
+                        self.operator_stack
+                            .push((Token::QuestionMark, Some(self.output.len() - 1)));
+                    }
+                }
             }
             last_is_var_or_fnc = is_var_or_fnc;
+
+            if self.operator_stack.len() > self.max_depth {
+                return Err(format!(
+                    "Expression exceeds the maximum nesting depth of {}",
+                    self.max_depth
+                ));
+            } else if self.output.len() > self.max_nodes {
+                return Err(format!(
+                    "Expression exceeds the maximum of {} nodes",
+                    self.max_nodes
+                ));
+            }
         }
 
         while let Some((token, jmp_pos)) = self.operator_stack.pop() {
@@ -220,6 +329,10 @@ where
                     self.output.push(Expression::BinaryOperator(bop))
                 }
                 Token::UnaryOperator(uop) => self.output.push(Expression::UnaryOperator(uop)),
+                Token::Colon => self.update_jmp_pos(jmp_pos),
+                Token::QuestionMark => {
+                    return Err("Missing ':' in conditional expression".to_string())
+                }
                 _ => return Err("Invalid token on the operator stack".to_string()),
             }
         }
@@ -249,11 +362,17 @@ where
     fn update_jmp_pos(&mut self, jmp_pos: Option<usize>) {
         if let Some(jmp_pos) = jmp_pos {
             let cur_pos = self.output.len();
-            if let Expression::JmpIf { pos, .. } = &mut self.output[jmp_pos] {
-                *pos = (cur_pos - jmp_pos) as u32;
-            } else {
-                #[cfg(test)]
-                panic!("Invalid jump position");
+            match &mut self.output[jmp_pos] {
+                Expression::JmpIf { pos, .. } => {
+                    *pos = (cur_pos - jmp_pos) as u32;
+                }
+                Expression::Branch { pos } | Expression::Jmp { pos } => {
+                    *pos = (cur_pos - jmp_pos - 1) as u32;
+                }
+                _ => {
+                    #[cfg(test)]
+                    panic!("Invalid jump position");
+                }
             }
         }
     }
@@ -262,11 +381,14 @@ where
 impl BinaryOperator {
     fn precedence(&self) -> i32 {
         match self {
-            BinaryOperator::Multiply | BinaryOperator::Divide => 7,
-            BinaryOperator::Add | BinaryOperator::Subtract => 6,
-            BinaryOperator::Gt | BinaryOperator::Ge | BinaryOperator::Lt | BinaryOperator::Le => 5,
-            BinaryOperator::Eq | BinaryOperator::Ne => 4,
-            BinaryOperator::Xor => 3,
+            BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Modulo => 10,
+            BinaryOperator::Add | BinaryOperator::Subtract => 9,
+            BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight => 8,
+            BinaryOperator::Gt | BinaryOperator::Ge | BinaryOperator::Lt | BinaryOperator::Le => 7,
+            BinaryOperator::Eq | BinaryOperator::Ne | BinaryOperator::In => 6,
+            BinaryOperator::BitwiseAnd => 5,
+            BinaryOperator::BitwiseXor | BinaryOperator::Xor => 4,
+            BinaryOperator::BitwiseOr => 3,
             BinaryOperator::And => 2,
             BinaryOperator::Or => 1,
         }
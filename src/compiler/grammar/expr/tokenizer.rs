@@ -44,6 +44,8 @@ where
     has_alpha: bool,
     is_start: bool,
     is_eof: bool,
+    after_operand: bool,
+    bitwise: bool,
 }
 
 impl<'x, F> Tokenizer<'x, F>
@@ -67,12 +69,34 @@ where
             has_alpha: false,
             is_start: true,
             is_eof: false,
+            after_operand: false,
+            bitwise: false,
             token_map,
         }
     }
 
+    pub(crate) fn with_bitwise_operators(mut self, bitwise: bool) -> Self {
+        self.bitwise = bitwise;
+        self
+    }
+
     #[allow(clippy::should_implement_trait)]
     pub(crate) fn next(&mut self) -> Result<Option<Token>, String> {
+        let token = self.read_token()?;
+        self.after_operand = matches!(
+            token,
+            Some(
+                Token::Variable(_)
+                    | Token::Number(_)
+                    | Token::String(_)
+                    | Token::CloseParen
+                    | Token::CloseBracket
+            )
+        );
+        Ok(token)
+    }
+
+    fn read_token(&mut self) -> Result<Option<Token>, String> {
         if let Some(token) = self.next_token.pop() {
             return Ok(Some(token));
         } else if self.is_eof {
@@ -125,14 +149,22 @@ where
                         b'&' => {
                             if matches!(self.iter.peek(), Some((_, b'&'))) {
                                 self.iter.next();
+                                Token::BinaryOperator(BinaryOperator::And)
+                            } else if self.bitwise {
+                                Token::BinaryOperator(BinaryOperator::BitwiseAnd)
+                            } else {
+                                Token::BinaryOperator(BinaryOperator::And)
                             }
-                            Token::BinaryOperator(BinaryOperator::And)
                         }
                         b'|' => {
                             if matches!(self.iter.peek(), Some((_, b'|'))) {
                                 self.iter.next();
+                                Token::BinaryOperator(BinaryOperator::Or)
+                            } else if self.bitwise {
+                                Token::BinaryOperator(BinaryOperator::BitwiseOr)
+                            } else {
+                                Token::BinaryOperator(BinaryOperator::Or)
                             }
-                            Token::BinaryOperator(BinaryOperator::Or)
                         }
                         b'!' => {
                             if matches!(self.iter.peek(), Some((_, b'='))) {
@@ -142,6 +174,7 @@ where
                                 Token::UnaryOperator(UnaryOperator::Not)
                             }
                         }
+                        b'^' if self.bitwise => Token::BinaryOperator(BinaryOperator::BitwiseXor),
                         b'^' => Token::BinaryOperator(BinaryOperator::Xor),
                         b'(' => {
                             self.depth += 1;
@@ -157,6 +190,7 @@ where
                         b'+' => Token::BinaryOperator(BinaryOperator::Add),
                         b'*' => Token::BinaryOperator(BinaryOperator::Multiply),
                         b'/' => Token::BinaryOperator(BinaryOperator::Divide),
+                        b'%' => Token::BinaryOperator(BinaryOperator::Modulo),
                         b'-' => {
                             if self.is_start {
                                 Token::UnaryOperator(UnaryOperator::Minus)
@@ -175,6 +209,10 @@ where
                                 self.iter.next();
                                 Token::BinaryOperator(BinaryOperator::Ge)
                             }
+                            Some((_, b'>')) => {
+                                self.iter.next();
+                                Token::BinaryOperator(BinaryOperator::ShiftRight)
+                            }
                             _ => Token::BinaryOperator(BinaryOperator::Gt),
                         },
                         b'<' => match self.iter.peek() {
@@ -182,9 +220,15 @@ where
                                 self.iter.next();
                                 Token::BinaryOperator(BinaryOperator::Le)
                             }
+                            Some((_, b'<')) => {
+                                self.iter.next();
+                                Token::BinaryOperator(BinaryOperator::ShiftLeft)
+                            }
                             _ => Token::BinaryOperator(BinaryOperator::Lt),
                         },
                         b',' => Token::Comma,
+                        b'?' => Token::QuestionMark,
+                        b':' => Token::Colon,
                         b'[' => Token::OpenBracket,
                         b']' => Token::CloseBracket,
                         b' ' | b'\r' | b'\n' => {
@@ -243,7 +287,11 @@ where
                     };
                     self.is_start = matches!(
                         token,
-                        Token::OpenParen | Token::Comma | Token::BinaryOperator(_)
+                        Token::OpenParen
+                            | Token::Comma
+                            | Token::QuestionMark
+                            | Token::Colon
+                            | Token::BinaryOperator(_)
                     );
 
                     return if prev_token.is_some() {
@@ -288,15 +336,39 @@ where
             self.has_number = false;
             self.has_dot = false;
 
-            if !has_number && !has_dot && [4, 5].contains(&buf.len()) {
+            // Only an operator between two operands, otherwise a variable name
+            if buf == "in" && self.after_operand {
+                return Ok(Token::BinaryOperator(BinaryOperator::In));
+            } else if !has_number && !has_dot && [4, 5].contains(&buf.len()) {
                 if buf == "true" {
                     return Ok(Token::Number(Number::Integer(1)));
                 } else if buf == "false" {
                     return Ok(Token::Number(Number::Integer(0)));
                 }
+            } else if has_number && !has_dot {
+                if let Some(seconds) = parse_duration(&buf) {
+                    return Ok(Token::Number(Number::Integer(seconds)));
+                }
             }
 
             (self.token_map)(&buf, has_dot)
         }
     }
 }
+
+// Durations such as "30s", "15m", "12h", "7d" or "2w" in seconds
+fn parse_duration(value: &str) -> Option<i64> {
+    let multiplier = match value.as_bytes().last()? {
+        b's' => 1,
+        b'm' => 60,
+        b'h' => 3600,
+        b'd' => 86400,
+        b'w' => 604800,
+        _ => return None,
+    };
+    let amount = &value[..value.len() - 1];
+    if amount.is_empty() || !amount.bytes().all(|ch| ch.is_ascii_digit()) {
+        return None;
+    }
+    amount.parse::<i64>().ok()?.checked_mul(multiplier)
+}
@@ -28,7 +28,7 @@ use crate::{
     compiler::{
         grammar::{test::Test, MatchType},
         lexer::{tokenizer::Tokenizer, word::Word, Token},
-        CompileError, ErrorType, Value, VariableType,
+        CompileError, CompileWarning, ErrorType, Span, Value, VariableType,
     },
     Compiler, Sieve,
 };
@@ -63,17 +63,17 @@ pub(crate) enum Instruction {
     Discard,
     Stop,
     Invalid(Invalid),
-    Test(Test),
-    Jmp(usize),
-    Jz(usize),
-    Jnz(usize),
+    Test(Box<Test>),
+    Jmp(u32),
+    Jz(u32),
+    Jnz(u32),
 
     // RFC 5703
     ForEveryPartPush,
     ForEveryPart(ForEveryPart),
-    ForEveryPartPop(usize),
-    Replace(Replace),
-    Enclose(Enclose),
+    ForEveryPartPop(u32),
+    Replace(Box<Replace>),
+    Enclose(Box<Enclose>),
     ExtractText(ExtractText),
 
     // RFC 6558
@@ -88,13 +88,13 @@ pub(crate) enum Instruction {
     Clear(Clear),
 
     // RFC 5435
-    Notify(Notify),
+    Notify(Box<Notify>),
 
     // RFC 5429
     Reject(Reject),
 
     // RFC 5230
-    Vacation(Vacation),
+    Vacation(Box<Vacation>),
 
     // RFC 5463
     Error(Error),
@@ -114,7 +114,7 @@ pub(crate) enum Instruction {
     Let(Let),
 
     // Test only
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testsuite"))]
     TestCmd(Vec<Value>),
 }
 
@@ -150,6 +150,8 @@ pub(crate) struct CompilerState<'x> {
     pub(crate) vars_local: usize,
     pub(crate) param_check: [bool; MAX_PARAMS],
     pub(crate) includes_num: usize,
+    pub(crate) uses_body: bool,
+    pub(crate) warnings: Vec<CompileWarning>,
 }
 
 impl Compiler {
@@ -162,6 +164,10 @@ impl Compiler {
             });
         }
 
+        if let Some(compiler) = self.with_script_pragmas(script)? {
+            return compiler.compile(script);
+        }
+
         let mut state = CompilerState {
             compiler: self,
             tokens: Tokenizer::new(self, script),
@@ -176,11 +182,30 @@ impl Compiler {
             vars_local: 0,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
+            uses_body: false,
+            warnings: Vec::new(),
         };
 
+        // Position of the command that produced each instruction
+        let mut spans = Vec::new();
+        let mut span = Span::default();
+
         while let Some(token_info) = state.tokens.next() {
             let token_info = token_info?;
             state.reset_param_check();
+            spans.resize(state.instructions.len(), span);
+            // Jump targets are stored as u32
+            if state.instructions.len() > self.max_instructions.min(u32::MAX as usize) {
+                return Err(CompileError {
+                    line_num: span.line_num,
+                    line_pos: span.line_pos,
+                    error_type: ErrorType::TooManyInstructions,
+                });
+            }
+            span = Span {
+                line_num: token_info.line_num,
+                line_pos: token_info.line_pos,
+            };
 
             match token_info.token {
                 Token::Identifier(instruction) => {
@@ -274,9 +299,7 @@ impl Compiler {
                             state.instructions.push(Instruction::ForEveryPartPush);
                             state
                                 .instructions
-                                .push(Instruction::ForEveryPart(ForEveryPart {
-                                    jz_pos: usize::MAX,
-                                }));
+                                .push(Instruction::ForEveryPart(ForEveryPart { jz_pos: u32::MAX }));
                         }
                         Word::Break => {
                             if let Some(Ok(Token::Tag(Word::Name))) =
@@ -338,7 +361,7 @@ impl Compiler {
                                 block.break_jmps.push(state.instructions.len());
                             }
 
-                            state.instructions.push(Instruction::Jmp(usize::MAX));
+                            state.instructions.push(Instruction::Jmp(u32::MAX));
                         }
                         Word::Replace => {
                             state.validate_argument(
@@ -365,6 +388,14 @@ impl Compiler {
                                 token_info.line_num,
                                 token_info.line_pos,
                             )?;
+                            if !matches!(&state.block.btype, Word::ForEveryPart)
+                                && !state
+                                    .block_stack
+                                    .iter()
+                                    .any(|b| matches!(&b.btype, Word::ForEveryPart))
+                            {
+                                return Err(token_info.custom(ErrorType::ExtractTextOutsideLoop));
+                            }
                             state.parse_extracttext()?;
                         }
 
@@ -529,7 +560,13 @@ impl Compiler {
                                 token_info.line_pos,
                             )?;
                             for global in state.parse_static_strings()? {
-                                if !state.is_var_local(&global) {
+                                if let Err(err) = self
+                                    .variable_names
+                                    .check_namespace("global")
+                                    .and_then(|_| self.variable_names.check_name(&global))
+                                {
+                                    return Err(state.tokens.unwrap_next()?.custom(err));
+                                } else if !state.is_var_local(&global) {
                                     if global.len() < self.max_variable_name_size {
                                         state.register_global_var(&global);
                                     } else {
@@ -582,7 +619,7 @@ impl Compiler {
                             let expr = state.parse_expr()?;
                             state.instructions.push(Instruction::While(While {
                                 expr,
-                                jz_pos: usize::MAX,
+                                jz_pos: u32::MAX,
                             }));
                         }
                         Word::Continue => {
@@ -602,7 +639,7 @@ impl Compiler {
                                 } else if found_while == 1 {
                                     state
                                         .instructions
-                                        .push(Instruction::Jmp(block.last_block_start));
+                                        .push(Instruction::Jmp(block.last_block_start as u32));
                                     found_while += 1;
                                     break;
                                 }
@@ -661,8 +698,8 @@ impl Compiler {
                         Word::ForEveryPart => {
                             state
                                 .instructions
-                                .push(Instruction::Jmp(prev_block.last_block_start));
-                            let cur_pos = state.instructions.len();
+                                .push(Instruction::Jmp(prev_block.last_block_start as u32));
+                            let cur_pos = state.instructions.len() as u32;
                             if let Instruction::ForEveryPart(fep) =
                                 &mut state.instructions[prev_block.last_block_start]
                             {
@@ -686,9 +723,9 @@ impl Compiler {
                             );
                             if next_is_block {
                                 prev_block.if_jmps.push(state.instructions.len());
-                                state.instructions.push(Instruction::Jmp(usize::MAX));
+                                state.instructions.push(Instruction::Jmp(u32::MAX));
                             }
-                            let cur_pos = state.instructions.len();
+                            let cur_pos = state.instructions.len() as u32;
                             if let Instruction::Jz(jmp_pos) =
                                 &mut state.instructions[prev_block.last_block_start]
                             {
@@ -711,7 +748,7 @@ impl Compiler {
                             }
                         }
                         Word::Else => {
-                            let cur_pos = state.instructions.len();
+                            let cur_pos = state.instructions.len() as u32;
                             for pos in prev_block.if_jmps.drain(..) {
                                 if let Instruction::Jmp(jmp_pos) = &mut state.instructions[pos] {
                                     *jmp_pos = cur_pos;
@@ -724,8 +761,8 @@ impl Compiler {
                         Word::While => {
                             state
                                 .instructions
-                                .push(Instruction::Jmp(prev_block.last_block_start));
-                            let cur_pos = state.instructions.len();
+                                .push(Instruction::Jmp(prev_block.last_block_start as u32));
+                            let cur_pos = state.instructions.len() as u32;
                             if let Instruction::While(fep) =
                                 &mut state.instructions[prev_block.last_block_start]
                             {
@@ -750,8 +787,8 @@ impl Compiler {
                     state.block = prev_block;
                 }
 
-                #[cfg(test)]
-                Token::Unknown(instruction) if instruction.contains("test") => {
+                #[cfg(any(test, feature = "testsuite"))]
+                Token::Unknown(instruction) if state.is_test_command(&instruction) => {
                     let has_arguments = instruction != "test";
                     let mut arguments = vec![Value::Text(instruction.into())];
 
@@ -767,10 +804,11 @@ impl Compiler {
                         state.block = new_block;
                     } else {
                         loop {
-                            arguments.push(match state.tokens.unwrap_next()?.token {
+                            let token_info = state.tokens.unwrap_next()?;
+                            arguments.push(match token_info.token {
                                 Token::StringConstant(s) => Value::from(s),
                                 Token::StringVariable(s) => state
-                                    .tokenize_string(&s, true)
+                                    .tokenize_string_token(s)
                                     .map_err(|error_type| CompileError {
                                         line_num: 0,
                                         line_pos: 0,
@@ -783,7 +821,7 @@ impl Compiler {
                                 Token::Tag(s) => Value::Text(format!(":{s}").into()),
                                 Token::Unknown(s) => Value::Text(s.into()),
                                 Token::Semicolon => break,
-                                other => panic!("Invalid test param {other:?}"),
+                                _ => return Err(token_info.expected("test argument")),
                             });
                         }
                         state.instructions.push(Instruction::TestCmd(arguments));
@@ -830,15 +868,106 @@ impl Compiler {
             num_vars += state.vars_local;
         }
 
-        Ok(Sieve {
+        spans.resize(state.instructions.len(), span);
+        if state.instructions.len() > self.max_instructions {
+            return Err(CompileError {
+                line_num: span.line_num,
+                line_pos: span.line_pos,
+                error_type: ErrorType::TooManyInstructions,
+            });
+        }
+
+        let mut capabilities: Vec<Capability> = Vec::new();
+        for instruction in &state.instructions {
+            if let Instruction::Require(required) = instruction {
+                for capability in required {
+                    if !capabilities.contains(capability) {
+                        capabilities.push(capability.clone());
+                    }
+                }
+            }
+        }
+
+        let sieve = Sieve {
+            uses_body: state.uses_body || state.instructions.iter().any(|i| i.uses_body()),
             instructions: state.instructions,
+            spans,
             num_vars,
             num_match_vars: state.vars_match_max,
-        })
+            unknown_tags: std::mem::take(&mut state.tokens.unknown_tags),
+            capabilities,
+            warnings: state.warnings,
+        };
+
+        if self.max_compiled_size > 0
+            && sieve
+                .serialized_size()
+                .map_or(true, |size| size > self.max_compiled_size)
+        {
+            return Err(CompileError {
+                line_num: 0,
+                line_pos: 0,
+                error_type: ErrorType::CompiledScriptTooLarge,
+            });
+        }
+
+        Ok(sieve)
+    }
+
+    /// Compiles a batch of scripts, returning one result per script in the
+    /// same order. With the `parallel` feature the scripts are compiled on
+    /// the rayon thread pool.
+    pub fn compile_many<S: AsRef<[u8]> + Sync>(
+        &self,
+        scripts: &[S],
+    ) -> Vec<Result<Sieve, CompileError>> {
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+
+            scripts
+                .par_iter()
+                .map(|script| self.compile(script.as_ref()))
+                .collect()
+        }
+
+        #[cfg(not(feature = "parallel"))]
+        {
+            scripts
+                .iter()
+                .map(|script| self.compile(script.as_ref()))
+                .collect()
+        }
+    }
+}
+
+impl Instruction {
+    pub(crate) fn uses_body(&self) -> bool {
+        match self {
+            Instruction::ForEveryPart(_)
+            | Instruction::Replace(_)
+            | Instruction::Enclose(_)
+            | Instruction::ExtractText(_)
+            | Instruction::Convert(_) => true,
+            Instruction::Test(test) => match test.as_ref() {
+                Test::Body(_) | Test::Convert(_) => true,
+                Test::Header(test) => test.mime_anychild,
+                Test::Address(test) => test.mime_anychild,
+                Test::Exists(test) => test.mime_anychild,
+                Test::Date(test) => test.mime_anychild,
+                _ => false,
+            },
+            _ => false,
+        }
     }
 }
 
 impl<'x> CompilerState<'x> {
+    #[cfg(any(test, feature = "testsuite"))]
+    pub(crate) fn is_test_command(&self, name: &str) -> bool {
+        self.compiler.test_commands && name.contains("test")
+    }
+
     pub(crate) fn is_var_local(&self, name: &str) -> bool {
         let name = name.to_ascii_lowercase();
         if self.block.vars_local.contains_key(&name) {
@@ -913,7 +1042,7 @@ impl<'x> CompilerState<'x> {
 
             for pos in &block.match_test_pos {
                 if let Instruction::Test(test) = &mut self.instructions[*pos] {
-                    let match_type = match test {
+                    let match_type = match test.as_mut() {
                         Test::Address(t) => &mut t.match_type,
                         Test::Body(t) => &mut t.match_type,
                         Test::Date(t) => &mut t.match_type,
@@ -1152,7 +1281,7 @@ impl MapLocalVars for Test {
                 v.handle.map_local_vars(last_id);
                 v.reason.map_local_vars(last_id);
             }
-            #[cfg(test)]
+            #[cfg(any(test, feature = "testsuite"))]
             Test::TestCmd { arguments, .. } => {
                 arguments.map_local_vars(last_id);
             }
@@ -29,8 +29,9 @@ use serde::{Deserialize, Serialize};
 use self::{expr::Expression, instruction::CompilerState};
 
 use super::{
+    header_key::HeaderKey,
     lexer::{tokenizer::TokenInfo, word::Word, Token},
-    CompileError, ErrorType, Regex, Value,
+    CompileError, ContainsKeys, ErrorType, Glob, Regex, Value,
 };
 
 pub mod actions;
@@ -39,6 +40,9 @@ pub mod instruction;
 pub mod test;
 pub mod tests;
 
+// Minimum number of constant keys for :contains to be matched using an automaton
+const MIN_CONTAINS_KEYS: usize = 4;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub enum Capability {
     Envelope,
@@ -90,6 +94,8 @@ pub enum Capability {
     // Extensions
     Expressions,
     While,
+    SizeModes,
+    DateParts,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -132,6 +138,18 @@ pub enum Comparator {
     Other(String),
 }
 
+impl Comparator {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Comparator::Elbonia => "elbonia",
+            Comparator::Octet => "i;octet",
+            Comparator::AsciiCaseMap => "i;ascii-casemap",
+            Comparator::AsciiNumeric => "i;ascii-numeric",
+            Comparator::Other(comparator) => comparator,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Clear {
     pub(crate) local_vars_idx: u32,
@@ -149,7 +167,7 @@ pub struct Invalid {
 #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub(crate) struct While {
     pub expr: Vec<Expression>,
-    pub jz_pos: usize,
+    pub jz_pos: u32,
 }
 
 impl<'x> CompilerState<'x> {
@@ -246,12 +264,26 @@ impl<'x> CompilerState<'x> {
     }
 
     pub(crate) fn parse_comparator(&mut self) -> Result<Comparator, CompileError> {
+        let (line_num, line_pos) = match self.tokens.peek() {
+            Some(Ok(token_info)) => (token_info.line_num, token_info.line_pos),
+            _ => (0, 0),
+        };
         let comparator = self.tokens.expect_static_string()?;
-        Ok(if let Some(comparator) = COMPARATOR.get(&comparator) {
+        let comparator = if let Some(comparator) = COMPARATOR.get(&comparator) {
             comparator.clone()
         } else {
             Comparator::Other(comparator)
-        })
+        };
+
+        if self.compiler.comparators.contains(&comparator) {
+            Ok(comparator)
+        } else {
+            Err(CompileError {
+                line_num,
+                line_pos,
+                error_type: ErrorType::UnsupportedComparator(comparator.as_str().to_string()),
+            })
+        }
     }
 
     pub(crate) fn parse_static_strings(&mut self) -> Result<Vec<String>, CompileError> {
@@ -282,7 +314,7 @@ impl<'x> CompilerState<'x> {
         match next_token.token {
             Token::StringConstant(s) => Ok(Value::from(s)),
             Token::StringVariable(s) => {
-                self.tokenize_string(&s, true)
+                self.tokenize_string_token(s)
                     .map_err(|error_type| CompileError {
                         line_num: next_token.line_num,
                         line_pos: next_token.line_pos,
@@ -306,7 +338,7 @@ impl<'x> CompilerState<'x> {
             Token::BracketOpen => self.parse_string_list(allow_empty),
             Token::StringConstant(s) => Ok(vec![Value::from(s)]),
             Token::StringVariable(s) => {
-                self.tokenize_string(&s, true)
+                self.tokenize_string_token(s)
                     .map(|s| vec![s])
                     .map_err(|error_type| CompileError {
                         line_num: token_info.line_num,
@@ -325,7 +357,7 @@ impl<'x> CompilerState<'x> {
         match token_info.token {
             Token::StringConstant(s) => Ok(Value::from(s)),
             Token::StringVariable(s) => {
-                self.tokenize_string(&s, true)
+                self.tokenize_string_token(s)
                     .map_err(|error_type| CompileError {
                         line_num: token_info.line_num,
                         line_pos: token_info.line_pos,
@@ -343,7 +375,7 @@ impl<'x> CompilerState<'x> {
         match token_info.token {
             Token::StringConstant(s) => Ok(vec![Value::from(s)]),
             Token::StringVariable(s) => {
-                self.tokenize_string(&s, true)
+                self.tokenize_string_token(s)
                     .map(|s| vec![s])
                     .map_err(|error_type| CompileError {
                         line_num: token_info.line_num,
@@ -368,7 +400,7 @@ impl<'x> CompilerState<'x> {
                     strings.push(Value::from(s));
                 }
                 Token::StringVariable(s) => {
-                    strings.push(self.tokenize_string(&s, true).map_err(|error_type| {
+                    strings.push(self.tokenize_string_token(s).map_err(|error_type| {
                         CompileError {
                             line_num: token_info.line_num,
                             line_pos: token_info.line_pos,
@@ -459,9 +491,45 @@ impl<'x> CompilerState<'x> {
                     }
                 }
             }
+        } else if matches!(match_type, MatchType::Matches(_)) {
+            for key in key_list {
+                if let Value::Text(expr) = key {
+                    *key = Value::Glob(Glob::new(expr.to_string()));
+                }
+            }
         }
         Ok(())
     }
+
+    pub(crate) fn validate_contains(&self, match_type: &MatchType, key_list: &mut Vec<Value>) {
+        if matches!(match_type, MatchType::Contains)
+            && key_list.len() >= MIN_CONTAINS_KEYS
+            && key_list
+                .iter()
+                .all(|key| matches!(key, Value::Text(_) | Value::Number(_)))
+        {
+            if let Ok(keys) =
+                ContainsKeys::new(key_list.iter().map(|key| key.to_string()).collect())
+            {
+                *key_list = vec![Value::Contains(keys)];
+            }
+        }
+    }
+
+    pub(crate) fn compile_header_names(&self, header_names: &mut [Value]) {
+        for header_name in header_names {
+            self.compile_header_name(header_name);
+        }
+    }
+
+    // Constant header names are parsed and lowercased once, rather than on every message
+    pub(crate) fn compile_header_name(&self, header_name: &mut Value) {
+        if let Value::Text(name) = header_name {
+            if let Some(key) = HeaderKey::new(name.as_str()) {
+                *header_name = Value::Header(key);
+            }
+        }
+    }
 }
 
 impl Capability {
@@ -625,6 +693,8 @@ impl Display for Capability {
             Capability::SpamTestPlus => f.write_str("spamtestplus"),
             Capability::VirusTest => f.write_str("virustest"),
             Capability::While => f.write_str("vnd.stalwart.while"),
+            Capability::SizeModes => f.write_str("vnd.stalwart.size"),
+            Capability::DateParts => f.write_str("vnd.stalwart.date"),
             Capability::Expressions => f.write_str("vnd.stalwart.expressions"),
             Capability::Other(capability) => f.write_str(capability),
         }
@@ -682,5 +752,7 @@ static CAPABILITIES: phf::Map<&'static str, Capability> = phf_map! {
 
     // Extensions
     "vnd.stalwart.while" => Capability::While,
+    "vnd.stalwart.size" => Capability::SizeModes,
+    "vnd.stalwart.date" => Capability::DateParts,
     "vnd.stalwart.expressions" => Capability::Expressions,
 };
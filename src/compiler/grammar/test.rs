@@ -25,12 +25,15 @@ use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
     lexer::{tokenizer::TokenInfo, word::Word, Token},
-    CompileError, ErrorType,
+    CompileError, ErrorType, VariableType,
 };
 
 use super::{
     actions::{action_convert::Convert, action_vacation::TestVacation},
-    expr::{parser::ExpressionParser, tokenizer::Tokenizer, Expression, UnaryOperator},
+    expr::{
+        functions::BuiltIn, parser::ExpressionParser, tokenizer::Tokenizer, Expression,
+        UnaryOperator,
+    },
     instruction::{CompilerState, Instruction},
     tests::{
         test_address::TestAddress,
@@ -114,7 +117,7 @@ pub(crate) enum Test {
     Vacation(TestVacation),
 
     // Only test
-    #[cfg(test)]
+    #[cfg(any(test, feature = "testsuite"))]
     TestCmd {
         arguments: Vec<crate::compiler::Value>,
         is_not: bool,
@@ -168,9 +171,9 @@ impl<'x> CompilerState<'x> {
                         is_not = block.is_not;
                         block.jmps.push(self.instructions.len());
                         self.instructions.push(if block.is_all {
-                            Instruction::Jz(usize::MAX)
+                            Instruction::Jz(u32::MAX)
                         } else {
-                            Instruction::Jnz(usize::MAX)
+                            Instruction::Jnz(u32::MAX)
                         });
                         continue;
                     }
@@ -183,7 +186,7 @@ impl<'x> CompilerState<'x> {
                             block.p_count -= 1;
                             continue;
                         } else if let Some(prev_block) = block_stack.pop() {
-                            let cur_pos = self.instructions.len();
+                            let cur_pos = self.instructions.len() as u32;
                             for jmp_pos in block.jmps {
                                 if let Instruction::Jnz(jmp_pos) | Instruction::Jz(jmp_pos) =
                                     &mut self.instructions[jmp_pos]
@@ -514,8 +517,8 @@ impl<'x> CompilerState<'x> {
                         })
                         .into()
                     }
-                    #[cfg(test)]
-                    Token::Unknown(name) if name.contains("test") => {
+                    #[cfg(any(test, feature = "testsuite"))]
+                    Token::Unknown(name) if self.is_test_command(&name) => {
                         use crate::compiler::Value;
 
                         let mut arguments = Vec::new();
@@ -526,7 +529,8 @@ impl<'x> CompilerState<'x> {
                                 | Token::ParenthesisClose
                                 | Token::CurlyOpen))
                         ) {
-                            arguments.push(match self.tokens.unwrap_next()?.token {
+                            let token_info = self.tokens.unwrap_next()?;
+                            arguments.push(match token_info.token {
                                 Token::StringConstant(s) => Value::from(s),
                                 Token::StringVariable(s) => self
                                     .tokenize_string(&s, true)
@@ -541,7 +545,7 @@ impl<'x> CompilerState<'x> {
                                 Token::Identifier(s) => Value::Text(s.to_string().into()),
                                 Token::Tag(s) => Value::Text(format!(":{s}").into()),
                                 Token::Unknown(s) => Value::Text(s.into()),
-                                other => panic!("Invalid test param {other:?}"),
+                                _ => return Err(token_info.expected("test argument")),
                             });
                         }
                         Test::TestCmd {
@@ -575,7 +579,7 @@ impl<'x> CompilerState<'x> {
             }
         }
 
-        self.instructions.push(Instruction::Jz(usize::MAX));
+        self.instructions.push(Instruction::Jz(u32::MAX));
         Ok(())
     }
 
@@ -587,13 +591,34 @@ impl<'x> CompilerState<'x> {
             _ => return Err(next_token.expected("string")),
         };
 
-        match ExpressionParser::from_tokenizer(Tokenizer::from_iter(
-            expr.iter().enumerate().peekable(),
-            |var_name, maybe_namespace| self.parse_expr_fnc_or_var(var_name, maybe_namespace),
-        ))
+        match ExpressionParser::from_tokenizer(
+            Tokenizer::from_iter(
+                expr.iter().enumerate().peekable(),
+                |var_name, maybe_namespace| self.parse_expr_fnc_or_var(var_name, maybe_namespace),
+            )
+            .with_bitwise_operators(self.compiler.bitwise_operators),
+        )
+        .with_limits(
+            self.compiler.max_expression_depth,
+            self.compiler.max_expression_nodes,
+        )
         .parse()
         {
-            Ok(parser) => Ok(parser.output),
+            Ok(parser) => {
+                if parser.output.iter().any(|e| {
+                    matches!(
+                        e,
+                        Expression::Variable(VariableType::Part(_))
+                            | Expression::BuiltIn {
+                                function: BuiltIn::BodyText,
+                                ..
+                            }
+                    )
+                }) {
+                    self.uses_body = true;
+                }
+                Ok(parser.output)
+            }
             Err(err) => {
                 let err = ErrorType::InvalidExpression(format!(
                     "{}: {}",
@@ -609,16 +634,16 @@ impl<'x> CompilerState<'x> {
 
 impl From<Test> for Instruction {
     fn from(test: Test) -> Self {
-        Instruction::Test(test)
+        Instruction::Test(Box::new(test))
     }
 }
 
 impl Instruction {
     pub fn set_not(mut self) -> Self {
         match &mut self {
-            Instruction::Test(test) => match test {
-                Test::True => return Instruction::Test(Test::False),
-                Test::False => return Instruction::Test(Test::True),
+            Instruction::Test(test) => match test.as_mut() {
+                Test::True => return Test::False.into(),
+                Test::False => return Test::True.into(),
                 Test::Address(op) => {
                     op.is_not = true;
                 }
@@ -688,7 +713,7 @@ impl Instruction {
                 Test::SpecialUseExists(op) => {
                     op.is_not = true;
                 }
-                #[cfg(test)]
+                #[cfg(any(test, feature = "testsuite"))]
                 Test::TestCmd { is_not, .. } => {
                     *is_not = true;
                 }
@@ -40,6 +40,7 @@ pub(crate) struct TestAddress {
     pub comparator: Comparator,
     pub index: Option<i32>,
 
+    pub mime: bool,
     pub mime_anychild: bool,
     pub is_not: bool,
 }
@@ -144,7 +145,9 @@ impl<'x> CompilerState<'x> {
                 }
                 _ => {
                     if header_list.is_none() {
-                        header_list = self.parse_strings_token(token_info)?.into();
+                        let mut headers = self.parse_strings_token(token_info)?;
+                        self.compile_header_names(&mut headers);
+                        header_list = headers.into();
                     } else {
                         key_list = self.parse_strings_token(token_info)?;
                         break;
@@ -165,6 +168,7 @@ impl<'x> CompilerState<'x> {
             match_type,
             comparator,
             index: if index_last { index.map(|i| -i) } else { index },
+            mime,
             mime_anychild,
             is_not: false,
         }))
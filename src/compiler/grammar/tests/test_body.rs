@@ -108,6 +108,7 @@ impl<'x> CompilerState<'x> {
             }
         }
         self.validate_match(&match_type, &mut key_list)?;
+        self.validate_contains(&match_type, &mut key_list);
 
         Ok(Test::Body(TestBody {
             key_list,
@@ -78,6 +78,11 @@ pub(crate) enum DatePart {
     Std11,
     Zone,
     Weekday,
+
+    // Extensions
+    Epoch,
+    IsoWeek,
+    Millisecond,
 }
 
 impl<'x> CompilerState<'x> {
@@ -170,7 +175,7 @@ impl<'x> CompilerState<'x> {
                 }
                 _ => {
                     if header_name.is_none() {
-                        let header = self.parse_string_token(token_info)?;
+                        let mut header = self.parse_string_token(token_info)?;
                         if let Value::Text(header_name) = &header {
                             if HeaderName::parse(header_name.as_ref()).is_none() {
                                 return Err(self
@@ -179,12 +184,21 @@ impl<'x> CompilerState<'x> {
                                     .custom(ErrorType::InvalidHeaderName));
                             }
                         }
+                        self.compile_header_name(&mut header);
                         header_name = header.into();
                     } else if date_part.is_none() {
                         if let Token::StringConstant(string) = &token_info.token {
                             if let Some(date_part_) =
                                 DATE_PART.get(&string.to_string().to_ascii_lowercase())
                             {
+                                if date_part_.is_extended() {
+                                    self.validate_argument(
+                                        0,
+                                        Capability::DateParts.into(),
+                                        token_info.line_num,
+                                        token_info.line_pos,
+                                    )?;
+                                }
                                 date_part = (*date_part_).into();
                                 continue;
                             }
@@ -263,6 +277,14 @@ impl<'x> CompilerState<'x> {
                             if let Some(date_part_) =
                                 DATE_PART.get(&string.to_string().to_ascii_lowercase())
                             {
+                                if date_part_.is_extended() {
+                                    self.validate_argument(
+                                        0,
+                                        Capability::DateParts.into(),
+                                        token_info.line_num,
+                                        token_info.line_pos,
+                                    )?;
+                                }
                                 date_part = (*date_part_).into();
                                 continue;
                             }
@@ -335,9 +357,17 @@ impl<'x> CompilerState<'x> {
                     offset of 0 (Zulu) always has a positive sign.
      "weekday"   => the day of the week expressed as an integer between
                     "0" and "6". "0" is Sunday, "1" is Monday, etc.
+
+   Extensions (vnd.stalwart.date):
+
+     "epoch"       => the number of seconds since the Unix epoch.
+     "isoweek"     => the ISO 8601 week date in "yyyy-Www" format.
+     "millisecond" => the millisecond, "000" .. "999". Only currentdate
+                      has sub-second precision, dates read from headers
+                      always return "000".
 */
 
-static DATE_PART: phf::Map<&'static str, DatePart> = phf_map! {
+pub(crate) static DATE_PART: phf::Map<&'static str, DatePart> = phf_map! {
     "year" => DatePart::Year,
     "month" => DatePart::Month,
     "day" => DatePart::Day,
@@ -351,4 +381,16 @@ static DATE_PART: phf::Map<&'static str, DatePart> = phf_map! {
     "std11" => DatePart::Std11,
     "zone" => DatePart::Zone,
     "weekday" => DatePart::Weekday,
+    "epoch" => DatePart::Epoch,
+    "isoweek" => DatePart::IsoWeek,
+    "millisecond" => DatePart::Millisecond,
 };
+
+impl DatePart {
+    pub(crate) fn is_extended(&self) -> bool {
+        matches!(
+            self,
+            DatePart::Epoch | DatePart::IsoWeek | DatePart::Millisecond
+        )
+    }
+}
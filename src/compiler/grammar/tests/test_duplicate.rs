@@ -69,7 +69,7 @@ impl<'x> CompilerState<'x> {
                 Token::Tag(Word::Header) => {
                     self.validate_argument(2, None, line_num, line_pos)?;
                     self.tokens.next();
-                    let header = self.parse_string()?;
+                    let mut header = self.parse_string()?;
                     if let Value::Text(header_name) = &header {
                         if HeaderName::parse(header_name.as_ref()).is_none() {
                             return Err(self
@@ -78,6 +78,7 @@ impl<'x> CompilerState<'x> {
                                 .custom(ErrorType::InvalidHeaderName));
                         }
                     }
+                    self.compile_header_name(&mut header);
                     dup_match = DupMatch::Header(header);
                 }
                 Token::Tag(Word::UniqueId) => {
@@ -47,7 +47,8 @@ impl<'x> CompilerState<'x> {
                     | Word::Matches
                     | Word::Value
                     | Word::Count
-                    | Word::Regex),
+                    | Word::Regex
+                    | Word::List),
                 ) => {
                     self.validate_argument(
                         1,
@@ -35,6 +35,7 @@ use crate::compiler::grammar::test::Test;
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) struct TestExists {
     pub header_names: Vec<Value>,
+    pub mime: bool,
     pub mime_anychild: bool,
     pub is_not: bool,
 }
@@ -68,7 +69,7 @@ impl<'x> CompilerState<'x> {
                     mime_anychild = true;
                 }
                 _ => {
-                    let headers = self.parse_strings_token(token_info)?;
+                    let mut headers = self.parse_strings_token(token_info)?;
                     for header in &headers {
                         if let Value::Text(header_name) = &header {
                             if HeaderName::parse(header_name.as_ref()).is_none() {
@@ -79,6 +80,7 @@ impl<'x> CompilerState<'x> {
                             }
                         }
                     }
+                    self.compile_header_names(&mut headers);
                     header_names = headers.into();
                 }
             }
@@ -90,6 +92,7 @@ impl<'x> CompilerState<'x> {
 
         Ok(Test::Exists(TestExists {
             header_names: header_names.unwrap(),
+            mime,
             mime_anychild,
             is_not: false,
         }))
@@ -45,6 +45,7 @@ pub(crate) struct TestHeader {
     pub index: Option<i32>,
 
     pub mime_opts: MimeOpts<Value>,
+    pub mime: bool,
     pub mime_anychild: bool,
     pub is_not: bool,
 }
@@ -144,7 +145,7 @@ impl<'x> CompilerState<'x> {
                 }
                 _ => {
                     if header_list.is_none() {
-                        let headers = self.parse_strings_token(token_info)?;
+                        let mut headers = self.parse_strings_token(token_info)?;
                         for header in &headers {
                             if let Value::Text(header_name) = &header {
                                 if HeaderName::parse(header_name.as_ref()).is_none() {
@@ -155,6 +156,7 @@ impl<'x> CompilerState<'x> {
                                 }
                             }
                         }
+                        self.compile_header_names(&mut headers);
                         header_list = headers.into();
                     } else {
                         key_list = self.parse_strings_token(token_info)?;
@@ -168,6 +170,7 @@ impl<'x> CompilerState<'x> {
             return Err(self.tokens.unwrap_next()?.missing_tag(":mime"));
         }
         self.validate_match(&match_type, &mut key_list)?;
+        self.validate_contains(&match_type, &mut key_list);
 
         Ok(Test::Header(TestHeader {
             header_list: header_list.unwrap(),
@@ -176,6 +179,7 @@ impl<'x> CompilerState<'x> {
             comparator,
             index: if index_last { index.map(|i| -i) } else { index },
             mime_opts,
+            mime,
             mime_anychild,
             is_not: false,
         }))
@@ -24,7 +24,7 @@
 use serde::{Deserialize, Serialize};
 
 use crate::compiler::{
-    grammar::instruction::CompilerState,
+    grammar::{instruction::CompilerState, Capability},
     lexer::{word::Word, Token},
     CompileError,
 };
@@ -35,28 +35,58 @@ use crate::compiler::grammar::test::Test;
 pub(crate) struct TestSize {
     pub over: bool,
     pub limit: usize,
+    pub mode: SizeMode,
     pub is_not: bool,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum SizeMode {
+    Raw,
+    Decoded,
+    Body,
+}
+
 impl<'x> CompilerState<'x> {
     pub(crate) fn parse_test_size(&mut self) -> Result<Test, CompileError> {
-        let token_info = self.tokens.unwrap_next()?;
-        let over = match token_info.token {
-            Token::Tag(Word::Over) => true,
-            Token::Tag(Word::Under) => false,
-            _ => {
-                return Err(token_info.expected("':over' or ':under'"));
+        let mut over = None;
+        let mut mode = SizeMode::Raw;
+
+        loop {
+            let token_info = self.tokens.unwrap_next()?;
+            match token_info.token {
+                Token::Tag(word @ (Word::Over | Word::Under)) => {
+                    self.validate_argument(1, None, token_info.line_num, token_info.line_pos)?;
+                    over = Some(word == Word::Over);
+                }
+                Token::Tag(word @ (Word::Raw | Word::Decoded | Word::Body)) => {
+                    self.validate_argument(
+                        2,
+                        Capability::SizeModes.into(),
+                        token_info.line_num,
+                        token_info.line_pos,
+                    )?;
+                    mode = match word {
+                        Word::Raw => SizeMode::Raw,
+                        Word::Decoded => SizeMode::Decoded,
+                        _ => SizeMode::Body,
+                    };
+                }
+                Token::Number(limit) if over.is_some() => {
+                    return Ok(Test::Size(TestSize {
+                        over: over.unwrap(),
+                        limit,
+                        mode,
+                        is_not: false,
+                    }));
+                }
+                _ => {
+                    return Err(token_info.expected(if over.is_some() {
+                        "number"
+                    } else {
+                        "':over' or ':under'"
+                    }));
+                }
             }
-        };
-        let token_info = self.tokens.unwrap_next()?;
-        if let Token::Number(limit) = token_info.token {
-            Ok(Test::Size(TestSize {
-                over,
-                limit,
-                is_not: false,
-            }))
-        } else {
-            Err(token_info.expected("number"))
         }
     }
 }
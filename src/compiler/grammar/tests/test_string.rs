@@ -88,6 +88,7 @@ impl<'x> CompilerState<'x> {
             }
         }
         self.validate_match(&match_type, &mut key_list)?;
+        self.validate_contains(&match_type, &mut key_list);
 
         Ok(Test::String(TestString {
             source: source.unwrap(),
@@ -0,0 +1,187 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    borrow::Borrow,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
+
+use mail_parser::HeaderName;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Header names are compared case-insensitively, so constant header names are
+// parsed and lowercased once at compile time. The lowercase form is also what
+// the runtime uses to key its per-part header index, and it is stored inline
+// since almost all header names fit in a few bytes.
+
+const INLINE_CAPACITY: usize = 22;
+
+#[derive(Debug, Clone)]
+pub struct HeaderKey {
+    pub expr: String,
+    pub name: HeaderName<'static>,
+    pub key: LowercaseName,
+}
+
+#[derive(Clone)]
+pub enum LowercaseName {
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_CAPACITY],
+    },
+    Heap(Box<str>),
+}
+
+impl HeaderKey {
+    pub fn new(expr: impl Into<String>) -> Option<Self> {
+        let expr = expr.into();
+        let name = match HeaderName::parse(expr.as_str())? {
+            HeaderName::Other(_) => HeaderName::Other(expr.clone().into()),
+            name => name.into_owned(),
+        };
+        Some(HeaderKey {
+            key: LowercaseName::new(&expr),
+            name,
+            expr,
+        })
+    }
+}
+
+impl LowercaseName {
+    pub fn new(name: &str) -> Self {
+        if name.len() <= INLINE_CAPACITY {
+            let mut bytes = [0u8; INLINE_CAPACITY];
+            bytes[..name.len()].copy_from_slice(name.as_bytes());
+            bytes[..name.len()].make_ascii_lowercase();
+            LowercaseName::Inline {
+                len: name.len() as u8,
+                bytes,
+            }
+        } else {
+            LowercaseName::Heap(name.to_ascii_lowercase().into_boxed_str())
+        }
+    }
+
+    #[inline(always)]
+    pub fn as_str(&self) -> &str {
+        match self {
+            // Lowercasing ASCII bytes keeps the copied string valid UTF-8
+            LowercaseName::Inline { len, bytes } => {
+                std::str::from_utf8(&bytes[..*len as usize]).unwrap_or_default()
+            }
+            LowercaseName::Heap(name) => name,
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(self, LowercaseName::Inline { .. })
+    }
+}
+
+impl PartialEq for HeaderKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.expr == other.expr
+    }
+}
+
+impl Eq for HeaderKey {}
+
+impl PartialEq for LowercaseName {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for LowercaseName {}
+
+// Hashes like `str` so that the header index can be queried with a `&str`
+impl Hash for LowercaseName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl Borrow<str> for LowercaseName {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Debug for LowercaseName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for HeaderKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.expr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <String>::deserialize(deserializer).and_then(|expr| {
+            HeaderKey::new(expr).ok_or_else(|| serde::de::Error::custom("Invalid header name"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use mail_parser::HeaderName;
+
+    use super::{HeaderKey, LowercaseName, INLINE_CAPACITY};
+
+    #[test]
+    fn header_key() {
+        let key = HeaderKey::new("X-Spam-Status").unwrap();
+        assert_eq!(key.expr, "X-Spam-Status");
+        assert_eq!(key.key.as_str(), "x-spam-status");
+        assert!(key.key.is_inline());
+        assert_eq!(HeaderKey::new("SUBJECT").unwrap().name, HeaderName::Subject);
+        assert!(HeaderKey::new("").is_none());
+
+        let long_name = "X-".repeat(INLINE_CAPACITY);
+        let long_key = LowercaseName::new(&long_name);
+        assert!(!long_key.is_inline());
+        assert_eq!(long_key.as_str(), long_name.to_ascii_lowercase());
+
+        // Keys can be looked up by their lowercase text
+        let mut index = HashMap::new();
+        index.insert(LowercaseName::new("Received"), 1);
+        index.insert(long_key, 2);
+        assert_eq!(index.get("received"), Some(&1));
+        assert_eq!(index.get(LowercaseName::new("RECEIVED").as_str()), Some(&1));
+        assert_eq!(index.get(long_name.to_ascii_lowercase().as_str()), Some(&2));
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+// Scratch buffers used while compiling a script. Token and decode buffers are
+// handed back once their contents have been parsed into a `Value`, so large
+// scripts reuse a handful of allocations instead of creating one per string.
+// Everything is released when the compiler state is dropped.
+
+const MAX_FREE_BUFFERS: usize = 32;
+const MAX_BUFFER_CAPACITY: usize = 64 * 1024;
+
+#[derive(Default)]
+pub(crate) struct ScratchArena {
+    free: Vec<Vec<u8>>,
+}
+
+impl ScratchArena {
+    pub fn new() -> Self {
+        ScratchArena {
+            free: Vec::with_capacity(MAX_FREE_BUFFERS),
+        }
+    }
+
+    #[inline(always)]
+    pub fn alloc(&mut self, capacity: usize) -> Vec<u8> {
+        match self.free.pop() {
+            Some(mut buf) => {
+                buf.reserve(capacity);
+                buf
+            }
+            None => Vec::with_capacity(capacity),
+        }
+    }
+
+    #[inline(always)]
+    pub fn alloc_from(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut buf = self.alloc(bytes.len());
+        buf.extend_from_slice(bytes);
+        buf
+    }
+
+    #[inline(always)]
+    pub fn free(&mut self, mut buf: Vec<u8>) {
+        if self.free.len() < MAX_FREE_BUFFERS && buf.capacity() <= MAX_BUFFER_CAPACITY {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ScratchArena, MAX_BUFFER_CAPACITY, MAX_FREE_BUFFERS};
+
+    #[test]
+    fn scratch_arena() {
+        let mut arena = ScratchArena::new();
+
+        let buf = arena.alloc_from(b"hello");
+        let ptr = buf.as_ptr();
+        assert_eq!(buf, b"hello");
+        arena.free(buf);
+
+        // Freed buffers are reused and come back empty
+        let buf = arena.alloc(3);
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        arena.free(buf);
+
+        // Oversized buffers are not kept around
+        let mut buf = arena.alloc(MAX_BUFFER_CAPACITY + 1);
+        buf.push(0);
+        arena.free(buf);
+        assert!(arena.free.is_empty());
+
+        for _ in 0..MAX_FREE_BUFFERS + 5 {
+            arena.free(Vec::with_capacity(8));
+        }
+        assert_eq!(arena.free.len(), MAX_FREE_BUFFERS);
+    }
+}
@@ -21,6 +21,8 @@
  * for more details.
 */
 
+pub mod arena;
+pub mod pragma;
 pub mod string;
 pub mod tokenizer;
 pub mod word;
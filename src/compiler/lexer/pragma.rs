@@ -0,0 +1,198 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{
+    compiler::{CompileError, ErrorType},
+    Compiler, PragmaBounds, UnknownTagPolicy,
+};
+
+const PRAGMA_PREFIX: &str = "sieve:";
+
+impl PragmaBounds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `strict=off`, which disables the checks for undeclared
+    /// capabilities.
+    pub fn set_allow_non_strict(&mut self, allow: bool) {
+        self.allow_non_strict = allow;
+    }
+
+    pub fn with_allow_non_strict(mut self, allow: bool) -> Self {
+        self.set_allow_non_strict(allow);
+        self
+    }
+
+    /// Allows `unknown-tags=error|ignore|preserve`.
+    pub fn set_allow_unknown_tags(&mut self, allow: bool) {
+        self.allow_unknown_tags = allow;
+    }
+
+    pub fn with_allow_unknown_tags(mut self, allow: bool) -> Self {
+        self.set_allow_unknown_tags(allow);
+        self
+    }
+
+    /// Highest value accepted by `max-nesting`, zero to reject it.
+    pub fn set_max_nesting(&mut self, max_nesting: usize) {
+        self.max_nesting = max_nesting;
+    }
+
+    pub fn with_max_nesting(mut self, max_nesting: usize) -> Self {
+        self.set_max_nesting(max_nesting);
+        self
+    }
+}
+
+impl Compiler {
+    // Applies the pragmas found in the comments preceding the first command,
+    // returning `None` when the script has none.
+    pub(crate) fn with_script_pragmas(&self, script: &[u8]) -> Result<Option<Self>, CompileError> {
+        let bounds = if let Some(bounds) = &self.pragma_bounds {
+            bounds
+        } else {
+            return Ok(None);
+        };
+        let mut compiler: Option<Compiler> = None;
+
+        for (line_num, line) in script.split(|&ch| ch == b'\n').enumerate() {
+            let line = String::from_utf8_lossy(line);
+            let line = line.trim();
+            let pragmas = if line.is_empty() {
+                continue;
+            } else if let Some(comment) = line.strip_prefix('#') {
+                if let Some(pragmas) = comment.trim_start().strip_prefix(PRAGMA_PREFIX) {
+                    pragmas
+                } else {
+                    continue;
+                }
+            } else {
+                break;
+            };
+
+            for pragma in pragmas.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+                let invalid = || CompileError {
+                    line_num: line_num + 1,
+                    line_pos: 0,
+                    error_type: ErrorType::InvalidPragma(pragma.to_string()),
+                };
+                let (name, value) = pragma.split_once('=').ok_or_else(invalid)?;
+                let compiler = compiler.get_or_insert_with(|| self.clone());
+
+                match (name.trim(), value.trim()) {
+                    ("strict", "on") => {
+                        compiler.no_capability_check = false;
+                    }
+                    ("strict", "off") if bounds.allow_non_strict => {
+                        compiler.no_capability_check = true;
+                    }
+                    ("max-nesting", value) => match value.parse::<usize>() {
+                        Ok(value) if value > 0 && value <= bounds.max_nesting => {
+                            compiler.max_nested_blocks = value;
+                            compiler.max_nested_tests = value;
+                        }
+                        _ => return Err(invalid()),
+                    },
+                    ("unknown-tags", value) if bounds.allow_unknown_tags => {
+                        compiler.unknown_tag_policy = match value {
+                            "error" => UnknownTagPolicy::Error,
+                            "ignore" => UnknownTagPolicy::Ignore,
+                            "preserve" => UnknownTagPolicy::Preserve,
+                            _ => return Err(invalid()),
+                        };
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+
+        Ok(compiler.map(|mut compiler| {
+            compiler.pragma_bounds = None;
+            compiler
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::{ErrorType, UnknownTag},
+        Compiler, PragmaBounds,
+    };
+
+    #[test]
+    fn script_pragmas() {
+        let compiler = Compiler::new().with_pragma_bounds(
+            PragmaBounds::new()
+                .with_allow_non_strict(true)
+                .with_allow_unknown_tags(true)
+                .with_max_nesting(20),
+        );
+
+        // Pragmas are plain comments unless enabled
+        let script = b"# sieve: strict=off\nfileinto \"Junk\";";
+        assert!(Compiler::new().compile(script).is_err());
+        assert!(compiler.compile(script).is_ok());
+        assert!(compiler
+            .compile(b"# sieve: strict=off\nkeep;\n# sieve: max-nesting=30\n")
+            .is_ok());
+
+        let sieve = compiler
+            .compile(
+                b"\n#sieve: unknown-tags=preserve, strict=off\nfileinto :vendor \"x\" \"Junk\";",
+            )
+            .unwrap();
+        assert_eq!(
+            sieve
+                .unknown_tags()
+                .iter()
+                .map(|t| t.tag.as_str())
+                .collect::<Vec<_>>(),
+            [":vendor"]
+        );
+        assert!(matches!(
+            sieve.unknown_tags(),
+            [UnknownTag { value: Some(value), .. }] if value == "x"
+        ));
+
+        for (script, line_num) in [
+            ("# sieve: max-nesting=21\nkeep;", 1),
+            ("# comment\n# sieve: strict=maybe\nkeep;", 2),
+            ("# sieve: fast\nkeep;", 1),
+        ] {
+            let err = compiler.compile(script.as_bytes()).unwrap_err();
+            assert!(
+                matches!(err.error_type(), ErrorType::InvalidPragma(_)),
+                "{err:?}"
+            );
+            assert_eq!(err.line_num(), line_num);
+        }
+
+        let err = Compiler::new()
+            .with_pragma_bounds(PragmaBounds::new())
+            .compile(b"# sieve: strict=off\nkeep;")
+            .unwrap_err();
+        assert!(matches!(err.error_type(), ErrorType::InvalidPragma(_)));
+    }
+}
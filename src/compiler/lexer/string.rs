@@ -28,7 +28,11 @@ use mail_parser::HeaderName;
 use crate::{
     compiler::{
         grammar::{
-            expr::{self},
+            expr::{
+                self,
+                functions::BuiltIn,
+                parser::{ID_BUILTIN, VARIADIC_ARGS},
+            },
             instruction::CompilerState,
             AddressPart,
         },
@@ -66,7 +70,7 @@ impl<'x> CompilerState<'x> {
         let mut text_has_dots = false;
 
         let mut hex_start = usize::MAX;
-        let mut decode_buf = Vec::with_capacity(bytes.len());
+        let mut decode_buf = self.tokens.arena.alloc(bytes.len());
 
         for (pos, &ch) in bytes.iter().enumerate() {
             let mut is_var_error = false;
@@ -130,7 +134,12 @@ impl<'x> CompilerState<'x> {
                             };
 
                             match var_type {
-                                Ok(Some(var)) => items.push(Value::Variable(var)),
+                                Ok(Some(var)) => {
+                                    if matches!(var, VariableType::Part(_)) {
+                                        self.uses_body = true;
+                                    }
+                                    items.push(Value::Variable(var))
+                                }
                                 Ok(None) => {}
                                 Err(
                                     ErrorType::InvalidNamespace(_) | ErrorType::InvalidEnvelope(_),
@@ -262,6 +271,7 @@ impl<'x> CompilerState<'x> {
                 text_has_dots,
             )?;
         }
+        self.tokens.arena.free(decode_buf);
 
         Ok(match items.len() {
             1 => items.pop().unwrap(),
@@ -270,6 +280,13 @@ impl<'x> CompilerState<'x> {
         })
     }
 
+    // Tokenizes a string token, returning its buffer to the scratch arena
+    pub(crate) fn tokenize_string_token(&mut self, bytes: Vec<u8>) -> Result<Value, ErrorType> {
+        let result = self.tokenize_string(&bytes, true);
+        self.tokens.arena.free(bytes);
+        result
+    }
+
     fn parse_match_variable(&mut self, var_name: &str) -> Result<Option<VariableType>, ErrorType> {
         let num = var_name
             .parse()
@@ -303,7 +320,16 @@ impl<'x> CompilerState<'x> {
                 Ok(None)
             }
         } else {
-            let var = match var_name.to_lowercase().split_once('.') {
+            let var_name_lc = var_name.to_lowercase();
+            // Unknown namespaces are not variables and are left as text
+            if let Some((
+                namespace @ ("global" | "t" | "env" | "envelope" | "header" | "body" | "part"),
+                _,
+            )) = var_name_lc.split_once('.')
+            {
+                self.compiler.variable_names.check_namespace(namespace)?;
+            }
+            let var = match var_name_lc.split_once('.') {
                 Some(("global" | "t", var_name)) if !var_name.is_empty() => {
                     VariableType::Global(var_name.to_string())
                 }
@@ -501,6 +527,12 @@ impl<'x> CompilerState<'x> {
                         id: *id,
                         num_args: *num_args,
                     })
+                } else if BuiltIn::parse(var_name).is_some() {
+                    Ok(expr::Token::Function {
+                        name: var_name.to_string(),
+                        id: ID_BUILTIN,
+                        num_args: VARIADIC_ARGS,
+                    })
                 } else {
                     Err(format!("Invalid variable or function name {var_name:?}"))
                 }
@@ -659,6 +691,9 @@ impl Display for Value {
             Value::Number(n) => n.fmt(f),
             Value::Variable(v) => v.fmt(f),
             Value::Regex(r) => f.write_str(&r.expr),
+            Value::Glob(g) => f.write_str(&g.expr),
+            Value::Contains(c) => f.write_str(&c.keys.join(" ")),
+            Value::Header(h) => f.write_str(&h.expr),
         }
     }
 }
@@ -756,13 +791,16 @@ mod tests {
         block.match_test_pos.push(0);
         let mut compiler = CompilerState {
             compiler: &c,
-            instructions: vec![Instruction::Test(Test::String(TestString {
-                match_type: MatchType::Regex(u64::MAX),
-                comparator: Comparator::AsciiCaseMap,
-                source: vec![Value::Variable(VariableType::Local(0))],
-                key_list: vec![Value::Variable(VariableType::Local(0))],
-                is_not: false,
-            }))],
+            instructions: vec![Instruction::Test(
+                Test::String(TestString {
+                    match_type: MatchType::Regex(u64::MAX),
+                    comparator: Comparator::AsciiCaseMap,
+                    source: vec![Value::Variable(VariableType::Local(0))],
+                    key_list: vec![Value::Variable(VariableType::Local(0))],
+                    is_not: false,
+                })
+                .into(),
+            )],
             block_stack: Vec::new(),
             block,
             last_block_type: Word::Not,
@@ -774,6 +812,8 @@ mod tests {
             vars_match_max: usize::MAX,
             param_check: [false; MAX_PARAMS],
             includes_num: 0,
+            uses_body: false,
+            warnings: Vec::new(),
         };
 
         for (input, expected_result) in [
@@ -24,17 +24,18 @@
 use std::{iter::Peekable, slice::Iter};
 
 use crate::{
-    compiler::{CompileError, ErrorType, Number},
+    compiler::{CompileError, ErrorType, Number, Span, UnknownTag},
     runtime::eval::IntoString,
-    Compiler,
+    Compiler, UnknownTagPolicy,
 };
 
-use super::{word::WORDS, StringConstant, Token};
+use super::{arena::ScratchArena, word::WORDS, StringConstant, Token};
 
 pub(crate) struct Tokenizer<'x> {
     pub compiler: &'x Compiler,
     pub iter: Peekable<Iter<'x, u8>>,
     pub buf: Vec<u8>,
+    pub arena: ScratchArena,
     pub next_token: Vec<TokenInfo>,
 
     pub pos: usize,
@@ -51,6 +52,9 @@ pub(crate) struct Tokenizer<'x> {
 
     pub last_ch: u8,
     pub state: State,
+
+    pub last_command: String,
+    pub unknown_tags: Vec<UnknownTag>,
 }
 
 #[derive(Debug)]
@@ -82,6 +86,7 @@ impl<'x> Tokenizer<'x> {
             compiler,
             iter: bytes.iter().peekable(),
             buf: Vec::with_capacity(bytes.len() / 2),
+            arena: ScratchArena::new(),
             pos: usize::MAX,
             line_num: 1,
             line_start: 0,
@@ -93,6 +98,8 @@ impl<'x> Tokenizer<'x> {
             next_token: Vec::with_capacity(2),
             last_ch: 0,
             state: State::None,
+            last_command: String::new(),
+            unknown_tags: Vec::new(),
         }
     }
 
@@ -173,7 +180,7 @@ impl<'x> Tokenizer<'x> {
     pub fn get_string(&mut self, str_type: StringType) -> Result<TokenInfo, CompileError> {
         if self.buf.len() < self.compiler.max_string_size {
             let token = if str_type.maybe_variable {
-                Token::StringVariable(self.buf.to_vec())
+                Token::StringVariable(self.arena.alloc_from(&self.buf))
             } else {
                 let constant = self.buf.to_vec().into_string();
                 if !str_type.has_other && str_type.has_digits {
@@ -331,6 +338,58 @@ impl<'x> Tokenizer<'x> {
         }
     }
 
+    // Drops unknown tags according to the policy of the command they belong to,
+    // along with their value when another argument follows it.
+    fn skip_unknown_tag(&mut self, tag: String, span: Span) -> Result<bool, CompileError> {
+        let policy = self
+            .compiler
+            .unknown_tag_policies
+            .get(&self.last_command)
+            .copied()
+            .unwrap_or(self.compiler.unknown_tag_policy);
+        if policy == UnknownTagPolicy::Error {
+            return Ok(false);
+        }
+
+        let mut value = None;
+        if let Some(next) = self.next_raw().transpose()? {
+            if matches!(
+                next.token,
+                Token::StringConstant(_) | Token::StringVariable(_) | Token::Number(_)
+            ) {
+                let after = self.next_raw().transpose()?;
+                let has_argument = after.as_ref().map_or(false, |t| match &t.token {
+                    Token::StringConstant(_)
+                    | Token::StringVariable(_)
+                    | Token::Number(_)
+                    | Token::BracketOpen
+                    | Token::Tag(_) => true,
+                    Token::Unknown(tag) => tag.starts_with(':'),
+                    _ => false,
+                });
+                if let Some(after) = after {
+                    self.next_token.push(after);
+                }
+                if has_argument {
+                    value = Some(next.token.to_string());
+                } else {
+                    self.next_token.push(next);
+                }
+            } else {
+                self.next_token.push(next);
+            }
+        }
+
+        self.unknown_tags.push(UnknownTag {
+            command: self.last_command.clone(),
+            tag,
+            value: value.filter(|_| policy == UnknownTagPolicy::Preserve),
+            span,
+        });
+
+        Ok(true)
+    }
+
     pub fn peek(&mut self) -> Option<Result<&TokenInfo, CompileError>> {
         if self.next_token.is_empty() {
             match self.next()? {
@@ -346,6 +405,38 @@ impl<'x> Iterator for Tokenizer<'x> {
     type Item = Result<TokenInfo, CompileError>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let token_info = match self.next_raw()? {
+                Ok(token_info) => token_info,
+                Err(err) => return Some(Err(err)),
+            };
+            match &token_info.token {
+                Token::Identifier(word) => {
+                    self.last_command = word.to_string();
+                }
+                Token::Unknown(tag) if tag.starts_with(':') => {
+                    let span = Span {
+                        line_num: token_info.line_num,
+                        line_pos: token_info.line_pos,
+                    };
+                    match self.skip_unknown_tag(tag.clone(), span) {
+                        Ok(true) => continue,
+                        Ok(false) => (),
+                        Err(err) => return Some(Err(err)),
+                    }
+                }
+                Token::Unknown(command) => {
+                    self.last_command = command.clone();
+                }
+                _ => (),
+            }
+            return Some(Ok(token_info));
+        }
+    }
+}
+
+impl<'x> Tokenizer<'x> {
+    fn next_raw(&mut self) -> Option<Result<TokenInfo, CompileError>> {
         if let Some(prev_token) = self.next_token.pop() {
             return Some(Ok(prev_token));
         }
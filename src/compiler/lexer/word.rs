@@ -52,6 +52,7 @@ pub(crate) enum Word {
     CurrentDate,
     Date,
     Days,
+    Decoded,
     DeleteHeader,
     Detail,
     Discard,
@@ -185,6 +186,7 @@ pub(crate) static WORDS: phf::Map<&'static str, Word> = phf_map! {
     "currentdate" => Word::CurrentDate,
     "date" => Word::Date,
     "days" => Word::Days,
+    "decoded" => Word::Decoded,
     "deleteheader" => Word::DeleteHeader,
     "detail" => Word::Detail,
     "discard" => Word::Discard,
@@ -318,6 +320,7 @@ impl Display for Word {
             Word::CurrentDate => f.write_str("currentdate"),
             Word::Date => f.write_str("date"),
             Word::Days => f.write_str("days"),
+            Word::Decoded => f.write_str("decoded"),
             Word::DeleteHeader => f.write_str("deleteheader"),
             Word::Detail => f.write_str("detail"),
             Word::Discard => f.write_str("discard"),
@@ -0,0 +1,614 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{Compiler, Sieve};
+
+use super::{grammar::instruction::Instruction, CompileError, Value, VariableType};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Lexeme {
+    Word(Vec<u8>),
+    // Quoted and multi-line strings are kept as written
+    String(Vec<u8>),
+    Text(Vec<u8>),
+    Symbol(u8),
+}
+
+struct SetCommand<'x> {
+    end: usize,
+    name_pos: usize,
+    name: &'x [u8],
+    value: &'x [u8],
+}
+
+impl Compiler {
+    /// Rewrites a script into a smaller, equivalent one: comments and
+    /// whitespace are stripped, `set` commands overwritten by the next
+    /// command are dropped, `anyof`/`allof` lists with a single test or
+    /// nested lists of the same kind are collapsed and local variables
+    /// are renamed to short names. Each of these steps is only kept when
+    /// the result compiles to the same instructions as the original script,
+    /// which is returned unchanged if none of them can be verified.
+    pub fn minify(&self, script: &[u8]) -> Result<Vec<u8>, CompileError> {
+        let expected = Program::new(self.compile(script)?);
+        let verify = |lexemes: &[Lexeme]| {
+            let result = serialize(lexemes);
+            self.compile(&result)
+                .map_or(false, |compiled| Program::new(compiled) == expected)
+                .then_some(result)
+        };
+
+        let mut lexemes = tokenize(script);
+        let mut result = match verify(&lexemes) {
+            Some(result) => result,
+            None => return Ok(script.to_vec()),
+        };
+        let steps: [fn(&[Lexeme]) -> Option<Vec<Lexeme>>; 3] = [
+            |lexemes| Some(merge_sets(lexemes)),
+            |lexemes| Some(collapse_tests(lexemes)),
+            rename_variables,
+        ];
+        for step in steps {
+            if let Some(candidate) = step(&lexemes) {
+                if let Some(candidate_result) = verify(&candidate) {
+                    lexemes = candidate;
+                    result = candidate_result;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+// Compiled script reduced to what the minifier has to preserve: positions are
+// ignored, jumps to a jump of the same kind are followed and a `set` of a
+// variable overwritten by the next instruction is dropped, as `merge_sets` does
+#[derive(Debug, PartialEq, Eq)]
+struct Program {
+    instructions: Vec<Instruction>,
+    num_vars: usize,
+    num_match_vars: usize,
+}
+
+impl Program {
+    fn new(sieve: Sieve) -> Self {
+        let instructions = sieve.instructions;
+        let is_dropped = (0..instructions.len())
+            .map(|pos| {
+                matches!(
+                    (&instructions[pos], instructions.get(pos + 1)),
+                    (Instruction::Set(set), Some(Instruction::Set(next)))
+                        if matches!(set.name, VariableType::Local(_) | VariableType::Global(_))
+                            && set.name == next.name
+                            && !references(&next.value, &set.name)
+                )
+            })
+            .collect::<Vec<_>>();
+        let new_pos = |pos: u32| {
+            pos - is_dropped
+                .iter()
+                .take(pos as usize)
+                .filter(|is_dropped| **is_dropped)
+                .count() as u32
+        };
+        let follow = |jump: &Instruction, mut pos: u32| {
+            for _ in 0..instructions.len() {
+                match instructions.get(pos as usize) {
+                    Some(
+                        next @ (Instruction::Jz(next_pos)
+                        | Instruction::Jnz(next_pos)
+                        | Instruction::Jmp(next_pos)),
+                    ) if std::mem::discriminant(next) == std::mem::discriminant(jump) => {
+                        pos = *next_pos;
+                    }
+                    _ => break,
+                }
+            }
+            new_pos(pos)
+        };
+
+        Program {
+            instructions: instructions
+                .iter()
+                .zip(&is_dropped)
+                .filter(|(_, is_dropped)| !**is_dropped)
+                .map(|(instruction, _)| match instruction {
+                    Instruction::Jz(pos) => Instruction::Jz(follow(instruction, *pos)),
+                    Instruction::Jnz(pos) => Instruction::Jnz(follow(instruction, *pos)),
+                    Instruction::Jmp(pos) => Instruction::Jmp(follow(instruction, *pos)),
+                    instruction => instruction.clone(),
+                })
+                .collect(),
+            num_vars: sieve.num_vars,
+            num_match_vars: sieve.num_match_vars,
+        }
+    }
+}
+
+fn references(value: &Value, name: &VariableType) -> bool {
+    match value {
+        Value::Variable(variable) => variable == name,
+        Value::List(values) => values.iter().any(|value| references(value, name)),
+        _ => false,
+    }
+}
+
+fn tokenize(script: &[u8]) -> Vec<Lexeme> {
+    let mut lexemes = Vec::new();
+    let mut pos = 0;
+
+    while let Some(&ch) = script.get(pos) {
+        match ch {
+            b' ' | b'\t' | b'\r' | b'\n' => {
+                pos += 1;
+            }
+            b'#' => {
+                pos = find(script, pos, b"\n").map_or(script.len(), |p| p + 1);
+            }
+            b'/' if script.get(pos + 1) == Some(&b'*') => {
+                pos = find(script, pos + 2, b"*/").map_or(script.len(), |p| p + 2);
+            }
+            b'"' => {
+                let mut end = pos + 1;
+                while let Some(&ch) = script.get(end) {
+                    match ch {
+                        b'"' => break,
+                        b'\\' => end += 2,
+                        _ => end += 1,
+                    }
+                }
+                let end = (end + 1).min(script.len());
+                lexemes.push(Lexeme::String(script[pos..end].to_vec()));
+                pos = end;
+            }
+            b'{' | b'}' | b'[' | b']' | b'(' | b')' | b',' | b';' => {
+                lexemes.push(Lexeme::Symbol(ch));
+                pos += 1;
+            }
+            _ => {
+                let start = pos;
+                pos += 1;
+                while let Some(&ch) = script.get(pos) {
+                    if ch.is_ascii_alphanumeric() || matches!(ch, b'_' | b'.' | b'$') {
+                        pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+
+                let word = &script[start..pos];
+                if word.eq_ignore_ascii_case(b"text") && script.get(pos) == Some(&b':') {
+                    // Anything after "text:" on the same line is ignored
+                    let body_start = find(script, pos, b"\n").map_or(script.len(), |p| p + 1);
+                    let mut end = body_start;
+                    while end < script.len() {
+                        if script[end] == b'.' && (end == body_start || script[end - 1] == b'\n') {
+                            if script.get(end + 1) == Some(&b'\n') {
+                                end += 2;
+                                break;
+                            } else if script.get(end + 1..end + 3) == Some(b"\r\n") {
+                                end += 3;
+                                break;
+                            }
+                        }
+                        end += 1;
+                    }
+                    let end = end.min(script.len());
+                    let mut text = b"text:\n".to_vec();
+                    text.extend_from_slice(&script[body_start..end]);
+                    lexemes.push(Lexeme::Text(text));
+                    pos = end;
+                } else {
+                    lexemes.push(Lexeme::Word(word.to_vec()));
+                }
+            }
+        }
+    }
+
+    lexemes
+}
+
+fn serialize(lexemes: &[Lexeme]) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut last_is_word = false;
+
+    for lexeme in lexemes {
+        match lexeme {
+            Lexeme::Word(bytes) | Lexeme::Text(bytes) => {
+                if last_is_word {
+                    result.push(b' ');
+                }
+                result.extend_from_slice(bytes);
+            }
+            Lexeme::String(bytes) => {
+                result.extend_from_slice(bytes);
+            }
+            Lexeme::Symbol(ch) => {
+                result.push(*ch);
+            }
+        }
+        last_is_word = matches!(lexeme, Lexeme::Word(_));
+    }
+
+    result
+}
+
+// Drops a `set` when the next command assigns the same variable without
+// referencing it
+fn merge_sets(lexemes: &[Lexeme]) -> Vec<Lexeme> {
+    let mut result = Vec::with_capacity(lexemes.len());
+    let mut pos = 0;
+
+    while pos < lexemes.len() {
+        if let Some(first) = parse_set(lexemes, pos) {
+            if let Some(next) = parse_set(lexemes, first.end) {
+                if first.name.eq_ignore_ascii_case(next.name)
+                    && !contains_ignore_case(next.value, first.name)
+                {
+                    pos = first.end;
+                    continue;
+                }
+            }
+        }
+        result.push(lexemes[pos].clone());
+        pos += 1;
+    }
+
+    result
+}
+
+fn collapse_tests(lexemes: &[Lexeme]) -> Vec<Lexeme> {
+    let mut result = Vec::with_capacity(lexemes.len());
+    let mut pos = 0;
+
+    while pos < lexemes.len() {
+        if let (Some(kind), Some(close)) = (
+            test_list_kind(lexemes, pos),
+            matching_paren(lexemes, pos + 1),
+        ) {
+            let inner = collapse_tests(&lexemes[pos + 2..close]);
+            let mut args = Vec::new();
+            for arg in split_args(&inner) {
+                if test_list_kind(arg, 0).map_or(false, |k| k.eq_ignore_ascii_case(kind))
+                    && matching_paren(arg, 1) == Some(arg.len() - 1)
+                {
+                    args.extend(split_args(&arg[2..arg.len() - 1]));
+                } else {
+                    args.push(arg);
+                }
+            }
+
+            if args.len() == 1 {
+                result.extend_from_slice(args[0]);
+            } else {
+                result.push(Lexeme::Word(kind.to_vec()));
+                result.push(Lexeme::Symbol(b'('));
+                for (arg_num, arg) in args.into_iter().enumerate() {
+                    if arg_num > 0 {
+                        result.push(Lexeme::Symbol(b','));
+                    }
+                    result.extend_from_slice(arg);
+                }
+                result.push(Lexeme::Symbol(b')'));
+            }
+            pos = close + 1;
+        } else {
+            result.push(lexemes[pos].clone());
+            pos += 1;
+        }
+    }
+
+    result
+}
+
+fn rename_variables(lexemes: &[Lexeme]) -> Option<Vec<Lexeme>> {
+    let mut names: Vec<String> = Vec::new();
+    let mut name_positions = Vec::new();
+    let mut taken: Vec<String> = Vec::new();
+
+    for (pos, lexeme) in lexemes.iter().enumerate() {
+        match lexeme {
+            Lexeme::String(bytes) | Lexeme::Text(bytes) => {
+                replace_variables(bytes, |name| {
+                    taken.push(String::from_utf8_lossy(name).to_ascii_lowercase());
+                    None
+                });
+            }
+            Lexeme::Word(_) => {
+                if let Some(set) = parse_set(lexemes, pos) {
+                    if let Ok(name) = std::str::from_utf8(set.name) {
+                        let name = name.to_ascii_lowercase();
+                        if is_identifier(&name) {
+                            name_positions.push(set.name_pos);
+                            if !names.contains(&name) {
+                                names.push(name.clone());
+                            }
+                        }
+                        taken.push(name);
+                    }
+                }
+            }
+            Lexeme::Symbol(_) => (),
+        }
+    }
+
+    let mut renames: Vec<(String, String)> = Vec::new();
+    let mut next_name = 0;
+    for name in names {
+        let mut candidate = next_name;
+        let short_name = loop {
+            let short_name = short_name(candidate);
+            candidate += 1;
+            if !taken.contains(&short_name) {
+                break short_name;
+            }
+        };
+        if short_name.len() < name.len() {
+            renames.push((name, short_name));
+            next_name = candidate;
+        }
+    }
+    if renames.is_empty() {
+        return None;
+    }
+
+    let rename = |name: &[u8]| {
+        renames
+            .iter()
+            .find(|(from, _)| from.as_bytes().eq_ignore_ascii_case(name))
+            .map(|(_, to)| to.as_bytes().to_vec())
+    };
+    Some(
+        lexemes
+            .iter()
+            .enumerate()
+            .map(|(pos, lexeme)| match lexeme {
+                Lexeme::String(bytes) if name_positions.contains(&pos) => {
+                    match rename(&bytes[1..bytes.len() - 1]) {
+                        Some(name) => {
+                            let mut result = Vec::with_capacity(name.len() + 2);
+                            result.push(b'"');
+                            result.extend_from_slice(&name);
+                            result.push(b'"');
+                            Lexeme::String(result)
+                        }
+                        None => lexeme.clone(),
+                    }
+                }
+                Lexeme::String(bytes) => Lexeme::String(replace_variables(bytes, rename)),
+                Lexeme::Text(bytes) => Lexeme::Text(replace_variables(bytes, rename)),
+                _ => lexeme.clone(),
+            })
+            .collect(),
+    )
+}
+
+fn parse_set(lexemes: &[Lexeme], pos: usize) -> Option<SetCommand> {
+    if pos > 0
+        && !matches!(
+            lexemes.get(pos - 1),
+            Some(Lexeme::Symbol(b';' | b'{' | b'}'))
+        )
+    {
+        return None;
+    }
+    match lexemes.get(pos)? {
+        Lexeme::Word(word) if word.eq_ignore_ascii_case(b"set") => (),
+        _ => return None,
+    }
+
+    let mut pos = pos + 1;
+    while let Some(Lexeme::Word(tag)) = lexemes.get(pos) {
+        if tag.first() != Some(&b':') {
+            return None;
+        }
+        pos += 1;
+    }
+
+    match (
+        lexemes.get(pos)?,
+        lexemes.get(pos + 1)?,
+        lexemes.get(pos + 2)?,
+    ) {
+        (
+            Lexeme::String(name),
+            Lexeme::String(value) | Lexeme::Text(value),
+            Lexeme::Symbol(b';'),
+        ) if name.len() >= 2 => Some(SetCommand {
+            end: pos + 3,
+            name_pos: pos,
+            name: &name[1..name.len() - 1],
+            value,
+        }),
+        _ => None,
+    }
+}
+
+fn test_list_kind(lexemes: &[Lexeme], pos: usize) -> Option<&[u8]> {
+    match (lexemes.get(pos)?, lexemes.get(pos + 1)?) {
+        (Lexeme::Word(word), Lexeme::Symbol(b'('))
+            if word.eq_ignore_ascii_case(b"anyof") || word.eq_ignore_ascii_case(b"allof") =>
+        {
+            Some(word)
+        }
+        _ => None,
+    }
+}
+
+fn matching_paren(lexemes: &[Lexeme], open_pos: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (pos, lexeme) in lexemes.iter().enumerate().skip(open_pos) {
+        match lexeme {
+            Lexeme::Symbol(b'(') => depth += 1,
+            Lexeme::Symbol(b')') => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(pos);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+fn split_args(lexemes: &[Lexeme]) -> Vec<&[Lexeme]> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (pos, lexeme) in lexemes.iter().enumerate() {
+        match lexeme {
+            Lexeme::Symbol(b'(' | b'[') => depth += 1,
+            Lexeme::Symbol(b')' | b']') => depth -= 1,
+            Lexeme::Symbol(b',') if depth == 0 => {
+                args.push(&lexemes[start..pos]);
+                start = pos + 1;
+            }
+            _ => (),
+        }
+    }
+    args.push(&lexemes[start..]);
+
+    args
+}
+
+// Replaces the names of "${name}" references, returning the updated bytes
+fn replace_variables(bytes: &[u8], mut rename: impl FnMut(&[u8]) -> Option<Vec<u8>>) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        if bytes[pos..].starts_with(b"${") {
+            if let Some(end) = find(bytes, pos + 2, b"}") {
+                if let Some(name) = rename(&bytes[pos + 2..end]) {
+                    result.extend_from_slice(b"${");
+                    result.extend_from_slice(&name);
+                    result.push(b'}');
+                    pos = end + 1;
+                    continue;
+                }
+            }
+        }
+        result.push(bytes[pos]);
+        pos += 1;
+    }
+
+    result
+}
+
+fn short_name(mut num: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'a' + (num % 26) as u8);
+        if num < 26 {
+            break;
+        }
+        num = num / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap()
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.bytes()
+        .next()
+        .map_or(false, |ch| ch.is_ascii_alphabetic() || ch == b'_')
+        && name
+            .bytes()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == b'_')
+}
+
+fn find(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| pos + from)
+}
+
+fn contains_ignore_case(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window.eq_ignore_ascii_case(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    #[test]
+    fn minify() {
+        let compiler = Compiler::new();
+
+        for (script, expected) in [
+            (
+                concat!(
+                    "# Filter\r\nrequire [\"fileinto\", \"variables\"];\n\n",
+                    "/* Work */\nif  header :contains \"Subject\"  \"report\" {\n",
+                    "    fileinto \"Work\";   # done\n    stop;\n}\n"
+                ),
+                "require[\"fileinto\",\"variables\"];if header :contains\"Subject\"\"report\"{fileinto\"Work\";stop;}",
+            ),
+            (
+                "if anyof (anyof (true, false), allof (true)) { keep; }",
+                "if anyof(true,false,true){keep;}",
+            ),
+            (
+                "if not allof (exists \"To\") { keep; }",
+                "if not exists\"To\"{keep;}",
+            ),
+            (
+                concat!(
+                    "require [\"variables\", \"fileinto\"];\n",
+                    "set \"mailbox_name\" \"Inbox\";\n",
+                    "set \"mailbox_name\" \"Archive\";\n",
+                    "set \"counter_value\" \"1\";\n",
+                    "set \"counter_value\" \"${counter_value}1\";\n",
+                    "fileinto \"${mailbox_name}/${counter_value}\";\n"
+                ),
+                concat!(
+                    "require[\"variables\",\"fileinto\"];set\"a\"\"Archive\";set\"b\"\"1\";",
+                    "set\"b\"\"${b}1\";fileinto\"${a}/${b}\";"
+                ),
+            ),
+            (
+                "require \"variables\";\nset \"subject\" text:\n..line\nfoo\n.\n;\nkeep;",
+                "require\"variables\";set\"a\"text:\n..line\nfoo\n.\n;keep;",
+            ),
+            (
+                "require \"fileinto\";\nfileinto  \"a\\\\\" ;  # \"\nkeep;",
+                "require\"fileinto\";fileinto\"a\\\\\";keep;",
+            ),
+        ] {
+            let minified = compiler.minify(script.as_bytes()).unwrap();
+            assert_eq!(
+                std::str::from_utf8(&minified).unwrap(),
+                expected,
+                "{script}"
+            );
+        }
+    }
+}
@@ -23,19 +23,83 @@
 
 use std::{borrow::Cow, fmt::Display, sync::Arc};
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use mail_parser::HeaderName;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{runtime::RuntimeError, Compiler, Envelope, FunctionMap};
+use crate::{
+    runtime::{tests::glob::GlobPattern, RuntimeError},
+    Compiler, Envelope, FunctionMap, NotifyUriValidator, PragmaBounds, Script, Sieve,
+    UnknownTagPolicy, VariableNameRules,
+};
 
 use self::{
-    grammar::{AddressPart, Capability},
+    grammar::{AddressPart, Capability, Comparator},
+    header_key::HeaderKey,
     lexer::tokenizer::TokenInfo,
 };
 
+pub mod analysis;
+pub mod audit;
+pub mod builder;
+pub mod complexity;
+pub mod diff;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
 pub mod grammar;
+pub mod header_key;
 pub mod lexer;
+pub mod minify;
+pub mod summary;
+pub mod taint;
+pub mod visitor;
+
+/// Line and column of a command in the script source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Span {
+    pub line_num: usize,
+    pub line_pos: usize,
+}
+
+/// Tagged argument skipped by the compiler, see [`crate::UnknownTagPolicy`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnknownTag {
+    pub command: String,
+    pub tag: String,
+    pub value: Option<String>,
+    pub span: Span,
+}
+
+/// Construct accepted by the compiler that is unlikely to do what the
+/// script author intended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompileWarning {
+    pub kind: CompileWarningKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompileWarningKind {
+    /// `modifier` has no effect on the result when combined with `with`.
+    RedundantModifier { modifier: String, with: String },
+}
+
+impl Sieve {
+    /// Tags skipped during compilation by the [`UnknownTagPolicy`] in effect.
+    pub fn unknown_tags(&self) -> &[UnknownTag] {
+        &self.unknown_tags
+    }
+
+    /// Capabilities declared with `require`, in order of appearance.
+    pub fn capabilities(&self) -> &[Capability] {
+        &self.capabilities
+    }
+
+    /// Warnings raised while compiling the script.
+    pub fn warnings(&self) -> &[CompileWarning] {
+        &self.warnings
+    }
+}
 
 #[derive(Debug)]
 pub struct CompileError {
@@ -58,6 +122,10 @@ pub enum ErrorType {
     InvalidArguments,
     InvalidAddress,
     InvalidURI,
+    InvalidNotifyUri {
+        uri: String,
+        reason: String,
+    },
     InvalidEnvelope(String),
     UnterminatedString,
     UnterminatedComment,
@@ -67,6 +135,10 @@ pub enum ErrorType {
     StringTooLong,
     VariableTooLong,
     VariableIsLocal(String),
+    VariableNameReserved(String),
+    NamespaceNotAllowed(String),
+    UnsupportedComparator(String),
+    ConflictingModifiers(String, String),
     HeaderTooLong,
     ExpectedConstantString,
     UnexpectedToken {
@@ -78,14 +150,19 @@ pub enum ErrorType {
     TooManyNestedTests,
     TooManyNestedForEveryParts,
     TooManyIncludes,
+    TooManyInstructions,
+    CompiledScriptTooLarge,
+    InvalidPragma(String),
     LabelAlreadyDefined(String),
     LabelUndefined(String),
     BreakOutsideLoop,
     ContinueOutsideLoop,
+    ExtractTextOutsideLoop,
     UnsupportedComparator(String),
     DuplicatedParameter,
     UndeclaredCapability(Capability),
     MissingTag(Cow<'static, str>),
+    RedirectNotAllowed(String),
 }
 
 impl Default for Compiler {
@@ -100,6 +177,9 @@ pub(crate) enum Value {
     Number(Number),
     Variable(VariableType),
     Regex(Regex),
+    Glob(Glob),
+    Contains(ContainsKeys),
+    Header(HeaderKey),
     List(Vec<Value>),
 }
 
@@ -109,6 +189,20 @@ pub struct Regex {
     pub expr: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct Glob {
+    pub expr: String,
+    pub octet: GlobPattern,
+    pub casemap: GlobPattern,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContainsKeys {
+    pub keys: Vec<String>,
+    pub octet: aho_corasick::AhoCorasick,
+    pub casemap: aho_corasick::AhoCorasick,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum VariableType {
     Local(usize),
@@ -221,7 +315,7 @@ impl Display for Number {
 }
 
 impl Compiler {
-    pub const VERSION: u32 = 2;
+    pub const VERSION: u32 = 11;
 
     pub fn new() -> Self {
         Compiler {
@@ -235,8 +329,27 @@ impl Compiler {
             max_local_variables: 128,
             max_header_size: 1024,
             max_includes: 6,
+            max_instructions: 100_000,
+            max_compiled_size: 0,
+            max_expression_depth: 32,
+            max_expression_nodes: 1024,
+            bitwise_operators: false,
+            unknown_tag_policy: UnknownTagPolicy::Error,
+            unknown_tag_policies: AHashMap::new(),
+            pragma_bounds: None,
+            notify_uri_validators: AHashMap::new(),
+            variable_names: VariableNameRules::default(),
+            comparators: AHashSet::from_iter([
+                Comparator::Octet,
+                Comparator::AsciiCaseMap,
+                Comparator::AsciiNumeric,
+                Comparator::Elbonia,
+            ]),
             functions: AHashMap::new(),
             no_capability_check: false,
+            redirect_domains: AHashSet::new(),
+            #[cfg(any(test, feature = "testsuite"))]
+            test_commands: false,
         }
     }
 
@@ -258,6 +371,82 @@ impl Compiler {
         self
     }
 
+    pub fn set_max_instructions(&mut self, size: usize) {
+        self.max_instructions = size;
+    }
+
+    pub fn with_max_instructions(mut self, size: usize) -> Self {
+        self.max_instructions = size;
+        self
+    }
+
+    /// Maximum size in bytes of the serialized script, or zero for no limit.
+    pub fn set_max_compiled_size(&mut self, size: usize) {
+        self.max_compiled_size = size;
+    }
+
+    pub fn with_max_compiled_size(mut self, size: usize) -> Self {
+        self.max_compiled_size = size;
+        self
+    }
+
+    pub fn set_max_expression_depth(&mut self, size: usize) {
+        self.max_expression_depth = size;
+    }
+
+    pub fn with_max_expression_depth(mut self, size: usize) -> Self {
+        self.max_expression_depth = size;
+        self
+    }
+
+    pub fn set_max_expression_nodes(&mut self, size: usize) {
+        self.max_expression_nodes = size;
+    }
+
+    pub fn with_max_expression_nodes(mut self, size: usize) -> Self {
+        self.max_expression_nodes = size;
+        self
+    }
+
+    pub fn set_unknown_tag_policy(&mut self, policy: UnknownTagPolicy) {
+        self.unknown_tag_policy = policy;
+    }
+
+    pub fn with_unknown_tag_policy(mut self, policy: UnknownTagPolicy) -> Self {
+        self.set_unknown_tag_policy(policy);
+        self
+    }
+
+    /// Overrides the unknown tag policy for a single test or action.
+    pub fn set_command_unknown_tag_policy(
+        &mut self,
+        command: impl Into<String>,
+        policy: UnknownTagPolicy,
+    ) {
+        self.unknown_tag_policies
+            .insert(command.into().to_ascii_lowercase(), policy);
+    }
+
+    pub fn with_command_unknown_tag_policy(
+        mut self,
+        command: impl Into<String>,
+        policy: UnknownTagPolicy,
+    ) -> Self {
+        self.set_command_unknown_tag_policy(command, policy);
+        self
+    }
+
+    /// Enables `# sieve: name=value, ...` pragma comments before the first
+    /// command of a script, accepting only the changes allowed by `bounds`.
+    pub fn set_pragma_bounds(&mut self, bounds: PragmaBounds) {
+        self.pragma_bounds = Some(bounds);
+    }
+
+    pub fn with_pragma_bounds(mut self, bounds: PragmaBounds) -> Self {
+        self.set_pragma_bounds(bounds);
+        self
+    }
+
     pub fn set_max_nested_blocks(&mut self, size: usize) {
         self.max_nested_blocks = size;
     }
@@ -330,6 +519,16 @@ impl Compiler {
         self
     }
 
+    /// Makes single `&`, `|` and `^` in expressions bitwise rather than logical.
+    pub fn set_bitwise_operators(&mut self, value: bool) {
+        self.bitwise_operators = value;
+    }
+
+    pub fn with_bitwise_operators(mut self, value: bool) -> Self {
+        self.bitwise_operators = value;
+        self
+    }
+
     pub fn register_functions<C>(mut self, fnc_map: &mut FunctionMap<C>) -> Self {
         self.functions = std::mem::take(&mut fnc_map.map);
         self
@@ -343,6 +542,87 @@ impl Compiler {
     pub fn set_no_capability_check(&mut self, value: bool) {
         self.no_capability_check = value;
     }
+
+    /// Parses unknown commands and tests containing `test` as the commands
+    /// of the svtest harness, see [`crate::testsuite::TestSuite`].
+    #[cfg(any(test, feature = "testsuite"))]
+    pub fn set_test_commands(&mut self, value: bool) {
+        self.test_commands = value;
+    }
+
+    #[cfg(any(test, feature = "testsuite"))]
+    pub fn with_test_commands(mut self, value: bool) -> Self {
+        self.test_commands = value;
+        self
+    }
+
+    /// Restricts constant `redirect` addresses to these domains and their subdomains.
+    pub fn set_redirect_domains(&mut self, domains: impl IntoIterator<Item = impl Into<String>>) {
+        self.redirect_domains = domains
+            .into_iter()
+            .map(|domain| domain.into().to_ascii_lowercase())
+            .collect();
+    }
+
+    pub fn with_redirect_domains(
+        mut self,
+        domains: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.redirect_domains = domains
+            .into_iter()
+            .map(|domain| domain.into().to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    pub fn set_variable_name_rules(&mut self, rules: VariableNameRules) {
+        self.variable_names = rules;
+    }
+
+    pub fn with_variable_name_rules(mut self, rules: VariableNameRules) -> Self {
+        self.set_variable_name_rules(rules);
+        self
+    }
+
+    /// Enables a comparator for `:comparator` arguments and `comparator-*`
+    /// requirements, scripts using any other comparator fail to compile.
+    pub fn set_comparator(&mut self, comparator: Comparator) {
+        self.comparators.insert(comparator);
+    }
+
+    pub fn with_comparator(mut self, comparator: Comparator) -> Self {
+        self.set_comparator(comparator);
+        self
+    }
+
+    pub fn unset_comparator(&mut self, comparator: &Comparator) {
+        self.comparators.remove(comparator);
+    }
+
+    pub fn without_comparator(mut self, comparator: &Comparator) -> Self {
+        self.unset_comparator(comparator);
+        self
+    }
+
+    /// Validates constant `notify` methods using `scheme` with `validator`
+    /// instead of the built-in checks, which also allows custom schemes.
+    pub fn set_notify_uri_validator(
+        &mut self,
+        scheme: impl Into<String>,
+        validator: NotifyUriValidator,
+    ) {
+        self.notify_uri_validators
+            .insert(scheme.into().to_ascii_lowercase(), validator);
+    }
+
+    pub fn with_notify_uri_validator(
+        mut self,
+        scheme: impl Into<String>,
+        validator: NotifyUriValidator,
+    ) -> Self {
+        self.set_notify_uri_validator(scheme, validator);
+        self
+    }
 }
 
 impl CompileError {
@@ -367,6 +647,80 @@ impl PartialEq for Regex {
 
 impl Eq for Regex {}
 
+impl Glob {
+    pub fn new(expr: String) -> Self {
+        Glob {
+            octet: GlobPattern::compile(&expr, false),
+            casemap: GlobPattern::compile(&expr, true),
+            expr,
+        }
+    }
+}
+
+impl PartialEq for Glob {
+    fn eq(&self, other: &Self) -> bool {
+        self.expr == other.expr
+    }
+}
+
+impl Eq for Glob {}
+
+impl Serialize for Glob {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.expr.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Glob {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <String>::deserialize(deserializer).map(Glob::new)
+    }
+}
+
+impl ContainsKeys {
+    pub fn new(keys: Vec<String>) -> Result<Self, aho_corasick::BuildError> {
+        Ok(ContainsKeys {
+            octet: aho_corasick::AhoCorasick::new(&keys)?,
+            casemap: aho_corasick::AhoCorasick::new(keys.iter().map(|k| k.to_lowercase()))?,
+            keys,
+        })
+    }
+}
+
+impl PartialEq for ContainsKeys {
+    fn eq(&self, other: &Self) -> bool {
+        self.keys == other.keys
+    }
+}
+
+impl Eq for ContainsKeys {}
+
+impl Serialize for ContainsKeys {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.keys.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ContainsKeys {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <Vec<String>>::deserialize(deserializer).and_then(|keys| {
+            ContainsKeys::new(keys).map_err(|err| serde::de::Error::custom(err.to_string()))
+        })
+    }
+}
+
 impl Serialize for Regex {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -439,6 +793,9 @@ impl Display for CompileError {
             ErrorType::InvalidArguments => write!(f, "Invalid Arguments"),
             ErrorType::InvalidAddress => write!(f, "Invalid Address"),
             ErrorType::InvalidURI => write!(f, "Invalid URI"),
+            ErrorType::InvalidNotifyUri { uri, reason } => {
+                write!(f, "Invalid notification URI {uri:?}: {reason}")
+            }
             ErrorType::InvalidEnvelope(value) => write!(f, "Invalid envelope {value:?}"),
             ErrorType::UnterminatedString => write!(f, "Unterminated string"),
             ErrorType::UnterminatedComment => write!(f, "Unterminated comment"),
@@ -450,6 +807,18 @@ impl Display for CompileError {
             ErrorType::VariableIsLocal(value) => {
                 write!(f, "Variable {value:?} was already defined as local")
             }
+            ErrorType::VariableNameReserved(value) => {
+                write!(f, "Variable name {value:?} is reserved")
+            }
+            ErrorType::NamespaceNotAllowed(value) => {
+                write!(f, "Variable namespace {value:?} is not allowed")
+            }
+            ErrorType::UnsupportedComparator(value) => {
+                write!(f, "Comparator {value:?} is not supported")
+            }
+            ErrorType::ConflictingModifiers(a, b) => {
+                write!(f, "Modifiers {a} and {b} cannot be used together")
+            }
             ErrorType::HeaderTooLong => write!(f, "Header value is too long"),
             ErrorType::ExpectedConstantString => write!(f, "Expected a constant string"),
             ErrorType::UnexpectedToken { expected, found } => {
@@ -462,10 +831,16 @@ impl Display for CompileError {
                 write!(f, "Too many nested foreverypart blocks")
             }
             ErrorType::TooManyIncludes => write!(f, "Too many includes"),
+            ErrorType::TooManyInstructions => write!(f, "Too many instructions"),
+            ErrorType::InvalidPragma(value) => write!(f, "Invalid or disallowed pragma {value:?}"),
+            ErrorType::CompiledScriptTooLarge => write!(f, "Compiled Sieve script is too large"),
             ErrorType::LabelAlreadyDefined(value) => write!(f, "Label {value:?} already defined"),
             ErrorType::LabelUndefined(value) => write!(f, "Label {value:?} does not exist"),
             ErrorType::BreakOutsideLoop => write!(f, "Break used outside of foreverypart loop"),
             ErrorType::ContinueOutsideLoop => write!(f, "Continue used outside of while loop"),
+            ErrorType::ExtractTextOutsideLoop => {
+                write!(f, "Extracttext used outside of foreverypart loop")
+            }
             ErrorType::UnsupportedComparator(value) => {
                 write!(f, "Comparator {value:?} is not supported")
             }
@@ -474,6 +849,9 @@ impl Display for CompileError {
                 write!(f, "Undeclared capability '{value}'")
             }
             ErrorType::MissingTag(value) => write!(f, "Missing tag {value:?}"),
+            ErrorType::RedirectNotAllowed(value) => {
+                write!(f, "Redirecting to {value:?} is not allowed")
+            }
         }?;
 
         write!(
@@ -488,7 +866,14 @@ impl Display for CompileError {
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            RuntimeError::TooManyIncludes => write!(f, ""),
+            RuntimeError::TooManyIncludes(chain) => {
+                write!(f, "Too many nested includes: ")?;
+                write_include_chain(f, chain)
+            }
+            RuntimeError::IncludeCycle(chain) => {
+                write!(f, "Script includes itself: ")?;
+                write_include_chain(f, chain)
+            }
             RuntimeError::InvalidInstruction(value) => write!(
                 f,
                 "Script executed invalid instruction {:?} at line {}, column {}.",
@@ -509,15 +894,63 @@ impl Display for RuntimeError {
                 f,
                 "Script exceeded the maximum number of instructions allowed to execute."
             ),
+            RuntimeError::PartIterationLimitReached => write!(
+                f,
+                "Script exceeded the maximum number of MIME parts allowed to iterate."
+            ),
+            RuntimeError::TooManyNestedForEveryPart => {
+                write!(f, "Too many nested foreverypart loops.")
+            }
+            RuntimeError::VariableMemoryLimitReached => write!(
+                f,
+                "Script exceeded the maximum amount of memory allowed for variables."
+            ),
+            RuntimeError::ExpansionLimitReached => write!(
+                f,
+                "Script expanded a string beyond the maximum size allowed."
+            ),
+            RuntimeError::GeneratedMessageLimitReached => write!(
+                f,
+                "Script exceeded the maximum number of messages allowed to generate."
+            ),
+            RuntimeError::RedirectNotAllowed(value) => {
+                write!(f, "Redirecting to {value:?} is not allowed.")
+            }
+            RuntimeError::Internal {
+                message,
+                span: Some(span),
+                ..
+            } => write!(
+                f,
+                "Internal error at line {}, column {}: {message}.",
+                span.line_num, span.line_pos
+            ),
+            RuntimeError::Internal { message, .. } => write!(f, "Internal error: {message}."),
+        }
+    }
+}
+
+fn write_include_chain(f: &mut std::fmt::Formatter<'_>, chain: &[Script]) -> std::fmt::Result {
+    for (pos, script) in chain.iter().enumerate() {
+        if pos > 0 {
+            f.write_str(" -> ")?;
         }
+        f.write_str(script.as_str())?;
     }
+    f.write_str(".")
 }
 
 #[cfg(test)]
 mod tests {
     use std::{fs, path::PathBuf};
 
-    use crate::Compiler;
+    use crate::{
+        compiler::{
+            grammar::{instruction::Instruction, Comparator},
+            CompileWarningKind, ErrorType, UnknownTag,
+        },
+        Compiler, UnknownTagPolicy, VariableNameRules,
+    };
 
     #[test]
     fn parse_rfc() {
@@ -574,4 +1007,357 @@ mod tests {
             test_dir.display()
         );
     }
+
+    #[test]
+    fn instruction_limits() {
+        let script = b"keep;\nkeep;\nkeep;\nkeep;\n";
+        let sieve = Compiler::new().compile(script).unwrap();
+        let num_instructions = sieve.instructions.len();
+        let size = sieve.serialized_size().unwrap();
+
+        assert!(Compiler::new()
+            .with_max_instructions(num_instructions)
+            .with_max_compiled_size(size)
+            .compile(script)
+            .is_ok());
+
+        let err = Compiler::new()
+            .with_max_instructions(2)
+            .compile(script)
+            .unwrap_err();
+        assert!(
+            matches!(err.error_type(), ErrorType::TooManyInstructions),
+            "{err:?}"
+        );
+        assert_eq!(err.line_num(), 3);
+
+        let err = Compiler::new()
+            .with_max_compiled_size(size - 1)
+            .compile(script)
+            .unwrap_err();
+        assert!(
+            matches!(err.error_type(), ErrorType::CompiledScriptTooLarge),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn expression_limits() {
+        let compiler = Compiler::new()
+            .with_max_expression_depth(4)
+            .with_max_expression_nodes(8);
+        for (expr, is_ok) in [
+            ("((1 + 2))", true),
+            ("((((((1))))))", false),
+            ("!!!!!!1", false),
+            ("1 + 2 + 3 + 4", true),
+            ("1 + 2 + 3 + 4 + 5 + 6", false),
+        ] {
+            let script =
+                format!("require \"vnd.stalwart.expressions\";\nif eval \"{expr}\" {{ keep; }}");
+            match compiler.compile(script.as_bytes()) {
+                Ok(_) => assert!(is_ok, "{expr}"),
+                Err(err) => {
+                    assert!(!is_ok, "{expr}: {err}");
+                    assert!(
+                        matches!(err.error_type(), ErrorType::InvalidExpression(_)),
+                        "{err:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn expression_arity() {
+        for (expr, message) in [
+            ("trim('a', 'b')", "expected 1 arguments, got 2"),
+            ("substring('abc')", "expected 2 to 3 arguments, got 1"),
+        ] {
+            let script =
+                format!("require \"vnd.stalwart.expressions\";\nif eval \"{expr}\" {{ keep; }}");
+            let err = Compiler::new().compile(script.as_bytes()).unwrap_err();
+            assert!(
+                matches!(err.error_type(), ErrorType::InvalidExpression(err) if err.ends_with(message)),
+                "{err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_tags() {
+        let script = concat!(
+            "require \"fileinto\";\n",
+            "fileinto :vendor \"Junk\";\n",
+            "if header :level \"3\" \"subject\" \"hi\" { keep; }\n"
+        );
+        let expected = Compiler::new()
+            .compile(
+                concat!(
+                    "require \"fileinto\";\n",
+                    "fileinto \"Junk\";\n",
+                    "if header \"subject\" \"hi\" { keep; }\n"
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        assert!(Compiler::new().compile(script.as_bytes()).is_err());
+        assert!(Compiler::new()
+            .with_command_unknown_tag_policy("fileinto", UnknownTagPolicy::Ignore)
+            .compile(script.as_bytes())
+            .is_err());
+
+        for (compiler, value) in [
+            (
+                Compiler::new().with_unknown_tag_policy(UnknownTagPolicy::Ignore),
+                None,
+            ),
+            (
+                Compiler::new().with_unknown_tag_policy(UnknownTagPolicy::Preserve),
+                Some("3".to_string()),
+            ),
+            (
+                Compiler::new()
+                    .with_command_unknown_tag_policy("fileinto", UnknownTagPolicy::Ignore)
+                    .with_command_unknown_tag_policy("HEADER", UnknownTagPolicy::Preserve),
+                Some("3".to_string()),
+            ),
+        ] {
+            let sieve = compiler.compile(script.as_bytes()).unwrap();
+            assert_eq!(sieve.instructions, expected.instructions);
+            assert_eq!(
+                sieve
+                    .unknown_tags()
+                    .iter()
+                    .map(|t| UnknownTag {
+                        span: Default::default(),
+                        ..t.clone()
+                    })
+                    .collect::<Vec<_>>(),
+                vec![
+                    UnknownTag {
+                        command: "fileinto".to_string(),
+                        tag: ":vendor".to_string(),
+                        value: None,
+                        span: Default::default(),
+                    },
+                    UnknownTag {
+                        command: "header".to_string(),
+                        tag: ":level".to_string(),
+                        value,
+                        span: Default::default(),
+                    }
+                ]
+            );
+            assert_eq!(sieve.unknown_tags()[1].span.line_num, 3);
+        }
+    }
+
+    #[test]
+    fn compile_many() {
+        let compiler = Compiler::new();
+        let scripts: [&[u8]; 3] = [
+            b"keep;",
+            b"if header :is \"subject\" \"test\" { discard; }",
+            b"fileinto \"Archive\";",
+        ];
+
+        let results = compiler.compile_many(&scripts);
+        assert_eq!(results.len(), scripts.len());
+        for (result, script) in results.iter().zip(scripts) {
+            match (result, compiler.compile(script)) {
+                (Ok(sieve), Ok(expected)) => assert_eq!(sieve, &expected),
+                (Err(err), Err(expected)) => assert_eq!(err.to_string(), expected.to_string()),
+                (result, expected) => panic!("{result:?} != {expected:?}"),
+            }
+        }
+        assert!(results[2].is_err());
+        assert!(compiler.compile_many::<&[u8]>(&[]).is_empty());
+    }
+
+    #[test]
+    fn variable_name_rules() {
+        let compiler = Compiler::new().with_variable_name_rules(
+            VariableNameRules::new()
+                .with_max_length(10)
+                .with_allowed_namespaces(["global", "envelope"])
+                .with_reserved_prefix("admin."),
+        );
+
+        for (script, expected) in [
+            ("set \"x\" \"1\";", None),
+            ("set \"y\" \"${global.admin.x}\";", None),
+            ("set \"y\" \"${foo.bar}\";", None),
+            ("set \"averyverylongname\" \"1\";", Some("too long")),
+            ("set \"global.admin.x\" \"1\";", Some("reserved")),
+            ("global \"admin.cfg\";", Some("reserved")),
+            ("set \"y\" \"${env.name}\";", Some("namespace")),
+        ] {
+            let script = format!("require [\"variables\", \"include\"];\n{script}");
+            match (compiler.compile(script.as_bytes()), expected) {
+                (Ok(_), None) => (),
+                (Err(err), Some(expected)) => assert!(
+                    match err.error_type() {
+                        ErrorType::VariableTooLong => expected == "too long",
+                        ErrorType::VariableNameReserved(_) => expected == "reserved",
+                        ErrorType::NamespaceNotAllowed(_) => expected == "namespace",
+                        _ => false,
+                    },
+                    "{script}: {err:?}"
+                ),
+                (result, _) => panic!("{script}: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn set_modifiers() {
+        let compiler = Compiler::new();
+
+        for (script, conflict) in [
+            ("set :lower :upper \"a\" \"b\";", (":lower", ":upper")),
+            (
+                "set :upperfirst :lowerfirst \"a\" \"b\";",
+                (":upperfirst", ":lowerfirst"),
+            ),
+            (
+                "set :quoteregex :quotewildcard \"a\" \"b\";",
+                (":quoteregex", ":quotewildcard"),
+            ),
+        ] {
+            let script = format!("require [\"variables\", \"regex\"];\n{script}");
+            match compiler.compile(script.as_bytes()) {
+                Err(err) => assert!(
+                    matches!(
+                        err.error_type(),
+                        ErrorType::ConflictingModifiers(a, b) if (a.as_str(), b.as_str()) == conflict
+                    ),
+                    "{script}: {err:?}"
+                ),
+                Ok(_) => panic!("{script}: expected a conflict"),
+            }
+        }
+
+        for (script, redundant) in [
+            ("set :lower :upperfirst \"a\" \"b\";", vec![]),
+            (
+                "set :lower :lowerfirst \"a\" \"b\";",
+                vec![(":lowerfirst", ":lower")],
+            ),
+            (
+                "set :upperfirst :upper \"a\" \"b\";",
+                vec![(":upperfirst", ":upper")],
+            ),
+            (
+                "set :length :lower \"a\" \"b\";",
+                vec![(":lower", ":length")],
+            ),
+            (
+                "set :length :length \"a\" \"b\";",
+                vec![(":length", ":length")],
+            ),
+            (
+                "set :upper :upperfirst :length \"a\" \"b\";",
+                vec![
+                    (":upperfirst", ":upper"),
+                    (":upper", ":length"),
+                    (":upperfirst", ":length"),
+                ],
+            ),
+        ] {
+            let script = format!("require \"variables\";\n{script}");
+            let sieve = compiler.compile(script.as_bytes()).unwrap();
+            assert_eq!(
+                sieve
+                    .warnings()
+                    .iter()
+                    .map(|warning| match &warning.kind {
+                        CompileWarningKind::RedundantModifier { modifier, with } => {
+                            (modifier.as_str(), with.as_str())
+                        }
+                    })
+                    .collect::<Vec<_>>(),
+                redundant,
+                "{script}"
+            );
+        }
+    }
+
+    #[test]
+    fn comparators() {
+        let compiler = Compiler::new();
+        let restricted = Compiler::new().without_comparator(&Comparator::AsciiNumeric);
+
+        for (compiler, script, expected) in [
+            (
+                &compiler,
+                "if header :comparator \"i;octet\" \"to\" \"a\" {}",
+                None,
+            ),
+            (
+                &compiler,
+                "if header :comparator \"i;unicode-casemap\" \"to\" \"a\" {}",
+                Some("i;unicode-casemap"),
+            ),
+            (
+                &compiler,
+                "if address :comparator [\"i;foo\"] \"to\" \"a\" {}",
+                Some("i;foo"),
+            ),
+            (
+                &compiler,
+                "require \"comparator-i;foo\";",
+                Some("i;foo"),
+            ),
+            (
+                &compiler,
+                "require \"ihave\"; if ihave \"comparator-i;foo\" { require \"comparator-i;foo\"; }",
+                None,
+            ),
+            (
+                &restricted,
+                "require \"comparator-i;ascii-numeric\";",
+                Some("i;ascii-numeric"),
+            ),
+            (
+                &restricted,
+                "if header :comparator \"i;ascii-numeric\" \"x\" \"1\" {}",
+                Some("i;ascii-numeric"),
+            ),
+            (
+                &Compiler::new().with_comparator(Comparator::Other("i;foo".to_string())),
+                "require \"comparator-i;foo\"; if header :comparator \"i;foo\" \"to\" \"a\" {}",
+                None,
+            ),
+        ] {
+            match (compiler.compile(script.as_bytes()), expected) {
+                (Ok(_), None) => (),
+                (Err(err), Some(expected)) => assert!(
+                    matches!(
+                        err.error_type(),
+                        ErrorType::UnsupportedComparator(comparator) if comparator == expected
+                    ),
+                    "{script}: {err:?}"
+                ),
+                (result, _) => panic!("{script}: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let script = b"test_set \"message\" \"Subject: a\";\nif test_result_execute { keep; }";
+
+        // Unknown commands are only harness commands when enabled
+        assert!(Compiler::new().compile(script).is_err());
+        let sieve = Compiler::new()
+            .with_test_commands(true)
+            .compile(script)
+            .unwrap();
+        assert!(sieve
+            .instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::TestCmd(_))));
+    }
 }
@@ -0,0 +1,730 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::ops::Range;
+
+use ahash::AHashMap;
+
+use crate::{Envelope, Sieve};
+
+use super::{
+    grammar::{
+        actions::action_flags::Action, instruction::Instruction, test::Test, AddressPart,
+        MatchType, RelationalMatch,
+    },
+    Value,
+};
+
+/// Phrases used to describe a script. Each phrase is a template where
+/// `{0}` and `{1}` are replaced with its arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phrase {
+    // Rules
+    If,
+    ElseIf,
+    Else,
+    Always,
+    NestedIf,
+    NestedElse,
+    ForEveryPart,
+
+    // Lists and values
+    ListSeparator,
+    And,
+    Or,
+    Quoted,
+    Dynamic,
+    Not,
+
+    // Message parts
+    Header,
+    Address,
+    AddressLocalPart,
+    AddressDomain,
+    AddressUser,
+    AddressDetail,
+    AddressName,
+    Sender,
+    SenderLocalPart,
+    SenderDomain,
+    SenderUser,
+    SenderDetail,
+    SenderName,
+    EnvelopeFrom,
+    EnvelopeTo,
+    Body,
+    CountOf,
+
+    // Tests
+    Is,
+    Contains,
+    Matches,
+    Regex,
+    GreaterThan,
+    GreaterOrEqual,
+    LessThan,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    InList,
+    Exists,
+    SizeOver,
+    SizeUnder,
+    HasFlag,
+    True,
+    False,
+    Expression,
+    OtherTest,
+
+    // Actions
+    Keep,
+    FileInto,
+    Redirect,
+    Discard,
+    Stop,
+    Reject,
+    Vacation,
+    Notify,
+    AddHeader,
+    DeleteHeader,
+    SetFlags,
+    AddFlags,
+    RemoveFlags,
+    Include,
+    Return,
+    Error,
+}
+
+/// Source of the phrases used by `Sieve::summarize`. Catalogs only need to
+/// return the phrases they translate, the English default is used for the
+/// rest.
+pub trait Catalog {
+    fn phrase(&self, phrase: Phrase) -> &str {
+        phrase.as_str()
+    }
+}
+
+/// English catalog.
+pub struct DefaultCatalog;
+
+impl Catalog for DefaultCatalog {}
+
+impl Catalog for AHashMap<Phrase, String> {
+    fn phrase(&self, phrase: Phrase) -> &str {
+        self.get(&phrase)
+            .map_or(phrase.as_str(), |text| text.as_str())
+    }
+}
+
+impl Phrase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phrase::If => "If {0}, {1}.",
+            Phrase::ElseIf => "Otherwise, if {0}, {1}.",
+            Phrase::Else => "Otherwise, {0}.",
+            Phrase::Always => "{0}.",
+            Phrase::NestedIf => "if {0}, {1}",
+            Phrase::NestedElse => "otherwise {0}",
+            Phrase::ForEveryPart => "for every MIME part, {0}",
+            Phrase::ListSeparator => ", ",
+            Phrase::And => "{0} and {1}",
+            Phrase::Or => "{0} or {1}",
+            Phrase::Quoted => "'{0}'",
+            Phrase::Dynamic => "a computed value",
+            Phrase::Not => "it is not true that {0}",
+            Phrase::Header => "the {0} header",
+            Phrase::Address => "the {0} address",
+            Phrase::AddressLocalPart => "the local part of the {0} address",
+            Phrase::AddressDomain => "the domain of the {0} address",
+            Phrase::AddressUser => "the user of the {0} address",
+            Phrase::AddressDetail => "the detail of the {0} address",
+            Phrase::AddressName => "the display name of the {0} address",
+            Phrase::Sender => "the sender's address",
+            Phrase::SenderLocalPart => "the sender's local part",
+            Phrase::SenderDomain => "the sender's domain",
+            Phrase::SenderUser => "the sender's user",
+            Phrase::SenderDetail => "the sender's detail",
+            Phrase::SenderName => "the sender's name",
+            Phrase::EnvelopeFrom => "the envelope sender",
+            Phrase::EnvelopeTo => "the envelope recipient",
+            Phrase::Body => "the message body",
+            Phrase::CountOf => "the number of values of {0}",
+            Phrase::Is => "{0} is {1}",
+            Phrase::Contains => "{0} contains {1}",
+            Phrase::Matches => "{0} matches {1}",
+            Phrase::Regex => "{0} matches the regular expression {1}",
+            Phrase::GreaterThan => "{0} is greater than {1}",
+            Phrase::GreaterOrEqual => "{0} is greater than or equal to {1}",
+            Phrase::LessThan => "{0} is less than {1}",
+            Phrase::LessOrEqual => "{0} is less than or equal to {1}",
+            Phrase::Equal => "{0} is equal to {1}",
+            Phrase::NotEqual => "{0} is not equal to {1}",
+            Phrase::InList => "{0} is in the list {1}",
+            Phrase::Exists => "{0} exists",
+            Phrase::SizeOver => "the message is larger than {0} bytes",
+            Phrase::SizeUnder => "the message is smaller than {0} bytes",
+            Phrase::HasFlag => "the message has the flag {0}",
+            Phrase::True => "always",
+            Phrase::False => "never",
+            Phrase::Expression => "an expression is true",
+            Phrase::OtherTest => "the {0} test succeeds",
+            Phrase::Keep => "keep the message",
+            Phrase::FileInto => "file into {0}",
+            Phrase::Redirect => "redirect to {0}",
+            Phrase::Discard => "discard the message",
+            Phrase::Stop => "stop",
+            Phrase::Reject => "reject the message",
+            Phrase::Vacation => "send a vacation reply",
+            Phrase::Notify => "send a notification to {0}",
+            Phrase::AddHeader => "add the {0} header",
+            Phrase::DeleteHeader => "remove the {0} header",
+            Phrase::SetFlags => "set the flags to {0}",
+            Phrase::AddFlags => "add the flags {0}",
+            Phrase::RemoveFlags => "remove the flags {0}",
+            Phrase::Include => "run the script {0}",
+            Phrase::Return => "return",
+            Phrase::Error => "fail",
+        }
+    }
+}
+
+struct Summarizer<'x, C: Catalog> {
+    sieve: &'x Sieve,
+    catalog: &'x C,
+}
+
+struct Branch {
+    condition: Option<String>,
+    actions: Vec<String>,
+}
+
+impl Sieve {
+    /// Describes each top-level rule of the script with one or more
+    /// sentences, using the phrases from `catalog`.
+    pub fn summarize(&self, catalog: &impl Catalog) -> Vec<String> {
+        let summarizer = Summarizer {
+            sieve: self,
+            catalog,
+        };
+        let mut rules = Vec::new();
+        let mut pos = 0;
+
+        while pos < self.instructions.len() {
+            let end = self.rule_end(pos);
+            let mut sentences = Vec::new();
+            if summarizer.is_condition(pos) {
+                for (branch_num, branch) in summarizer.branches(pos..end).into_iter().enumerate() {
+                    let actions = summarizer.list(&branch.actions, Phrase::And);
+                    let sentence = match branch.condition {
+                        Some(condition) if branch_num == 0 => {
+                            summarizer.fill(Phrase::If, &[&condition, &actions])
+                        }
+                        Some(condition) => summarizer.fill(Phrase::ElseIf, &[&condition, &actions]),
+                        None => summarizer.fill(Phrase::Else, &[&actions]),
+                    };
+                    sentences.push(capitalize(sentence));
+                }
+            } else {
+                let actions = summarizer.actions(pos..end);
+                if !actions.is_empty() {
+                    let actions = summarizer.list(&actions, Phrase::And);
+                    sentences.push(capitalize(summarizer.fill(Phrase::Always, &[&actions])));
+                }
+            }
+            if !sentences.is_empty() {
+                rules.push(sentences.join(" "));
+            }
+            pos = end;
+        }
+
+        rules
+    }
+
+    // Describes the condition of the innermost `if` whose body contains the
+    // instruction at `pos`, if any.
+    pub(crate) fn condition_at(&self, pos: usize, catalog: &impl Catalog) -> Option<String> {
+        let summarizer = Summarizer {
+            sieve: self,
+            catalog,
+        };
+        let is_condition = |pos: usize| {
+            matches!(
+                self.instructions.get(pos),
+                Some(
+                    Instruction::Test(_)
+                        | Instruction::Eval(_)
+                        | Instruction::Jz(_)
+                        | Instruction::Jnz(_)
+                )
+            )
+        };
+
+        for start in (0..pos.min(self.instructions.len())).rev() {
+            if summarizer.is_condition(start) && (start == 0 || !is_condition(start - 1)) {
+                if let Some((jz_pos, target)) = self.if_jump(start, pos) {
+                    if target > pos {
+                        return Some(summarizer.condition(start..jz_pos));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl<'x, C: Catalog> Summarizer<'x, C> {
+    fn is_condition(&self, pos: usize) -> bool {
+        matches!(
+            self.sieve.instructions.get(pos),
+            Some(Instruction::Test(_) | Instruction::Eval(_))
+        )
+    }
+
+    // Splits an if/elsif/else chain into its branches
+    fn branches(&self, range: Range<usize>) -> Vec<Branch> {
+        let instructions = &self.sieve.instructions;
+        let mut branches = Vec::new();
+        let mut pos = range.start;
+
+        while pos < range.end {
+            if !self.is_condition(pos) {
+                branches.push(Branch {
+                    condition: None,
+                    actions: self.actions(pos..range.end),
+                });
+                break;
+            }
+
+            let Some((jz_pos, target)) = self.sieve.if_jump(pos, range.end) else {
+                break;
+            };
+            let target = target.min(range.end);
+
+            let mut body_end = target;
+            let has_else = matches!(
+                instructions.get(target.wrapping_sub(1)),
+                Some(Instruction::Jmp(jmp_pos)) if target > jz_pos + 1 && *jmp_pos as usize >= target
+            );
+            if has_else {
+                body_end -= 1;
+            }
+
+            branches.push(Branch {
+                condition: self.condition(pos..jz_pos).into(),
+                actions: self.actions(jz_pos + 1..body_end),
+            });
+
+            if !has_else {
+                break;
+            }
+            pos = target;
+        }
+
+        branches
+    }
+
+    fn actions(&self, range: Range<usize>) -> Vec<String> {
+        let instructions = &self.sieve.instructions;
+        let mut actions = Vec::new();
+        let mut pos = range.start;
+
+        while pos < range.end {
+            match &instructions[pos] {
+                Instruction::Test(_) | Instruction::Eval(_) => {
+                    let end = self.sieve.rule_end(pos).min(range.end);
+                    for branch in self.branches(pos..end) {
+                        let branch_actions = self.list(&branch.actions, Phrase::And);
+                        actions.push(if let Some(condition) = branch.condition {
+                            self.fill(Phrase::NestedIf, &[&condition, &branch_actions])
+                        } else {
+                            self.fill(Phrase::NestedElse, &[&branch_actions])
+                        });
+                    }
+                    pos = end;
+                }
+                Instruction::ForEveryPart(fep) => {
+                    let end = (fep.jz_pos as usize).clamp(pos + 1, range.end);
+                    let part_actions = self.actions(pos + 1..end);
+                    if !part_actions.is_empty() {
+                        let part_actions = self.list(&part_actions, Phrase::And);
+                        actions.push(self.fill(Phrase::ForEveryPart, &[&part_actions]));
+                    }
+                    pos = end;
+                }
+                instruction => {
+                    if let Some(action) = self.action(instruction) {
+                        actions.push(action);
+                    }
+                    pos += 1;
+                }
+            }
+        }
+
+        actions
+    }
+
+    fn action(&self, instruction: &Instruction) -> Option<String> {
+        Some(match instruction {
+            Instruction::Keep(_) => self.fill(Phrase::Keep, &[]),
+            Instruction::FileInto(fileinto) => {
+                self.fill(Phrase::FileInto, &[&self.value(&fileinto.folder)])
+            }
+            Instruction::Redirect(redirect) => {
+                self.fill(Phrase::Redirect, &[&self.value(&redirect.address)])
+            }
+            Instruction::Discard => self.fill(Phrase::Discard, &[]),
+            Instruction::Stop => self.fill(Phrase::Stop, &[]),
+            Instruction::Reject(_) => self.fill(Phrase::Reject, &[]),
+            Instruction::Vacation(_) => self.fill(Phrase::Vacation, &[]),
+            Instruction::Notify(notify) => {
+                self.fill(Phrase::Notify, &[&self.value(&notify.method)])
+            }
+            Instruction::AddHeader(add_header) => {
+                self.fill(Phrase::AddHeader, &[&self.value(&add_header.field_name)])
+            }
+            Instruction::DeleteHeader(delete_header) => self.fill(
+                Phrase::DeleteHeader,
+                &[&self.value(&delete_header.field_name)],
+            ),
+            Instruction::EditFlags(edit_flags) => self.fill(
+                match edit_flags.action {
+                    Action::Set => Phrase::SetFlags,
+                    Action::Add => Phrase::AddFlags,
+                    Action::Remove => Phrase::RemoveFlags,
+                },
+                &[&self.values(&edit_flags.flags, Phrase::And)],
+            ),
+            Instruction::Include(include) => {
+                self.fill(Phrase::Include, &[&self.value(&include.value)])
+            }
+            Instruction::Return => self.fill(Phrase::Return, &[]),
+            Instruction::Error(_) => self.fill(Phrase::Error, &[]),
+            _ => return None,
+        })
+    }
+
+    fn condition(&self, range: Range<usize>) -> String {
+        let instructions = &self.sieve.instructions[range];
+        let tests = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Test(test) => self.test(test).into(),
+                Instruction::Eval(_) => self.fill(Phrase::Expression, &[]).into(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let joiner = if instructions
+            .iter()
+            .any(|instruction| matches!(instruction, Instruction::Jnz(_)))
+        {
+            Phrase::Or
+        } else {
+            Phrase::And
+        };
+        self.list(&tests, joiner)
+    }
+
+    fn test(&self, test: &Test) -> String {
+        let (description, is_not) = match test {
+            Test::Header(test) => (
+                self.match_keys(
+                    &self.fill(
+                        Phrase::Header,
+                        &[&self.values(&test.header_list, Phrase::Or)],
+                    ),
+                    &test.match_type,
+                    &test.key_list,
+                ),
+                test.is_not,
+            ),
+            Test::Address(test) => {
+                let is_sender = matches!(test.header_list.as_slice(), [header]
+                    if header.to_constant().map_or(false, |h| h.eq_ignore_ascii_case("from")));
+                let subject = if is_sender {
+                    self.fill(
+                        match test.address_part {
+                            AddressPart::All => Phrase::Sender,
+                            AddressPart::LocalPart => Phrase::SenderLocalPart,
+                            AddressPart::Domain => Phrase::SenderDomain,
+                            AddressPart::User => Phrase::SenderUser,
+                            AddressPart::Detail => Phrase::SenderDetail,
+                            AddressPart::Name => Phrase::SenderName,
+                        },
+                        &[],
+                    )
+                } else {
+                    self.address_part(
+                        &test.address_part,
+                        &self.values(&test.header_list, Phrase::Or),
+                    )
+                };
+                (
+                    self.match_keys(&subject, &test.match_type, &test.key_list),
+                    test.is_not,
+                )
+            }
+            Test::Envelope(test) => {
+                let subjects = test
+                    .envelope_list
+                    .iter()
+                    .map(|envelope| match envelope {
+                        Envelope::From => self.fill(Phrase::EnvelopeFrom, &[]),
+                        Envelope::To => self.fill(Phrase::EnvelopeTo, &[]),
+                        _ => self.fill(Phrase::OtherTest, &["envelope"]),
+                    })
+                    .collect::<Vec<_>>();
+                let subject = self.list(&subjects, Phrase::Or);
+                let subject = if test.address_part != AddressPart::All {
+                    self.address_part(&test.address_part, &subject)
+                } else {
+                    subject
+                };
+                (
+                    self.match_keys(&subject, &test.match_type, &test.key_list),
+                    test.is_not,
+                )
+            }
+            Test::Exists(test) => {
+                let headers = test
+                    .header_names
+                    .iter()
+                    .map(|header| self.fill(Phrase::Header, &[&self.value(header)]))
+                    .collect::<Vec<_>>();
+                (
+                    self.fill(Phrase::Exists, &[&self.list(&headers, Phrase::And)]),
+                    test.is_not,
+                )
+            }
+            Test::Size(test) => (
+                self.fill(
+                    if test.over {
+                        Phrase::SizeOver
+                    } else {
+                        Phrase::SizeUnder
+                    },
+                    &[&test.limit.to_string()],
+                ),
+                test.is_not,
+            ),
+            Test::Body(test) => (
+                self.match_keys(
+                    &self.fill(Phrase::Body, &[]),
+                    &test.match_type,
+                    &test.key_list,
+                ),
+                test.is_not,
+            ),
+            Test::String(test) => (
+                self.match_keys(
+                    &self.values(&test.source, Phrase::Or),
+                    &test.match_type,
+                    &test.key_list,
+                ),
+                test.is_not,
+            ),
+            Test::HasFlag(test) if matches!(test.match_type, MatchType::Is) => (
+                self.fill(Phrase::HasFlag, &[&self.values(&test.flags, Phrase::Or)]),
+                test.is_not,
+            ),
+            Test::True => (self.fill(Phrase::True, &[]), false),
+            Test::False => (self.fill(Phrase::False, &[]), false),
+            test => (self.fill(Phrase::OtherTest, &[test_name(test)]), false),
+        };
+
+        if is_not {
+            self.fill(Phrase::Not, &[&description])
+        } else {
+            description
+        }
+    }
+
+    fn address_part(&self, address_part: &AddressPart, subject: &str) -> String {
+        self.fill(
+            match address_part {
+                AddressPart::All => Phrase::Address,
+                AddressPart::LocalPart => Phrase::AddressLocalPart,
+                AddressPart::Domain => Phrase::AddressDomain,
+                AddressPart::User => Phrase::AddressUser,
+                AddressPart::Detail => Phrase::AddressDetail,
+                AddressPart::Name => Phrase::AddressName,
+            },
+            &[subject],
+        )
+    }
+
+    fn match_keys(&self, subject: &str, match_type: &MatchType, keys: &[Value]) -> String {
+        let keys = self.values(keys, Phrase::Or);
+        match match_type {
+            MatchType::Is => self.fill(Phrase::Is, &[subject, &keys]),
+            MatchType::Contains => self.fill(Phrase::Contains, &[subject, &keys]),
+            MatchType::Matches(_) => self.fill(Phrase::Matches, &[subject, &keys]),
+            MatchType::Regex(_) => self.fill(Phrase::Regex, &[subject, &keys]),
+            MatchType::Value(relation) => self.fill(relation_phrase(relation), &[subject, &keys]),
+            MatchType::Count(relation) => self.fill(
+                relation_phrase(relation),
+                &[&self.fill(Phrase::CountOf, &[subject]), &keys],
+            ),
+            MatchType::List => self.fill(Phrase::InList, &[subject, &keys]),
+        }
+    }
+
+    fn value(&self, value: &Value) -> String {
+        if let Some(value) = value.to_constant() {
+            self.fill(Phrase::Quoted, &[&value])
+        } else {
+            self.fill(Phrase::Dynamic, &[])
+        }
+    }
+
+    fn values(&self, values: &[Value], joiner: Phrase) -> String {
+        let values = values
+            .iter()
+            .flat_map(|value| match value {
+                Value::Contains(contains) => contains
+                    .keys
+                    .iter()
+                    .map(|key| self.fill(Phrase::Quoted, &[key]))
+                    .collect(),
+                value => vec![self.value(value)],
+            })
+            .collect::<Vec<_>>();
+        self.list(&values, joiner)
+    }
+
+    // Joins items as "a, b and c"
+    fn list(&self, items: &[String], joiner: Phrase) -> String {
+        match items {
+            [] => String::new(),
+            [item] => item.clone(),
+            [items @ .., last] => {
+                let items = items.join(self.catalog.phrase(Phrase::ListSeparator));
+                self.fill(joiner, &[&items, last])
+            }
+        }
+    }
+
+    fn fill(&self, phrase: Phrase, args: &[&str]) -> String {
+        let mut result = self.catalog.phrase(phrase).to_string();
+        for (pos, arg) in args.iter().enumerate() {
+            result = result.replace(&format!("{{{pos}}}"), arg);
+        }
+        result
+    }
+}
+
+fn relation_phrase(relation: &RelationalMatch) -> Phrase {
+    match relation {
+        RelationalMatch::Gt => Phrase::GreaterThan,
+        RelationalMatch::Ge => Phrase::GreaterOrEqual,
+        RelationalMatch::Lt => Phrase::LessThan,
+        RelationalMatch::Le => Phrase::LessOrEqual,
+        RelationalMatch::Eq => Phrase::Equal,
+        RelationalMatch::Ne => Phrase::NotEqual,
+    }
+}
+
+fn test_name(test: &Test) -> &'static str {
+    match test {
+        Test::Convert(_) => "convert",
+        Test::Date(_) => "date",
+        Test::CurrentDate(_) => "currentdate",
+        Test::Duplicate(_) => "duplicate",
+        Test::Environment(_) => "environment",
+        Test::NotifyMethodCapability(_) => "notify_method_capability",
+        Test::ValidNotifyMethod(_) => "valid_notify_method",
+        Test::ValidExtList(_) => "valid_ext_list",
+        Test::Ihave(_) => "ihave",
+        Test::HasFlag(_) => "hasflag",
+        Test::MailboxExists(_) => "mailboxexists",
+        Test::Metadata(_) => "metadata",
+        Test::MetadataExists(_) => "metadataexists",
+        Test::MailboxIdExists(_) => "mailboxidexists",
+        Test::SpamTest(_) => "spamtest",
+        Test::VirusTest(_) => "virustest",
+        Test::SpecialUseExists(_) => "specialuse_exists",
+        Test::Vacation(_) => "vacation",
+        _ => "unknown",
+    }
+}
+
+fn capitalize(text: String) -> String {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(first) if first.is_lowercase() => first.to_uppercase().chain(chars).collect(),
+        _ => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ahash::AHashMap;
+
+    use crate::Compiler;
+
+    use super::{DefaultCatalog, Phrase};
+
+    #[test]
+    fn summarize() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "imap4flags"];
+
+if address :domain "From" "example.com" {
+    fileinto "Work";
+    stop;
+} elsif anyof (header :contains "Subject" ["sale", "offer"], size :over 100K) {
+    addflag "\\Seen";
+    if exists "List-Id" { discard; }
+} else {
+    keep;
+}
+
+redirect "archive@example.org";
+"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            script.summarize(&DefaultCatalog),
+            vec![
+                concat!(
+                    "If the sender's domain is 'example.com', file into 'Work' and stop. ",
+                    "Otherwise, if the 'Subject' header contains 'sale' or 'offer' or ",
+                    "the message is larger than 102400 bytes, add the flags '\\Seen' and ",
+                    "if the 'List-Id' header exists, discard the message. ",
+                    "Otherwise, keep the message."
+                ),
+                "Redirect to 'archive@example.org'.",
+            ]
+        );
+
+        let mut catalog = AHashMap::new();
+        catalog.insert(Phrase::Always, "{0}!".to_string());
+        catalog.insert(Phrase::Redirect, "réexpédier à {0}".to_string());
+        assert_eq!(
+            script.summarize(&catalog).last().unwrap(),
+            "Réexpédier à 'archive@example.org'!"
+        );
+    }
+}
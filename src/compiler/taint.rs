@@ -0,0 +1,362 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use ahash::AHashMap;
+
+use crate::{Envelope, Sieve};
+
+use super::{
+    grammar::{
+        actions::action_set::Modifier, expr::Expression, instruction::Instruction, test::Test,
+        MatchType,
+    },
+    Value, VariableType,
+};
+
+/// Message or envelope data an action argument may be built from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TaintSource {
+    Header(String),
+    Body,
+    Envelope(Envelope),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaintSink {
+    RedirectAddress,
+    NotifyMethod,
+    NotifyFrom,
+}
+
+/// An action whose target may be controlled by the sender of the message.
+/// `rule` is the position of the action's top-level rule in the script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaintedAction {
+    pub rule: usize,
+    pub sink: TaintSink,
+    pub sources: Vec<TaintSource>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Taint {
+    sources: Vec<TaintSource>,
+    // Set with :encodeurl, safe to use as part of a URI
+    url_encoded: bool,
+}
+
+#[derive(Default)]
+struct TaintState {
+    variables: AHashMap<VariableKey, Taint>,
+    // Sources of every test that captured match variables
+    matches: Vec<TaintSource>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum VariableKey {
+    Local(usize),
+    Global(String),
+}
+
+impl Sieve {
+    /// Lists the redirect addresses and notification URIs that are built
+    /// from message headers, the body or the envelope. Values are followed
+    /// through variables and match variables; a variable set with
+    /// `:encodeurl` is not reported when used as part of a URI.
+    pub fn tainted_actions(&self) -> Vec<TaintedAction> {
+        let mut state = TaintState::default();
+
+        // Propagate until no variable changes, so that values assigned
+        // inside loops reach the instructions before them.
+        loop {
+            let mut has_changes = false;
+            for instruction in &self.instructions {
+                has_changes |= state.update(instruction);
+            }
+            if !has_changes {
+                break;
+            }
+        }
+
+        let mut result = Vec::new();
+        for (rule, range) in self
+            .rule_ranges()
+            .into_iter()
+            .filter(|range| !matches!(&self.instructions[range.start], Instruction::Require(_)))
+            .enumerate()
+        {
+            for instruction in &self.instructions[range] {
+                match instruction {
+                    Instruction::Redirect(redirect) => state.report(
+                        &mut result,
+                        rule,
+                        TaintSink::RedirectAddress,
+                        &redirect.address,
+                        false,
+                    ),
+                    Instruction::Notify(notify) => {
+                        state.report(
+                            &mut result,
+                            rule,
+                            TaintSink::NotifyMethod,
+                            &notify.method,
+                            true,
+                        );
+                        if let Some(from) = &notify.from {
+                            state.report(&mut result, rule, TaintSink::NotifyFrom, from, false);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl TaintState {
+    fn update(&mut self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::Test(test) => {
+                if let Some(sources) = self.test_sources(test) {
+                    for source in sources {
+                        if !self.matches.contains(&source) {
+                            self.matches.push(source);
+                        }
+                    }
+                }
+                false
+            }
+            Instruction::Set(set) => {
+                let mut taint = self.value(&set.value);
+                taint.url_encoded = set
+                    .modifiers
+                    .iter()
+                    .any(|modifier| matches!(modifier, Modifier::EncodeUrl));
+                self.assign(&set.name, taint)
+            }
+            Instruction::Let(let_) => {
+                let taint = self.expression(&let_.expr);
+                self.assign(&let_.name, taint)
+            }
+            Instruction::ExtractText(extract) => self.assign(
+                &extract.name,
+                Taint {
+                    sources: vec![TaintSource::Body],
+                    url_encoded: false,
+                },
+            ),
+            _ => false,
+        }
+    }
+
+    fn assign(&mut self, name: &VariableType, taint: Taint) -> bool {
+        let key = match name {
+            VariableType::Local(id) => VariableKey::Local(*id),
+            VariableType::Global(name) => VariableKey::Global(name.to_ascii_lowercase()),
+            _ => return false,
+        };
+        if taint.sources.is_empty() {
+            return false;
+        }
+
+        // Variables keep the sources of every assignment
+        let current = self.variables.entry(key).or_default();
+        let mut has_changes = false;
+        for source in taint.sources {
+            if !current.sources.contains(&source) {
+                current.sources.push(source);
+                has_changes = true;
+            }
+        }
+        if has_changes {
+            current.url_encoded = taint.url_encoded;
+        }
+        has_changes
+    }
+
+    fn value(&self, value: &Value) -> Taint {
+        let mut taint = Taint::default();
+        self.add_value(&mut taint, value, false);
+        taint
+    }
+
+    // Sources of a value, skipping URL encoded variables if `for_uri` is set
+    fn add_value(&self, taint: &mut Taint, value: &Value, for_uri: bool) {
+        match value {
+            Value::Variable(var) => self.add_variable(taint, var, for_uri),
+            Value::List(items) => {
+                for item in items {
+                    self.add_value(taint, item, for_uri);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn add_variable(&self, taint: &mut Taint, var: &VariableType, for_uri: bool) {
+        let sources = match var {
+            VariableType::Local(id) => self.variable(&VariableKey::Local(*id), for_uri),
+            VariableType::Global(name) => {
+                self.variable(&VariableKey::Global(name.to_ascii_lowercase()), for_uri)
+            }
+            VariableType::Match(_) => self.matches.clone(),
+            VariableType::Header(header) => header
+                .name
+                .iter()
+                .map(|name| TaintSource::Header(name.as_str().to_ascii_lowercase()))
+                .collect(),
+            VariableType::Part(_) => vec![TaintSource::Body],
+            VariableType::Envelope(envelope) => vec![TaintSource::Envelope(*envelope)],
+            VariableType::Environment(_) => Vec::new(),
+        };
+        for source in sources {
+            if !taint.sources.contains(&source) {
+                taint.sources.push(source);
+            }
+        }
+    }
+
+    fn variable(&self, key: &VariableKey, for_uri: bool) -> Vec<TaintSource> {
+        match self.variables.get(key) {
+            Some(taint) if !(for_uri && taint.url_encoded) => taint.sources.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn expression(&self, expr: &[Expression]) -> Taint {
+        let mut taint = Taint::default();
+        for item in expr {
+            if let Expression::Variable(var) = item {
+                self.add_variable(&mut taint, var, false);
+            }
+        }
+        taint
+    }
+
+    fn test_sources(&self, test: &Test) -> Option<Vec<TaintSource>> {
+        let (match_type, sources) = match test {
+            Test::Header(test) => (&test.match_type, header_sources(&test.header_list)),
+            Test::Address(test) => (&test.match_type, header_sources(&test.header_list)),
+            Test::Envelope(test) => (
+                &test.match_type,
+                test.envelope_list
+                    .iter()
+                    .map(|envelope| TaintSource::Envelope(*envelope))
+                    .collect(),
+            ),
+            Test::Body(test) => (&test.match_type, vec![TaintSource::Body]),
+            Test::Date(test) => (&test.match_type, header_sources([&test.header_name])),
+            Test::String(test) => {
+                let mut taint = Taint::default();
+                for value in &test.source {
+                    self.add_value(&mut taint, value, false);
+                }
+                (&test.match_type, taint.sources)
+            }
+            _ => return None,
+        };
+        match match_type {
+            MatchType::Matches(positions) | MatchType::Regex(positions) if *positions != 0 => {
+                Some(sources)
+            }
+            _ => None,
+        }
+    }
+
+    fn report(
+        &self,
+        result: &mut Vec<TaintedAction>,
+        rule: usize,
+        sink: TaintSink,
+        value: &Value,
+        for_uri: bool,
+    ) {
+        let mut taint = Taint::default();
+        self.add_value(&mut taint, value, for_uri);
+        if !taint.sources.is_empty() {
+            result.push(TaintedAction {
+                rule,
+                sink,
+                sources: taint.sources,
+            });
+        }
+    }
+}
+
+fn header_sources<'x>(headers: impl IntoIterator<Item = &'x Value>) -> Vec<TaintSource> {
+    headers
+        .into_iter()
+        .filter_map(|header| {
+            header
+                .to_constant()
+                .map(|name| TaintSource::Header(name.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Compiler, Envelope};
+
+    use super::{TaintSink, TaintSource, TaintedAction};
+
+    #[test]
+    fn tainted_actions() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["variables", "enotify", "envelope"];
+                if header :matches "Reply-To" "*" {
+                    redirect "${1}";
+                }
+                set "sender" "${envelope.from}";
+                set :encodeurl "subject" "${header.subject}";
+                notify :from "${sender}" "mailto:admin@example.org?subject=${subject}";
+                set "raw" "${header.subject}";
+                notify "mailto:admin@example.org?subject=${raw}";
+                redirect "admin@example.org";"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            script.tainted_actions(),
+            vec![
+                TaintedAction {
+                    rule: 0,
+                    sink: TaintSink::RedirectAddress,
+                    sources: vec![TaintSource::Header("reply-to".to_string())],
+                },
+                TaintedAction {
+                    rule: 3,
+                    sink: TaintSink::NotifyFrom,
+                    sources: vec![TaintSource::Envelope(Envelope::From)],
+                },
+                TaintedAction {
+                    rule: 5,
+                    sink: TaintSink::NotifyMethod,
+                    sources: vec![TaintSource::Header("subject".to_string())],
+                },
+            ]
+        );
+    }
+}
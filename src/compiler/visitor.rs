@@ -0,0 +1,500 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use crate::{compiler::ErrorType, Metadata, Sieve};
+
+use super::{
+    grammar::{
+        actions::action_mime::MimeOpts,
+        instruction::Instruction,
+        test::Test,
+        tests::{test_body::BodyTransform, test_duplicate::DupMatch},
+    },
+    header_key::HeaderKey,
+    ContainsKeys, Glob, Regex, Value,
+};
+
+/// What a command or test argument is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    Mailbox,
+    MailboxId,
+    SpecialUse,
+    Flag,
+    Address,
+    NotifyMethod,
+    HeaderName,
+    HeaderValue,
+    Key,
+    Source,
+    Script,
+    Subject,
+    From,
+    Reason,
+    Message,
+    Other,
+}
+
+/// An argument of a command or test, which can be inspected and, when
+/// passed to [`Sieve::rewrite`], replaced.
+pub struct Argument<'x> {
+    command: &'static str,
+    role: Role,
+    value: &'x mut Value,
+}
+
+/// Callbacks for every command, test and argument of a compiled script,
+/// in script order. Arguments are visited after the command or test they
+/// belong to.
+pub trait Visitor {
+    fn visit_command(&mut self, _command: &'static str) {}
+
+    fn visit_test(&mut self, _test: &'static str) {}
+
+    fn visit_argument(&mut self, _argument: &mut Argument<'_>) {}
+}
+
+impl Sieve {
+    pub fn visit(&self, visitor: &mut impl Visitor) {
+        self.clone().rewrite(visitor);
+    }
+
+    /// Visits the script, applying any argument changes made by `visitor`.
+    ///
+    /// ```rust
+    ///     use sieve::{compiler::visitor::{Argument, Role, Visitor}, Compiler};
+    ///
+    ///     struct Rename;
+    ///
+    ///     impl Visitor for Rename {
+    ///         fn visit_argument(&mut self, argument: &mut Argument<'_>) {
+    ///             if argument.role() == Role::Mailbox
+    ///                 && argument.as_constant().as_deref() == Some("Spam")
+    ///             {
+    ///                 argument.set_constant("Junk").unwrap();
+    ///             }
+    ///         }
+    ///     }
+    ///
+    ///     let mut script = Compiler::new()
+    ///         .compile(b"require \"fileinto\"; fileinto \"Spam\";")
+    ///         .unwrap();
+    ///     script.rewrite(&mut Rename);
+    ///     assert_eq!(script.analyze().mailboxes, ["Junk"]);
+    /// ```
+    pub fn rewrite(&mut self, visitor: &mut impl Visitor) {
+        let mut walker = Walker { visitor };
+        for instruction in &mut self.instructions {
+            walker.instruction(instruction);
+        }
+    }
+}
+
+impl Argument<'_> {
+    pub fn command(&self) -> &'static str {
+        self.command
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Returns the value of the argument, or `None` if it depends on
+    /// variables or other run time values.
+    pub fn as_constant(&self) -> Option<String> {
+        self.value.to_constant()
+    }
+
+    /// Replaces the argument with a constant. Match keys are recompiled
+    /// for the match type in use, which fails for invalid regular expressions.
+    pub fn set_constant(&mut self, value: impl Into<String>) -> Result<(), ErrorType> {
+        let value = value.into();
+        *self.value = match self.value {
+            Value::Regex(_) => match fancy_regex::Regex::new(&value) {
+                Ok(regex) => Value::Regex(Regex { regex, expr: value }),
+                Err(err) => return Err(ErrorType::InvalidRegex(format!("{value}: {err}"))),
+            },
+            Value::Glob(_) => Value::Glob(Glob::new(value)),
+            Value::Header(_) => match HeaderKey::new(value.as_str()) {
+                Some(key) => Value::Header(key),
+                None => Value::Text(Arc::new(value)),
+            },
+            _ => Value::Text(Arc::new(value)),
+        };
+        Ok(())
+    }
+}
+
+struct Walker<'x, V: Visitor> {
+    visitor: &'x mut V,
+}
+
+impl<V: Visitor> Walker<'_, V> {
+    fn instruction(&mut self, instruction: &mut Instruction) {
+        let command = match instruction {
+            Instruction::Keep(_) => "keep",
+            Instruction::FileInto(_) => "fileinto",
+            Instruction::Redirect(_) => "redirect",
+            Instruction::Discard => "discard",
+            Instruction::Stop => "stop",
+            Instruction::Test(test) => {
+                self.test(test);
+                return;
+            }
+            Instruction::ForEveryPart(_) => "foreverypart",
+            Instruction::Replace(_) => "replace",
+            Instruction::Enclose(_) => "enclose",
+            Instruction::ExtractText(_) => "extracttext",
+            Instruction::Convert(_) => "convert",
+            Instruction::AddHeader(_) => "addheader",
+            Instruction::DeleteHeader(_) => "deleteheader",
+            Instruction::Set(_) => "set",
+            Instruction::Clear(_) => "clear",
+            Instruction::Notify(_) => "notify",
+            Instruction::Reject(reject) if reject.ereject => "ereject",
+            Instruction::Reject(_) => "reject",
+            Instruction::Vacation(_) => "vacation",
+            Instruction::Error(_) => "error",
+            Instruction::EditFlags(_) => "flags",
+            Instruction::Include(_) => "include",
+            Instruction::Return => "return",
+            Instruction::While(_) => "while",
+            Instruction::Eval(_) => "eval",
+            Instruction::Let(_) => "let",
+            _ => return,
+        };
+        self.visitor.visit_command(command);
+
+        match instruction {
+            Instruction::Keep(keep) => self.values(command, Role::Flag, &mut keep.flags),
+            Instruction::FileInto(fi) => {
+                self.value(command, Role::Mailbox, &mut fi.folder);
+                self.values(command, Role::Flag, &mut fi.flags);
+                self.option(command, Role::MailboxId, &mut fi.mailbox_id);
+                self.option(command, Role::SpecialUse, &mut fi.special_use);
+            }
+            Instruction::Redirect(redirect) => {
+                self.value(command, Role::Address, &mut redirect.address)
+            }
+            Instruction::Replace(replace) => {
+                self.option(command, Role::Subject, &mut replace.subject);
+                self.option(command, Role::From, &mut replace.from);
+                self.value(command, Role::Message, &mut replace.replacement);
+            }
+            Instruction::Enclose(enclose) => {
+                self.option(command, Role::Subject, &mut enclose.subject);
+                self.values(command, Role::HeaderValue, &mut enclose.headers);
+                self.value(command, Role::Message, &mut enclose.value);
+            }
+            Instruction::Convert(convert) => {
+                self.value(command, Role::Other, &mut convert.from_media_type);
+                self.value(command, Role::Other, &mut convert.to_media_type);
+                self.values(command, Role::Other, &mut convert.transcoding_params);
+            }
+            Instruction::AddHeader(add) => {
+                self.value(command, Role::HeaderName, &mut add.field_name);
+                self.value(command, Role::HeaderValue, &mut add.value);
+            }
+            Instruction::DeleteHeader(delete) => {
+                self.value(command, Role::HeaderName, &mut delete.field_name);
+                self.values(command, Role::Key, &mut delete.value_patterns);
+            }
+            Instruction::Set(set) => self.value(command, Role::Source, &mut set.value),
+            Instruction::Notify(notify) => {
+                self.option(command, Role::From, &mut notify.from);
+                self.option(command, Role::Other, &mut notify.importance);
+                self.values(command, Role::Other, &mut notify.options);
+                self.option(command, Role::Message, &mut notify.message);
+                if let Some(fcc) = &mut notify.fcc {
+                    self.value(command, Role::Mailbox, &mut fcc.mailbox);
+                    self.option(command, Role::MailboxId, &mut fcc.mailbox_id);
+                    self.values(command, Role::Flag, &mut fcc.flags);
+                    self.option(command, Role::SpecialUse, &mut fcc.special_use);
+                }
+                self.value(command, Role::NotifyMethod, &mut notify.method);
+            }
+            Instruction::Reject(reject) => self.value(command, Role::Reason, &mut reject.reason),
+            Instruction::Vacation(vacation) => {
+                self.option(command, Role::Subject, &mut vacation.subject);
+                self.option(command, Role::From, &mut vacation.from);
+                if let Some(fcc) = &mut vacation.fcc {
+                    self.value(command, Role::Mailbox, &mut fcc.mailbox);
+                    self.option(command, Role::MailboxId, &mut fcc.mailbox_id);
+                    self.values(command, Role::Flag, &mut fcc.flags);
+                    self.option(command, Role::SpecialUse, &mut fcc.special_use);
+                }
+                self.value(command, Role::Reason, &mut vacation.reason);
+            }
+            Instruction::Error(error) => self.value(command, Role::Message, &mut error.message),
+            Instruction::EditFlags(flags) => self.values(command, Role::Flag, &mut flags.flags),
+            Instruction::Include(include) => self.value(command, Role::Script, &mut include.value),
+            _ => (),
+        }
+    }
+
+    fn test(&mut self, test: &mut Test) {
+        let name = match test {
+            Test::True => "true",
+            Test::False => "false",
+            Test::Address(_) => "address",
+            Test::Envelope(_) => "envelope",
+            Test::Exists(_) => "exists",
+            Test::Header(_) => "header",
+            Test::Size(_) => "size",
+            Test::Body(_) => "body",
+            Test::Convert(_) => "convert",
+            Test::Date(_) => "date",
+            Test::CurrentDate(_) => "currentdate",
+            Test::Duplicate(_) => "duplicate",
+            Test::String(_) => "string",
+            Test::Environment(_) => "environment",
+            Test::NotifyMethodCapability(_) => "notify_method_capability",
+            Test::ValidNotifyMethod(_) => "valid_notify_method",
+            Test::ValidExtList(_) => "valid_ext_list",
+            Test::Ihave(_) => "ihave",
+            Test::HasFlag(_) => "hasflag",
+            Test::MailboxExists(_) => "mailboxexists",
+            Test::Metadata(_) => "metadata",
+            Test::MetadataExists(_) => "metadataexists",
+            Test::MailboxIdExists(_) => "mailboxidexists",
+            Test::SpamTest(_) => "spamtest",
+            Test::VirusTest(_) => "virustest",
+            Test::SpecialUseExists(_) => "specialuse_exists",
+            Test::Vacation(_) => "vacation",
+            _ => return,
+        };
+        self.visitor.visit_test(name);
+
+        match test {
+            Test::Address(test) => {
+                self.values(name, Role::HeaderName, &mut test.header_list);
+                self.values(name, Role::Key, &mut test.key_list);
+            }
+            Test::Envelope(test) => self.values(name, Role::Key, &mut test.key_list),
+            Test::Exists(test) => self.values(name, Role::HeaderName, &mut test.header_names),
+            Test::Header(test) => {
+                self.values(name, Role::HeaderName, &mut test.header_list);
+                if let MimeOpts::Param(params) = &mut test.mime_opts {
+                    self.values(name, Role::Other, params);
+                }
+                self.values(name, Role::Key, &mut test.key_list);
+            }
+            Test::Body(test) => {
+                if let BodyTransform::Content(types) = &mut test.body_transform {
+                    self.values(name, Role::Other, types);
+                }
+                self.values(name, Role::Key, &mut test.key_list);
+            }
+            Test::Convert(test) => {
+                self.value(name, Role::Other, &mut test.from_media_type);
+                self.value(name, Role::Other, &mut test.to_media_type);
+                self.values(name, Role::Other, &mut test.transcoding_params);
+            }
+            Test::Date(test) => {
+                self.value(name, Role::HeaderName, &mut test.header_name);
+                self.values(name, Role::Key, &mut test.key_list);
+            }
+            Test::CurrentDate(test) => self.values(name, Role::Key, &mut test.key_list),
+            Test::Duplicate(test) => {
+                self.option(name, Role::Other, &mut test.handle);
+                match &mut test.dup_match {
+                    DupMatch::Header(header) => self.value(name, Role::HeaderName, header),
+                    DupMatch::UniqueId(id) => self.value(name, Role::Other, id),
+                    DupMatch::Default => (),
+                }
+            }
+            Test::String(test) | Test::Environment(test) => {
+                self.values(name, Role::Source, &mut test.source);
+                self.values(name, Role::Key, &mut test.key_list);
+            }
+            Test::NotifyMethodCapability(test) => {
+                self.value(name, Role::NotifyMethod, &mut test.notification_uri);
+                self.value(name, Role::Other, &mut test.notification_capability);
+                self.values(name, Role::Key, &mut test.key_list);
+            }
+            Test::ValidNotifyMethod(test) => {
+                self.values(name, Role::NotifyMethod, &mut test.notification_uris)
+            }
+            Test::ValidExtList(test) => self.values(name, Role::Other, &mut test.list_names),
+            Test::HasFlag(test) => self.values(name, Role::Flag, &mut test.flags),
+            Test::MailboxExists(test) => self.values(name, Role::Mailbox, &mut test.mailbox_names),
+            Test::Metadata(test) => {
+                match &mut test.medatata {
+                    Metadata::Server { annotation } => self.value(name, Role::Other, annotation),
+                    Metadata::Mailbox {
+                        name: mailbox,
+                        annotation,
+                    } => {
+                        self.value(name, Role::Mailbox, mailbox);
+                        self.value(name, Role::Other, annotation);
+                    }
+                }
+                self.values(name, Role::Key, &mut test.key_list);
+            }
+            Test::MetadataExists(test) => {
+                self.option(name, Role::Mailbox, &mut test.mailbox);
+                self.values(name, Role::Other, &mut test.annotation_names);
+            }
+            Test::MailboxIdExists(test) => {
+                self.values(name, Role::MailboxId, &mut test.mailbox_ids)
+            }
+            Test::SpamTest(test) => self.value(name, Role::Key, &mut test.value),
+            Test::VirusTest(test) => self.value(name, Role::Key, &mut test.value),
+            Test::SpecialUseExists(test) => {
+                self.option(name, Role::Mailbox, &mut test.mailbox);
+                self.values(name, Role::SpecialUse, &mut test.attributes);
+            }
+            Test::Vacation(test) => {
+                self.values(name, Role::Address, &mut test.addresses);
+                self.option(name, Role::Other, &mut test.handle);
+                self.value(name, Role::Reason, &mut test.reason);
+            }
+            _ => (),
+        }
+    }
+
+    fn value(&mut self, command: &'static str, role: Role, value: &mut Value) {
+        if let Value::Contains(contains) = value {
+            // Keys combined into a single matcher are visited one by one
+            let mut keys = contains.keys.clone();
+            for key in &mut keys {
+                let mut item = Value::Text(Arc::new(key.clone()));
+                self.visitor.visit_argument(&mut Argument {
+                    command,
+                    role,
+                    value: &mut item,
+                });
+                if let Some(item) = item.to_constant() {
+                    *key = item;
+                }
+            }
+            if keys != contains.keys {
+                if let Ok(keys) = ContainsKeys::new(keys) {
+                    *contains = keys;
+                }
+            }
+        } else {
+            self.visitor.visit_argument(&mut Argument {
+                command,
+                role,
+                value,
+            });
+        }
+    }
+
+    fn values(&mut self, command: &'static str, role: Role, values: &mut [Value]) {
+        for value in values {
+            self.value(command, role, value);
+        }
+    }
+
+    fn option(&mut self, command: &'static str, role: Role, value: &mut Option<Value>) {
+        if let Some(value) = value {
+            self.value(command, role, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    use super::{Argument, Role, Visitor};
+
+    #[derive(Default)]
+    struct Collect {
+        names: Vec<String>,
+        arguments: Vec<(&'static str, Role, Option<String>)>,
+    }
+
+    impl Visitor for Collect {
+        fn visit_command(&mut self, command: &'static str) {
+            self.names.push(command.to_string());
+        }
+
+        fn visit_test(&mut self, test: &'static str) {
+            self.names.push(format!("test:{test}"));
+        }
+
+        fn visit_argument(&mut self, argument: &mut Argument<'_>) {
+            self.arguments
+                .push((argument.command(), argument.role(), argument.as_constant()));
+            if argument.role() == Role::Mailbox {
+                let mailbox = argument.as_constant().unwrap_or_default();
+                argument.set_constant(format!("Archive/{mailbox}")).unwrap();
+            } else if argument.role() == Role::Key {
+                let key = argument.as_constant().unwrap_or_default();
+                argument.set_constant(key.to_uppercase()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn visit_and_rewrite() {
+        let mut script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "mailbox"];
+                if header :contains "Subject" ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k"] {
+                    fileinto :create "Lists";
+                } elsif header :matches "From" "*@example.org" {
+                    fileinto "Example";
+                }"#,
+            )
+            .unwrap();
+
+        let mut visitor = Collect::default();
+        script.visit(&mut visitor);
+        assert_eq!(
+            visitor.names,
+            ["test:header", "fileinto", "test:header", "fileinto"]
+        );
+        assert_eq!(visitor.arguments.len(), 16);
+        assert_eq!(
+            visitor.arguments[0],
+            ("header", Role::HeaderName, Some("Subject".to_string()))
+        );
+        assert_eq!(
+            visitor.arguments[12],
+            ("fileinto", Role::Mailbox, Some("Lists".to_string()))
+        );
+        assert_eq!(script.analyze().mailboxes, ["Lists", "Example"]);
+
+        script.rewrite(&mut Collect::default());
+        assert_eq!(
+            script.analyze().mailboxes,
+            ["Archive/Lists", "Archive/Example"]
+        );
+        let mut visitor = Collect::default();
+        script.visit(&mut visitor);
+        assert_eq!(
+            visitor.arguments[1],
+            ("header", Role::Key, Some("A".to_string()))
+        );
+        assert_eq!(
+            visitor.arguments[14],
+            ("header", Role::Key, Some("*@EXAMPLE.ORG".to_string()))
+        );
+    }
+}
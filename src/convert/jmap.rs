@@ -0,0 +1,631 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compiler::{
+        grammar::{
+            actions::{action_flags::Action, action_mime::MimeOpts},
+            instruction::Instruction,
+            test::Test,
+            tests::{test_body::BodyTransform, test_size::SizeMode},
+            AddressPart, Capability, Comparator, MatchType,
+        },
+        Value,
+    },
+    Sieve,
+};
+
+use super::{quote, quote_list, Conversion, ScriptWriter, Warning};
+
+/// A filter rule as edited by JMAP clients: a list of conditions joined
+/// with `operator` and the actions to run when they match.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FilterRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub operator: Operator,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<FilterAction>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Operator {
+    #[default]
+    AllOf,
+    AnyOf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    pub field: Field,
+    /// Header name, only used with `Field::Header`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+    pub comparator: MatchOperator,
+    #[serde(default)]
+    pub value: String,
+    #[serde(default)]
+    pub negate: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Field {
+    From,
+    To,
+    Cc,
+    /// Any of the To or Cc addresses.
+    Recipient,
+    Subject,
+    Header,
+    Body,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MatchOperator {
+    Is,
+    Contains,
+    Matches,
+    Regex,
+    Exists,
+    Over,
+    Under,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum FilterAction {
+    FileInto {
+        mailbox: String,
+    },
+    Redirect {
+        address: String,
+        #[serde(default)]
+        copy: bool,
+    },
+    Keep,
+    Discard,
+    Reject {
+        reason: String,
+    },
+    AddFlag {
+        flag: String,
+    },
+    Stop,
+}
+
+/// Generates a Sieve script from a list of filter rules. Conditions that
+/// cannot be expressed cause their whole rule to be skipped.
+pub fn to_sieve(rules: &[FilterRule]) -> Conversion<String> {
+    let mut writer = ScriptWriter::default();
+    let mut warnings = Vec::new();
+
+    for (position, rule) in rules.iter().enumerate() {
+        if let Err(message) = write_rule(&mut writer, rule) {
+            warnings.push(Warning { position, message });
+        }
+    }
+
+    Conversion {
+        output: writer.finish(),
+        warnings,
+    }
+}
+
+pub(crate) fn write_rule(writer: &mut ScriptWriter, rule: &FilterRule) -> Result<(), String> {
+    let tests = rule
+        .conditions
+        .iter()
+        .map(|condition| condition.to_sieve(writer))
+        .collect::<Result<Vec<_>, _>>()?;
+    if rule.actions.is_empty() {
+        return Err("Rule has no actions".to_string());
+    }
+
+    let actions = rule
+        .actions
+        .iter()
+        .map(|action| action.to_sieve(writer))
+        .collect::<Vec<_>>();
+    if let Some(name) = &rule.name {
+        writer.comment(name);
+    }
+    writer.rule(&tests, rule.operator == Operator::AnyOf, &actions);
+    Ok(())
+}
+
+/// Extracts the filter rules of a compiled script. Top-level commands
+/// that cannot be represented as a filter rule (`elsif`/`else` branches,
+/// nested tests, variables, extensions other than `fileinto`, `copy`,
+/// `reject`, `body`, `regex` and `imap4flags`) are reported as warnings.
+pub fn from_sieve(sieve: &Sieve) -> Conversion<Vec<FilterRule>> {
+    let mut rules = Vec::new();
+    let mut warnings = Vec::new();
+
+    for range in sieve.rule_ranges() {
+        let position = rules.len() + warnings.len();
+        if matches!(
+            &sieve.instructions[range.clone()],
+            [Instruction::Require(_)]
+        ) {
+            continue;
+        }
+
+        match parse_rule(sieve, range) {
+            Ok(rule) => rules.push(rule),
+            Err(message) => warnings.push(Warning { position, message }),
+        }
+    }
+
+    Conversion {
+        output: rules,
+        warnings,
+    }
+}
+
+// Converts the top-level rule spanning `range`
+pub(crate) fn parse_rule(sieve: &Sieve, range: Range<usize>) -> Result<FilterRule, String> {
+    let instructions = &sieve.instructions[range.clone()];
+    if matches!(
+        instructions.first(),
+        Some(Instruction::Test(_) | Instruction::Eval(_))
+    ) {
+        match sieve.if_jump(range.start, range.end) {
+            Some((jz_pos, target))
+                if target >= range.end
+                    && !matches!(
+                        sieve.instructions.get(range.end.wrapping_sub(1)),
+                        Some(Instruction::Jmp(_))
+                    ) =>
+            {
+                let (operator, conditions) =
+                    parse_conditions(&sieve.instructions[range.start..jz_pos])?;
+                Ok(FilterRule {
+                    name: None,
+                    operator,
+                    conditions,
+                    actions: parse_actions(&sieve.instructions[jz_pos + 1..range.end])?,
+                })
+            }
+            _ => Err("Rules with elsif or else branches are not supported".to_string()),
+        }
+    } else {
+        parse_actions(instructions).map(|actions| FilterRule {
+            name: None,
+            operator: Operator::AllOf,
+            conditions: Vec::new(),
+            actions,
+        })
+    }
+}
+
+impl Condition {
+    fn to_sieve(&self, writer: &mut ScriptWriter) -> Result<String, String> {
+        let headers = match self.field {
+            Field::From => quote("From"),
+            Field::To => quote("To"),
+            Field::Cc => quote("Cc"),
+            Field::Recipient => quote_list(&["To", "Cc"]),
+            Field::Subject => quote("Subject"),
+            Field::Header => match self.header.as_deref() {
+                Some(header) if !header.is_empty() => quote(header),
+                _ => return Err("Header condition without a header name".to_string()),
+            },
+            Field::Body | Field::Size => String::new(),
+        };
+
+        let match_type = match self.comparator {
+            MatchOperator::Is => ":is",
+            MatchOperator::Contains => ":contains",
+            MatchOperator::Matches => ":matches",
+            MatchOperator::Regex => {
+                writer.require(Capability::Regex);
+                ":regex"
+            }
+            MatchOperator::Exists | MatchOperator::Over | MatchOperator::Under => "",
+        };
+
+        let test = match (self.field, self.comparator) {
+            (Field::Size, MatchOperator::Over | MatchOperator::Under) => {
+                let limit = self
+                    .value
+                    .parse::<u64>()
+                    .map_err(|_| format!("Invalid size {:?}", self.value))?;
+                format!(
+                    "size {} {limit}",
+                    if self.comparator == MatchOperator::Over {
+                        ":over"
+                    } else {
+                        ":under"
+                    }
+                )
+            }
+            (Field::Size, _) | (_, MatchOperator::Over | MatchOperator::Under) => {
+                return Err("Size conditions require the over or under comparators".to_string());
+            }
+            (Field::Body, MatchOperator::Exists) => {
+                return Err("Body conditions do not support exists".to_string());
+            }
+            (Field::Body, _) => {
+                writer.require(Capability::Body);
+                format!("body {match_type} {}", quote(&self.value))
+            }
+            (_, MatchOperator::Exists) => format!("exists {headers}"),
+            (Field::From | Field::To | Field::Cc | Field::Recipient, _) => {
+                format!("address {match_type} {headers} {}", quote(&self.value))
+            }
+            (Field::Subject | Field::Header, _) => {
+                format!("header {match_type} {headers} {}", quote(&self.value))
+            }
+        };
+
+        Ok(if self.negate {
+            format!("not {test}")
+        } else {
+            test
+        })
+    }
+}
+
+impl FilterAction {
+    fn to_sieve(&self, writer: &mut ScriptWriter) -> String {
+        match self {
+            FilterAction::FileInto { mailbox } => {
+                writer.require(Capability::FileInto);
+                format!("fileinto {}", quote(mailbox))
+            }
+            FilterAction::Redirect { address, copy } => {
+                if *copy {
+                    writer.require(Capability::Copy);
+                    format!("redirect :copy {}", quote(address))
+                } else {
+                    format!("redirect {}", quote(address))
+                }
+            }
+            FilterAction::Keep => "keep".to_string(),
+            FilterAction::Discard => "discard".to_string(),
+            FilterAction::Reject { reason } => {
+                writer.require(Capability::Reject);
+                format!("reject {}", quote(reason))
+            }
+            FilterAction::AddFlag { flag } => {
+                writer.require(Capability::Imap4Flags);
+                format!("addflag {}", quote(flag))
+            }
+            FilterAction::Stop => "stop".to_string(),
+        }
+    }
+}
+
+fn parse_conditions(instructions: &[Instruction]) -> Result<(Operator, Vec<Condition>), String> {
+    let mut conditions = Vec::new();
+    let mut has_jz = false;
+    let mut has_jnz = false;
+
+    for instruction in instructions {
+        match instruction {
+            Instruction::Test(test) => conditions.push(parse_test(test)?),
+            Instruction::Jz(_) => has_jz = true,
+            Instruction::Jnz(_) => has_jnz = true,
+            _ => return Err("Expressions are not supported".to_string()),
+        }
+    }
+
+    match (has_jz, has_jnz) {
+        (true, true) => Err("Nested anyof and allof tests are not supported".to_string()),
+        (_, true) => Ok((Operator::AnyOf, conditions)),
+        _ => Ok((Operator::AllOf, conditions)),
+    }
+}
+
+fn parse_test(test: &Test) -> Result<Condition, String> {
+    let (field, header, comparator, value, is_not) = match test {
+        Test::Header(test)
+            if test.index.is_none() && test.mime_opts == MimeOpts::None && !test.mime_anychild =>
+        {
+            let header = single_constant(&test.header_list, "header name")?;
+            let (field, header) = if header.eq_ignore_ascii_case("subject") {
+                (Field::Subject, None)
+            } else {
+                (Field::Header, Some(header))
+            };
+            (
+                field,
+                header,
+                match_operator(&test.match_type, &test.comparator)?,
+                single_constant(&test.key_list, "value")?,
+                test.is_not,
+            )
+        }
+        Test::Address(test)
+            if test.index.is_none()
+                && !test.mime_anychild
+                && test.address_part == AddressPart::All =>
+        {
+            (
+                address_field(&test.header_list)?,
+                None,
+                match_operator(&test.match_type, &test.comparator)?,
+                single_constant(&test.key_list, "value")?,
+                test.is_not,
+            )
+        }
+        Test::Exists(test) if !test.mime_anychild => {
+            let (field, header) = match address_field(&test.header_names) {
+                Ok(field) => (field, None),
+                Err(_) => {
+                    let header = single_constant(&test.header_names, "header name")?;
+                    if header.eq_ignore_ascii_case("subject") {
+                        (Field::Subject, None)
+                    } else {
+                        (Field::Header, Some(header))
+                    }
+                }
+            };
+            (
+                field,
+                header,
+                MatchOperator::Exists,
+                String::new(),
+                test.is_not,
+            )
+        }
+        Test::Size(test) if test.mode == SizeMode::Raw => (
+            Field::Size,
+            None,
+            if test.over {
+                MatchOperator::Over
+            } else {
+                MatchOperator::Under
+            },
+            test.limit.to_string(),
+            test.is_not,
+        ),
+        Test::Body(test) if test.body_transform == BodyTransform::Text && !test.include_subject => {
+            (
+                Field::Body,
+                None,
+                match_operator(&test.match_type, &test.comparator)?,
+                single_constant(&test.key_list, "value")?,
+                test.is_not,
+            )
+        }
+        _ => return Err("Unsupported test".to_string()),
+    };
+
+    Ok(Condition {
+        field,
+        header,
+        comparator,
+        value,
+        negate: is_not,
+    })
+}
+
+fn parse_actions(instructions: &[Instruction]) -> Result<Vec<FilterAction>, String> {
+    let mut actions = Vec::new();
+
+    for instruction in instructions {
+        match instruction {
+            Instruction::Require(_) => (),
+            Instruction::Keep(keep) if keep.flags.is_empty() => actions.push(FilterAction::Keep),
+            Instruction::FileInto(fileinto)
+                if !fileinto.copy
+                    && !fileinto.create
+                    && fileinto.flags.is_empty()
+                    && fileinto.mailbox_id.is_none()
+                    && fileinto.special_use.is_none() =>
+            {
+                actions.push(FilterAction::FileInto {
+                    mailbox: constant(&fileinto.folder, "mailbox")?,
+                });
+            }
+            Instruction::Redirect(redirect) if !redirect.list => {
+                actions.push(FilterAction::Redirect {
+                    address: constant(&redirect.address, "address")?,
+                    copy: redirect.copy,
+                });
+            }
+            Instruction::Discard => actions.push(FilterAction::Discard),
+            Instruction::Reject(reject) if !reject.ereject => {
+                actions.push(FilterAction::Reject {
+                    reason: constant(&reject.reason, "reason")?,
+                });
+            }
+            Instruction::EditFlags(edit_flags)
+                if edit_flags.action == Action::Add && edit_flags.name.is_none() =>
+            {
+                for flag in &edit_flags.flags {
+                    actions.push(FilterAction::AddFlag {
+                        flag: constant(flag, "flag")?,
+                    });
+                }
+            }
+            Instruction::Stop => actions.push(FilterAction::Stop),
+            _ => return Err("Unsupported action".to_string()),
+        }
+    }
+
+    if !actions.is_empty() {
+        Ok(actions)
+    } else {
+        Err("Rule has no actions".to_string())
+    }
+}
+
+fn address_field(headers: &[Value]) -> Result<Field, String> {
+    let mut headers = headers
+        .iter()
+        .map(|header| constant(header, "header name").map(|h| h.to_ascii_lowercase()))
+        .collect::<Result<Vec<_>, _>>()?;
+    headers.sort_unstable();
+
+    match headers
+        .iter()
+        .map(|header| header.as_str())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["from"] => Ok(Field::From),
+        ["to"] => Ok(Field::To),
+        ["cc"] => Ok(Field::Cc),
+        ["cc", "to"] => Ok(Field::Recipient),
+        _ => Err("Unsupported address headers".to_string()),
+    }
+}
+
+fn match_operator(
+    match_type: &MatchType,
+    comparator: &Comparator,
+) -> Result<MatchOperator, String> {
+    if comparator != &Comparator::AsciiCaseMap {
+        return Err("Only the default comparator is supported".to_string());
+    }
+    match match_type {
+        MatchType::Is => Ok(MatchOperator::Is),
+        MatchType::Contains => Ok(MatchOperator::Contains),
+        MatchType::Matches(_) => Ok(MatchOperator::Matches),
+        MatchType::Regex(_) => Ok(MatchOperator::Regex),
+        _ => Err("Unsupported match type".to_string()),
+    }
+}
+
+fn single_constant(values: &[Value], name: &str) -> Result<String, String> {
+    match values {
+        [value] => constant(value, name),
+        _ => Err(format!("Only a single {name} is supported")),
+    }
+}
+
+fn constant(value: &Value, name: &str) -> Result<String, String> {
+    value
+        .to_constant()
+        .ok_or_else(|| format!("Variable {name} is not supported"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    use super::{from_sieve, to_sieve, FilterRule};
+
+    #[test]
+    fn jmap_round_trip() {
+        let rules: Vec<FilterRule> = serde_json::from_str(
+            r#"[
+                {
+                    "name": "Work mail",
+                    "conditions": [
+                        {"field": "from", "comparator": "contains", "value": "@example.com"},
+                        {"field": "subject", "comparator": "is", "value": "report", "negate": true}
+                    ],
+                    "actions": [
+                        {"type": "fileInto", "mailbox": "Work \"2023\""},
+                        {"type": "stop"}
+                    ]
+                },
+                {
+                    "operator": "anyOf",
+                    "conditions": [
+                        {"field": "header", "header": "X-Spam", "comparator": "exists"},
+                        {"field": "size", "comparator": "over", "value": "1000000"},
+                        {"field": "body", "comparator": "regex", "value": "viagra|casino"}
+                    ],
+                    "actions": [{"type": "addFlag", "flag": "\\Junk"}, {"type": "discard"}]
+                },
+                {
+                    "conditions": [{"field": "header", "comparator": "is", "value": "x"}],
+                    "actions": [{"type": "keep"}]
+                },
+                {
+                    "actions": [{"type": "redirect", "address": "backup@example.org", "copy": true}]
+                }
+            ]"#,
+        )
+        .unwrap();
+
+        let script = to_sieve(&rules);
+        assert_eq!(script.warnings.len(), 1);
+        assert_eq!(script.warnings[0].position, 2);
+        assert_eq!(
+            script.output,
+            concat!(
+                "require [\"fileinto\", \"regex\", \"body\", \"imap4flags\", \"copy\"];\n\n",
+                "# Work mail\n",
+                "if allof (address :contains \"From\" \"@example.com\", ",
+                "not header :is \"Subject\" \"report\") {\n",
+                "    fileinto \"Work \\\"2023\\\"\";\n",
+                "    stop;\n",
+                "}\n",
+                "if anyof (exists \"X-Spam\", size :over 1000000, ",
+                "body :regex \"viagra|casino\") {\n",
+                "    addflag \"\\\\Junk\";\n",
+                "    discard;\n",
+                "}\n",
+                "redirect :copy \"backup@example.org\";\n",
+            )
+        );
+
+        let compiled = Compiler::new().compile(script.output.as_bytes()).unwrap();
+        let converted = from_sieve(&compiled);
+        assert_eq!(converted.warnings, vec![]);
+
+        let mut expected = rules;
+        expected.remove(2);
+        expected[0].name = None;
+        assert_eq!(converted.output, expected);
+
+        // Unsupported constructs are reported
+        let compiled = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "variables"];
+if header :is "Subject" "a" { keep; } else { discard; }
+set "mailbox" "Inbox";
+if address :domain "From" "example.org" { fileinto "${mailbox}"; }
+discard;
+"#,
+            )
+            .unwrap();
+        let converted = from_sieve(&compiled);
+        assert_eq!(converted.output.len(), 1);
+        assert_eq!(
+            converted
+                .warnings
+                .iter()
+                .map(|w| w.position)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+    }
+}
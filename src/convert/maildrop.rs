@@ -0,0 +1,718 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::compiler::grammar::Capability;
+
+use super::{deliver, parse_header_regex, quote, quote_list, Conversion, ScriptWriter, Warning};
+
+// Assignments that only affect how maildrop itself runs
+const IGNORED_VARIABLES: &[&str] = &[
+    "DEFAULT",
+    "HOME",
+    "LOCKEXT",
+    "LOCKREFRESH",
+    "LOCKSLEEP",
+    "LOCKTIMEOUT",
+    "LOGNAME",
+    "MAILDIRQUOTA",
+    "PATH",
+    "SENDMAIL",
+    "SHELL",
+    "UMASK",
+    "VERBOSE",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    String(String),
+    Regex { pattern: String, flags: String },
+    Symbol(&'static str),
+    Newline,
+}
+
+#[derive(Debug)]
+enum Statement {
+    If {
+        line_num: usize,
+        branches: Vec<(Option<Vec<Token>>, Vec<Statement>)>,
+    },
+    Assign {
+        line_num: usize,
+        name: String,
+        value: String,
+    },
+    Action {
+        line_num: usize,
+        command: String,
+        argument: String,
+    },
+    Unsupported {
+        line_num: usize,
+        command: String,
+    },
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+/// Converts a maildrop filter file to a Sieve script. Header and body
+/// patterns, `$SIZE` comparisons, `hasaddr`, `to`, `cc`, `exit` and headers
+/// added or replaced with `xfilter "reformail ..."` are supported; other
+/// statements are skipped with a warning.
+pub fn to_sieve(mailfilter: &str) -> Conversion<String> {
+    let mut parser = Parser {
+        tokens: tokenize(mailfilter),
+        pos: 0,
+    };
+    let statements = parser.statements(false);
+    let mut converter = Converter {
+        writer: ScriptWriter::default(),
+        warnings: Vec::new(),
+        maildir: None,
+    };
+
+    for statement in statements {
+        converter.statement(statement);
+    }
+
+    Conversion {
+        output: converter.writer.finish(),
+        warnings: converter.warnings,
+    }
+}
+
+struct Converter {
+    writer: ScriptWriter,
+    warnings: Vec<Warning>,
+    maildir: Option<String>,
+}
+
+impl Converter {
+    fn statement(&mut self, statement: Statement) {
+        match statement {
+            Statement::If { line_num, branches } => {
+                let mut rules = Vec::with_capacity(branches.len());
+                for (condition, statements) in branches {
+                    let (tests, any_of) = match condition {
+                        Some(condition) => match self.condition(&condition) {
+                            Ok(condition) => condition,
+                            Err(message) => {
+                                self.warn(line_num, message);
+                                return;
+                            }
+                        },
+                        None => (Vec::new(), false),
+                    };
+                    let mut actions = Vec::new();
+                    for statement in statements {
+                        match statement {
+                            Statement::Action {
+                                line_num,
+                                command,
+                                argument,
+                            } => match self.action(&command, &argument) {
+                                Ok(result) => actions.extend(result),
+                                Err(message) => self.warn(line_num, message),
+                            },
+                            Statement::If { line_num, .. } | Statement::Assign { line_num, .. } => {
+                                self.warn(
+                                    line_num,
+                                    "Nested statements are not supported".to_string(),
+                                );
+                            }
+                            Statement::Unsupported { line_num, command } => {
+                                self.warn(line_num, format!("{command:?} is not supported"));
+                            }
+                        }
+                    }
+                    rules.push((tests, any_of, actions));
+                }
+
+                for (pos, (tests, any_of, actions)) in rules.iter().enumerate() {
+                    if pos == 0 {
+                        self.writer.rule(tests, *any_of, actions);
+                    } else {
+                        self.writer.branch(tests, *any_of, actions);
+                    }
+                }
+            }
+            Statement::Assign {
+                line_num,
+                name,
+                value,
+            } => {
+                if name == "MAILDIR" {
+                    self.maildir = Some(value);
+                } else if !IGNORED_VARIABLES.contains(&name.as_str()) {
+                    self.warn(line_num, format!("Variable {name} is not supported"));
+                }
+            }
+            Statement::Action {
+                line_num,
+                command,
+                argument,
+            } => match self.action(&command, &argument) {
+                Ok(actions) => self.writer.rule(&[], false, &actions),
+                Err(message) => self.warn(line_num, message),
+            },
+            Statement::Unsupported { line_num, command } => {
+                self.warn(line_num, format!("{command:?} is not supported"));
+            }
+        }
+    }
+
+    fn condition(&mut self, tokens: &[Token]) -> Result<(Vec<String>, bool), String> {
+        let is_all_of = tokens.contains(&Token::Symbol("&&"));
+        let any_of = tokens.contains(&Token::Symbol("||"));
+        if is_all_of && any_of {
+            return Err("Mixing && and || is not supported".to_string());
+        }
+
+        let mut tests = Vec::new();
+        for term in tokens.split(|token| matches!(token, Token::Symbol("&&" | "||"))) {
+            let (is_not, term) = match term {
+                [Token::Symbol("!"), term @ ..] => (true, term),
+                term => (false, term),
+            };
+            let test = match term {
+                [Token::Regex { pattern, flags }] => self.pattern(pattern, flags)?,
+                [Token::Word(var), Token::Symbol(op), Token::Word(size)] if var == "$SIZE" => {
+                    let size: u64 = size.parse().map_err(|_| format!("Invalid size {size:?}"))?;
+                    match *op {
+                        "<" => format!("size :under {size}"),
+                        "<=" => format!("size :under {}", size + 1),
+                        ">" => format!("size :over {size}"),
+                        ">=" => format!("size :over {}", size.saturating_sub(1)),
+                        _ => return Err(format!("Size comparison {op:?} is not supported")),
+                    }
+                }
+                [Token::Word(function), Token::Symbol("("), Token::String(address), Token::Symbol(")")]
+                    if function == "hasaddr" =>
+                {
+                    format!("address :is [\"To\", \"Cc\"] {}", quote(address))
+                }
+                _ => return Err("Unsupported condition".to_string()),
+            };
+            tests.push(if is_not { format!("not {test}") } else { test });
+        }
+
+        Ok((tests, any_of))
+    }
+
+    fn pattern(&mut self, pattern: &str, flags: &str) -> Result<String, String> {
+        let mut is_body = false;
+        let mut is_header = false;
+        let mut is_case_sensitive = false;
+        for flag in flags.chars() {
+            match flag {
+                'b' => is_body = true,
+                'h' => is_header = true,
+                'D' => is_case_sensitive = true,
+                'w' | 'W' => (),
+                _ => return Err(format!("Pattern flag {flag:?} is not supported")),
+            }
+        }
+        let comparator = if is_case_sensitive {
+            ":comparator \"i;octet\" "
+        } else {
+            ""
+        };
+
+        if is_body && is_header {
+            Err("Matching both headers and body is not supported".to_string())
+        } else if is_body {
+            self.writer.require(Capability::Body);
+            self.writer.require(Capability::Regex);
+            Ok(format!("body {comparator}:regex {}", quote(pattern)))
+        } else {
+            let (headers, value) = parse_header_regex(pattern)?;
+            if value.is_empty() || value == ".*" {
+                Ok(format!("exists {}", quote_list(&headers)))
+            } else {
+                self.writer.require(Capability::Regex);
+                Ok(format!(
+                    "header {comparator}:regex {} {}",
+                    quote_list(&headers),
+                    quote(value)
+                ))
+            }
+        }
+    }
+
+    fn action(&mut self, command: &str, argument: &str) -> Result<Vec<String>, String> {
+        match command {
+            "to" | "cc" => deliver(
+                &mut self.writer,
+                argument,
+                command == "cc",
+                self.maildir.as_deref(),
+            ),
+            "xfilter" => self.reformail(argument),
+            "exit" => Ok(vec!["stop".to_string()]),
+            _ => Err(format!("{command:?} is not supported")),
+        }
+    }
+
+    // Header edits done by piping the message through reformail
+    fn reformail(&mut self, command: &str) -> Result<Vec<String>, String> {
+        let args = shell_words(command);
+        if args.first().map_or(true, |arg| arg != "reformail") {
+            return Err("Filtering through external programs is not supported".to_string());
+        }
+
+        let mut actions = Vec::new();
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            let (option, header) = match arg.get(..2) {
+                Some(option @ ("-A" | "-I")) if arg.len() > 2 => (option, arg[2..].to_string()),
+                Some(option @ ("-A" | "-I")) => (
+                    option,
+                    args.next()
+                        .ok_or_else(|| format!("Missing header for reformail {option}"))?
+                        .to_string(),
+                ),
+                _ => return Err(format!("reformail option {arg:?} is not supported")),
+            };
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid header {header:?}"))?;
+            if value.contains('$') {
+                return Err(format!("Header {name:?} uses variables"));
+            }
+
+            self.writer.require(Capability::EditHeader);
+            if option == "-I" {
+                actions.push(format!("deleteheader {}", quote(name)));
+            }
+            actions.push(format!(
+                "addheader {} {}",
+                quote(name),
+                quote(value.trim_start())
+            ));
+        }
+
+        Ok(actions)
+    }
+
+    fn warn(&mut self, line_num: usize, message: String) {
+        self.warnings.push(Warning {
+            position: line_num,
+            message,
+        });
+    }
+}
+
+impl Parser {
+    fn statements(&mut self, is_block: bool) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                None => break,
+                Some(Token::Symbol("}")) => {
+                    self.pos += 1;
+                    if is_block {
+                        break;
+                    }
+                }
+                Some(_) => statements.push(self.statement()),
+            }
+        }
+        statements
+    }
+
+    fn statement(&mut self) -> Statement {
+        let (line_num, token) = self.tokens[self.pos].clone();
+        self.pos += 1;
+
+        match token {
+            Token::Word(word) if word == "if" => {
+                let mut branches = Vec::new();
+                branches.push((self.condition(), self.body()));
+                loop {
+                    let pos = self.pos;
+                    while self.peek() == Some(&Token::Newline) {
+                        self.pos += 1;
+                    }
+                    match self.peek() {
+                        Some(Token::Word(word)) if word == "else" => {
+                            self.pos += 1;
+                            if matches!(self.peek(), Some(Token::Word(word)) if word == "if") {
+                                self.pos += 1;
+                                branches.push((self.condition(), self.body()));
+                            } else {
+                                branches.push((None, self.body()));
+                                break;
+                            }
+                        }
+                        Some(Token::Word(word)) if word == "elsif" => {
+                            self.pos += 1;
+                            branches.push((self.condition(), self.body()));
+                        }
+                        _ => {
+                            self.pos = pos;
+                            break;
+                        }
+                    }
+                }
+                Statement::If { line_num, branches }
+            }
+            Token::Word(name) if self.peek() == Some(&Token::Symbol("=")) => {
+                self.pos += 1;
+                let value = match self.peek() {
+                    Some(Token::Word(value) | Token::String(value)) => {
+                        let value = value.clone();
+                        self.pos += 1;
+                        value
+                    }
+                    _ => String::new(),
+                };
+                self.skip_statement();
+                Statement::Assign {
+                    line_num,
+                    name,
+                    value,
+                }
+            }
+            Token::Word(command) if ["to", "cc", "xfilter", "exit"].contains(&command.as_str()) => {
+                let argument = match self.peek() {
+                    Some(Token::Word(argument) | Token::String(argument)) => {
+                        let argument = argument.clone();
+                        self.pos += 1;
+                        argument
+                    }
+                    _ => String::new(),
+                };
+                self.skip_statement();
+                Statement::Action {
+                    line_num,
+                    command,
+                    argument,
+                }
+            }
+            token => {
+                self.skip_statement();
+                Statement::Unsupported {
+                    line_num,
+                    command: match token {
+                        Token::Word(word) | Token::String(word) => word,
+                        Token::Regex { pattern, .. } => pattern,
+                        Token::Symbol(symbol) => symbol.to_string(),
+                        Token::Newline => String::new(),
+                    },
+                }
+            }
+        }
+    }
+
+    // Tokens between the parentheses of a condition
+    fn condition(&mut self) -> Option<Vec<Token>> {
+        if self.peek() != Some(&Token::Symbol("(")) {
+            return None;
+        }
+        self.pos += 1;
+
+        let mut tokens = Vec::new();
+        let mut depth = 1;
+        while let Some((_, token)) = self.tokens.get(self.pos) {
+            self.pos += 1;
+            match token {
+                Token::Symbol("(") => depth += 1,
+                Token::Symbol(")") => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                Token::Newline => continue,
+                _ => (),
+            }
+            tokens.push(token.clone());
+        }
+        Some(tokens)
+    }
+
+    fn body(&mut self) -> Vec<Statement> {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+        match self.peek() {
+            Some(Token::Symbol("{")) => {
+                self.pos += 1;
+                self.statements(true)
+            }
+            Some(_) => vec![self.statement()],
+            None => Vec::new(),
+        }
+    }
+
+    // Skips the rest of a statement, including any blocks it opens
+    fn skip_statement(&mut self) {
+        let mut depth = 0;
+        while let Some((_, token)) = self.tokens.get(self.pos) {
+            match token {
+                Token::Symbol("{") => depth += 1,
+                Token::Symbol("}") if depth == 0 => break,
+                Token::Symbol("}") => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        break;
+                    }
+                }
+                Token::Newline | Token::Symbol(";") if depth == 0 => break,
+                _ => (),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn skip_separators(&mut self) {
+        while matches!(self.peek(), Some(Token::Newline | Token::Symbol(";"))) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+}
+
+fn tokenize(text: &str) -> Vec<(usize, Token)> {
+    let mut tokens: Vec<(usize, Token)> = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line_num = 1;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\n' => {
+                tokens.push((line_num, Token::Newline));
+                line_num += 1;
+            }
+            '\\' if chars.peek() == Some(&'\n') => {
+                chars.next();
+                line_num += 1;
+            }
+            '#' => while chars.next_if(|&ch| ch != '\n').is_some() {},
+            '"' | '\'' => {
+                let mut value = String::new();
+                while let Some(next_ch) = chars.next() {
+                    match next_ch {
+                        '\\' if ch == '"' => {
+                            if let Some(next_ch) = chars.next() {
+                                value.push(next_ch);
+                            }
+                        }
+                        _ if next_ch == ch => break,
+                        '\n' => {
+                            line_num += 1;
+                            value.push(next_ch);
+                        }
+                        _ => value.push(next_ch),
+                    }
+                }
+                tokens.push((line_num, Token::String(value)));
+            }
+            // Patterns can only appear within conditions
+            '/' if matches!(
+                tokens.last(),
+                Some((_, Token::Symbol("(" | "!" | "&&" | "||")))
+            ) =>
+            {
+                let mut pattern = String::new();
+                while let Some(next_ch) = chars.next() {
+                    match next_ch {
+                        '\\' if chars.peek() == Some(&'/') => {
+                            pattern.push(chars.next().unwrap());
+                        }
+                        '/' | '\n' => break,
+                        _ => pattern.push(next_ch),
+                    }
+                }
+                let mut flags = String::new();
+                if chars.next_if_eq(&':').is_some() {
+                    while let Some(flag) = chars.next_if(|ch| ch.is_ascii_alphabetic()) {
+                        flags.push(flag);
+                    }
+                }
+                tokens.push((line_num, Token::Regex { pattern, flags }));
+            }
+            '&' | '|' if chars.next_if_eq(&ch).is_some() => {
+                tokens.push((line_num, Token::Symbol(if ch == '&' { "&&" } else { "||" })));
+            }
+            '<' | '>' | '=' | '!' if chars.next_if_eq(&'=').is_some() => {
+                tokens.push((
+                    line_num,
+                    Token::Symbol(match ch {
+                        '<' => "<=",
+                        '>' => ">=",
+                        '=' => "==",
+                        _ => "!=",
+                    }),
+                ));
+            }
+            '(' | ')' | '{' | '}' | ';' | ',' | '<' | '>' | '=' | '!' => {
+                tokens.push((
+                    line_num,
+                    Token::Symbol(match ch {
+                        '(' => "(",
+                        ')' => ")",
+                        '{' => "{",
+                        '}' => "}",
+                        ';' => ";",
+                        ',' => ",",
+                        '<' => "<",
+                        '>' => ">",
+                        '=' => "=",
+                        _ => "!",
+                    }),
+                ));
+            }
+            _ if ch.is_whitespace() => (),
+            _ => {
+                let mut word = ch.to_string();
+                while let Some(next_ch) =
+                    chars.next_if(|ch| !ch.is_whitespace() && !"(){};,<>=!&|\"'#".contains(*ch))
+                {
+                    word.push(next_ch);
+                }
+                tokens.push((line_num, Token::Word(word)));
+            }
+        }
+    }
+
+    tokens
+}
+
+// Splits a command line into its arguments, honoring quotes
+fn shell_words(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote_ch = None;
+    let mut in_word = false;
+
+    for ch in command.chars() {
+        match (quote_ch, ch) {
+            (None, '"' | '\'') => {
+                quote_ch = Some(ch);
+                in_word = true;
+            }
+            (Some(q), _) if q == ch => quote_ch = None,
+            (None, _) if ch.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            _ => {
+                word.push(ch);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    use super::to_sieve;
+
+    #[test]
+    fn maildrop_import() {
+        let mailfilter = r#"MAILDIR="$HOME/Maildir"
+logfile "$HOME/maildrop.log"
+
+# Mailing lists
+if (/^List-Id:.*rust-users/)
+{
+    to "$MAILDIR/.Lists.rust/"
+}
+
+if (/^From:.*boss@example\.com/ || /^Subject: *urgent/:D)
+    cc "!assistant@example.org"
+
+if (/free money/:b && $SIZE < 100000)
+{
+    xfilter "reformail -A 'X-Spam-Flag: YES' -I'X-Spam-Level: high'"
+    to "$MAILDIR/.Spam/"
+}
+else
+{
+    xfilter "spamc"
+}
+
+if (hasaddr("sales@example.com"))
+{
+    exit
+}
+"#;
+
+        let conversion = to_sieve(mailfilter);
+        assert_eq!(
+            conversion.output,
+            concat!(
+                "require [\"regex\", \"fileinto\", \"copy\", \"body\", \"editheader\"];\n\n",
+                "if header :regex \"List-Id\" \".*rust-users\" {\n",
+                "    fileinto \"Lists.rust\";\n",
+                "    stop;\n",
+                "}\n",
+                "if anyof (header :regex \"From\" \".*boss@example\\\\.com\", ",
+                "header :comparator \"i;octet\" :regex \"Subject\" \"urgent\") {\n",
+                "    redirect :copy \"assistant@example.org\";\n",
+                "}\n",
+                "if allof (body :regex \"free money\", size :under 100000) {\n",
+                "    addheader \"X-Spam-Flag\" \"YES\";\n",
+                "    deleteheader \"X-Spam-Level\";\n",
+                "    addheader \"X-Spam-Level\" \"high\";\n",
+                "    fileinto \"Spam\";\n",
+                "    stop;\n",
+                "} else {\n",
+                "}\n",
+                "if address :is [\"To\", \"Cc\"] \"sales@example.com\" {\n",
+                "    stop;\n",
+                "}\n",
+            )
+        );
+        assert_eq!(
+            conversion
+                .warnings
+                .iter()
+                .map(|warning| warning.position)
+                .collect::<Vec<_>>(),
+            [2, 20]
+        );
+
+        Compiler::new()
+            .compile(conversion.output.as_bytes())
+            .unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+pub mod jmap;
+pub mod maildrop;
+pub mod procmail;
+pub mod rules;
+pub mod thunderbird;
+
+use crate::compiler::grammar::Capability;
+
+/// Output of a conversion between Sieve and another filter format, along
+/// with the constructs that could not be converted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conversion<T> {
+    pub output: T,
+    pub warnings: Vec<Warning>,
+}
+
+/// A construct that was skipped or approximated. `position` is the index
+/// of the rule, or the line number for text based formats.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub position: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ScriptWriter {
+    capabilities: Vec<Capability>,
+    script: String,
+}
+
+impl ScriptWriter {
+    pub(crate) fn require(&mut self, capability: Capability) {
+        if !self.capabilities.contains(&capability) {
+            self.capabilities.push(capability);
+        }
+    }
+
+    pub(crate) fn raw(&mut self, text: &str) {
+        self.script.push_str(text);
+        if !text.ends_with('\n') {
+            self.script.push('\n');
+        }
+    }
+
+    pub(crate) fn comment(&mut self, text: &str) {
+        for line in text.lines() {
+            self.script.push_str("# ");
+            self.script.push_str(line);
+            self.script.push('\n');
+        }
+    }
+
+    // Writes a rule made of tests joined with allof or anyof
+    pub(crate) fn rule(&mut self, tests: &[String], any_of: bool, actions: &[String]) {
+        if let Some(condition) = condition(tests, any_of) {
+            self.script.push_str(&format!("if {condition} {{\n"));
+            self.block(actions);
+        } else {
+            for action in actions {
+                self.script.push_str(action);
+                self.script.push_str(";\n");
+            }
+        }
+    }
+
+    // Continues the last rule with an elsif or, without tests, an else
+    pub(crate) fn branch(&mut self, tests: &[String], any_of: bool, actions: &[String]) {
+        debug_assert!(self.script.ends_with("}\n"));
+        self.script.pop();
+        if let Some(condition) = condition(tests, any_of) {
+            self.script.push_str(&format!(" elsif {condition} {{\n"));
+        } else {
+            self.script.push_str(" else {\n");
+        }
+        self.block(actions);
+    }
+
+    fn block(&mut self, actions: &[String]) {
+        for action in actions {
+            self.script.push_str("    ");
+            self.script.push_str(action);
+            self.script.push_str(";\n");
+        }
+        self.script.push_str("}\n");
+    }
+
+    pub(crate) fn finish(self) -> String {
+        if !self.capabilities.is_empty() {
+            let capabilities = self
+                .capabilities
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect::<Vec<_>>();
+            format!("require {};\n\n{}", quote_list(&capabilities), self.script)
+        } else {
+            self.script
+        }
+    }
+}
+
+fn condition(tests: &[String], any_of: bool) -> Option<String> {
+    match tests {
+        [] => None,
+        [test] => Some(test.to_string()),
+        tests => Some(format!(
+            "{} ({})",
+            if any_of { "anyof" } else { "allof" },
+            tests.join(", ")
+        )),
+    }
+}
+
+pub(crate) fn quote(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for ch in value.chars() {
+        if matches!(ch, '"' | '\\') {
+            result.push('\\');
+        }
+        result.push(ch);
+    }
+    result.push('"');
+    result
+}
+
+pub(crate) fn quote_list(values: &[impl AsRef<str>]) -> String {
+    match values {
+        [value] => quote(value.as_ref()),
+        values => format!(
+            "[{}]",
+            values
+                .iter()
+                .map(|value| quote(value.as_ref()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+// Converts a delivery to a folder, "/dev/null" or "!" forwarding
+// addresses, as found in procmail and maildrop filters
+pub(crate) fn deliver(
+    writer: &mut ScriptWriter,
+    target: &str,
+    is_copy: bool,
+    maildir: Option<&str>,
+) -> Result<Vec<String>, String> {
+    let mut actions = Vec::new();
+    if let Some(addresses) = target.strip_prefix('!') {
+        if is_copy {
+            writer.require(Capability::Copy);
+        }
+        for address in addresses.split_whitespace() {
+            actions.push(format!(
+                "redirect {}{}",
+                if is_copy { ":copy " } else { "" },
+                quote(address)
+            ));
+        }
+        if actions.is_empty() {
+            return Err("Forward without addresses".to_string());
+        }
+    } else if target.starts_with('|') {
+        return Err("Piping to programs is not supported".to_string());
+    } else if target == "/dev/null" {
+        if is_copy {
+            return Err("Copies to /dev/null have no effect".to_string());
+        }
+        actions.push("discard".to_string());
+    } else {
+        let mailbox = parse_folder(target, maildir)?;
+        writer.require(Capability::FileInto);
+        if is_copy {
+            writer.require(Capability::Copy);
+            actions.push(format!("fileinto :copy {}", quote(&mailbox)));
+        } else {
+            actions.push(format!("fileinto {}", quote(&mailbox)));
+        }
+    }
+
+    // Deliveries end processing unless they are copies
+    if !is_copy {
+        actions.push("stop".to_string());
+    }
+
+    Ok(actions)
+}
+
+// Splits a "^Name:value" header line regex into its header names and
+// value regex
+pub(crate) fn parse_header_regex(condition: &str) -> Result<(Vec<&str>, &str), String> {
+    let (names, mut value) = condition
+        .strip_prefix('^')
+        .and_then(|condition| condition.split_once(':'))
+        .ok_or_else(|| "Conditions must match a header".to_string())?;
+    let names = match names.strip_prefix('(').and_then(|n| n.strip_suffix(')')) {
+        Some(names) => names.split('|').collect::<Vec<_>>(),
+        None => vec![names],
+    };
+    if names.iter().any(|name| {
+        name.is_empty()
+            || !name
+                .bytes()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == b'-' || ch == b'_')
+    }) {
+        return Err(format!("Unsupported header expression {condition:?}"));
+    }
+
+    // Header values are matched without their leading whitespace
+    loop {
+        let trimmed = value.trim_start_matches(' ');
+        value = ["*", "[ ]*", "[ \t]*", "\\s*"]
+            .iter()
+            .find_map(|prefix| trimmed.strip_prefix(prefix))
+            .unwrap_or(trimmed);
+        if value == trimmed {
+            break;
+        }
+    }
+
+    Ok((names, value))
+}
+
+fn parse_folder(path: &str, maildir: Option<&str>) -> Result<String, String> {
+    let path = path.trim_matches('"');
+    let path = path
+        .strip_prefix("$MAILDIR/")
+        .or_else(|| path.strip_prefix("${MAILDIR}/"))
+        .or_else(|| maildir.and_then(|maildir| path.strip_prefix(maildir)?.strip_prefix('/')))
+        .unwrap_or(path);
+
+    if path.contains('$') {
+        return Err(format!("Folder {path:?} uses variables"));
+    } else if path.starts_with('/') {
+        return Err(format!("Folder {path:?} is outside MAILDIR"));
+    }
+
+    // Maildir folders end with '/', MH folders with '/.' and Maildir++
+    // folders start with '.'
+    let mailbox = path
+        .trim_end_matches("/.")
+        .trim_end_matches('/')
+        .trim_start_matches('.');
+    if !mailbox.is_empty() {
+        Ok(mailbox.to_string())
+    } else {
+        Err("Missing folder name".to_string())
+    }
+}
@@ -0,0 +1,339 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::compiler::grammar::Capability;
+
+use super::{deliver, parse_header_regex, quote, quote_list, Conversion, ScriptWriter, Warning};
+
+const TO_HEADERS: &[&str] = &["To", "Cc", "Bcc", "Resent-To", "Resent-Cc", "Resent-Bcc"];
+
+// Assignments that only affect how procmail itself runs
+const IGNORED_VARIABLES: &[&str] = &[
+    "DEFAULT",
+    "HOME",
+    "LOCKFILE",
+    "LOCKSLEEP",
+    "LOCKTIMEOUT",
+    "LOGABSTRACT",
+    "LOGFILE",
+    "LOGNAME",
+    "PATH",
+    "SHELL",
+    "SHELLFLAGS",
+    "UMASK",
+    "VERBOSE",
+];
+
+struct Recipe<'x> {
+    line_num: usize,
+    flags: &'x str,
+    conditions: Vec<&'x str>,
+    action: &'x str,
+}
+
+/// Converts the recipes of a `.procmailrc` file to a Sieve script.
+/// Header and body regular expressions, size conditions, deliveries to
+/// folders and `/dev/null`, forwards and the `c`, `B` and `D` flags are
+/// supported; recipes using anything else are skipped with a warning.
+pub fn to_sieve(procmailrc: &str) -> Conversion<String> {
+    let mut writer = ScriptWriter::default();
+    let mut warnings = Vec::new();
+    let mut maildir = None;
+    let lines = logical_lines(procmailrc);
+    let mut lines = lines.iter().peekable();
+
+    while let Some(&(line_num, ref line)) = lines.next() {
+        if let Some(flags) = line.strip_prefix(":0") {
+            let mut recipe = Recipe {
+                line_num,
+                flags: flags.split(':').next().unwrap_or_default().trim(),
+                conditions: Vec::new(),
+                action: "",
+            };
+            while let Some((_, condition)) = lines.next_if(|(_, line)| line.starts_with('*')) {
+                recipe.conditions.push(condition[1..].trim());
+            }
+            if let Some((_, action)) = lines.next() {
+                recipe.action = action;
+            }
+
+            if recipe.action.starts_with('{') {
+                let mut depth = braces(recipe.action);
+                while depth > 0 {
+                    match lines.next() {
+                        Some((_, line)) if line.starts_with('{') || line.starts_with('}') => {
+                            depth += braces(line);
+                        }
+                        Some(_) => (),
+                        None => break,
+                    }
+                }
+                warnings.push(Warning {
+                    position: line_num,
+                    message: "Nested blocks are not supported".to_string(),
+                });
+                continue;
+            }
+
+            match recipe.to_sieve(&mut writer, maildir.as_deref()) {
+                Ok((tests, actions)) => writer.rule(&tests, false, &actions),
+                Err(message) => warnings.push(Warning {
+                    position: line_num,
+                    message,
+                }),
+            }
+        } else if let Some((name, value)) = line.split_once('=').filter(|(name, _)| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == b'_')
+        }) {
+            if name == "MAILDIR" {
+                maildir = Some(value.trim_matches('"').to_string());
+            } else if !IGNORED_VARIABLES.contains(&name) {
+                warnings.push(Warning {
+                    position: line_num,
+                    message: format!("Variable {name} is not supported"),
+                });
+            }
+        } else {
+            warnings.push(Warning {
+                position: line_num,
+                message: "Unrecognized line".to_string(),
+            });
+        }
+    }
+
+    Conversion {
+        output: writer.finish(),
+        warnings,
+    }
+}
+
+impl<'x> Recipe<'x> {
+    fn to_sieve(
+        &self,
+        writer: &mut ScriptWriter,
+        maildir: Option<&str>,
+    ) -> Result<(Vec<String>, Vec<String>), String> {
+        let mut is_copy = false;
+        let mut is_body = false;
+        let mut is_header = false;
+        let mut is_case_sensitive = false;
+        for flag in self.flags.chars() {
+            match flag {
+                'c' => is_copy = true,
+                'B' => is_body = true,
+                'H' => is_header = true,
+                'D' => is_case_sensitive = true,
+                'w' | 'W' | 'h' | 'b' | 'i' | 'r' => (),
+                _ => return Err(format!("Flag {flag:?} is not supported")),
+            }
+        }
+        if is_body && is_header {
+            return Err("Matching both headers and body is not supported".to_string());
+        }
+        let comparator = if is_case_sensitive {
+            ":comparator \"i;octet\" "
+        } else {
+            ""
+        };
+
+        let mut tests = Vec::with_capacity(self.conditions.len());
+        for condition in &self.conditions {
+            let (is_not, condition) = match condition.strip_prefix('!') {
+                Some(condition) => (true, condition.trim_start()),
+                None => (false, *condition),
+            };
+
+            let test = if let Some(size) = condition.strip_prefix('<') {
+                format!("size :under {}", parse_size(size)?)
+            } else if let Some(size) = condition.strip_prefix('>') {
+                format!("size :over {}", parse_size(size)?)
+            } else if condition.starts_with(['?', '$'])
+                || condition.starts_with(|ch: char| ch.is_ascii_digit() || ch == '-')
+            {
+                return Err(format!("Condition {condition:?} is not supported"));
+            } else if condition.contains("\\/") {
+                return Err("Match extraction is not supported".to_string());
+            } else if is_body {
+                writer.require(Capability::Body);
+                writer.require(Capability::Regex);
+                format!("body {comparator}:regex {}", quote(condition))
+            } else {
+                let (headers, value) = parse_header_condition(condition)?;
+                if value.is_empty() || value == ".*" {
+                    format!("exists {}", quote_list(&headers))
+                } else {
+                    writer.require(Capability::Regex);
+                    format!(
+                        "header {comparator}:regex {} {}",
+                        quote_list(&headers),
+                        quote(value)
+                    )
+                }
+            };
+
+            tests.push(if is_not { format!("not {test}") } else { test });
+        }
+
+        let actions = deliver(writer, self.action, is_copy, maildir)?;
+
+        Ok((tests, actions))
+    }
+}
+
+// Splits "^Name:value" into its header names and value regex
+fn parse_header_condition(condition: &str) -> Result<(Vec<&str>, &str), String> {
+    if condition.starts_with("^FROM_") {
+        return Err(format!("Macro {condition:?} is not supported"));
+    } else if let Some(value) = condition
+        .strip_prefix("^TO_")
+        .or_else(|| condition.strip_prefix("^TO"))
+    {
+        return Ok((TO_HEADERS.to_vec(), value));
+    }
+
+    parse_header_regex(condition)
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    size.trim()
+        .parse()
+        .map_err(|_| format!("Invalid size {size:?}"))
+}
+
+fn braces(line: &str) -> i32 {
+    line.chars().fold(0, |depth, ch| match ch {
+        '{' => depth + 1,
+        '}' => depth - 1,
+        _ => depth,
+    })
+}
+
+// Trimmed lines joined on trailing backslashes, without comments and
+// blank lines, along with their line numbers
+fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut current: Option<(usize, String)> = None;
+
+    for (line_num, line) in text.lines().enumerate() {
+        let line_num = line_num + 1;
+        let (text, continues) = match line.strip_suffix('\\') {
+            Some(text) => (text, true),
+            None => (line, false),
+        };
+        let (start, mut joined) = current.take().unwrap_or_else(|| (line_num, String::new()));
+        joined.push_str(if joined.is_empty() {
+            text.trim()
+        } else {
+            text.trim_end()
+        });
+        if continues {
+            current = Some((start, joined));
+        } else if !joined.is_empty() && !joined.starts_with('#') {
+            lines.push((start, joined));
+        }
+    }
+    if let Some((start, joined)) = current {
+        if !joined.is_empty() && !joined.starts_with('#') {
+            lines.push((start, joined));
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    use super::to_sieve;
+
+    #[test]
+    fn procmail_import() {
+        let procmailrc = r#"MAILDIR=$HOME/Mail
+LOGFILE=$MAILDIR/procmail.log
+
+# Mailing lists
+:0:
+* ^List-Id:.*rust-users
+.Lists.rust/
+
+:0 c
+* ^From:.*boss@example\.com
+! assistant@example.org
+
+:0
+* ^Subject: *\[SPAM\]
+* < 100000
+/dev/null
+
+:0 B
+* free money
+spam
+
+:0
+* ^TO_sales@example\.com
+$MAILDIR/sales/
+
+:0 Wh
+| /usr/bin/vacation \
+    -a me
+"#;
+
+        let conversion = to_sieve(procmailrc);
+        assert_eq!(
+            conversion.output,
+            concat!(
+                "require [\"regex\", \"fileinto\", \"copy\", \"body\"];\n\n",
+                "if header :regex \"List-Id\" \".*rust-users\" {\n",
+                "    fileinto \"Lists.rust\";\n",
+                "    stop;\n",
+                "}\n",
+                "if header :regex \"From\" \".*boss@example\\\\.com\" {\n",
+                "    redirect :copy \"assistant@example.org\";\n",
+                "}\n",
+                "if allof (header :regex \"Subject\" \"\\\\[SPAM\\\\]\", size :under 100000) {\n",
+                "    discard;\n",
+                "    stop;\n",
+                "}\n",
+                "if body :regex \"free money\" {\n",
+                "    fileinto \"spam\";\n",
+                "    stop;\n",
+                "}\n",
+                "if header :regex [\"To\", \"Cc\", \"Bcc\", \"Resent-To\", \"Resent-Cc\", ",
+                "\"Resent-Bcc\"] \"sales@example\\\\.com\" {\n",
+                "    fileinto \"sales\";\n",
+                "    stop;\n",
+                "}\n",
+            )
+        );
+        assert_eq!(conversion.warnings.len(), 1);
+        assert_eq!(conversion.warnings[0].position, 26);
+
+        Compiler::new()
+            .compile(conversion.output.as_bytes())
+            .unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! A representation of a script as an ordered list of blocks, meant for
+//! graphical filter editors. Serialized as JSON it looks like:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "capabilities": ["variables"],
+//!   "blocks": [
+//!     {
+//!       "type": "rule",
+//!       "name": "Work mail",
+//!       "operator": "allOf",
+//!       "conditions": [{"field": "from", "comparator": "contains", "value": "@example.com"}],
+//!       "actions": [{"type": "fileInto", "mailbox": "Work"}, {"type": "stop"}]
+//!     },
+//!     {
+//!       "type": "advanced",
+//!       "source": "set \"folder\" \"Lists\";"
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Rules use the same conditions and actions as the JMAP filter rules in
+//! [`super::jmap`]. Any top-level command the rule model cannot express is
+//! kept verbatim as an `advanced` block, so that editors can display it as
+//! read-only text and write it back unchanged. `capabilities` lists the
+//! extensions required by these blocks.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compiler::{
+        grammar::{instruction::Instruction, Capability},
+        CompileError,
+    },
+    Compiler, Sieve,
+};
+
+use super::{
+    jmap::{parse_rule, write_rule, FilterRule},
+    Conversion, ScriptWriter, Warning,
+};
+
+pub const RULE_MODEL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleModel {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Block {
+    Rule(FilterRule),
+    Advanced { source: String },
+}
+
+/// Compiles a script and splits it into rules and advanced blocks.
+pub fn to_rule_model(compiler: &Compiler, script: &[u8]) -> Result<RuleModel, CompileError> {
+    let sieve = compiler.compile(script)?;
+    let source = Source::new(script);
+    let mut blocks = Vec::new();
+
+    let ranges = sieve.rule_ranges();
+    for (pos, range) in ranges.iter().enumerate() {
+        if matches!(
+            &sieve.instructions[range.clone()],
+            [Instruction::Require(_)]
+        ) {
+            continue;
+        }
+
+        let start = source.offset(&sieve, range.start);
+        if let Ok(mut rule) = parse_rule(&sieve, range.clone()) {
+            rule.name = source.comment_before(start);
+            blocks.push(Block::Rule(rule));
+        } else {
+            let end = ranges
+                .get(pos + 1)
+                .map_or(script.len(), |next| source.offset(&sieve, next.start));
+            blocks.push(Block::Advanced {
+                source: source.text(start, end),
+            });
+        }
+    }
+
+    Ok(RuleModel {
+        version: RULE_MODEL_VERSION,
+        capabilities: if blocks
+            .iter()
+            .any(|block| matches!(block, Block::Advanced { .. }))
+        {
+            sieve
+                .required_capabilities()
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect()
+        } else {
+            Vec::new()
+        },
+        blocks,
+    })
+}
+
+/// Generates a script from a rule model. Rules that cannot be expressed
+/// are skipped and reported as warnings, advanced blocks are written as is.
+pub fn from_rule_model(model: &RuleModel) -> Conversion<String> {
+    let mut writer = ScriptWriter::default();
+    let mut warnings = Vec::new();
+
+    if model
+        .blocks
+        .iter()
+        .any(|block| matches!(block, Block::Advanced { .. }))
+    {
+        for capability in &model.capabilities {
+            writer.require(Capability::parse(capability));
+        }
+    }
+
+    for (position, block) in model.blocks.iter().enumerate() {
+        match block {
+            Block::Rule(rule) => {
+                if let Err(message) = write_rule(&mut writer, rule) {
+                    warnings.push(Warning { position, message });
+                }
+            }
+            Block::Advanced { source } => writer.raw(source),
+        }
+    }
+
+    Conversion {
+        output: writer.finish(),
+        warnings,
+    }
+}
+
+struct Source<'x> {
+    text: &'x [u8],
+    lines: Vec<usize>,
+}
+
+impl<'x> Source<'x> {
+    fn new(text: &'x [u8]) -> Self {
+        let mut lines = vec![0];
+        lines.extend(
+            text.iter()
+                .enumerate()
+                .filter(|(_, ch)| **ch == b'\n')
+                .map(|(pos, _)| pos + 1),
+        );
+        Source { text, lines }
+    }
+
+    // Byte offset of the command that produced the instruction at `pos`,
+    // spans hold one-based lines and columns
+    fn offset(&self, sieve: &Sieve, pos: usize) -> usize {
+        sieve
+            .spans
+            .get(pos)
+            .and_then(|span| {
+                self.lines
+                    .get(span.line_num.checked_sub(1)?)
+                    .map(|line| line + span.line_pos.saturating_sub(1))
+            })
+            .unwrap_or(0)
+            .min(self.text.len())
+    }
+
+    // Text between two commands, without the comments preceding the next one
+    fn text(&self, start: usize, end: usize) -> String {
+        let mut text = String::from_utf8_lossy(&self.text[start..end]).into_owned();
+        loop {
+            let trimmed = text.trim_end();
+            match trimmed.rsplit_once('\n') {
+                Some((before, last)) if last.trim_start().starts_with('#') => {
+                    text = before.to_string();
+                }
+                _ => {
+                    text.truncate(trimmed.len());
+                    return text;
+                }
+            }
+        }
+    }
+
+    // The text of a line comment placed right above the command at `offset`
+    fn comment_before(&self, offset: usize) -> Option<String> {
+        let line = self.lines.iter().rposition(|&start| start <= offset)?;
+        let start = *self.lines.get(line.checked_sub(1)?)?;
+        let comment = std::str::from_utf8(&self.text[start..self.lines[line]])
+            .ok()?
+            .trim()
+            .strip_prefix('#')?
+            .trim();
+        (!comment.is_empty()).then(|| comment.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    use super::{from_rule_model, to_rule_model, Block, RuleModel};
+
+    #[test]
+    fn rule_model_round_trip() {
+        let script = r#"require ["fileinto", "variables"];
+
+# Work mail
+if address :contains "From" "@example.com" {
+    fileinto "Work";
+    stop;
+}
+
+# Not representable
+set "folder" "Lists";
+if header :contains "List-Id" "rust" { fileinto "${folder}"; } else { keep; }
+discard;
+"#;
+        let compiler = Compiler::new();
+        let model = to_rule_model(&compiler, script.as_bytes()).unwrap();
+        assert_eq!(model.capabilities, ["fileinto", "variables"]);
+        assert_eq!(
+            model
+                .blocks
+                .iter()
+                .map(|block| match block {
+                    Block::Rule(rule) => format!("rule {:?}", rule.name),
+                    Block::Advanced { source } => format!("advanced {source}"),
+                })
+                .collect::<Vec<_>>(),
+            [
+                "rule Some(\"Work mail\")".to_string(),
+                "advanced set \"folder\" \"Lists\";".to_string(),
+                concat!(
+                    "advanced if header :contains \"List-Id\" \"rust\" ",
+                    "{ fileinto \"${folder}\"; } else { keep; }"
+                )
+                .to_string(),
+                "rule None".to_string(),
+            ]
+        );
+
+        let json = serde_json::to_string(&model).unwrap();
+        let model: RuleModel = serde_json::from_str(&json).unwrap();
+        let script = from_rule_model(&model);
+        assert_eq!(script.warnings, vec![]);
+        assert_eq!(
+            to_rule_model(&compiler, script.output.as_bytes()).unwrap(),
+            model
+        );
+    }
+}
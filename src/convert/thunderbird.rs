@@ -0,0 +1,474 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::compiler::grammar::Capability;
+
+use super::{quote, quote_list, Conversion, ScriptWriter, Warning};
+
+// Filter types that run on incoming mail, before and after junk
+// classification
+const TYPE_INCOMING: u32 = 0x01 | 0x20;
+
+#[derive(Debug, Default)]
+struct Filter {
+    line_num: usize,
+    name: String,
+    is_enabled: bool,
+    filter_type: Option<u32>,
+    condition: String,
+    actions: Vec<(usize, String, String)>,
+}
+
+/// Converts the filters of a Thunderbird `msgFilterRules.dat` file to a
+/// Sieve script. Header, address, body, size and tag conditions are
+/// supported along with moving, copying, forwarding, tagging and marking
+/// messages; disabled filters, filters that do not run on incoming mail
+/// and unsupported actions are reported as warnings.
+pub fn to_sieve(rules: &str) -> Conversion<String> {
+    let mut writer = ScriptWriter::default();
+    let mut warnings = Vec::new();
+
+    for filter in parse_filters(rules) {
+        if !filter.is_enabled {
+            warnings.push(Warning {
+                position: filter.line_num,
+                message: format!("Filter {:?} is disabled", filter.name),
+            });
+            continue;
+        } else if filter
+            .filter_type
+            .map_or(false, |filter_type| filter_type & TYPE_INCOMING == 0)
+        {
+            warnings.push(Warning {
+                position: filter.line_num,
+                message: format!("Filter {:?} does not run on incoming mail", filter.name),
+            });
+            continue;
+        }
+
+        let (tests, any_of) =
+            match parse_condition(&filter.condition).and_then(|(conditions, any_of)| {
+                conditions
+                    .iter()
+                    .map(|(attribute, operator, value)| {
+                        condition(&mut writer, attribute, operator, value)
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|tests| (tests, any_of))
+            }) {
+                Ok(result) => result,
+                Err(message) => {
+                    warnings.push(Warning {
+                        position: filter.line_num,
+                        message,
+                    });
+                    continue;
+                }
+            };
+
+        let mut actions = Vec::with_capacity(filter.actions.len());
+        for (line_num, action_type, value) in &filter.actions {
+            match action(&mut writer, action_type, value) {
+                Ok(action) => actions.push(action),
+                Err(message) => warnings.push(Warning {
+                    position: *line_num,
+                    message,
+                }),
+            }
+        }
+
+        if !actions.is_empty() {
+            if !filter.name.is_empty() {
+                writer.comment(&filter.name);
+            }
+            writer.rule(&tests, any_of, &actions);
+        }
+    }
+
+    Conversion {
+        output: writer.finish(),
+        warnings,
+    }
+}
+
+fn condition(
+    writer: &mut ScriptWriter,
+    attribute: &str,
+    operator: &str,
+    value: &str,
+) -> Result<String, String> {
+    let (is_not, match_type, value) = match operator {
+        "contains" => (false, ":contains", value.to_string()),
+        "doesn't contain" => (true, ":contains", value.to_string()),
+        "is" => (false, ":is", value.to_string()),
+        "isn't" => (true, ":is", value.to_string()),
+        "begins with" => (false, ":matches", format!("{}*", escape_glob(value))),
+        "ends with" => (false, ":matches", format!("*{}", escape_glob(value))),
+        "is empty" => (false, ":is", String::new()),
+        "isn't empty" => (true, ":is", String::new()),
+        "is greater than" | "is less than" if attribute == "size" => {
+            let size = value
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("Invalid size {value:?}"))?;
+            return Ok(format!(
+                "size {} {size}K",
+                if operator == "is greater than" {
+                    ":over"
+                } else {
+                    ":under"
+                }
+            ));
+        }
+        _ => return Err(format!("Operator {operator:?} is not supported")),
+    };
+
+    let test = match attribute {
+        "subject" => format!("header {match_type} \"Subject\" {}", quote(&value)),
+        "from" | "to" | "cc" | "to or cc" | "all addresses" => {
+            let headers: &[&str] = match attribute {
+                "from" => &["From"],
+                "to" => &["To"],
+                "cc" => &["Cc"],
+                "to or cc" => &["To", "Cc"],
+                _ => &["From", "To", "Cc", "Bcc"],
+            };
+            format!(
+                "address {match_type} {} {}",
+                quote_list(headers),
+                quote(&value)
+            )
+        }
+        "body" => {
+            writer.require(Capability::Body);
+            format!("body {match_type} {}", quote(&value))
+        }
+        "tag" => {
+            writer.require(Capability::Imap4Flags);
+            format!("hasflag {match_type} {}", quote(&value))
+        }
+        // Custom headers are the only quoted attributes
+        _ if attribute.starts_with('"') => {
+            let header = attribute.trim_matches('"');
+            if header.is_empty() {
+                return Err("Missing header name".to_string());
+            }
+            format!("header {match_type} {} {}", quote(header), quote(&value))
+        }
+        _ => return Err(format!("Attribute {attribute:?} is not supported")),
+    };
+
+    Ok(if is_not { format!("not {test}") } else { test })
+}
+
+fn action(writer: &mut ScriptWriter, action_type: &str, value: &str) -> Result<String, String> {
+    let flag = match action_type {
+        "Move to folder" | "Copy to folder" => {
+            let mailbox = parse_folder_uri(value)?;
+            writer.require(Capability::FileInto);
+            return Ok(if action_type == "Copy to folder" {
+                writer.require(Capability::Copy);
+                format!("fileinto :copy {}", quote(&mailbox))
+            } else {
+                format!("fileinto {}", quote(&mailbox))
+            });
+        }
+        "Delete" => {
+            writer.require(Capability::FileInto);
+            return Ok("fileinto \"Trash\"".to_string());
+        }
+        "Forward" => {
+            if value.is_empty() {
+                return Err("Forward without an address".to_string());
+            }
+            writer.require(Capability::Copy);
+            return Ok(format!("redirect :copy {}", quote(value)));
+        }
+        "Stop execution" => return Ok("stop".to_string()),
+        "Mark unread" => {
+            writer.require(Capability::Imap4Flags);
+            return Ok("removeflag \"\\\\Seen\"".to_string());
+        }
+        "Mark read" => "\\Seen".to_string(),
+        "Mark flagged" => "\\Flagged".to_string(),
+        "AddTag" if !value.is_empty() => value.to_string(),
+        "Label" if !value.is_empty() => format!("$label{value}"),
+        "JunkScore" if value == "0" => "NonJunk".to_string(),
+        "JunkScore" => "Junk".to_string(),
+        _ => return Err(format!("Action {action_type:?} is not supported")),
+    };
+
+    writer.require(Capability::Imap4Flags);
+    Ok(format!("addflag {}", quote(&flag)))
+}
+
+fn parse_filters(rules: &str) -> Vec<Filter> {
+    let mut filters: Vec<Filter> = Vec::new();
+
+    for (line_num, line) in rules.lines().enumerate() {
+        let line_num = line_num + 1;
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let value = unescape(value.trim());
+
+        if key == "name" {
+            filters.push(Filter {
+                line_num,
+                name: value,
+                is_enabled: true,
+                ..Default::default()
+            });
+        } else if let Some(filter) = filters.last_mut() {
+            match key {
+                "enabled" => filter.is_enabled = value == "yes",
+                "type" => filter.filter_type = value.parse().ok(),
+                "condition" => filter.condition = value,
+                "action" => filter.actions.push((line_num, value, String::new())),
+                "actionValue" => {
+                    if let Some((_, _, action_value)) = filter.actions.last_mut() {
+                        *action_value = value;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    filters
+}
+
+// Parses "AND (attribute,operator,value) AND ..." into its conditions and
+// whether they are joined with OR
+fn parse_condition(text: &str) -> Result<(Vec<(String, String, String)>, bool), String> {
+    let text = text.trim();
+    if text == "ALL" {
+        return Ok((Vec::new(), false));
+    }
+
+    let mut conditions = Vec::new();
+    let mut operators = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|ch| ch.is_whitespace()).is_some() {}
+        let mut operator = String::new();
+        while let Some(ch) = chars.next_if(|&ch| ch != '(' && !ch.is_whitespace()) {
+            operator.push(ch);
+        }
+        if operator.is_empty() {
+            break;
+        } else if !matches!(operator.as_str(), "AND" | "OR") {
+            return Err(format!("Invalid condition operator {operator:?}"));
+        }
+        while chars.next_if(|ch| ch.is_whitespace()).is_some() {}
+        if chars.next() != Some('(') {
+            return Err("Invalid condition".to_string());
+        }
+
+        let mut fields = Vec::with_capacity(3);
+        let mut field = String::new();
+        let mut in_quotes = false;
+        loop {
+            match chars.next() {
+                Some('\\') if in_quotes => {
+                    if let Some(ch) = chars.next() {
+                        field.push(ch);
+                    }
+                }
+                Some('"') => {
+                    in_quotes = !in_quotes;
+                    // Quotes are kept on attributes to tell custom headers apart
+                    if fields.is_empty() {
+                        field.push('"');
+                    }
+                }
+                Some(',') if !in_quotes && fields.len() < 2 => {
+                    fields.push(std::mem::take(&mut field));
+                }
+                Some(')') if !in_quotes => {
+                    fields.push(std::mem::take(&mut field));
+                    break;
+                }
+                Some(ch) => field.push(ch),
+                None => return Err("Unterminated condition".to_string()),
+            }
+        }
+        if fields.len() != 3 {
+            return Err("Invalid condition".to_string());
+        }
+
+        let value = fields.pop().unwrap_or_default();
+        let attribute_operator = fields.pop().unwrap_or_default();
+        let attribute = fields.pop().unwrap_or_default();
+        conditions.push((attribute, attribute_operator, value));
+        operators.push(operator);
+    }
+
+    if conditions.is_empty() {
+        Err("Filter has no conditions".to_string())
+    } else if operators.iter().any(|operator| operator != &operators[0]) {
+        Err("Mixing AND and OR conditions is not supported".to_string())
+    } else {
+        Ok((conditions, operators[0] == "OR"))
+    }
+}
+
+// Extracts the mailbox name of a folder URI such as "imap://user@host/INBOX/Lists"
+fn parse_folder_uri(uri: &str) -> Result<String, String> {
+    let path = uri
+        .split_once("://")
+        .and_then(|(_, rest)| rest.split_once('/'))
+        .map(|(_, path)| path.trim_end_matches('/'))
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| format!("Invalid folder {uri:?}"))?;
+
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut iter = path.bytes();
+    while let Some(ch) = iter.next() {
+        if ch == b'%' {
+            let hex = [iter.next().unwrap_or(0), iter.next().unwrap_or(0)];
+            match std::str::from_utf8(&hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(ch) => bytes.push(ch),
+                None => return Err(format!("Invalid folder {uri:?}")),
+            }
+        } else {
+            bytes.push(ch);
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn unescape(value: &str) -> String {
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(ch) = chars.next() {
+        if ch == '\\' {
+            if let Some(ch) = chars.next() {
+                result.push(ch);
+            }
+        } else {
+            result.push(ch);
+        }
+    }
+    result
+}
+
+fn escape_glob(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for ch in value.chars() {
+        if matches!(ch, '*' | '?' | '\\') {
+            result.push('\\');
+        }
+        result.push(ch);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Compiler;
+
+    use super::to_sieve;
+
+    #[test]
+    fn thunderbird_import() {
+        let rules = r#"version="9"
+logging="no"
+name="Mailing lists"
+enabled="yes"
+type="17"
+action="Move to folder"
+actionValue="imap://john%40example.com@imap.example.com/Lists/Rust%20Users"
+action="AddTag"
+actionValue="$label4"
+condition="OR (subject,begins with,[rust]) OR (\"List-Id\",contains,\"rust-users, announce\")"
+name="Boss"
+enabled="yes"
+type="1"
+action="Mark flagged"
+action="Forward"
+actionValue="assistant@example.org"
+action="Reply"
+actionValue="mailbox://nobody@Local%20Folders/Templates"
+condition="AND (from,is,boss@example.com) AND (size,is greater than,10)"
+name="Old"
+enabled="no"
+type="17"
+action="Delete"
+condition="ALL"
+name="Newsletters"
+enabled="yes"
+type="17"
+action="Mark read"
+action="Copy to folder"
+actionValue="mailbox://nobody@Local%20Folders/News"
+action="Stop execution"
+condition="AND (to or cc,isn't,me@example.com)"
+"#;
+
+        let conversion = to_sieve(rules);
+        assert_eq!(
+            conversion.output,
+            concat!(
+                "require [\"fileinto\", \"imap4flags\", \"copy\"];\n\n",
+                "# Mailing lists\n",
+                "if anyof (header :matches \"Subject\" \"[rust]*\", ",
+                "header :contains \"List-Id\" \"rust-users, announce\") {\n",
+                "    fileinto \"Lists/Rust Users\";\n",
+                "    addflag \"$label4\";\n",
+                "}\n",
+                "# Boss\n",
+                "if allof (address :is \"From\" \"boss@example.com\", size :over 10K) {\n",
+                "    addflag \"\\\\Flagged\";\n",
+                "    redirect :copy \"assistant@example.org\";\n",
+                "}\n",
+                "# Newsletters\n",
+                "if not address :is [\"To\", \"Cc\"] \"me@example.com\" {\n",
+                "    addflag \"\\\\Seen\";\n",
+                "    fileinto :copy \"News\";\n",
+                "    stop;\n",
+                "}\n",
+            )
+        );
+        assert_eq!(
+            conversion
+                .warnings
+                .iter()
+                .map(|warning| warning.position)
+                .collect::<Vec<_>>(),
+            [17, 20]
+        );
+
+        Compiler::new()
+            .compile(conversion.output.as_bytes())
+            .unwrap();
+    }
+}
@@ -173,8 +173,11 @@
 //!             },
 //!             Err(error) => {
 //!                 match error {
-//!                     RuntimeError::TooManyIncludes => {
-//!                         eprintln!("Too many included scripts.");
+//!                     RuntimeError::TooManyIncludes(chain) => {
+//!                         eprintln!("Too many included scripts: {:?}.", chain);
+//!                     }
+//!                     RuntimeError::IncludeCycle(chain) => {
+//!                         eprintln!("Script includes itself: {:?}.", chain);
 //!                     }
 //!                     RuntimeError::InvalidInstruction(instruction) => {
 //!                         eprintln!(
@@ -199,6 +202,19 @@
 //!                     RuntimeError::CPULimitReached => {
 //!                         eprintln!("Script exceeded the configured CPU limit.");
 //!                     }
+//!                     RuntimeError::PartIterationLimitReached
+//!                     | RuntimeError::TooManyNestedForEveryPart
+//!                     | RuntimeError::VariableMemoryLimitReached
+//!                     | RuntimeError::ExpansionLimitReached
+//!                     | RuntimeError::GeneratedMessageLimitReached => {
+//!                         eprintln!("Script exceeded the configured resource limits.");
+//!                     }
+//!                     RuntimeError::RedirectNotAllowed(address) => {
+//!                         eprintln!("Redirect to {:?} blocked by policy.", address);
+//!                     }
+//!                     RuntimeError::Internal { message, .. } => {
+//!                         eprintln!("Script execution failed: {}", message);
+//!                     }
 //!                 }
 //!                 input = true.into();
 //!             }
@@ -214,6 +230,10 @@
 //!  $ cargo test --all-features
 //! ```
 //!
+//! The `testsuite` feature exposes the `.svtest` runner used by the testsuite
+//! as [`testsuite::TestSuite`], for running the same kind of tests against
+//! custom compiler and runtime configurations.
+//!
 //! To fuzz the library with `cargo-fuzz`:
 //!
 //! ```bash
@@ -264,20 +284,41 @@
 //! Copyright (C) 2020-2023, Stalwart Labs Ltd.
 //!
 
-use std::{borrow::Cow, sync::Arc, vec::IntoIter};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    net::IpAddr,
+    sync::Arc,
+    vec::IntoIter,
+};
 
 use ahash::{AHashMap, AHashSet};
-use compiler::grammar::{
-    actions::action_redirect::{ByTime, Notify, Ret},
-    instruction::Instruction,
-    Capability,
+use compiler::{
+    grammar::{
+        actions::action_redirect::{ByTime, Notify, Ret},
+        instruction::Instruction,
+        Capability, Comparator,
+    },
+    header_key::LowercaseName,
 };
 use mail_parser::{HeaderName, Message};
-use runtime::{context::ScriptStack, Variable};
+use runtime::{
+    actions::action_mime::MessageSnapshot, cache::RegexCache, context::ScriptStack,
+    mailbox::MailboxNormalizer, metadata::MetadataProvider, source::MessageSource, Variable,
+};
 use serde::{Deserialize, Serialize};
 
 pub mod compiler;
+pub mod convert;
+pub mod managesieve;
 pub mod runtime;
+#[cfg(any(test, feature = "testsuite"))]
+pub mod testsuite;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 pub(crate) const MAX_MATCH_VARIABLES: usize = 63;
 pub(crate) const MAX_LOCAL_VARIABLES: usize = 256;
@@ -285,10 +326,16 @@ pub(crate) const MAX_LOCAL_VARIABLES: usize = 256;
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Sieve {
     instructions: Vec<Instruction>,
+    spans: Vec<compiler::Span>,
     num_vars: usize,
     num_match_vars: usize,
+    uses_body: bool,
+    unknown_tags: Vec<compiler::UnknownTag>,
+    capabilities: Vec<Capability>,
+    warnings: Vec<compiler::CompileWarning>,
 }
 
+#[derive(Debug, Clone)]
 pub struct Compiler {
     // Settings
     pub(crate) max_script_size: usize,
@@ -301,7 +348,21 @@ pub struct Compiler {
     pub(crate) max_local_variables: usize,
     pub(crate) max_header_size: usize,
     pub(crate) max_includes: usize,
+    pub(crate) max_instructions: usize,
+    pub(crate) max_compiled_size: usize,
+    pub(crate) max_expression_depth: usize,
+    pub(crate) max_expression_nodes: usize,
+    pub(crate) bitwise_operators: bool,
+    pub(crate) unknown_tag_policy: UnknownTagPolicy,
+    pub(crate) unknown_tag_policies: AHashMap<String, UnknownTagPolicy>,
+    pub(crate) pragma_bounds: Option<PragmaBounds>,
+    pub(crate) notify_uri_validators: AHashMap<String, NotifyUriValidator>,
+    pub(crate) variable_names: VariableNameRules,
+    pub(crate) comparators: AHashSet<Comparator>,
     pub(crate) no_capability_check: bool,
+    pub(crate) redirect_domains: AHashSet<String>,
+    #[cfg(any(test, feature = "testsuite"))]
+    pub(crate) test_commands: bool,
 
     // Functions
     pub(crate) functions: AHashMap<String, (u32, u32)>,
@@ -320,44 +381,88 @@ pub struct Runtime<C> {
     pub(crate) allowed_capabilities: AHashSet<Capability>,
     pub(crate) valid_notification_uris: AHashSet<Cow<'static, str>>,
     pub(crate) valid_ext_lists: AHashSet<Cow<'static, str>>,
-    pub(crate) protected_headers: Vec<HeaderName<'static>>,
+    pub(crate) protected_headers: Vec<(HeaderName<'static>, HeaderPolicy)>,
     pub(crate) environment: AHashMap<Cow<'static, str>, Variable>,
     pub(crate) metadata: Vec<(Metadata<String>, Cow<'static, str>)>,
     pub(crate) include_scripts: AHashMap<String, Arc<Sieve>>,
     pub(crate) local_hostname: Cow<'static, str>,
     pub(crate) functions: Vec<Function<C>>,
+    pub(crate) regex_cache: Arc<RegexCache>,
+    pub(crate) integer_overflow: IntegerOverflow,
+    pub(crate) integer_division: IntegerDivision,
 
     pub(crate) max_nested_includes: usize,
     pub(crate) cpu_limit: usize,
     pub(crate) max_variable_size: usize,
+    pub(crate) max_variable_memory: usize,
+    pub(crate) max_expansion_size: usize,
     pub(crate) max_redirects: usize,
     pub(crate) max_received_headers: usize,
     pub(crate) max_header_size: usize,
     pub(crate) max_out_messages: usize,
+    pub(crate) max_generated_messages: usize,
+    pub(crate) max_part_iterations: usize,
+    pub(crate) max_nested_foreverypart: usize,
 
     pub(crate) default_vacation_expiry: u64,
     pub(crate) default_duplicate_expiry: u64,
 
     pub(crate) vacation_use_orig_rcpt: bool,
+    pub(crate) vacation_address_wildcards: bool,
+    pub(crate) vacation_ignore_subaddress: bool,
     pub(crate) vacation_default_subject: Cow<'static, str>,
     pub(crate) vacation_subject_prefix: Cow<'static, str>,
 
+    pub(crate) redirect_policy: Option<RedirectPolicy>,
+    pub(crate) mailbox_create_policy: Option<MailboxCreatePolicy>,
+    pub(crate) duplicate_id_hasher: Option<DuplicateIdHasher>,
+    pub(crate) redactor: Option<Redactor>,
+    pub(crate) mailbox_normalizer: Option<Arc<dyn MailboxNormalizer>>,
+    pub(crate) metadata_provider: Option<Arc<dyn MetadataProvider>>,
+    pub(crate) preview_modifications: bool,
+    pub(crate) address_options: AddressOptions,
+    pub(crate) mime_leniency: MimeLeniency,
+    pub(crate) flag_options: FlagOptions,
+    pub(crate) loop_options: LoopOptions,
+    pub(crate) received_environment: bool,
+    pub(crate) reject_message: bool,
+    pub(crate) failure_policy: FailurePolicy,
+    pub(crate) profiles: AHashMap<String, Arc<RuntimeProfile<C>>>,
+
     pub(crate) context: C,
 }
 
+/// Runtime settings of a tenant, selected with [`Context::set_tenant`] or
+/// [`Runtime::tenant`] so that a single runtime can apply different limits,
+/// capabilities, policies and providers to each tenant.
+#[derive(Debug, Clone)]
+pub struct RuntimeProfile<C> {
+    pub(crate) runtime: Runtime<C>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Runner<C> {
+    pub(crate) script: Arc<Sieve>,
+    pub(crate) runtime: Arc<Runtime<C>>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Context<'x, C> {
-    #[cfg(test)]
-    pub(crate) runtime: Runtime<C>,
-    #[cfg(not(test))]
-    pub(crate) runtime: &'x Runtime<C>,
+    pub(crate) runtime: runtime::context::RuntimeRef<'x, C>,
     pub(crate) user_address: Cow<'x, str>,
     pub(crate) user_full_name: Cow<'x, str>,
     pub(crate) current_time: i64,
+    pub(crate) current_time_millis: u16,
 
-    pub(crate) message: Message<'x>,
+    pub(crate) message: Arc<Message<'x>>,
     pub(crate) message_size: usize,
+    pub(crate) message_source: Option<&'x dyn MessageSource>,
+    pub(crate) message_source_error: Option<String>,
+    pub(crate) header_index: RefCell<Arc<AHashMap<usize, AHashMap<LowercaseName, Vec<usize>>>>>,
+    pub(crate) expansion_exceeded: Cell<bool>,
+    pub(crate) metadata_cache: RefCell<Arc<AHashMap<Metadata<String>, Option<String>>>>,
     pub(crate) envelope: Vec<(Envelope, Variable)>,
+    pub(crate) subaddress: AHashMap<String, Subaddress>,
     pub(crate) metadata: Vec<(Metadata<String>, Cow<'x, str>)>,
 
     pub(crate) part: usize,
@@ -370,11 +475,13 @@ pub struct Context<'x, C> {
     pub(crate) pos: usize,
     pub(crate) test_result: bool,
     pub(crate) script_cache: AHashMap<Script, Arc<Sieve>>,
+    pub(crate) scripts_included: AHashSet<Script>,
     pub(crate) script_stack: Vec<ScriptStack>,
-    pub(crate) vars_global: AHashMap<Cow<'static, str>, Variable>,
+    pub(crate) vars_global: Arc<AHashMap<Cow<'static, str>, Variable>>,
     pub(crate) vars_env: AHashMap<Cow<'static, str>, Variable>,
     pub(crate) vars_local: Vec<Variable>,
     pub(crate) vars_match: Vec<Variable>,
+    pub(crate) vars_size: usize,
     pub(crate) expr_stack: Vec<Variable>,
     pub(crate) expr_pos: usize,
 
@@ -386,10 +493,38 @@ pub struct Context<'x, C> {
     pub(crate) has_changes: bool,
     pub(crate) num_redirects: usize,
     pub(crate) num_instructions: usize,
+    pub(crate) num_part_iterations: usize,
     pub(crate) num_out_messages: usize,
+    pub(crate) num_generated_messages: usize,
+    pub(crate) correlation_id: Option<String>,
+    pub(crate) duplicate_id: Option<String>,
+    pub(crate) vacation_id: Option<String>,
+    pub(crate) warnings: Vec<RuntimeWarning>,
+    pub(crate) final_event_origin: Option<(Script, compiler::Span)>,
+    pub(crate) pending_modification: Option<Box<MessageSnapshot<'x>>>,
+    pub(crate) profiler: Option<Box<runtime::profile::Profiler>>,
+    pub(crate) audit_log: Option<Vec<runtime::audit::AuditRecord>>,
+    pub(crate) is_deferred: bool,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+/// Connection details derived from the Received header chain,
+/// see [`Context::received_chain`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ReceivedChain {
+    pub remote_host: Option<String>,
+    pub remote_ip: Option<IpAddr>,
+    pub earliest_date: Option<i64>,
+}
+
+/// Origin of an event, see [`Context::event_metadata`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct EventMetadata {
+    pub script: Option<Script>,
+    pub span: Option<compiler::Span>,
+    pub correlation_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Script {
     Personal(String),
     Global(String),
@@ -409,6 +544,17 @@ pub enum Envelope {
     Envid,
 }
 
+/// How `:user` and `:detail` split the local part of the addresses of a
+/// domain, see [`Context::set_subaddress`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Subaddress {
+    /// Splits at the first occurrence of any of the separators.
+    Separators(Vec<char>),
+    /// The domain does not use subaddressing: `:user` is the whole local
+    /// part and `:detail` never matches.
+    None,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Metadata<T> {
     Server { annotation: T },
@@ -443,6 +589,11 @@ pub enum Event {
         id: ExternalId,
         arguments: Vec<Variable>,
     },
+    PreviewModification {
+        modification: Modification,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
 
     // Actions
     Keep {
@@ -453,6 +604,9 @@ pub enum Event {
     Reject {
         extended: bool,
         reason: String,
+        /// The DSN or MDN to send to the envelope sender, only built when
+        /// enabled with [`Runtime::set_reject_message`].
+        message: Option<Vec<u8>>,
     },
     FileInto {
         folder: String,
@@ -472,6 +626,7 @@ pub enum Event {
     Notify {
         from: Option<String>,
         importance: Importance,
+        importance_level: u8,
         options: Vec<String>,
         message: String,
         method: String,
@@ -507,6 +662,208 @@ pub enum MatchAs {
     Number,
 }
 
+/// Decides whether a redirect address is allowed, optionally rewriting it.
+pub type RedirectPolicy = fn(&str) -> RedirectAction;
+
+/// Validates a constant `notify` method URI at compile time, returning the
+/// reason why it was rejected.
+pub type NotifyUriValidator = fn(uri: &str) -> Result<(), String>;
+
+/// Derives the id reported by the `duplicate` test from the `:handle` (empty
+/// when not specified) and the `:header`, `:uniqueid` or Message-ID value.
+pub type DuplicateIdHasher = fn(handle: &str, id: &str) -> String;
+
+/// Rewrites text that may come from the message, such as addresses, folder
+/// names or header values, before it is recorded in traces, audit records
+/// or errors, see [`Runtime::set_redactor`].
+pub type Redactor = fn(&str) -> String;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RedirectAction {
+    Allow,
+    Rewrite(String),
+    Deny,
+}
+
+/// Decides how `fileinto :create` is honored for a mailbox name.
+pub type MailboxCreatePolicy = fn(&str) -> MailboxCreateAction;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MailboxCreateAction {
+    /// The mailbox is created if it does not exist.
+    Allow,
+    /// The message is filed into, and if needed creates, another mailbox,
+    /// such as one under a prefix.
+    Rewrite(String),
+    /// The mailbox is not created and the message is kept instead.
+    Deny,
+}
+
+/// How the address test treats the parts of an address header that RFC 5228
+/// leaves to the implementation, see [`Runtime::set_address_options`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct AddressOptions {
+    pub(crate) group_members: bool,
+    pub(crate) empty_groups: AddressFallback,
+    pub(crate) comments_as_name: bool,
+    pub(crate) invalid_addresses: AddressFallback,
+}
+
+/// How the flags used by imap4flags are normalized before they are stored
+/// or tested, see [`Runtime::set_flag_options`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FlagOptions {
+    pub(crate) canonical_system_flags: bool,
+    pub(crate) validate_keywords: bool,
+    pub(crate) aliases: Vec<(String, String)>,
+    pub(crate) merge: FlagMerge,
+}
+
+/// The checks performed before `redirect`, `vacation` and `notify` to avoid
+/// mail loops, see [`Runtime::set_loop_options`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LoopOptions {
+    pub(crate) auto_submitted: bool,
+    pub(crate) precedence: bool,
+    pub(crate) redirect_auto_submitted: bool,
+    pub(crate) loop_header: Option<(String, usize)>,
+    pub(crate) self_redirect: bool,
+}
+
+/// How the `:flags` argument of `keep` and `fileinto` interacts with the
+/// internal flag variable set with `setflag` and `addflag`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum FlagMerge {
+    /// The `:flags` argument replaces the internal flags, as in RFC 5232.
+    #[default]
+    Replace,
+    /// The message is stored with both the internal flags and the `:flags`.
+    Union,
+}
+
+/// What the compiler does with tagged arguments it does not know about, such
+/// as vendor extensions of other interpreters, see
+/// [`Compiler::set_unknown_tag_policy`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum UnknownTagPolicy {
+    /// The script fails to compile.
+    #[default]
+    Error,
+    /// The tag and its value are dropped and listed in [`Sieve::unknown_tags`].
+    Ignore,
+    /// As `Ignore`, but the value of the tag is also kept so it can be
+    /// handled by the host.
+    Preserve,
+}
+
+/// Restrictions on the variable names used by scripts, see
+/// [`Compiler::set_variable_name_rules`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct VariableNameRules {
+    pub(crate) max_length: usize,
+    pub(crate) allowed_namespaces: Option<Vec<String>>,
+    pub(crate) reserved_prefixes: Vec<String>,
+}
+
+/// Compiler options that scripts may change with `# sieve:` pragma comments,
+/// see [`Compiler::set_pragma_bounds`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PragmaBounds {
+    pub(crate) allow_non_strict: bool,
+    pub(crate) allow_unknown_tags: bool,
+    pub(crate) max_nesting: usize,
+}
+
+/// Edits that `addheader` and `deleteheader` may perform on a header, see
+/// [`Runtime::set_header_policy`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum HeaderPolicy {
+    #[default]
+    Allow,
+    DenyAdd,
+    DenyDelete,
+    Deny,
+}
+
+/// Non fatal problem found while running a script, see [`Context::warnings`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RuntimeWarning {
+    pub kind: RuntimeWarningKind,
+    pub script: Option<Script>,
+    pub span: Option<compiler::Span>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RuntimeWarningKind {
+    /// `addheader` or `enclose :headers` tried to add a protected header.
+    HeaderAddDenied { name: String },
+    /// `deleteheader` tried to delete a protected header.
+    HeaderDeleteDenied { name: String },
+}
+
+/// How broken messages, such as multiparts with a missing boundary, parts
+/// that failed to decode or undeclared 8-bit headers, are seen by the header,
+/// address and body tests, see [`Runtime::set_mime_leniency`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum MimeLeniency {
+    /// Broken parts are tested as opaque binary data and 8-bit headers as
+    /// their raw, undecoded text, which is never parsed as an address.
+    Strict,
+    /// Broken text parts are tested as their raw text and 8-bit text headers
+    /// are decoded as ISO-8859-1.
+    Lenient,
+    /// Messages are tested as returned by the MIME parser, which is how
+    /// previous versions of the interpreter behaved.
+    #[default]
+    Legacy,
+}
+
+/// What happens to a message when its script fails with a [`RuntimeError`],
+/// see [`Runtime::set_failure_policy`]. The disposition is returned by the
+/// calls to [`Context::run`] that follow the error. Actions returned before
+/// the error, such as a `fileinto` or `redirect`, are not undone.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum FailurePolicy {
+    /// The final action set before the error is returned, which is the
+    /// implicit keep unless the script cancelled it.
+    #[default]
+    Continue,
+    /// The original message is kept.
+    Keep,
+    /// The original message is kept with a flag, such as `$SieveError`.
+    KeepWithFlag(String),
+    /// No action is returned and [`Context::is_deferred`] is `true`, so
+    /// that the message can be delivered again later.
+    Defer,
+    /// The message is discarded.
+    Discard,
+}
+
+/// What to do with an address that has no usable addr-spec.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AddressFallback {
+    /// The address is not tested.
+    Skip,
+    /// The raw text is tested instead: the display name of an empty group,
+    /// or the name or empty string of an invalid address.
+    MatchRaw,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum IntegerOverflow {
+    #[default]
+    Saturate,
+    Wrap,
+    Float,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum IntegerDivision {
+    #[default]
+    Float,
+    Truncate,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Recipient {
     Address(String),
@@ -519,9 +876,57 @@ pub enum Input {
     True,
     False,
     FncResult(Variable),
-    Script { name: Script, script: Arc<Sieve> },
+    Script {
+        name: Script,
+        script: Arc<Sieve>,
+    },
+    /// A positive answer that also sets global variables, which scripts
+    /// read with `global` or `${global.name}`.
+    Variables(Vec<(String, Variable)>),
+}
+
+/// Message modifications that are previewed before being applied, see
+/// [`Runtime::set_preview_modifications`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Modification {
+    Replace,
+    Enclose,
+    DeleteHeader,
+}
+
+/// An event that is not an action, returned by [`Event::request`]. Each
+/// variant can only be answered with the type its event expects, which is
+/// turned into the [`Input`] for the runtime.
+#[derive(Debug)]
+pub enum Request<'x> {
+    IncludeScript(IncludeScriptRequest<'x>),
+    MailboxExists(MailboxExistsRequest),
+    ListContains(ListContainsRequest),
+    DuplicateId(DuplicateIdRequest),
+    Function(FunctionRequest),
+    PreviewModification(PreviewModificationRequest),
 }
 
+#[derive(Debug)]
+pub struct IncludeScriptRequest<'x> {
+    name: &'x Script,
+}
+
+#[derive(Debug)]
+pub struct MailboxExistsRequest(());
+
+#[derive(Debug)]
+pub struct ListContainsRequest(());
+
+#[derive(Debug)]
+pub struct DuplicateIdRequest(());
+
+#[derive(Debug)]
+pub struct FunctionRequest(());
+
+#[derive(Debug)]
+pub struct PreviewModificationRequest(());
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Mailbox {
     Name(String),
@@ -548,30 +953,13 @@ pub enum VirusStatus {
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        fs,
-        path::{Path, PathBuf},
-    };
-
-    use ahash::{AHashMap, AHashSet};
-    use mail_parser::{
-        parsers::MessageStream, Encoding, HeaderValue, Message, MessageParser, MessagePart,
-        PartType,
-    };
+    use std::{fs, path::PathBuf};
 
     use crate::{
-        compiler::grammar::Capability,
-        runtime::{actions::action_mime::reset_test_boundary, Variable},
-        Compiler, Context, Envelope, Event, FunctionMap, Input, Mailbox, Recipient, Runtime,
-        SpamStatus, VirusStatus,
+        compiler::grammar::Capability, runtime::Variable, testsuite::TestSuite, Compiler,
+        FunctionMap, Runtime,
     };
 
-    impl Variable {
-        pub fn unwrap_string(self) -> String {
-            self.to_string().into_owned()
-        }
-    }
-
     #[test]
     fn test_suite() {
         let mut tests = Vec::new();
@@ -580,6 +968,7 @@ mod tests {
 
         read_dir(path, &mut tests);
 
+        let runner = test_suite_runner();
         for test in tests {
             /*if !test
                 .file_name()
@@ -591,7 +980,7 @@ mod tests {
                 continue;
             }*/
             println!("===== {} =====", test.display());
-            run_test(&test);
+            runner.run_file(&test).unwrap_or_else(|err| panic!("{err}"));
         }
     }
 
@@ -611,7 +1000,7 @@ mod tests {
         }
     }
 
-    fn run_test(script_path: &Path) {
+    fn test_suite_runner() -> TestSuite {
         let mut fnc_map = FunctionMap::new()
             .with_function("trim", |_, v| match v.into_iter().next().unwrap() {
                 crate::runtime::Variable::String(s) => s.trim().to_string().into(),
@@ -672,6 +1061,13 @@ mod tests {
                 |_, v| format!("{}-{}-{}", v[0], v[1], v[2]).into(),
                 3,
             )
+            .with_function_variadic("concat_all", |_, v| {
+                v.iter()
+                    .map(|v| v.to_string().into_owned())
+                    .collect::<Vec<_>>()
+                    .join("-")
+                    .into()
+            })
             .with_function_args(
                 "in_array",
                 |_, v| {
@@ -686,526 +1082,45 @@ mod tests {
             .with_external_function("ext_two", 2, 2)
             .with_external_function("ext_three", 3, 3)
             .with_external_function("ext_true", 4, 0)
-            .with_external_function("ext_false", 5, 0);
-        let mut compiler = Compiler::new()
+            .with_external_function("ext_false", 5, 0)
+            .with_external_function_variadic("ext_join", 6);
+        let compiler = Compiler::new()
             .with_max_string_size(10240)
             .register_functions(&mut fnc_map);
+        let runtime = Runtime::new()
+            .with_protected_header("Auto-Submitted")
+            .with_protected_header("Received")
+            .with_valid_notification_uri("mailto")
+            .with_max_out_messages(100)
+            .with_capability(Capability::While)
+            .with_capability(Capability::Expressions)
+            .with_capability(Capability::SizeModes)
+            .with_capability(Capability::DateParts)
+            .with_functions(&mut fnc_map);
 
-        let mut ancestors = script_path.ancestors();
-        ancestors.next();
-        let base_path = ancestors.next().unwrap();
-        let script = compiler
-            .compile(&add_crlf(&fs::read(script_path).unwrap()))
-            .unwrap();
-
-        let mut input = Input::script("", script);
-        let mut current_test = String::new();
-        let mut raw_message_: Option<Vec<u8>> = None;
-        let mut prev_state = None;
-        let mut mailboxes = Vec::new();
-        let mut lists: AHashMap<String, AHashSet<String>> = AHashMap::new();
-        let mut duplicated_ids = AHashSet::new();
-        let mut actions = Vec::new();
-
-        'outer: loop {
-            let runtime = Runtime::new()
-                .with_protected_header("Auto-Submitted")
-                .with_protected_header("Received")
-                .with_valid_notification_uri("mailto")
-                .with_max_out_messages(100)
-                .with_capability(Capability::While)
-                .with_capability(Capability::Expressions)
-                .with_functions(&mut fnc_map.clone());
-            let mut instance = Context::new(
-                &runtime,
-                Message {
-                    parts: vec![MessagePart {
-                        headers: vec![],
-                        is_encoding_problem: false,
-                        body: PartType::Text("".into()),
-                        encoding: Encoding::None,
-                        offset_header: 0,
-                        offset_body: 0,
-                        offset_end: 0,
-                    }],
-                    raw_message: b""[..].into(),
-                    ..Default::default()
-                },
-            );
-            let raw_message = raw_message_.take().unwrap_or_default();
-            instance.message =
-                MessageParser::new()
-                    .parse(&raw_message)
-                    .unwrap_or_else(|| Message {
-                        html_body: vec![],
-                        text_body: vec![],
-                        attachments: vec![],
-                        parts: vec![MessagePart {
-                            headers: vec![],
-                            is_encoding_problem: false,
-                            body: PartType::Text("".into()),
-                            encoding: Encoding::None,
-                            offset_header: 0,
-                            offset_body: 0,
-                            offset_end: 0,
-                        }],
-                        raw_message: b""[..].into(),
-                    });
-            instance.message_size = raw_message.len();
-            if let Some((pos, script_cache, script_stack, vars_global, vars_local, vars_match)) =
-                prev_state.take()
-            {
-                instance.pos = pos;
-                instance.script_cache = script_cache;
-                instance.script_stack = script_stack;
-                instance.vars_global = vars_global;
-                instance.vars_local = vars_local;
-                instance.vars_match = vars_match;
-            }
-            instance.set_env_variable("vnd.stalwart.default_mailbox", "INBOX");
-            instance.set_env_variable("vnd.stalwart.username", "john.doe");
-            instance.set_user_address("MAILER-DAEMON");
-            if let Some(addr) = instance
-                .message
-                .from()
-                .and_then(|a| a.first())
-                .and_then(|a| a.address.as_ref())
-            {
-                instance.set_envelope(Envelope::From, addr.to_string());
-            }
-            if let Some(addr) = instance
-                .message
-                .to()
-                .and_then(|a| a.first())
-                .and_then(|a| a.address.as_ref())
-            {
-                instance.set_envelope(Envelope::To, addr.to_string());
-            }
-
-            while let Some(event) = instance.run(input) {
-                match event.unwrap() {
-                    Event::IncludeScript { name, optional } => {
-                        let mut include_path = PathBuf::from(base_path);
-                        include_path.push(if matches!(name, crate::Script::Personal(_)) {
-                            "included"
-                        } else {
-                            "included-global"
-                        });
-                        include_path.push(format!("{name}.sieve"));
-
-                        if let Ok(bytes) = fs::read(include_path.as_path()) {
-                            let script = compiler.compile(&add_crlf(&bytes)).unwrap();
-                            input = Input::script(name, script);
-                        } else if optional {
-                            input = Input::False;
-                        } else {
-                            panic!("Script {} not found.", include_path.display());
-                        }
-                    }
-                    Event::MailboxExists {
-                        mailboxes: mailboxes_,
-                        special_use,
-                    } => {
-                        for action in &actions {
-                            if let Event::FileInto { folder, create, .. } = action {
-                                if *create && !mailboxes.contains(folder) {
-                                    mailboxes.push(folder.to_string());
-                                }
-                            }
-                        }
-                        input = (special_use.is_empty()
-                            && mailboxes_.iter().all(|n| {
-                                if let Mailbox::Name(n) = n {
-                                    mailboxes.contains(n)
-                                } else {
-                                    false
-                                }
-                            }))
-                        .into();
-                    }
-                    Event::ListContains {
-                        lists: lists_,
-                        values,
-                        ..
-                    } => {
-                        let mut result = false;
-                        'list: for list in &lists_ {
-                            if let Some(list) = lists.get(list) {
-                                for value in &values {
-                                    if list.contains(value) {
-                                        result = true;
-                                        break 'list;
-                                    }
-                                }
-                            }
-                        }
-
-                        input = result.into();
-                    }
-                    Event::DuplicateId { id, .. } => {
-                        input = duplicated_ids.contains(&id).into();
-                    }
-                    Event::Function { id, arguments } => {
-                        if id == u32::MAX {
-                            // Test functions
-                            input = Input::True;
-                            let mut arguments = arguments.into_iter();
-                            let command = arguments.next().unwrap().unwrap_string();
-                            let mut params =
-                                arguments.map(|arg| arg.unwrap_string()).collect::<Vec<_>>();
-
-                            match command.as_str() {
-                                "test" => {
-                                    current_test = params.pop().unwrap();
-                                    println!("Running test '{current_test}'...");
-                                }
-                                "test_set" => {
-                                    let mut params = params.into_iter();
-                                    let target = params.next().expect("test_set parameter");
-                                    if target == "message" {
-                                        let value = params.next().unwrap();
-                                        raw_message_ = if value.eq_ignore_ascii_case(":smtp") {
-                                            let mut message = None;
-                                            for action in actions.iter().rev() {
-                                                if let Event::SendMessage { message_id, .. } =
-                                                    action
-                                                {
-                                                    let message_ = actions
-                                                        .iter()
-                                                        .find_map(|item| {
-                                                            if let Event::CreatedMessage {
-                                                                message_id: message_id_,
-                                                                message,
-                                                            } = item
-                                                            {
-                                                                if message_id == message_id_ {
-                                                                    return Some(message);
-                                                                }
-                                                            }
-                                                            None
-                                                        })
-                                                        .unwrap();
-                                                    /*println!(
-                                                        "<[{}]>",
-                                                        std::str::from_utf8(message_).unwrap()
-                                                    );*/
-                                                    message = message_.into();
-                                                    break;
-                                                }
-                                            }
-                                            message.expect("No SMTP message found").to_vec().into()
-                                        } else {
-                                            value.into_bytes().into()
-                                        };
-                                        prev_state = (
-                                            instance.pos,
-                                            instance.script_cache,
-                                            instance.script_stack,
-                                            instance.vars_global,
-                                            instance.vars_local,
-                                            instance.vars_match,
-                                        )
-                                            .into();
-
-                                        continue 'outer;
-                                    } else if let Some(envelope) = target.strip_prefix("envelope.")
-                                    {
-                                        let envelope =
-                                            Envelope::try_from(envelope.to_string()).unwrap();
-                                        instance.envelope.retain(|(e, _)| e != &envelope);
-                                        instance.set_envelope(envelope, params.next().unwrap());
-                                    } else if target == "currentdate" {
-                                        let bytes = params.next().unwrap().into_bytes();
-                                        if let HeaderValue::DateTime(dt) =
-                                            MessageStream::new(&bytes).parse_date()
-                                        {
-                                            instance.current_time = dt.to_timestamp();
-                                        } else {
-                                            panic!("Invalid currentdate");
-                                        }
-                                    } else {
-                                        panic!("test_set {target} not implemented.");
-                                    }
-                                }
-                                "test_message" => {
-                                    let mut params = params.into_iter();
-                                    input = match params.next().unwrap().as_str() {
-                                    ":folder" => {
-                                        let folder_name = params.next().expect("test_message folder name");
-                                        matches!(&instance.final_event, Some(Event::Keep { .. })) ||
-                                            actions.iter().any(|a| if !folder_name.eq_ignore_ascii_case("INBOX") {
-                                                matches!(a, Event::FileInto { folder, .. } if folder == &folder_name )
-                                            } else {
-                                                matches!(a, Event::Keep { .. })
-                                            })
-                                    }
-                                    ":smtp" => {
-                                        actions.iter().any(|a| matches!(a, Event::SendMessage { .. } ))
-                                    }
-                                    param => panic!("Invalid test_message param '{param}'" ),
-                                }.into();
-                                }
-                                "test_assert_message" => {
-                                    let expected_message =
-                                        params.first().expect("test_set parameter");
-                                    let built_message = instance.build_message();
-                                    if expected_message.as_bytes() != built_message {
-                                        //fs::write("_deleteme.json", serde_json::to_string_pretty(&Message::parse(&built_message).unwrap()).unwrap()).unwrap();
-                                        print!("<[");
-                                        print!("{}", String::from_utf8(built_message).unwrap());
-                                        println!("]>");
-                                        panic!("Message built incorrectly at '{current_test}'");
-                                    }
-                                }
-                                "test_config_set" => {
-                                    let mut params = params.into_iter();
-                                    let name = params.next().unwrap();
-                                    let value = params.next().expect("test_config_set value");
-
-                                    match name.as_str() {
-                                        "sieve_editheader_protected"
-                                        | "sieve_editheader_forbid_add"
-                                        | "sieve_editheader_forbid_delete" => {
-                                            if !value.is_empty() {
-                                                for header_name in value.split(' ') {
-                                                    instance.runtime.set_protected_header(
-                                                        header_name.to_string(),
-                                                    );
-                                                }
-                                            } else {
-                                                instance.runtime.protected_headers.clear();
-                                            }
-                                        }
-                                        "sieve_variables_max_variable_size" => {
-                                            instance
-                                                .runtime
-                                                .set_max_variable_size(value.parse().unwrap());
-                                        }
-                                        "sieve_valid_ext_list" => {
-                                            instance.runtime.set_valid_ext_list(value);
-                                        }
-                                        "sieve_ext_list_item" => {
-                                            lists
-                                                .entry(value)
-                                                .or_insert_with(AHashSet::new)
-                                                .insert(params.next().expect("list item value"));
-                                        }
-                                        "sieve_duplicated_id" => {
-                                            duplicated_ids.insert(value);
-                                        }
-                                        "sieve_user_email" => {
-                                            instance.set_user_address(value);
-                                        }
-                                        "sieve_vacation_use_original_recipient" => {
-                                            instance.runtime.set_vacation_use_orig_rcpt(
-                                                value.eq_ignore_ascii_case("yes"),
-                                            );
-                                        }
-                                        "sieve_vacation_default_subject" => {
-                                            instance.runtime.set_vacation_default_subject(value);
-                                        }
-                                        "sieve_vacation_default_subject_template" => {
-                                            instance.runtime.set_vacation_subject_prefix(value);
-                                        }
-                                        "sieve_spam_status" => {
-                                            instance.set_spam_status(SpamStatus::from_number(
-                                                value.parse().unwrap(),
-                                            ));
-                                        }
-                                        "sieve_spam_status_plus" => {
-                                            instance.set_spam_status(
-                                                match value.parse::<u32>().unwrap() {
-                                                    0 => SpamStatus::Unknown,
-                                                    100.. => SpamStatus::Spam,
-                                                    n => SpamStatus::MaybeSpam((n as f64) / 100.0),
-                                                },
-                                            );
-                                        }
-                                        "sieve_virus_status" => {
-                                            instance.set_virus_status(VirusStatus::from_number(
-                                                value.parse().unwrap(),
-                                            ));
-                                        }
-                                        "sieve_editheader_max_header_size" => {
-                                            let mhs = if !value.is_empty() {
-                                                value.parse::<usize>().unwrap()
-                                            } else {
-                                                1024
-                                            };
-                                            instance.runtime.set_max_header_size(mhs);
-                                            compiler.set_max_header_size(mhs);
-                                        }
-                                        "sieve_include_max_includes" => {
-                                            compiler.set_max_includes(if !value.is_empty() {
-                                                value.parse::<usize>().unwrap()
-                                            } else {
-                                                3
-                                            });
-                                        }
-                                        "sieve_include_max_nesting_depth" => {
-                                            compiler.set_max_nested_blocks(if !value.is_empty() {
-                                                value.parse::<usize>().unwrap()
-                                            } else {
-                                                3
-                                            });
-                                        }
-                                        param => panic!("Invalid test_config_set param '{param}'"),
-                                    }
-                                }
-                                "test_result_execute" => {
-                                    input =
-                                        (matches!(&instance.final_event, Some(Event::Keep { .. }))
-                                            || actions.iter().any(|a| {
-                                                matches!(
-                                                    a,
-                                                    Event::Keep { .. }
-                                                        | Event::FileInto { .. }
-                                                        | Event::SendMessage { .. }
-                                                )
-                                            }))
-                                        .into();
-                                }
-                                "test_result_action" => {
-                                    let param =
-                                        params.first().expect("test_result_action parameter");
-                                    input = if param == "reject" {
-                                        (actions.iter().any(|a| matches!(a, Event::Reject { .. })))
-                                            .into()
-                                    } else if param == "redirect" {
-                                        let param = params
-                                            .last()
-                                            .expect("test_result_action redirect address");
-                                        (actions
-                                        .iter()
-                                        .any(|a| matches!(a, Event::SendMessage { recipient: Recipient::Address(address), .. } if address == param)))
-                                    .into()
-                                    } else if param == "keep" {
-                                        (matches!(&instance.final_event, Some(Event::Keep { .. }))
-                                            || actions
-                                                .iter()
-                                                .any(|a| matches!(a, Event::Keep { .. })))
-                                        .into()
-                                    } else if param == "send_message" {
-                                        (actions
-                                            .iter()
-                                            .any(|a| matches!(a, Event::SendMessage { .. })))
-                                        .into()
-                                    } else {
-                                        panic!("test_result_action {param} not implemented");
-                                    };
-                                }
-                                "test_result_action_count" => {
-                                    input = (actions.len()
-                                        == params.first().unwrap().parse::<usize>().unwrap())
-                                    .into();
-                                }
-                                "test_imap_metadata_set" => {
-                                    let mut params = params.into_iter();
-                                    let first = params.next().expect("metadata parameter");
-                                    let (mailbox, annotation) = if first == ":mailbox" {
-                                        (
-                                            params.next().expect("metadata mailbox name").into(),
-                                            params.next().expect("metadata annotation name"),
-                                        )
-                                    } else {
-                                        (None, first)
-                                    };
-                                    let value = params.next().expect("metadata value");
-                                    if let Some(mailbox) = mailbox {
-                                        instance.set_medatata((mailbox, annotation), value);
-                                    } else {
-                                        instance.set_medatata(annotation, value);
-                                    }
-                                }
-                                "test_mailbox_create" => {
-                                    mailboxes.push(params.pop().expect("mailbox to create"));
-                                }
-                                "test_result_reset" => {
-                                    actions.clear();
-                                    instance.final_event = Event::Keep {
-                                        flags: vec![],
-                                        message_id: 0,
-                                    }
-                                    .into();
-                                    instance.metadata.clear();
-                                    instance.has_changes = false;
-                                    instance.num_redirects = 0;
-                                    instance.runtime.vacation_use_orig_rcpt = false;
-                                    mailboxes.clear();
-                                    lists.clear();
-                                    reset_test_boundary();
-                                }
-                                "test_script_compile" => {
-                                    let mut include_path = PathBuf::from(base_path);
-                                    include_path.push(params.first().unwrap());
-
-                                    if let Ok(bytes) = fs::read(include_path.as_path()) {
-                                        let result = compiler.compile(&add_crlf(&bytes));
-                                        /*if let Err(err) = &result {
-                                            println!("Error: {:?}", err);
-                                        }*/
-                                        input = result.is_ok().into();
-                                    } else {
-                                        panic!("Script {} not found.", include_path.display());
-                                    }
-                                }
-                                "test_config_reload" => (),
-                                "test_fail" => {
-                                    panic!(
-                                        "Test '{}' failed: {}",
-                                        current_test,
-                                        params.pop().unwrap()
-                                    );
-                                }
-                                _ => panic!("Test command {command} not implemented."),
-                            }
-                        } else {
-                            let result = match id {
-                                0 => Variable::from("my_value"),
-                                1 => Variable::from(arguments[0].to_string().to_uppercase()),
-                                2 => Variable::from(format!(
-                                    "{}-{}",
-                                    arguments[0].to_string(),
-                                    arguments[1].to_string()
-                                )),
-                                3 => Variable::from(format!(
-                                    "{}-{}-{}",
-                                    arguments[0].to_string(),
-                                    arguments[1].to_string(),
-                                    arguments[2].to_string()
-                                )),
-                                4 => true.into(),
-                                5 => false.into(),
-                                _ => {
-                                    panic!("Unknown external function {id}");
-                                }
-                            };
-
-                            input = result.into();
-                        }
-                    }
-
-                    action => {
-                        actions.push(action);
-                        input = true.into();
-                    }
+        TestSuite::new()
+            .with_compiler(compiler)
+            .with_runtime(runtime)
+            .with_external_functions(|id, arguments| match id {
+                0 => Variable::from("my_value"),
+                1 => Variable::from(arguments[0].to_string().to_uppercase()),
+                2 => Variable::from(format!(
+                    "{}-{}",
+                    arguments[0].to_string(),
+                    arguments[1].to_string()
+                )),
+                3 => Variable::from(format!(
+                    "{}-{}-{}",
+                    arguments[0].to_string(),
+                    arguments[1].to_string(),
+                    arguments[2].to_string()
+                )),
+                4 => true.into(),
+                5 => false.into(),
+                6 => arguments.into(),
+                _ => {
+                    panic!("Unknown external function {id}");
                 }
-            }
-
-            return;
-        }
-    }
-
-    fn add_crlf(bytes: &[u8]) -> Vec<u8> {
-        let mut result = Vec::with_capacity(bytes.len());
-        let mut last_ch = 0;
-        for &ch in bytes {
-            if ch == b'\n' && last_ch != b'\r' {
-                result.push(b'\r');
-            }
-            result.push(ch);
-            last_ch = ch;
-        }
-        result
+            })
     }
 }
@@ -0,0 +1,439 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{borrow::Cow, fmt::Display};
+
+use crate::{
+    compiler::grammar::{Capability, Comparator},
+    Compiler, Runtime,
+};
+
+/// Server settings for the ManageSieve protocol (RFC 5804) that are not
+/// part of the Sieve compiler or runtime configuration.
+#[derive(Debug, Clone)]
+pub struct ManageSieve {
+    pub(crate) implementation: Cow<'static, str>,
+    pub(crate) sasl_mechanisms: Vec<Cow<'static, str>>,
+    pub(crate) starttls: bool,
+    pub(crate) language: Option<Cow<'static, str>>,
+    pub(crate) max_script_name: usize,
+    pub(crate) max_scripts: Option<usize>,
+    pub(crate) max_quota: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok {
+        code: Option<ResponseCode>,
+        message: Option<String>,
+    },
+    No {
+        code: Option<ResponseCode>,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResponseCode {
+    Quota,
+    QuotaMaxScripts,
+    QuotaMaxSize,
+}
+
+impl ManageSieve {
+    pub fn new() -> Self {
+        ManageSieve {
+            implementation: concat!("Stalwart Sieve v", env!("CARGO_PKG_VERSION")).into(),
+            sasl_mechanisms: Vec::new(),
+            starttls: false,
+            language: None,
+            max_script_name: 512,
+            max_scripts: None,
+            max_quota: None,
+        }
+    }
+
+    pub fn set_implementation(&mut self, implementation: impl Into<Cow<'static, str>>) {
+        self.implementation = implementation.into();
+    }
+
+    pub fn with_implementation(mut self, implementation: impl Into<Cow<'static, str>>) -> Self {
+        self.implementation = implementation.into();
+        self
+    }
+
+    pub fn set_sasl_mechanism(&mut self, mechanism: impl Into<Cow<'static, str>>) {
+        self.sasl_mechanisms.push(mechanism.into());
+    }
+
+    pub fn with_sasl_mechanism(mut self, mechanism: impl Into<Cow<'static, str>>) -> Self {
+        self.sasl_mechanisms.push(mechanism.into());
+        self
+    }
+
+    pub fn set_starttls(&mut self, starttls: bool) {
+        self.starttls = starttls;
+    }
+
+    pub fn with_starttls(mut self, starttls: bool) -> Self {
+        self.starttls = starttls;
+        self
+    }
+
+    pub fn set_language(&mut self, language: impl Into<Cow<'static, str>>) {
+        self.language = Some(language.into());
+    }
+
+    pub fn with_language(mut self, language: impl Into<Cow<'static, str>>) -> Self {
+        self.language = Some(language.into());
+        self
+    }
+
+    pub fn set_max_script_name(&mut self, size: usize) {
+        self.max_script_name = size;
+    }
+
+    pub fn with_max_script_name(mut self, size: usize) -> Self {
+        self.max_script_name = size;
+        self
+    }
+
+    pub fn set_max_scripts(&mut self, max_scripts: usize) {
+        self.max_scripts = Some(max_scripts);
+    }
+
+    pub fn with_max_scripts(mut self, max_scripts: usize) -> Self {
+        self.max_scripts = Some(max_scripts);
+        self
+    }
+
+    pub fn set_max_quota(&mut self, size: usize) {
+        self.max_quota = Some(size);
+    }
+
+    pub fn with_max_quota(mut self, size: usize) -> Self {
+        self.max_quota = Some(size);
+        self
+    }
+
+    /// Returns the capability listing sent on connection and in response
+    /// to CAPABILITY, including the final OK line.
+    pub fn capabilities<C>(&self, runtime: &Runtime<C>) -> String {
+        let mut extensions = runtime
+            .allowed_capabilities
+            .iter()
+            .filter(|capability| !matches!(capability, Capability::Comparator(Comparator::Elbonia)))
+            .map(|capability| capability.to_string())
+            .collect::<Vec<_>>();
+        extensions.sort_unstable();
+
+        let mut response = String::new();
+        capability(&mut response, "IMPLEMENTATION", Some(&self.implementation));
+        capability(&mut response, "SASL", Some(&self.sasl_mechanisms.join(" ")));
+        capability(&mut response, "SIEVE", Some(&extensions.join(" ")));
+        if runtime.allowed_capabilities.contains(&Capability::Enotify) {
+            // Only plain URI schemes are notification methods
+            let mut methods = runtime
+                .valid_notification_uris
+                .iter()
+                .filter(|uri| !uri.contains(':'))
+                .map(|uri| uri.as_ref())
+                .collect::<Vec<_>>();
+            if !methods.is_empty() {
+                methods.sort_unstable();
+                capability(&mut response, "NOTIFY", Some(&methods.join(" ")));
+            }
+        }
+        capability(
+            &mut response,
+            "MAXREDIRECTS",
+            Some(&runtime.max_redirects.to_string()),
+        );
+        if let Some(language) = &self.language {
+            capability(&mut response, "LANGUAGE", Some(language));
+        }
+        if self.starttls {
+            capability(&mut response, "STARTTLS", None);
+        }
+        capability(&mut response, "VERSION", Some("1.0"));
+        response.push_str(&Response::ok().to_string());
+        response
+    }
+
+    /// Validates a script name as defined in RFC 5804, section 1.6.
+    pub fn validate_script_name(&self, name: &str) -> Result<(), Response> {
+        if name.is_empty() {
+            Err(Response::no(None, "Script name cannot be empty"))
+        } else if name.chars().count() > self.max_script_name {
+            Err(Response::no(None, "Script name is too long"))
+        } else if name.chars().any(
+            |ch| matches!(ch, '\u{0}'..='\u{1f}' | '\u{7f}'..='\u{9f}' | '\u{2028}' | '\u{2029}'),
+        ) {
+            Err(Response::no(
+                None,
+                "Script name contains invalid characters",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Implements HAVESPACE, checking whether a script of `size` bytes can
+    /// be stored as `name` given the sizes of the scripts already stored.
+    pub fn have_space<'x>(
+        &self,
+        compiler: &Compiler,
+        name: &str,
+        size: usize,
+        scripts: impl IntoIterator<Item = (&'x str, usize)>,
+    ) -> Response {
+        if let Err(response) = self.validate_script_name(name) {
+            return response;
+        } else if size > compiler.max_script_size {
+            return Response::no(Some(ResponseCode::QuotaMaxSize), "Script is too large");
+        }
+
+        // Scripts replaced by this one do not count towards the quota
+        let mut num_scripts = 1;
+        let mut total_size = size;
+        for (script_name, script_size) in scripts {
+            if script_name != name {
+                num_scripts += 1;
+                total_size += script_size;
+            }
+        }
+
+        if self.max_scripts.map_or(false, |max| num_scripts > max) {
+            Response::no(Some(ResponseCode::QuotaMaxScripts), "Too many scripts")
+        } else if self.max_quota.map_or(false, |max| total_size > max) {
+            Response::no(Some(ResponseCode::Quota), "Quota exceeded")
+        } else {
+            Response::ok()
+        }
+    }
+
+    /// Implements CHECKSCRIPT: the script is compiled and every required
+    /// extension must be allowed by the runtime.
+    pub fn check_script<C>(
+        &self,
+        compiler: &Compiler,
+        runtime: &Runtime<C>,
+        script: &[u8],
+    ) -> Response {
+        if script.len() > compiler.max_script_size {
+            return Response::no(Some(ResponseCode::QuotaMaxSize), "Script is too large");
+        }
+
+        match compiler.compile(script) {
+            Ok(sieve) => {
+                for capability in sieve.required_capabilities() {
+                    if !runtime.allowed_capabilities.contains(&capability) {
+                        return Response::no(
+                            None,
+                            format!("Extension {:?} is not supported", capability.to_string()),
+                        );
+                    }
+                }
+                Response::ok()
+            }
+            Err(err) => Response::no(
+                None,
+                format!("Line {}, column {}: {err}", err.line_num(), err.line_pos()),
+            ),
+        }
+    }
+}
+
+impl Default for ManageSieve {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Response::Ok {
+            code: None,
+            message: None,
+        }
+    }
+
+    pub fn no(code: Option<ResponseCode>, message: impl Into<String>) -> Self {
+        Response::No {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self, Response::Ok { .. })
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (status, code, message) = match self {
+            Response::Ok { code, message } => ("OK", code, message.as_deref()),
+            Response::No { code, message } => ("NO", code, Some(message.as_str())),
+        };
+        f.write_str(status)?;
+        if let Some(code) = code {
+            write!(f, " ({code})")?;
+        }
+        if let Some(message) = message {
+            f.write_str(" ")?;
+            f.write_str(&string(message))?;
+        }
+        f.write_str("\r\n")
+    }
+}
+
+impl Display for ResponseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ResponseCode::Quota => "QUOTA",
+            ResponseCode::QuotaMaxScripts => "QUOTA/MAXSCRIPTS",
+            ResponseCode::QuotaMaxSize => "QUOTA/MAXSIZE",
+        })
+    }
+}
+
+fn capability(response: &mut String, name: &str, value: Option<&str>) {
+    response.push_str(&string(name));
+    if let Some(value) = value {
+        response.push(' ');
+        response.push_str(&string(value));
+    }
+    response.push_str("\r\n");
+}
+
+// Quoted string, or a literal when the value cannot be quoted
+fn string(value: &str) -> String {
+    if value.len() <= 1024 && !value.contains(['\r', '\n', '\0']) {
+        let mut result = String::with_capacity(value.len() + 2);
+        result.push('"');
+        for ch in value.chars() {
+            if matches!(ch, '"' | '\\') {
+                result.push('\\');
+            }
+            result.push(ch);
+        }
+        result.push('"');
+        result
+    } else {
+        format!("{{{}}}\r\n{value}", value.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ahash::AHashSet;
+
+    use crate::{compiler::grammar::Capability, Compiler, Runtime};
+
+    use super::{ManageSieve, Response, ResponseCode};
+
+    #[test]
+    fn managesieve_helpers() {
+        let compiler = Compiler::new().with_max_script_size(100);
+        let mut runtime = Runtime::new().with_valid_notification_uri("mailto");
+        runtime.allowed_capabilities =
+            AHashSet::from_iter([Capability::FileInto, Capability::Enotify]);
+        let managesieve = ManageSieve::new()
+            .with_implementation("Example Sieve")
+            .with_sasl_mechanism("PLAIN")
+            .with_sasl_mechanism("OAUTHBEARER")
+            .with_starttls(true)
+            .with_max_scripts(2)
+            .with_max_quota(150);
+
+        assert_eq!(
+            managesieve.capabilities(&runtime),
+            concat!(
+                "\"IMPLEMENTATION\" \"Example Sieve\"\r\n",
+                "\"SASL\" \"PLAIN OAUTHBEARER\"\r\n",
+                "\"SIEVE\" \"enotify fileinto\"\r\n",
+                "\"NOTIFY\" \"mailto\"\r\n",
+                "\"MAXREDIRECTS\" \"1\"\r\n",
+                "\"STARTTLS\"\r\n",
+                "\"VERSION\" \"1.0\"\r\n",
+                "OK\r\n"
+            )
+        );
+
+        assert!(managesieve.validate_script_name("My filters").is_ok());
+        assert!(managesieve.validate_script_name("").is_err());
+        assert!(managesieve.validate_script_name("bad\r\nname").is_err());
+        assert!(managesieve.validate_script_name("bad\u{2028}name").is_err());
+
+        let scripts = [("main", 60), ("vacation", 40)];
+        assert!(managesieve
+            .have_space(&compiler, "main", 100, scripts)
+            .is_ok());
+        for (name, size, code) in [
+            ("main", 101, ResponseCode::QuotaMaxSize),
+            ("other", 10, ResponseCode::QuotaMaxScripts),
+            ("vacation", 95, ResponseCode::Quota),
+        ] {
+            assert_eq!(
+                managesieve.have_space(&compiler, name, size, scripts),
+                Response::No {
+                    code: Some(code),
+                    message: match code {
+                        ResponseCode::Quota => "Quota exceeded",
+                        ResponseCode::QuotaMaxScripts => "Too many scripts",
+                        ResponseCode::QuotaMaxSize => "Script is too large",
+                    }
+                    .to_string()
+                },
+                "{name} {size}"
+            );
+        }
+        assert_eq!(
+            Response::no(Some(ResponseCode::QuotaMaxSize), "Script is too large").to_string(),
+            "NO (QUOTA/MAXSIZE) \"Script is too large\"\r\n"
+        );
+
+        assert_eq!(
+            managesieve
+                .check_script(
+                    &compiler,
+                    &runtime,
+                    b"require \"fileinto\"; fileinto \"a\";"
+                )
+                .to_string(),
+            "OK\r\n"
+        );
+        assert!(!managesieve
+            .check_script(&compiler, &runtime, b"require \"vacation\"; keep;")
+            .is_ok());
+        let response = managesieve
+            .check_script(&compiler, &runtime, b"keep;\r\nfileinto \"a\";")
+            .to_string();
+        assert!(
+            response.starts_with("NO \"Line 2, column ")
+                && response.ends_with("Undeclared capability 'fileinto'\"\r\n"),
+            "{response}"
+        );
+    }
+}
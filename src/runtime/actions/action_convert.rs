@@ -21,6 +21,8 @@
  * for more details.
 */
 
+use std::sync::Arc;
+
 use mail_parser::{
     decoders::html::{html_to_text, text_to_html},
     Encoding, Header, HeaderName, HeaderValue, MimeHeaders, PartType,
@@ -66,7 +68,7 @@ impl Convert {
             return TestResult::Bool(false ^ self.is_not);
         };
         let mut did_convert = false;
-        for part in ctx.message.parts.iter_mut() {
+        for part in Arc::make_mut(&mut ctx.message).parts.iter_mut() {
             let (new_body, ct) = match (&part.body, conversion) {
                 (PartType::Html(html), Conversion::HtmlToText) => (
                     PartType::Text(html_to_text(html.as_ref()).into()),
@@ -112,6 +114,7 @@ impl Convert {
 
         if did_convert {
             ctx.has_changes = true;
+            *ctx.header_index.get_mut() = Arc::default();
         }
 
         TestResult::Bool(did_convert ^ self.is_not)
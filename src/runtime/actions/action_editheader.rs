@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use std::borrow::Cow;
+use std::{borrow::Cow, sync::Arc};
 
 use mail_parser::{Header, HeaderName, HeaderValue};
 
@@ -33,7 +33,7 @@ use crate::{
         },
         MatchType,
     },
-    Context,
+    Context, HeaderPolicy, RuntimeWarningKind,
 };
 
 impl AddHeader {
@@ -50,7 +50,11 @@ impl AddHeader {
 
         if !header_name.is_empty() {
             if let Some(header_name) = HeaderName::parse(header_name) {
-                if !ctx.runtime.protected_headers.contains(&header_name) {
+                if !ctx.runtime.header_policy(&header_name).allows_add() {
+                    ctx.warn(RuntimeWarningKind::HeaderAddDenied {
+                        name: header_name.as_str().to_string(),
+                    });
+                } else {
                     ctx.has_changes = true;
                     ctx.insert_header(
                         ctx.part,
@@ -69,9 +73,7 @@ impl AddHeader {
 
 impl DeleteHeader {
     pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) {
-        let header_name__ = ctx.eval_value(&self.field_name);
-        let header_name_ = header_name__.to_string();
-        let header_name = if let Some(header_name) = HeaderName::parse(header_name_.as_ref()) {
+        let header_name = if let Some(header_name) = ctx.parse_header_name(&self.field_name) {
             header_name
         } else {
             return;
@@ -80,13 +82,17 @@ impl DeleteHeader {
         let mut deleted_headers = Vec::new();
         let mut deleted_bytes = 0;
 
-        if ctx.runtime.protected_headers.contains(&header_name) {
+        if !ctx.runtime.header_policy(&header_name.name).allows_delete() {
+            ctx.warn(RuntimeWarningKind::HeaderDeleteDenied {
+                name: header_name.name.as_str().to_string(),
+            });
             return;
         }
 
         ctx.find_headers(
             &[header_name],
             self.index,
+            true,
             self.mime_anychild,
             |header, part_id, header_pos| {
                 if !value_patterns.is_empty() {
@@ -109,6 +115,7 @@ impl DeleteHeader {
                                     &mut Vec::new(),
                                 ),
                                 MatchType::Regex(_) => self.comparator.regex(
+                                    &ctx.runtime.regex_cache,
                                     pattern,
                                     pattern_expr,
                                     value,
@@ -142,15 +149,36 @@ impl DeleteHeader {
 
         if !deleted_headers.is_empty() {
             ctx.has_changes = true;
+            let message = Arc::make_mut(&mut ctx.message);
             for (part_id, header_pos) in deleted_headers.iter().rev() {
-                ctx.message.parts[*part_id].headers.remove(*header_pos);
+                message.parts[*part_id].headers.remove(*header_pos);
             }
+            *ctx.header_index.get_mut() = Arc::default();
         }
 
         ctx.message_size -= deleted_bytes;
     }
 }
 
+impl HeaderPolicy {
+    pub(crate) fn from_permissions(allow_add: bool, allow_delete: bool) -> Self {
+        match (allow_add, allow_delete) {
+            (true, true) => HeaderPolicy::Allow,
+            (false, true) => HeaderPolicy::DenyAdd,
+            (true, false) => HeaderPolicy::DenyDelete,
+            (false, false) => HeaderPolicy::Deny,
+        }
+    }
+
+    pub fn allows_add(&self) -> bool {
+        matches!(self, HeaderPolicy::Allow | HeaderPolicy::DenyDelete)
+    }
+
+    pub fn allows_delete(&self) -> bool {
+        matches!(self, HeaderPolicy::Allow | HeaderPolicy::DenyAdd)
+    }
+}
+
 pub(crate) trait RemoveCrLf {
     fn remove_crlf(&self, max_len: usize) -> String;
 }
@@ -189,10 +217,62 @@ impl<'x, C> Context<'x, C> {
             offset_field: 0,
         };
 
+        let headers = &mut Arc::make_mut(&mut self.message).parts[part_id].headers;
         if !last {
-            self.message.parts[part_id].headers.insert(0, header);
+            headers.insert(0, header);
         } else {
-            self.message.parts[part_id].headers.push(header);
+            headers.push(header);
         }
+        *self.header_index.get_mut() = Arc::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{HeaderPolicy, Runtime, RuntimeWarningKind};
+
+    #[test]
+    fn header_policy() {
+        let runtime = Runtime::new()
+            .with_header_policy("X-Frop", HeaderPolicy::DenyAdd)
+            .with_header_policy("Subject", HeaderPolicy::Deny);
+        let mut instance = runtime
+            .filter(b"Received: from mx.example.org\r\nX-Frop: 0\r\nSubject: Hi\r\n\r\nBody");
+        instance.run_test_events(
+            br#"require "editheader";
+                deleteheader "received";
+                addheader "received" "from localhost";
+                addheader "x-frop" "1";
+                deleteheader "x-frop";
+                deleteheader "subject";"#,
+        );
+
+        assert_eq!(
+            instance
+                .warnings()
+                .iter()
+                .map(|w| w.kind.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                RuntimeWarningKind::HeaderDeleteDenied {
+                    name: "Received".to_string()
+                },
+                RuntimeWarningKind::HeaderAddDenied {
+                    name: "x-frop".to_string()
+                },
+                RuntimeWarningKind::HeaderDeleteDenied {
+                    name: "Subject".to_string()
+                },
+            ]
+        );
+        assert_eq!(instance.warnings()[0].span.unwrap().line_num, 2);
+        assert_eq!(
+            instance.message.parts[0]
+                .headers
+                .iter()
+                .map(|h| h.name.as_str())
+                .collect::<Vec<_>>(),
+            ["Received", "Received", "Subject"]
+        );
     }
 }
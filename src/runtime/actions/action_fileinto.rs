@@ -21,12 +21,39 @@
  * for more details.
 */
 
-use crate::{compiler::grammar::actions::action_fileinto::FileInto, Context, Event};
+use crate::{
+    compiler::grammar::actions::action_fileinto::FileInto, Context, Event, MailboxCreateAction,
+};
 
 impl FileInto {
     pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) {
-        let folder = ctx.eval_value(&self.folder).to_string().into_owned();
+        let mut folder =
+            ctx.normalize_mailbox(ctx.eval_value(&self.folder).to_string().into_owned());
         let mut events = Vec::with_capacity(2);
+
+        if let (true, Some(policy)) = (self.create, ctx.runtime.mailbox_create_policy) {
+            match policy(&folder) {
+                MailboxCreateAction::Allow => (),
+                MailboxCreateAction::Rewrite(rewritten) => folder = rewritten,
+                MailboxCreateAction::Deny => {
+                    // The message is kept as if filing into the mailbox failed
+                    if ctx.final_event.is_none() {
+                        if let Some(event) = ctx.build_message_id() {
+                            events.push(event);
+                        }
+                        ctx.final_event = Event::Keep {
+                            flags: ctx.get_local_or_global_flags(&self.flags),
+                            message_id: ctx.main_message_id,
+                        }
+                        .into();
+                        ctx.final_event_origin = ctx.event_origin();
+                    }
+                    ctx.queued_events = events.into_iter();
+                    return;
+                }
+            }
+        }
+
         if let Some(event) = ctx.build_message_id() {
             events.push(event);
         }
@@ -55,3 +82,33 @@ impl FileInto {
         ctx.queued_events = events.into_iter();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Event, MailboxCreateAction, Runtime};
+
+    #[test]
+    fn mailbox_create_policy() {
+        let runtime = Runtime::new().with_mailbox_create_policy(|name| match name {
+            "Spam" => MailboxCreateAction::Deny,
+            name => MailboxCreateAction::Rewrite(format!("Auto/{name}")),
+        });
+        let events = runtime
+            .filter(b"Subject: test\r\n\r\nbody")
+            .run_test_events(
+                br#"require ["fileinto", "mailbox"];
+                fileinto :create "Lists/Rust";
+                fileinto :create "Spam";"#,
+            )
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::FileInto { folder, create, .. } => {
+                    Some(format!("fileinto {folder} create={create}"))
+                }
+                Event::Keep { .. } => Some("keep".to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(events, ["fileinto Auto/Lists/Rust create=true", "keep"]);
+    }
+}
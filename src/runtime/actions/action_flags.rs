@@ -21,14 +21,25 @@
  * for more details.
 */
 
+use std::borrow::Cow;
+
 use crate::{
     compiler::{
         grammar::actions::action_flags::{Action, EditFlags},
         Value, VariableType,
     },
-    Context,
+    Context, FlagMerge, FlagOptions,
 };
 
+const SYSTEM_FLAGS: [&str; 6] = [
+    "\\Seen",
+    "\\Answered",
+    "\\Flagged",
+    "\\Deleted",
+    "\\Draft",
+    "\\Recent",
+];
+
 impl EditFlags {
     pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) {
         let mut var_name_ = None;
@@ -40,7 +51,7 @@ impl EditFlags {
             Action::Set => {
                 let mut flags_lc = Vec::new();
                 let mut flags = String::new();
-                ctx.tokenize_flags(&self.flags, |flag| {
+                ctx.tokenize_flags(&self.flags, true, |flag| {
                     let flag_lc = flag.to_lowercase();
                     if !flags_lc.contains(&flag_lc) {
                         if !flags.is_empty() {
@@ -64,7 +75,7 @@ impl EditFlags {
                     .map(|f| f.to_lowercase())
                     .collect::<Vec<_>>();
 
-                ctx.tokenize_flags(&self.flags, |flag| {
+                ctx.tokenize_flags(&self.flags, true, |flag| {
                     let flag_lc = flag.to_lowercase();
                     if !current_flags.contains(&flag_lc) {
                         if !new_flags.is_empty() {
@@ -89,7 +100,7 @@ impl EditFlags {
                     current_flags.push(flag);
                     current_flags_lc.push(flag.to_lowercase());
                 }
-                ctx.tokenize_flags(&self.flags, |flag| {
+                ctx.tokenize_flags(&self.flags, true, |flag| {
                     let flag = flag.to_lowercase();
                     if let Some(pos) = current_flags_lc.iter().position(|lflag| lflag == &flag) {
                         current_flags.swap_remove(pos);
@@ -107,20 +118,26 @@ impl<'x, C> Context<'x, C> {
     pub(crate) fn tokenize_flags(
         &self,
         strings: &[Value],
+        validate: bool,
         mut cb: impl FnMut(&str) -> bool,
     ) -> bool {
+        let options = &self.runtime.flag_options;
         for (pos, string) in strings.iter().enumerate() {
             let flag_ = self.eval_value(string);
             let flag = flag_.to_string();
             if !flag.is_empty() {
                 if pos == 0 && strings.len() == 1 {
                     for flag in flag.split_ascii_whitespace() {
-                        if !flag.is_empty() && cb(flag) {
-                            return true;
+                        if let Some(flag) = options.normalize(flag, validate) {
+                            if cb(&flag) {
+                                return true;
+                            }
                         }
                     }
-                } else if cb(flag.trim()) {
-                    return true;
+                } else if let Some(flag) = options.normalize(flag.trim(), validate) {
+                    if cb(&flag) {
+                        return true;
+                    }
                 }
             }
         }
@@ -128,9 +145,11 @@ impl<'x, C> Context<'x, C> {
     }
 
     pub(crate) fn get_local_flags(&self, strings: &[Value]) -> Vec<String> {
-        let mut flags = Vec::new();
-        self.tokenize_flags(strings, |flag| {
-            flags.push(flag.to_string());
+        let mut flags: Vec<String> = Vec::new();
+        self.tokenize_flags(strings, true, |flag| {
+            if !flags.iter().any(|f| f.eq_ignore_ascii_case(flag)) {
+                flags.push(flag.to_string());
+            }
             false
         });
         flags
@@ -150,8 +169,178 @@ impl<'x, C> Context<'x, C> {
     pub(crate) fn get_local_or_global_flags(&self, strings: &[Value]) -> Vec<String> {
         if strings.is_empty() {
             self.get_global_flags()
+        } else if self.runtime.flag_options.merge == FlagMerge::Union {
+            let mut flags = self.get_global_flags();
+            for flag in self.get_local_flags(strings) {
+                if !flags.iter().any(|f| f.eq_ignore_ascii_case(&flag)) {
+                    flags.push(flag);
+                }
+            }
+            flags
         } else {
             self.get_local_flags(strings)
         }
     }
 }
+
+impl FlagOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rewrites system flags using their canonical case, such as `\\seen`
+    /// to `\\Seen`. When disabled, flags are stored as written by the script.
+    pub fn set_canonical_system_flags(&mut self, value: bool) {
+        self.canonical_system_flags = value;
+    }
+
+    pub fn with_canonical_system_flags(mut self, value: bool) -> Self {
+        self.set_canonical_system_flags(value);
+        self
+    }
+
+    /// Ignores flags that are neither a system flag nor a valid IMAP
+    /// keyword atom, instead of passing them on to the mail store.
+    pub fn set_validate_keywords(&mut self, value: bool) {
+        self.validate_keywords = value;
+    }
+
+    pub fn with_validate_keywords(mut self, value: bool) -> Self {
+        self.set_validate_keywords(value);
+        self
+    }
+
+    /// Replaces the flag `name`, compared case-insensitively, with `flag`.
+    /// For example, `"Junk"` to `"$Junk"`.
+    pub fn set_alias(&mut self, name: impl Into<String>, flag: impl Into<String>) {
+        let name = name.into();
+        self.aliases.retain(|(n, _)| !n.eq_ignore_ascii_case(&name));
+        self.aliases.push((name, flag.into()));
+    }
+
+    pub fn with_alias(mut self, name: impl Into<String>, flag: impl Into<String>) -> Self {
+        self.set_alias(name, flag);
+        self
+    }
+
+    /// Decides whether the `:flags` of `keep` and `fileinto` replace the
+    /// flags set with `setflag` and `addflag` or are added to them.
+    pub fn set_merge(&mut self, merge: FlagMerge) {
+        self.merge = merge;
+    }
+
+    pub fn with_merge(mut self, merge: FlagMerge) -> Self {
+        self.set_merge(merge);
+        self
+    }
+
+    pub(crate) fn normalize<'y>(&'y self, flag: &'y str, validate: bool) -> Option<Cow<'y, str>> {
+        let flag = self
+            .aliases
+            .iter()
+            .find_map(|(name, alias)| {
+                if name.eq_ignore_ascii_case(flag) {
+                    Some(alias.as_str())
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(flag);
+
+        match flag.strip_prefix('\\').and_then(|name| {
+            SYSTEM_FLAGS
+                .iter()
+                .find(|system| system[1..].eq_ignore_ascii_case(name))
+        }) {
+            Some(system) if self.canonical_system_flags => Some(Cow::Borrowed(*system)),
+            Some(_) => Some(flag.into()),
+            None if validate && self.validate_keywords && !is_keyword(flag) => None,
+            None => Some(flag.into()),
+        }
+    }
+}
+
+// RFC 3501 flag-keyword, an atom without wildcards, quotes or brackets
+fn is_keyword(flag: &str) -> bool {
+    !flag.is_empty()
+        && flag
+            .bytes()
+            .all(|ch| ch.is_ascii_graphic() && !b"(){%*\"\\]".contains(&ch))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Event, FlagMerge, FlagOptions, Runtime};
+
+    #[test]
+    fn flag_options() {
+        let script = br#"require ["imap4flags", "fileinto"];
+            setflag "\\seen junk Junk (invalid) \\SEEN";
+            addflag "\\flagged";
+            fileinto "Global";
+            fileinto :flags "\\answered \\Answered" "Local";"#;
+
+        for (options, expected_flags) in [
+            (
+                FlagOptions::new(),
+                [
+                    vec!["\\seen", "junk", "(invalid)", "\\flagged"],
+                    vec!["\\answered"],
+                ],
+            ),
+            (
+                FlagOptions::new()
+                    .with_canonical_system_flags(true)
+                    .with_validate_keywords(true)
+                    .with_alias("junk", "$Junk"),
+                [vec!["\\Seen", "$Junk", "\\Flagged"], vec!["\\Answered"]],
+            ),
+        ] {
+            let runtime = Runtime::new().with_flag_options(options);
+            let flags = runtime
+                .filter(b"Subject: test\r\n\r\nbody")
+                .run_test_events(script)
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::FileInto { flags, .. } => Some(flags),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(flags, expected_flags);
+        }
+    }
+
+    #[test]
+    fn flag_merge() {
+        let script = br#"require ["imap4flags", "fileinto"];
+            setflag "\\Seen $Work";
+            fileinto :flags "$work \\Flagged" "Work";
+            keep;"#;
+
+        for (merge, expected_flags) in [
+            (
+                FlagMerge::Replace,
+                [vec!["$work", "\\Flagged"], vec!["\\Seen", "$Work"]],
+            ),
+            (
+                FlagMerge::Union,
+                [
+                    vec!["\\Seen", "$Work", "\\Flagged"],
+                    vec!["\\Seen", "$Work"],
+                ],
+            ),
+        ] {
+            let runtime = Runtime::new().with_flag_options(FlagOptions::new().with_merge(merge));
+            let flags = runtime
+                .filter(b"Subject: test\r\n\r\nbody")
+                .run_test_events(script)
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::FileInto { flags, .. } | Event::Keep { flags, .. } => Some(flags),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(flags, expected_flags);
+        }
+    }
+}
@@ -30,7 +30,7 @@ use crate::{
 };
 
 pub(crate) enum IncludeResult {
-    Cached(Arc<Sieve>),
+    Cached(Script, Arc<Sieve>),
     Event(Event),
     Error(RuntimeError),
     None,
@@ -46,13 +46,18 @@ impl Include {
                 Script::Personal(script_name.to_string().into_owned())
             };
 
-            let cached_script = ctx.script_cache.get(&script_name);
-            if !self.once || cached_script.is_none() {
-                if ctx.script_stack.len() < ctx.runtime.max_nested_includes {
-                    if let Some(script) = cached_script
+            if !self.once || !ctx.scripts_included.contains(&script_name) {
+                if ctx.script_stack.iter().any(|s| s.name == script_name) {
+                    return IncludeResult::Error(RuntimeError::IncludeCycle(
+                        ctx.include_chain(script_name),
+                    ));
+                } else if ctx.script_stack.len() < ctx.runtime.max_nested_includes {
+                    if let Some(script) = ctx
+                        .script_cache
+                        .get(&script_name)
                         .or_else(|| ctx.runtime.include_scripts.get(script_name.as_str()))
                     {
-                        return IncludeResult::Cached(script.clone());
+                        return IncludeResult::Cached(script_name, script.clone());
                     } else {
                         return IncludeResult::Event(Event::IncludeScript {
                             name: script_name,
@@ -60,7 +65,9 @@ impl Include {
                         });
                     }
                 } else {
-                    return IncludeResult::Error(RuntimeError::TooManyIncludes);
+                    return IncludeResult::Error(RuntimeError::TooManyIncludes(
+                        ctx.include_chain(script_name),
+                    ));
                 }
             }
         }
@@ -68,3 +75,14 @@ impl Include {
         IncludeResult::None
     }
 }
+
+impl<'x, C> Context<'x, C> {
+    // Scripts being executed, from the main script to `script_name`
+    fn include_chain(&self, script_name: Script) -> Vec<Script> {
+        self.script_stack
+            .iter()
+            .map(|s| s.name.clone())
+            .chain([script_name])
+            .collect()
+    }
+}
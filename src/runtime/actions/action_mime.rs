@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use std::cmp::Reverse;
+use std::{cmp::Reverse, sync::Arc};
 
 use mail_parser::{
     decoders::html::html_to_text, Encoding, HeaderName, Message, MessagePart, PartType,
@@ -32,21 +32,24 @@ use crate::{
         grammar::actions::action_mime::{Enclose, ExtractText, Replace},
         VariableType,
     },
-    Context, Event,
+    Context, Event, Modification, RuntimeWarningKind,
 };
 
 use super::action_editheader::RemoveCrLf;
 
 #[cfg(not(test))]
-use mail_builder::headers::message_id::generate_message_id_header;
+use crate::runtime::platform::{unix_timestamp, write_message_id};
+#[cfg(not(test))]
+use mail_builder::headers::date::Date;
 
 impl Replace {
     pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) {
         // Delete children parts
         let mut part_ids = ctx.find_nested_parts_ids(false);
         part_ids.sort_unstable_by_key(|a| Reverse(*a));
+        let message = Arc::make_mut(&mut ctx.message);
         for part_id in part_ids {
-            ctx.message.parts.remove(part_id);
+            message.parts.remove(part_id);
         }
         ctx.has_changes = true;
 
@@ -54,7 +57,7 @@ impl Replace {
         let body = ctx.eval_value(&self.replacement).to_string().into_owned();
         let body_len = body.len();
 
-        let part = &mut ctx.message.parts[ctx.part];
+        let part = &mut Arc::make_mut(&mut ctx.message).parts[ctx.part];
 
         ctx.message_size = ctx.message_size + body_len
             - (if part.offset_body != 0 {
@@ -143,7 +146,7 @@ impl Replace {
             // Add Date
             if add_date {
                 #[cfg(not(test))]
-                let header_value = mail_builder::headers::date::Date::now().to_rfc822();
+                let header_value = Date::new(unix_timestamp()).to_rfc822();
                 #[cfg(test)]
                 let header_value = "Tue, 20 Nov 2022 05:14:20 -0300".to_string();
 
@@ -158,7 +161,7 @@ impl Replace {
             // Add Message-ID
             let mut header_value = Vec::with_capacity(20);
             #[cfg(not(test))]
-            generate_message_id_header(&mut header_value, &ctx.runtime.local_hostname).unwrap();
+            write_message_id(&mut header_value, &ctx.runtime.local_hostname);
             #[cfg(test)]
             header_value.extend_from_slice(b"<auto-generated@message-id>");
 
@@ -178,6 +181,7 @@ impl Replace {
                 true,
             );
         }
+        *ctx.header_index.get_mut() = Arc::default();
     }
 }
 
@@ -197,6 +201,7 @@ impl Enclose {
             .unwrap_or_default();
 
         let message = std::mem::take(&mut ctx.message);
+        let message = Arc::try_unwrap(message).unwrap_or_else(|message| (*message).clone());
         #[cfg(test)]
         let boundary = make_test_boundary();
         #[cfg(not(test))]
@@ -205,7 +210,8 @@ impl Enclose {
         ctx.message_size += ((boundary.len() + 6) * 3) + body.len() + 2;
         ctx.part = 0;
         ctx.has_changes = true;
-        ctx.message = Message {
+        *ctx.header_index.get_mut() = Arc::default();
+        ctx.message = Arc::new(Message {
             html_body: Vec::with_capacity(0),
             text_body: Vec::with_capacity(0),
             attachments: Vec::with_capacity(0),
@@ -239,7 +245,7 @@ impl Enclose {
                 },
             ],
             raw_message: b""[..].into(),
-        };
+        });
 
         ctx.insert_header(
             0,
@@ -274,7 +280,11 @@ impl Enclose {
                 header_value = header_value.trim();
                 if !header_value.is_empty() {
                     if let Some(name) = HeaderName::parse(header_name) {
-                        if !ctx.runtime.protected_headers.contains(&name) {
+                        if !ctx.runtime.header_policy(&name).allows_add() {
+                            ctx.warn(RuntimeWarningKind::HeaderAddDenied {
+                                name: header_name.to_string(),
+                            });
+                        } else {
                             match &name {
                                 HeaderName::Date => {
                                     add_date = false;
@@ -311,7 +321,7 @@ impl Enclose {
 
         if add_date {
             #[cfg(not(test))]
-            let header_value = mail_builder::headers::date::Date::now().to_rfc822();
+            let header_value = Date::new(unix_timestamp()).to_rfc822();
             #[cfg(test)]
             let header_value = "Tue, 20 Nov 2022 05:14:20 -0300".to_string();
 
@@ -326,7 +336,7 @@ impl Enclose {
         if add_message_id {
             let mut header_value = Vec::with_capacity(20);
             #[cfg(not(test))]
-            generate_message_id_header(&mut header_value, &ctx.runtime.local_hostname).unwrap();
+            write_message_id(&mut header_value, &ctx.runtime.local_hostname);
             #[cfg(test)]
             header_value.extend_from_slice(b"<auto-generated@message-id>");
 
@@ -373,14 +383,19 @@ impl ExtractText {
         match &self.name {
             VariableType::Local(var_id) => {
                 if let Some(var) = ctx.vars_local.get_mut(*var_id) {
+                    let old_len = var.len();
                     *var = value.into();
+                    ctx.vars_size = (ctx.vars_size + var.len()).saturating_sub(old_len);
                 } else {
                     debug_assert!(false, "Non-existent local variable {var_id}");
                 }
             }
             VariableType::Global(var_name) => {
-                ctx.vars_global
-                    .insert(var_name.to_string().into(), value.into());
+                let new_len = value.len();
+                let old_len = Arc::make_mut(&mut ctx.vars_global)
+                    .insert(var_name.to_string().into(), value.into())
+                    .map_or(0, |var| var.len());
+                ctx.vars_size = (ctx.vars_size + new_len).saturating_sub(old_len);
             }
             VariableType::Envelope(env) => {
                 ctx.queued_events = vec![Event::SetEnvelope {
@@ -394,6 +409,15 @@ impl ExtractText {
     }
 }
 
+// Message state saved while a modification preview is pending
+#[derive(Debug, Clone)]
+pub(crate) struct MessageSnapshot<'x> {
+    message: Arc<Message<'x>>,
+    message_size: usize,
+    part: usize,
+    has_changes: bool,
+}
+
 enum StackItem<'x> {
     Message(&'x Message<'x>),
     Boundary(&'x str),
@@ -401,8 +425,57 @@ enum StackItem<'x> {
 }
 
 impl<'x, C> Context<'x, C> {
+    // Applies a modification, keeping a copy of the message to undo it
+    // when previews are enabled
+    pub(crate) fn modify_message(
+        &mut self,
+        modification: Modification,
+        modify: impl FnOnce(&mut Self),
+    ) -> Option<Event> {
+        if !self.runtime.preview_modifications {
+            modify(self);
+            return None;
+        }
+
+        let before = self.build_modified_message();
+        let snapshot = MessageSnapshot {
+            message: self.message.clone(),
+            message_size: self.message_size,
+            part: self.part,
+            has_changes: self.has_changes,
+        };
+        self.has_changes = false;
+        modify(self);
+        if !self.has_changes {
+            self.has_changes = snapshot.has_changes;
+            return None;
+        }
+
+        let after = self.build_message();
+        self.pending_modification = Some(Box::new(snapshot));
+        Some(Event::PreviewModification {
+            modification,
+            before,
+            after,
+        })
+    }
+
+    // Undoes the pending modification unless it was accepted
+    pub(crate) fn finish_modification(&mut self, accept: bool) {
+        if let Some(snapshot) = self.pending_modification.take() {
+            if !accept {
+                self.message = snapshot.message;
+                self.message_size = snapshot.message_size;
+                self.part = snapshot.part;
+                self.has_changes = snapshot.has_changes;
+                *self.header_index.get_mut() = Arc::default();
+            }
+        }
+    }
+
     pub(crate) fn build_message_id(&mut self) -> Option<Event> {
         if self.has_changes {
+            self.load_message();
             self.last_message_id += 1;
             self.main_message_id = self.last_message_id;
             self.has_changes = false;
@@ -416,8 +489,19 @@ impl<'x, C> Context<'x, C> {
         }
     }
 
-    pub(crate) fn build_message(&mut self) -> Vec<u8> {
-        let mut current_message = &self.message;
+    /// Returns the message with all modifications performed by the script
+    /// applied, or a copy of the original message if it was not modified.
+    pub fn build_modified_message(&mut self) -> Vec<u8> {
+        self.load_message();
+        if self.has_changes || self.main_message_id > 0 {
+            self.build_message()
+        } else {
+            self.message.raw_message.to_vec()
+        }
+    }
+
+    pub(crate) fn build_message(&self) -> Vec<u8> {
+        let mut current_message: &Message = &self.message;
         let mut current_boundary = "";
         let mut message = Vec::with_capacity(self.message_size);
         let mut iter = [0].iter();
@@ -462,9 +546,14 @@ impl<'x, C> Context<'x, C> {
                             ct_pos = header_pos;
                         }
 
-                        message.extend_from_slice(header.name.as_str().as_bytes());
+                        let header_name = header.name.as_str();
+                        message.extend_from_slice(header_name.as_bytes());
                         message.extend_from_slice(b": ");
-                        message.extend_from_slice(header.value.as_text().unwrap_or("").as_bytes());
+                        fold_header_value(
+                            &mut message,
+                            header_name.len() + 2,
+                            header.value.as_text().unwrap_or(""),
+                        );
                         message.extend_from_slice(b"\r\n");
                     }
                 }
@@ -579,6 +668,22 @@ impl<'x, C> Context<'x, C> {
     }
 }
 
+fn fold_header_value(message: &mut Vec<u8>, mut line_len: usize, value: &str) {
+    // Fold long inserted headers at whitespace boundaries (RFC 5322, section 2.2.3)
+    for (pos, word) in value.split(' ').enumerate() {
+        if pos > 0 {
+            if !word.is_empty() && line_len + word.len() + 1 > 78 {
+                message.extend_from_slice(b"\r\n");
+                line_len = 0;
+            }
+            message.push(b' ');
+            line_len += 1;
+        }
+        message.extend_from_slice(word.as_bytes());
+        line_len += word.len();
+    }
+}
+
 #[cfg(test)]
 thread_local!(static COUNTER: std::cell::Cell<u64>  = 0.into());
 
@@ -591,3 +696,49 @@ pub(crate) fn make_test_boundary() -> String {
 pub(crate) fn reset_test_boundary() {
     COUNTER.with(|c| c.replace(0));
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Compiler, Event, Input, Modification, Runtime};
+
+    #[test]
+    fn preview_modifications() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["editheader", "mime", "replace"];
+                deleteheader "X-Missing";
+                deleteheader "X-Spam";
+                replace "Replaced body";"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new().with_preview_modifications(true);
+        let message = b"Subject: test\r\nX-Spam: yes\r\n\r\nbody\r\n";
+
+        let mut instance = runtime.filter(message);
+        let mut input = Input::script("", script);
+        let mut previews = Vec::new();
+        while let Some(event) = instance.run(input) {
+            input = match event.unwrap() {
+                Event::PreviewModification {
+                    modification,
+                    before,
+                    after,
+                } => {
+                    assert_ne!(before, after);
+                    previews.push(modification);
+                    // Only header deletions are allowed
+                    (modification == Modification::DeleteHeader).into()
+                }
+                _ => Input::True,
+            };
+        }
+
+        assert_eq!(
+            previews,
+            [Modification::DeleteHeader, Modification::Replace]
+        );
+        let message = String::from_utf8(instance.build_modified_message()).unwrap();
+        assert!(!message.contains("X-Spam"), "{message}");
+        assert!(!message.contains("Replaced body"), "{message}");
+    }
+}
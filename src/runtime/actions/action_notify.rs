@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
+use mail_builder::headers::date::Date;
 use mail_parser::{decoders::quoted_printable::HEX_MAP, HeaderName};
 
 use crate::{
@@ -29,6 +29,7 @@ use crate::{
         action_notify::Notify,
         action_redirect::{ByTime, Ret},
     },
+    runtime::platform::{unix_timestamp, write_message_id},
     Context, Event, Importance, Recipient,
 };
 
@@ -37,15 +38,10 @@ use super::action_vacation::MAX_SUBJECT_LEN;
 impl Notify {
     pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) {
         // Do not notify on Auto-Submitted messages
-        for header in &ctx.message.parts[0].headers {
-            if matches!(&header.name, HeaderName::Other(name) if name.eq_ignore_ascii_case("Auto-Submitted"))
-                && header
-                    .value
-                    .as_text()
-                    .map_or(true, |v| !v.eq_ignore_ascii_case("no"))
-            {
-                return;
-            }
+        if (ctx.runtime.loop_options.auto_submitted && ctx.is_auto_submitted(&[]))
+            || ctx.is_looping()
+        {
+            return;
         }
 
         let uri = ctx.eval_value(&self.method).to_string().into_owned();
@@ -55,6 +51,9 @@ impl Notify {
             return;
         };
 
+        let importance = self.importance.as_ref().map_or(Importance::Normal, |i| {
+            Importance::from_level(ctx.eval_value(i).to_string().as_ref())
+        });
         let has_fcc = self.fcc.is_some();
         let is_mailto = scheme.eq_ignore_ascii_case("mailto")
             && ctx.num_out_messages < ctx.runtime.max_out_messages;
@@ -161,32 +160,26 @@ impl Notify {
 
             if !has_date {
                 message.extend_from_slice(b"Date: ");
-                message.extend_from_slice(Date::now().to_rfc822().as_bytes());
+                message.extend_from_slice(Date::new(unix_timestamp()).to_rfc822().as_bytes());
                 message.extend_from_slice(b"\r\n");
             }
 
             if !has_message_id {
                 message.extend_from_slice(b"Message-ID: ");
-                generate_message_id_header(&mut message, &ctx.runtime.local_hostname).unwrap();
+                write_message_id(&mut message, &ctx.runtime.local_hostname);
                 message.extend_from_slice(b"\r\n");
             }
 
-            let (importance, priority) =
-                self.importance
-                    .as_ref()
-                    .map_or(("Normal", "3 (Normal)"), |i| {
-                        match ctx.eval_value(i).to_string().as_ref() {
-                            "1" => ("High", "1 (High)"),
-                            "3" => ("Low", "5 (Low)"),
-                            _ => ("Normal", "3 (Normal)"),
-                        }
-                    });
             message.extend_from_slice(b"Importance: ");
-            message.extend_from_slice(importance.as_bytes());
+            message.extend_from_slice(importance.as_importance_header().as_bytes());
             message.extend_from_slice(b"\r\n");
 
             message.extend_from_slice(b"X-Priority: ");
-            message.extend_from_slice(priority.as_bytes());
+            message.extend_from_slice(importance.as_x_priority_header().as_bytes());
+            message.extend_from_slice(b"\r\n");
+
+            message.extend_from_slice(b"Priority: ");
+            message.extend_from_slice(importance.as_priority_header().as_bytes());
             message.extend_from_slice(b"\r\n");
 
             message.extend_from_slice(b"Subject: ");
@@ -266,13 +259,8 @@ impl Notify {
                     .from
                     .as_ref()
                     .map(|f| ctx.eval_value(f).to_string().into_owned()),
-                importance: self.importance.as_ref().map_or(Importance::Normal, |i| {
-                    match ctx.eval_value(i).to_string().as_ref() {
-                        "1" => Importance::High,
-                        "3" => Importance::Low,
-                        _ => Importance::Normal,
-                    }
-                }),
+                importance,
+                importance_level: importance.level(),
                 options: ctx.eval_values_owned(&self.options),
                 message: self
                     .message
@@ -287,7 +275,8 @@ impl Notify {
         if let Some(fcc) = &self.fcc {
             // File carbon copy
             events.push(Event::FileInto {
-                folder: ctx.eval_value(&fcc.mailbox).to_string().into_owned(),
+                folder: ctx
+                    .normalize_mailbox(ctx.eval_value(&fcc.mailbox).to_string().into_owned()),
                 flags: ctx.get_local_flags(&fcc.flags),
                 mailbox_id: fcc
                     .mailbox_id
@@ -305,6 +294,49 @@ impl Notify {
     }
 }
 
+impl Importance {
+    pub fn from_level(level: &str) -> Self {
+        match level.trim() {
+            "1" => Importance::High,
+            "3" => Importance::Low,
+            _ => Importance::Normal,
+        }
+    }
+
+    /// Numeric level as defined in RFC 5435: 1 (high), 2 (normal) or 3 (low).
+    pub fn level(&self) -> u8 {
+        match self {
+            Importance::High => 1,
+            Importance::Normal => 2,
+            Importance::Low => 3,
+        }
+    }
+
+    pub fn as_importance_header(&self) -> &'static str {
+        match self {
+            Importance::High => "High",
+            Importance::Normal => "Normal",
+            Importance::Low => "Low",
+        }
+    }
+
+    pub fn as_x_priority_header(&self) -> &'static str {
+        match self {
+            Importance::High => "1 (High)",
+            Importance::Normal => "3 (Normal)",
+            Importance::Low => "5 (Low)",
+        }
+    }
+
+    pub fn as_priority_header(&self) -> &'static str {
+        match self {
+            Importance::High => "urgent",
+            Importance::Normal => "normal",
+            Importance::Low => "non-urgent",
+        }
+    }
+}
+
 pub fn validate_from(addr: &str) -> bool {
     let mut has_at = false;
     let mut has_dot = false;
@@ -365,6 +397,79 @@ pub fn validate_uri(uri: &str) -> Option<&str> {
     }
 }
 
+/// Compile time validator for `mailto` URIs, see
+/// [`crate::Compiler::set_notify_uri_validator`].
+pub fn validate_mailto_uri(uri: &str) -> Result<(), String> {
+    match parse_uri(uri) {
+        Some((scheme, params)) if scheme.eq_ignore_ascii_case("mailto") => parse_mailto(params)
+            .map(|_| ())
+            .ok_or_else(|| "invalid recipients or header fields".to_string()),
+        _ => Err("expected a mailto URI".to_string()),
+    }
+}
+
+/// Compile time validator for `xmpp` URIs (RFC 5122).
+pub fn validate_xmpp_uri(uri: &str) -> Result<(), String> {
+    let jid = match parse_uri(uri) {
+        Some((scheme, jid)) if scheme.eq_ignore_ascii_case("xmpp") => {
+            jid.split_once('?').map_or(jid, |(jid, _)| jid)
+        }
+        _ => return Err("expected an xmpp URI".to_string()),
+    };
+    // Skip the optional authority component
+    let jid = if let Some(authority) = jid.strip_prefix("//") {
+        authority.split_once('/').map_or("", |(_, jid)| jid)
+    } else {
+        jid
+    };
+    let (node, domain) = jid
+        .split_once('@')
+        .map_or((None, jid), |(node, domain)| (Some(node), domain));
+    let domain = domain.split_once('/').map_or(domain, |(domain, _)| domain);
+
+    if jid.chars().any(|ch| ch.is_whitespace()) {
+        Err("invalid whitespace in address".to_string())
+    } else if node == Some("") {
+        Err("empty node identifier".to_string())
+    } else if domain.is_empty() {
+        Err("missing domain".to_string())
+    } else if domain.contains('@') {
+        Err("invalid domain".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Compile time validator for `tel` URIs (RFC 3966).
+pub fn validate_tel_uri(uri: &str) -> Result<(), String> {
+    let (number, params) = match parse_uri(uri) {
+        Some((scheme, number)) if scheme.eq_ignore_ascii_case("tel") => {
+            number.split_once(';').unwrap_or((number, ""))
+        }
+        _ => return Err("expected a tel URI".to_string()),
+    };
+    let (is_global, digits) = number
+        .strip_prefix('+')
+        .map_or((false, number), |digits| (true, digits));
+
+    if !digits.bytes().any(|ch| ch.is_ascii_digit()) {
+        Err("missing phone number".to_string())
+    } else if let Some(ch) = digits
+        .chars()
+        .find(|ch| !ch.is_ascii_hexdigit() && !['-', '.', '(', ')', '*', '#'].contains(ch))
+    {
+        Err(format!("invalid character {ch:?} in phone number"))
+    } else if !is_global
+        && !params
+            .split(';')
+            .any(|param| param.to_ascii_lowercase().starts_with("phone-context="))
+    {
+        Err("local numbers require a phone-context".to_string())
+    } else {
+        Ok(())
+    }
+}
+
 pub(crate) fn parse_uri(uri: &str) -> Option<(&str, &str)> {
     let (scheme, uri) = uri.split_once(':')?;
 
@@ -598,3 +703,142 @@ fn insert_address(params: &mut MailtoMessage, name: HeaderName, value: String) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::ErrorType, runtime::RuntimeError, Compiler, Event, Importance, Runtime};
+
+    use super::{validate_mailto_uri, validate_tel_uri, validate_xmpp_uri};
+
+    #[test]
+    fn notify_uri_validators() {
+        for (uri, is_valid) in [
+            ("xmpp:romeo@example.net", true),
+            ("xmpp:example.net?message", true),
+            ("xmpp://guest@example.com/support@example.com", true),
+            ("xmpp:@example.net", false),
+            ("xmpp:romeo@", false),
+            ("mailto:romeo@example.net", false),
+        ] {
+            assert_eq!(validate_xmpp_uri(uri).is_ok(), is_valid, "{uri}");
+        }
+        for (uri, is_valid) in [
+            ("tel:+1-201-555-0123", true),
+            ("tel:7042;phone-context=example.com", true),
+            ("tel:7042", false),
+            ("tel:+1-201-CALL-NOW", false),
+            ("tel:+", false),
+        ] {
+            assert_eq!(validate_tel_uri(uri).is_ok(), is_valid, "{uri}");
+        }
+        assert!(validate_mailto_uri("mailto:romeo@example.net?subject=hi").is_ok());
+        assert!(validate_mailto_uri("xmpp:romeo@example.net").is_err());
+
+        let compiler = Compiler::new()
+            .with_notify_uri_validator("TEL", validate_tel_uri)
+            .with_notify_uri_validator("sms", |uri| {
+                if uri.len() > 8 {
+                    Ok(())
+                } else {
+                    Err("number too short".to_string())
+                }
+            });
+        let script = |uri: &str| format!("require \"enotify\";\nnotify \"{uri}\";");
+
+        assert!(Compiler::new()
+            .compile(script("sms:+15550123").as_bytes())
+            .is_err());
+        assert!(compiler.compile(script("sms:+15550123").as_bytes()).is_ok());
+        assert!(compiler
+            .compile(script("tel:+1-201-555-0123").as_bytes())
+            .is_ok());
+        assert!(compiler
+            .compile(script("xmpp:romeo@example.net").as_bytes())
+            .is_ok());
+        for uri in ["sms:+1", "tel:555-0123"] {
+            let err = compiler.compile(script(uri).as_bytes()).unwrap_err();
+            assert!(
+                matches!(err.error_type(), ErrorType::InvalidNotifyUri { uri: uri_, .. } if uri_ == uri),
+                "{err:?}"
+            );
+            assert_eq!(err.line_num(), 2);
+        }
+    }
+
+    #[test]
+    fn notify_importance() {
+        let runtime = Runtime::new().with_valid_notification_uri("xmpp");
+        let raw_message = b"From: sender@example.com\r\nSubject: Hello\r\n\r\nHi";
+
+        for (level, importance, headers) in [
+            ("1", Importance::High, ["High", "1 (High)", "urgent"]),
+            ("2", Importance::Normal, ["Normal", "3 (Normal)", "normal"]),
+            ("3", Importance::Low, ["Low", "5 (Low)", "non-urgent"]),
+        ] {
+            for method in ["mailto:user@example.org", "xmpp:user@example.org"] {
+                let script =
+                    format!("require \"enotify\";\nnotify :importance \"{level}\" \"{method}\";");
+                let mut found = false;
+                for event in runtime
+                    .filter(raw_message)
+                    .run_test_events(script.as_bytes())
+                {
+                    match event {
+                        Event::CreatedMessage { message, .. } => {
+                            let text = std::str::from_utf8(&message).unwrap();
+                            for (name, value) in
+                                ["Importance", "X-Priority", "Priority"].iter().zip(headers)
+                            {
+                                assert!(
+                                    text.contains(&format!("\r\n{name}: {value}\r\n")),
+                                    "{text}"
+                                );
+                            }
+                            found = true;
+                        }
+                        Event::Notify {
+                            importance: importance_,
+                            importance_level,
+                            ..
+                        } => {
+                            assert_eq!(importance_, importance);
+                            assert_eq!(importance_level.to_string(), level);
+                            found = true;
+                        }
+                        _ => (),
+                    }
+                }
+                assert!(found, "{method} {level}");
+            }
+        }
+    }
+
+    #[test]
+    fn generated_message_limit() {
+        let runtime = Runtime::new()
+            .with_valid_notification_uri("xmpp")
+            .with_max_generated_messages(2);
+        let mut instance = runtime.filter(b"Subject: Hello\r\n\r\nHi");
+        let events = instance.run_test_script(
+            br#"require "enotify";
+notify "xmpp:a@example.org";
+notify "xmpp:b@example.org";
+notify "xmpp:c@example.org";
+"#,
+        );
+
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    Ok(Event::Notify { method: a, .. }),
+                    Ok(Event::Notify { method: b, .. }),
+                    Err(RuntimeError::GeneratedMessageLimitReached),
+                    Ok(Event::Keep { .. })
+                ] if a == "xmpp:a@example.org" && b == "xmpp:b@example.org"
+            ),
+            "{events:?}"
+        );
+        assert_eq!(instance.generated_messages(), 2);
+    }
+}
@@ -25,13 +25,32 @@ use mail_parser::{DateTime, HeaderName};
 
 use crate::{
     compiler::grammar::actions::action_redirect::{ByTime, Redirect},
-    Context, Envelope, Event, Recipient,
+    runtime::RuntimeError,
+    Context, Envelope, Event, Recipient, RedirectAction,
 };
 
 impl Redirect {
-    pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) {
-        if let Some(address) = sanitize_address(ctx.eval_value(&self.address).to_string().as_ref())
+    pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) -> Result<(), RuntimeError> {
+        if let Some(mut address) =
+            sanitize_address(ctx.eval_value(&self.address).to_string().as_ref())
         {
+            if let (false, Some(policy)) = (self.list, ctx.runtime.redirect_policy) {
+                match policy(&address) {
+                    RedirectAction::Allow => (),
+                    RedirectAction::Rewrite(rewritten) => match sanitize_address(&rewritten) {
+                        Some(rewritten) => address = rewritten,
+                        None => return Err(RuntimeError::RedirectNotAllowed(address)),
+                    },
+                    RedirectAction::Deny => return Err(RuntimeError::RedirectNotAllowed(address)),
+                }
+            }
+
+            if (ctx.runtime.loop_options.redirect_auto_submitted && ctx.is_auto_submitted(&[]))
+                || ctx.is_looping()
+            {
+                return Ok(());
+            }
+
             if ctx.num_redirects < ctx.runtime.max_redirects
                 && ctx.num_out_messages < ctx.runtime.max_out_messages
                 && ctx.message.parts[0]
@@ -43,13 +62,14 @@ impl Redirect {
             {
                 // Try to avoid forwarding loops
                 if !self.list
+                    && ctx.runtime.loop_options.self_redirect
                     && (address.eq_ignore_ascii_case(ctx.user_address.as_ref())
                         || ctx.envelope.iter().any(|(e, v)| {
                             matches!(e, Envelope::From)
                                 && v.to_string().eq_ignore_ascii_case(address.as_str())
                         }))
                 {
-                    return;
+                    return Ok(());
                 }
 
                 if !self.copy && matches!(&ctx.final_event, Some(Event::Keep { .. })) {
@@ -106,6 +126,7 @@ impl Redirect {
                 ctx.queued_events = events.into_iter();
             }
         }
+        Ok(())
     }
 }
 
@@ -158,3 +179,65 @@ pub(crate) fn sanitize_address(addr: &str) -> Option<String> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::ErrorType, runtime::RuntimeError, Compiler, Event, Recipient, RedirectAction,
+        RedirectPolicy, Runtime,
+    };
+
+    #[test]
+    fn redirect_policy() {
+        let compiler = Compiler::new().with_redirect_domains(["example.org"]);
+        for (script, allowed) in [
+            ("redirect \"jdoe@example.org\";", true),
+            ("redirect \"jdoe@lists.EXAMPLE.org\";", true),
+            ("redirect \"jdoe@badexample.org\";", false),
+            ("redirect \"jdoe@example.org.evil.com\";", false),
+            ("require \"variables\"; redirect \"${0}\";", true),
+        ] {
+            match compiler.compile(script.as_bytes()) {
+                Ok(_) => assert!(allowed, "{script}"),
+                Err(err) => {
+                    assert!(!allowed, "{script}");
+                    assert!(matches!(err.error_type(), ErrorType::RedirectNotAllowed(_)));
+                }
+            }
+        }
+
+        for (policy, expected) in [
+            (
+                (|_: &str| RedirectAction::Allow) as RedirectPolicy,
+                Ok("jdoe@example.com"),
+            ),
+            (
+                (|address: &str| RedirectAction::Rewrite(address.replace(".com", ".org")))
+                    as RedirectPolicy,
+                Ok("jdoe@example.org"),
+            ),
+            (
+                (|_: &str| RedirectAction::Deny) as RedirectPolicy,
+                Err("jdoe@example.com"),
+            ),
+        ] {
+            let runtime = Runtime::new().with_redirect_policy(policy);
+            let result = runtime
+                .filter(b"Subject: test\r\n\r\nbody")
+                .run_test_script(b"redirect \"jdoe@example.com\";")
+                .into_iter()
+                .find_map(|event| match event {
+                    Ok(Event::SendMessage {
+                        recipient: Recipient::Address(address),
+                        ..
+                    }) => Some(Ok(address)),
+                    Err(RuntimeError::RedirectNotAllowed(address)) => Some(Err(address)),
+                    _ => None,
+                });
+            assert_eq!(
+                result,
+                Some(expected.map(String::from).map_err(String::from))
+            );
+        }
+    }
+}
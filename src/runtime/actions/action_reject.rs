@@ -0,0 +1,284 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::hash::BuildHasher;
+
+use mail_builder::headers::date::Date;
+use mail_parser::HeaderName;
+
+use crate::{
+    runtime::platform::{unix_timestamp, write_message_id},
+    Context, Envelope,
+};
+
+impl<'x, C> Context<'x, C> {
+    /// Builds the message sent to the envelope sender of a rejected message
+    /// when the rejection cannot be done at the SMTP level. This is a delivery
+    /// status notification (RFC 3464) for `ereject`, and a message disposition
+    /// notification (RFC 8098) for `reject`, including the refusal reason and
+    /// the headers of the original message. Returns `None` when the message
+    /// has no envelope sender, as bounces must not be sent to a null path.
+    pub fn build_reject_message(&self, extended: bool, reason: &str) -> Option<Vec<u8>> {
+        let envelope = |name: Envelope| {
+            self.envelope
+                .iter()
+                .rev()
+                .find(|(n, v)| n == &name && !v.is_empty())
+                .map(|(_, v)| v.to_string().into_owned())
+        };
+        let sender = envelope(Envelope::From)?;
+        let recipient = envelope(Envelope::To)
+            .or_else(|| (!self.user_address.is_empty()).then(|| self.user_address.to_string()))
+            .unwrap_or_default();
+        let part = self.message.parts.first()?;
+        let original_headers = self
+            .message
+            .raw_message
+            .get(part.offset_header..part.offset_body)
+            .unwrap_or_default();
+        let subject = part
+            .headers
+            .iter()
+            .find(|h| h.name == HeaderName::Subject)
+            .and_then(|h| h.value.as_text())
+            .unwrap_or_default();
+        let boundary = format!(
+            "reject_{:x}_{:x}",
+            unix_timestamp(),
+            ahash::RandomState::new().hash_one(&sender)
+        );
+
+        let mut message = Vec::with_capacity(original_headers.len() + reason.len() + 1024);
+        if extended {
+            write_header(
+                &mut message,
+                "From: ",
+                &format!(
+                    "Mail Delivery System <MAILER-DAEMON@{}>",
+                    self.runtime.local_hostname
+                ),
+            );
+            write_header(
+                &mut message,
+                "Subject: ",
+                "Undelivered Mail Returned to Sender",
+            );
+        } else {
+            write_header(
+                &mut message,
+                "From: ",
+                &if !self.user_address.is_empty() {
+                    self.user_from_field()
+                } else if !recipient.is_empty() {
+                    recipient.clone()
+                } else {
+                    format!("MAILER-DAEMON@{}", self.runtime.local_hostname)
+                },
+            );
+            write_header(
+                &mut message,
+                "Subject: ",
+                &format!("Rejected: {}", subject.replace(['\r', '\n'], " ")),
+            );
+        }
+        write_header(&mut message, "To: ", &format!("<{sender}>"));
+        if let Some(message_id) = self.message.message_id() {
+            write_header(&mut message, "In-Reply-To: ", &format!("<{message_id}>"));
+        }
+        message.extend_from_slice(b"Date: ");
+        message.extend_from_slice(Date::new(unix_timestamp()).to_rfc822().as_bytes());
+        message.extend_from_slice(b"\r\n");
+        message.extend_from_slice(b"Message-ID: ");
+        write_message_id(&mut message, &self.runtime.local_hostname);
+        message.extend_from_slice(b"\r\n");
+        write_header(&mut message, "Auto-Submitted: ", "auto-replied (rejected)");
+        write_header(&mut message, "MIME-Version: ", "1.0");
+        write_header(
+            &mut message,
+            "Content-Type: ",
+            &format!(
+                "multipart/report; report-type={}; boundary=\"{boundary}\"",
+                if extended {
+                    "delivery-status"
+                } else {
+                    "disposition-notification"
+                }
+            ),
+        );
+        message.extend_from_slice(b"\r\n");
+
+        // Human readable part
+        write_boundary(&mut message, &boundary);
+        write_header(&mut message, "Content-Type: ", "text/plain; charset=utf-8");
+        write_header(&mut message, "Content-Transfer-Encoding: ", "8bit");
+        message.extend_from_slice(b"\r\n");
+        if extended {
+            message.extend_from_slice(
+                format!("Your message to <{recipient}> was rejected by the recipient:\r\n\r\n")
+                    .as_bytes(),
+            );
+        } else {
+            message.extend_from_slice(
+                format!("Your message to <{recipient}> was automatically rejected:\r\n\r\n")
+                    .as_bytes(),
+            );
+        }
+        message.extend_from_slice(reason.as_bytes());
+        message.extend_from_slice(b"\r\n");
+
+        // Machine readable part
+        write_boundary(&mut message, &boundary);
+        if extended {
+            write_header(&mut message, "Content-Type: ", "message/delivery-status");
+            message.extend_from_slice(b"\r\n");
+            write_header(
+                &mut message,
+                "Reporting-MTA: dns;",
+                &self.runtime.local_hostname,
+            );
+            message.extend_from_slice(b"\r\n");
+            write_header(&mut message, "Final-Recipient: rfc822;", &recipient);
+            write_header(&mut message, "Action: ", "failed");
+            write_header(&mut message, "Status: ", "5.7.1");
+            write_header(
+                &mut message,
+                "Diagnostic-Code: smtp; 550 5.7.1 ",
+                &reason.replace(['\r', '\n'], " "),
+            );
+        } else {
+            write_header(
+                &mut message,
+                "Content-Type: ",
+                "message/disposition-notification",
+            );
+            message.extend_from_slice(b"\r\n");
+            write_header(
+                &mut message,
+                "Reporting-UA: ",
+                &format!("{}; Stalwart Sieve", self.runtime.local_hostname),
+            );
+            write_header(&mut message, "Final-Recipient: rfc822;", &recipient);
+            if let Some(message_id) = self.message.message_id() {
+                write_header(
+                    &mut message,
+                    "Original-Message-ID: ",
+                    &format!("<{message_id}>"),
+                );
+            }
+            write_header(
+                &mut message,
+                "Disposition: ",
+                "automatic-action/MDN-sent-automatically; deleted",
+            );
+        }
+
+        // Original headers
+        write_boundary(&mut message, &boundary);
+        write_header(&mut message, "Content-Type: ", "text/rfc822-headers");
+        message.extend_from_slice(b"\r\n");
+        message.extend_from_slice(original_headers);
+        if !original_headers.ends_with(b"\n") {
+            message.extend_from_slice(b"\r\n");
+        }
+        message.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        Some(message)
+    }
+}
+
+fn write_header(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(value.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn write_boundary(buf: &mut Vec<u8>, boundary: &str) {
+    buf.extend_from_slice(b"\r\n--");
+    buf.extend_from_slice(boundary.as_bytes());
+    buf.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use mail_parser::MessageParser;
+
+    use crate::{Envelope, Event, Runtime};
+
+    #[test]
+    fn reject_message() {
+        let runtime = Runtime::new()
+            .with_reject_message(true)
+            .with_local_hostname("mx.example.org");
+        let raw_message =
+            b"From: sender@example.com\r\nSubject: Offer\r\nMessage-ID: <1@example.com>\r\n\r\nBuy!";
+
+        for (script, report_type, field) in [
+            (
+                "require \"ereject\";\nereject \"No offers\";",
+                "delivery-status",
+                "Status: 5.7.1",
+            ),
+            (
+                "require \"reject\";\nreject \"No offers\";",
+                "disposition-notification",
+                "Disposition: automatic-action/MDN-sent-automatically; deleted",
+            ),
+        ] {
+            let bounce = runtime
+                .filter(raw_message)
+                .with_envelope(Envelope::From, "sender@example.com")
+                .with_envelope(Envelope::To, "user@example.org")
+                .run_test_events(script.as_bytes())
+                .into_iter()
+                .find_map(|event| match event {
+                    Event::Reject { message, .. } => message,
+                    _ => None,
+                })
+                .expect("missing reject message");
+            let text = std::str::from_utf8(&bounce).unwrap();
+            assert!(
+                text.contains(&format!("report-type={report_type};")),
+                "{text}"
+            );
+            assert!(text.contains(field), "{text}");
+            assert!(text.contains("No offers"), "{text}");
+            assert!(text.contains("Auto-Submitted: auto-replied"), "{text}");
+            assert!(
+                text.contains("Final-Recipient: rfc822;user@example.org"),
+                "{text}"
+            );
+
+            let parsed = MessageParser::new().parse(&bounce).unwrap();
+            assert_eq!(
+                parsed.to().unwrap().first().unwrap().address(),
+                Some("sender@example.com")
+            );
+            assert_eq!(parsed.parts.len(), 4);
+            assert!(text.contains("Subject: Offer\r\n"), "{text}");
+        }
+
+        // No bounces to the null sender
+        let instance = runtime.filter(raw_message);
+        assert_eq!(instance.build_reject_message(true, "No offers"), None);
+    }
+}
@@ -29,7 +29,7 @@ use crate::{
     runtime::Variable,
     Context, Event,
 };
-use std::fmt::Write;
+use std::{fmt::Write, sync::Arc};
 
 impl Set {
     pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) {
@@ -59,14 +59,18 @@ impl<'x, C> Context<'x, C> {
         match var_name {
             VariableType::Local(var_id) => {
                 if let Some(var) = self.vars_local.get_mut(*var_id) {
+                    let old_len = var.len();
                     *var = variable.clone();
+                    self.vars_size = (self.vars_size + var.len()).saturating_sub(old_len);
                 } else {
                     debug_assert!(false, "Non-existent local variable {var_id}");
                 }
             }
             VariableType::Global(var_name) => {
-                self.vars_global
-                    .insert(var_name.to_string().into(), variable.clone());
+                let old_len = Arc::make_mut(&mut self.vars_global)
+                    .insert(var_name.to_string().into(), variable.clone())
+                    .map_or(0, |var| var.len());
+                self.vars_size = (self.vars_size + variable.len()).saturating_sub(old_len);
             }
             VariableType::Envelope(env) => {
                 self.queued_events = vec![Event::SetEnvelope {
@@ -23,7 +23,7 @@
 
 use std::borrow::Cow;
 
-use mail_builder::headers::{date::Date, message_id::generate_message_id_header};
+use mail_builder::headers::date::Date;
 use mail_parser::{HeaderName, HeaderValue};
 
 use crate::{
@@ -34,8 +34,11 @@ use crate::{
         },
         AddressPart,
     },
-    runtime::tests::TestResult,
-    Context, Envelope, Event, Recipient,
+    runtime::{
+        platform::{unix_timestamp, write_message_id},
+        tests::{glob::GlobPattern, test_envelope::orcpt_address, TestResult},
+    },
+    Context, Envelope, Event, Recipient, Subaddress,
 };
 
 pub(crate) const MAX_SUBJECT_LEN: usize = 256;
@@ -62,7 +65,9 @@ impl TestVacation {
                     }
                     Envelope::Orcpt => {
                         if ctx.runtime.vacation_use_orig_rcpt {
-                            user_addresses.push(value.to_string());
+                            if let Some(addr) = orcpt_address(value.to_string().as_ref()) {
+                                user_addresses.push(addr.to_string().into());
+                            }
                         }
                     }
                     _ => (),
@@ -71,23 +76,50 @@ impl TestVacation {
         }
 
         // Add user specified addresses
+        let mut address_patterns = Vec::new();
         for address in &self.addresses {
             let address = ctx.eval_value(address).to_string().into_owned();
-            if !address.is_empty() {
-                user_addresses.push(address.into());
+            if address.is_empty() {
+                continue;
             }
+            if ctx.runtime.vacation_address_wildcards {
+                if let Some(domain) = address.strip_prefix('@') {
+                    address_patterns.push(GlobPattern::compile(&format!("*@{domain}"), true));
+                    continue;
+                } else if address.contains(['*', '?']) {
+                    address_patterns.push(GlobPattern::compile(&address, true));
+                    continue;
+                }
+            }
+            user_addresses.push(address.into());
         }
         if !ctx.user_address.is_empty() {
             user_addresses.push(ctx.user_address.as_ref().into());
         }
+        if ctx.runtime.vacation_ignore_subaddress {
+            for address in user_addresses.iter_mut() {
+                if let Some(address_) = ctx.remove_subaddress(address) {
+                    *address = address_.into();
+                }
+            }
+        }
 
         // Do not reply to own address
         if from.is_empty()
-            || user_addresses.is_empty()
+            || (user_addresses.is_empty() && address_patterns.is_empty())
             || from.starts_with("mailer-daemon")
             || from.starts_with("owner-")
             || from.contains("-request@")
-            || user_addresses.iter().any(|a| a.eq_ignore_ascii_case(&from))
+            || user_addresses
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(&from) || ctx.is_subaddress_of(&from, a))
+        {
+            return TestResult::Bool(false);
+        }
+
+        // Do not reply to automatically generated messages
+        if (ctx.runtime.loop_options.auto_submitted && ctx.is_auto_submitted(&["bulk"]))
+            || ctx.is_looping()
         {
             return TestResult::Bool(false);
         }
@@ -106,7 +138,10 @@ impl TestVacation {
                     if !found_rcpt =>
                 {
                     found_rcpt = ctx.find_addresses(header, &AddressPart::All, |addr| {
-                        user_addresses.iter().any(|a| a.eq_ignore_ascii_case(addr))
+                        user_addresses
+                            .iter()
+                            .any(|a| a.eq_ignore_ascii_case(addr) || ctx.is_subaddress_of(addr, a))
+                            || address_patterns.iter().any(|p| p.matches(addr))
                     });
                 }
                 HeaderName::ListArchive
@@ -122,29 +157,15 @@ impl TestVacation {
                 HeaderName::Received => {
                     received_count += 1;
                 }
-                HeaderName::Other(header_name) => {
-                    if header_name.eq_ignore_ascii_case("Auto-Submitted") {
-                        if header
-                            .value
-                            .as_text()
-                            .map_or(true, |v| !v.eq_ignore_ascii_case("no"))
-                        {
-                            return TestResult::Bool(false);
-                        }
-                    } else if header_name.eq_ignore_ascii_case("X-Auto-Response-Suppress") {
-                        if header.value.as_text().map_or(false, |v| {
-                            v.to_ascii_lowercase()
-                                .split(',')
-                                .any(|v| ["all", "oof"].contains(&v.trim()))
-                        }) {
-                            return TestResult::Bool(false);
-                        }
-                    } else if header_name.eq_ignore_ascii_case("Precedence")
-                        && header
-                            .value
-                            .as_text()
-                            .map_or(false, |v| v.eq_ignore_ascii_case("bulk"))
-                    {
+                HeaderName::Other(header_name)
+                    if ctx.runtime.loop_options.auto_submitted
+                        && header_name.eq_ignore_ascii_case("X-Auto-Response-Suppress") =>
+                {
+                    if header.value.as_text().map_or(false, |v| {
+                        v.to_ascii_lowercase()
+                            .split(',')
+                            .any(|v| ["all", "oof"].contains(&v.trim()))
+                    }) {
                         return TestResult::Bool(false);
                     }
                 }
@@ -154,13 +175,17 @@ impl TestVacation {
 
         // No user address found in header or possible loop
         if found_rcpt && received_count <= ctx.runtime.max_received_headers {
+            let id = vacation_id(
+                &from,
+                ctx.eval_value(self.handle.as_ref().unwrap_or(&self.reason))
+                    .to_string()
+                    .as_ref(),
+            );
+            ctx.vacation_id = Some(id.clone());
+
             TestResult::Event {
                 event: Event::DuplicateId {
-                    id: if let Some(handle) = &self.handle {
-                        format!("_v{}{}", from, ctx.eval_value(handle).to_string())
-                    } else {
-                        format!("_v{}{}", from, ctx.eval_value(&self.reason).to_string())
-                    },
+                    id,
                     expiry: match &self.period {
                         Period::Days(days) => days * 86400,
                         Period::Seconds(seconds) => *seconds,
@@ -303,11 +328,11 @@ impl Vacation {
             }
         }
         message.extend_from_slice(b"Date: ");
-        message.extend_from_slice(Date::now().to_rfc822().as_bytes());
+        message.extend_from_slice(Date::new(unix_timestamp()).to_rfc822().as_bytes());
         message.extend_from_slice(b"\r\n");
 
         message.extend_from_slice(b"Message-ID: ");
-        generate_message_id_header(&mut message, &ctx.runtime.local_hostname).unwrap();
+        write_message_id(&mut message, &ctx.runtime.local_hostname);
         message.extend_from_slice(b"\r\n");
 
         write_header(&mut message, "Auto-Submitted: ", "auto-replied");
@@ -335,7 +360,8 @@ impl Vacation {
         // File carbon copy
         if let Some(fcc) = &self.fcc {
             events.push(Event::FileInto {
-                folder: ctx.eval_value(&fcc.mailbox).to_string().into_owned(),
+                folder: ctx
+                    .normalize_mailbox(ctx.eval_value(&fcc.mailbox).to_string().into_owned()),
                 flags: ctx.get_local_flags(&fcc.flags),
                 mailbox_id: fcc
                     .mailbox_id
@@ -358,3 +384,87 @@ fn write_header(buf: &mut Vec<u8>, name: &str, value: &str) {
     buf.extend_from_slice(value.as_bytes());
     buf.extend_from_slice(b"\r\n");
 }
+
+impl<'x, C> Context<'x, C> {
+    /// Returns the id the vacation action uses to track the responses sent
+    /// to the envelope sender of this message, without running a script.
+    /// The `handle` is the `:handle` argument or, when not specified, the
+    /// reason.
+    pub fn compute_vacation_id(&self, handle: &str) -> Option<String> {
+        self.envelope
+            .iter()
+            .rev()
+            .find(|(name, value)| name == &Envelope::From && !value.is_empty())
+            .map(|(_, from)| vacation_id(&from.to_string().to_ascii_lowercase(), handle))
+    }
+
+    /// Returns the id of the last response tracked by the vacation action,
+    /// which is also the id of its [`Event::DuplicateId`].
+    pub fn vacation_id(&self) -> Option<&str> {
+        self.vacation_id.as_deref()
+    }
+
+    // Removes the detail part of an address, using the separators configured
+    // for its domain or "+" otherwise
+    fn remove_subaddress(&self, address: &str) -> Option<String> {
+        let (local_part, domain) = address.rsplit_once('@')?;
+        let user = match self.subaddress.get(&domain.to_lowercase()) {
+            Some(Subaddress::Separators(separators)) => {
+                local_part.split_once(separators.as_slice())?.0
+            }
+            Some(Subaddress::None) => return None,
+            None => local_part.split_once('+')?.0,
+        };
+        Some(format!("{user}@{domain}"))
+    }
+
+    fn is_subaddress_of(&self, address: &str, user_address: &str) -> bool {
+        self.runtime.vacation_ignore_subaddress
+            && self
+                .remove_subaddress(address)
+                .map_or(false, |address| address.eq_ignore_ascii_case(user_address))
+    }
+}
+
+fn vacation_id(from: &str, handle: &str) -> String {
+    format!("_v{from}{handle}")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Compiler, Envelope, Event, Input, Runtime};
+
+    #[test]
+    fn vacation_id() {
+        let runtime = Runtime::new();
+        let message =
+            b"From: Sender@Example.org\r\nTo: user@example.org\r\nSubject: test\r\n\r\nbody";
+
+        for (script, handle) in [
+            (r#"vacation "I am away";"#, "I am away"),
+            (r#"vacation :handle "away" "I am away";"#, "away"),
+        ] {
+            let script = Compiler::new()
+                .compile(format!("require \"vacation\";\n{script}").as_bytes())
+                .unwrap();
+            let mut instance = runtime
+                .filter(message)
+                .with_user_address("user@example.org")
+                .with_envelope(Envelope::From, "Sender@Example.org")
+                .with_envelope(Envelope::To, "user@example.org");
+            let expected_id = instance.compute_vacation_id(handle).unwrap();
+            assert_eq!(expected_id, format!("_vsender@example.org{handle}"));
+
+            let mut input = Input::script("", script);
+            let mut ids = Vec::new();
+            while let Some(event) = instance.run(input) {
+                if let Event::DuplicateId { id, .. } = event.unwrap() {
+                    assert_eq!(instance.vacation_id(), Some(id.as_str()));
+                    ids.push(id);
+                }
+                input = false.into();
+            }
+            assert_eq!(ids, [expected_id]);
+        }
+    }
+}
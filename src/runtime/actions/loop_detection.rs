@@ -0,0 +1,231 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_parser::HeaderName;
+
+use crate::{Context, LoopOptions};
+
+impl LoopOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skips `vacation` and `notify` when the message was automatically
+    /// generated, as indicated by an `Auto-Submitted` header other than `no`.
+    /// `vacation` also skips messages with `Precedence: bulk` or an
+    /// `X-Auto-Response-Suppress` of `all` or `oof`.
+    pub fn set_auto_submitted(&mut self, value: bool) {
+        self.auto_submitted = value;
+    }
+
+    pub fn with_auto_submitted(mut self, value: bool) -> Self {
+        self.set_auto_submitted(value);
+        self
+    }
+
+    /// Also treats a `Precedence` of `bulk`, `list` or `junk` as automatically
+    /// generated, for `notify` and `redirect` as well as `vacation`.
+    pub fn set_precedence(&mut self, value: bool) {
+        self.precedence = value;
+    }
+
+    pub fn with_precedence(mut self, value: bool) -> Self {
+        self.set_precedence(value);
+        self
+    }
+
+    /// Also skips `redirect` when the message was automatically generated.
+    pub fn set_redirect_auto_submitted(&mut self, value: bool) {
+        self.redirect_auto_submitted = value;
+    }
+
+    pub fn with_redirect_auto_submitted(mut self, value: bool) -> Self {
+        self.set_redirect_auto_submitted(value);
+        self
+    }
+
+    /// Skips `redirect`, `vacation` and `notify` when the message contains
+    /// `max` or more `name` headers, such as `X-Sieve`. The header itself is
+    /// expected to be added by the host to the messages it sends.
+    pub fn set_loop_header(&mut self, name: impl Into<String>, max: usize) {
+        self.loop_header = Some((name.into(), max));
+    }
+
+    pub fn with_loop_header(mut self, name: impl Into<String>, max: usize) -> Self {
+        self.set_loop_header(name, max);
+        self
+    }
+
+    /// Skips a `redirect` to the address of the user or to the envelope
+    /// sender of the message.
+    pub fn set_self_redirect(&mut self, value: bool) {
+        self.self_redirect = value;
+    }
+
+    pub fn with_self_redirect(mut self, value: bool) -> Self {
+        self.set_self_redirect(value);
+        self
+    }
+}
+
+impl Default for LoopOptions {
+    fn default() -> Self {
+        Self {
+            auto_submitted: true,
+            precedence: false,
+            redirect_auto_submitted: false,
+            loop_header: None,
+            self_redirect: true,
+        }
+    }
+}
+
+impl<'x, C> Context<'x, C> {
+    // Whether the message was generated automatically, `precedences` lists the
+    // Precedence values that count unless all of them were enabled
+    pub(crate) fn is_auto_submitted(&self, precedences: &[&str]) -> bool {
+        let precedences: &[&str] = if self.runtime.loop_options.precedence {
+            &["bulk", "list", "junk"]
+        } else {
+            precedences
+        };
+
+        self.message.parts[0].headers.iter().any(|header| {
+            if let HeaderName::Other(name) = &header.name {
+                if name.eq_ignore_ascii_case("Auto-Submitted") {
+                    header
+                        .value
+                        .as_text()
+                        .map_or(true, |v| !v.trim().eq_ignore_ascii_case("no"))
+                } else if name.eq_ignore_ascii_case("Precedence") {
+                    header.value.as_text().map_or(false, |v| {
+                        precedences.iter().any(|p| v.trim().eq_ignore_ascii_case(p))
+                    })
+                } else {
+                    false
+                }
+            } else {
+                false
+            }
+        })
+    }
+
+    pub(crate) fn is_looping(&self) -> bool {
+        if let Some((loop_header, max)) = &self.runtime.loop_options.loop_header {
+            self.message.parts[0]
+                .headers
+                .iter()
+                .filter(|h| h.name.as_str().eq_ignore_ascii_case(loop_header))
+                .count()
+                >= *max
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Event, LoopOptions, Recipient, Runtime};
+
+    #[test]
+    fn loop_prevention() {
+        let script = br#"require "enotify";
+redirect "bob@example.org";
+redirect "jdoe@example.org";
+notify "xmpp:bob@example.org";
+"#;
+        let run = |options: LoopOptions, headers: &str| {
+            let runtime = Runtime::new()
+                .with_valid_notification_uri("xmpp")
+                .with_max_redirects(5)
+                .with_loop_options(options);
+            let raw_message = format!("{headers}Subject: Hello\r\n\r\nHi");
+            runtime
+                .filter(raw_message.as_bytes())
+                .with_user_address("jdoe@example.org")
+                .run_test_events(script)
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::SendMessage {
+                        recipient: Recipient::Address(address),
+                        ..
+                    } => Some(address),
+                    Event::Notify { method, .. } => Some(method),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            run(LoopOptions::new(), ""),
+            ["bob@example.org", "xmpp:bob@example.org"]
+        );
+        assert_eq!(
+            run(LoopOptions::new().with_self_redirect(false), ""),
+            [
+                "bob@example.org",
+                "jdoe@example.org",
+                "xmpp:bob@example.org"
+            ]
+        );
+        for (headers, options) in [
+            ("Auto-Submitted: auto-replied\r\n", LoopOptions::new()),
+            (
+                "Precedence: list\r\n",
+                LoopOptions::new().with_precedence(true),
+            ),
+        ] {
+            assert_eq!(run(options.clone(), headers), ["bob@example.org"]);
+            assert_eq!(
+                run(options.clone().with_auto_submitted(false), headers),
+                ["bob@example.org", "xmpp:bob@example.org"]
+            );
+            assert!(run(options.with_redirect_auto_submitted(true), headers).is_empty());
+        }
+        for headers in ["Precedence: list\r\n", "Precedence: bulk\r\n"] {
+            assert_eq!(
+                run(LoopOptions::new(), headers),
+                ["bob@example.org", "xmpp:bob@example.org"]
+            );
+        }
+        assert_eq!(
+            run(LoopOptions::new(), "Auto-Submitted: no\r\n"),
+            ["bob@example.org", "xmpp:bob@example.org"]
+        );
+
+        let loop_headers = "X-Sieve: a\r\nX-Sieve: b\r\n";
+        assert_eq!(
+            run(
+                LoopOptions::new().with_loop_header("X-Sieve", 3),
+                loop_headers
+            ),
+            ["bob@example.org", "xmpp:bob@example.org"]
+        );
+        assert!(run(
+            LoopOptions::new().with_loop_header("x-sieve", 2),
+            loop_headers
+        )
+        .is_empty());
+    }
+}
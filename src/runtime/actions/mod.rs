@@ -29,5 +29,7 @@ pub mod action_include;
 pub mod action_mime;
 pub mod action_notify;
 pub mod action_redirect;
+pub mod action_reject;
 pub mod action_set;
 pub mod action_vacation;
+pub mod loop_detection;
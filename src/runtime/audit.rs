@@ -0,0 +1,175 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compiler::{summary::DefaultCatalog, Span},
+    Context, Event, EventMetadata, Recipient, Script,
+};
+
+/// Record of an action returned by [`Context::run`], describing the rule
+/// that caused it. Records serialize to JSON, or any other serde format,
+/// for audit logs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub script: Option<Script>,
+    pub span: Option<Span>,
+    pub action: AuditAction,
+    /// Folder, recipient or notification method of the action.
+    pub target: Option<String>,
+    /// Description of the condition of the `if` the action is in.
+    pub test: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Keep,
+    Discard,
+    Reject,
+    FileInto,
+    SendMessage,
+    Notify,
+}
+
+impl<'x, C> Context<'x, C> {
+    /// Keeps an [`AuditRecord`] of every action returned by [`Context::run`].
+    /// The audit log is disabled by default.
+    pub fn set_audit_log(&mut self, enable: bool) {
+        self.audit_log = if enable { Some(Vec::new()) } else { None };
+    }
+
+    pub fn with_audit_log(mut self, enable: bool) -> Self {
+        self.set_audit_log(enable);
+        self
+    }
+
+    /// Audit records of the actions returned so far, if enabled.
+    pub fn audit_log(&self) -> &[AuditRecord] {
+        self.audit_log.as_deref().unwrap_or_default()
+    }
+
+    pub fn take_audit_log(&mut self) -> Vec<AuditRecord> {
+        self.audit_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn audit(&mut self, event: &Event) {
+        let (action, target) = match event {
+            Event::Keep { .. } => (AuditAction::Keep, None),
+            Event::Discard => (AuditAction::Discard, None),
+            Event::Reject { .. } => (AuditAction::Reject, None),
+            Event::FileInto { folder, .. } => (AuditAction::FileInto, Some(folder.clone())),
+            Event::SendMessage { recipient, .. } => (
+                AuditAction::SendMessage,
+                Some(match recipient {
+                    Recipient::Address(address) => address.clone(),
+                    Recipient::List(list) => list.clone(),
+                    Recipient::Group(group) => group.join(", "),
+                }),
+            ),
+            Event::Notify { method, .. } => (AuditAction::Notify, Some(method.clone())),
+            _ => return,
+        };
+
+        let target = match (target, self.runtime.redactor) {
+            (Some(target), Some(redactor)) => Some(redactor(&target)),
+            (target, _) => target,
+        };
+        let EventMetadata { script, span, .. } = self.event_metadata();
+        let test = script
+            .as_ref()
+            .zip(span.as_ref())
+            .and_then(|(script, span)| {
+                let sieve = self.script_cache.get(script)?;
+                let pos = sieve.spans.iter().position(|s| s == span)?;
+                sieve.condition_at(pos, &DefaultCatalog)
+            });
+
+        if let Some(audit_log) = &mut self.audit_log {
+            audit_log.push(AuditRecord {
+                script,
+                span,
+                action,
+                target,
+                test,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Compiler, Event, Input, Runtime};
+
+    use super::AuditAction;
+
+    #[test]
+    fn audit_log() {
+        let script = Compiler::new()
+            .compile(
+                br#"require "fileinto";
+if header :contains "Subject" "invoice" {
+    fileinto "Invoices";
+}
+if size :over 1M {
+    discard;
+}
+"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut ctx = runtime
+            .filter(b"Subject: Your invoice\r\n\r\nbody")
+            .with_audit_log(true);
+
+        let mut input = Input::script("main", script);
+        while let Some(event) = ctx.run(input) {
+            input = match event.unwrap() {
+                Event::FileInto { .. } | Event::Keep { .. } => Input::True,
+                _ => Input::False,
+            };
+        }
+
+        let audit_log = ctx.take_audit_log();
+        assert_eq!(
+            audit_log
+                .iter()
+                .map(|record| (record.action, record.target.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![(AuditAction::FileInto, Some("Invoices"))]
+        );
+        let record = &audit_log[0];
+        assert_eq!(record.span.map(|span| span.line_num), Some(3));
+        assert_eq!(
+            record.test.as_deref(),
+            Some("the 'Subject' header contains 'invoice'")
+        );
+
+        let json = serde_json::to_string(record).unwrap();
+        assert!(json.contains("\"action\":\"fileinto\""), "{json}");
+    }
+}
@@ -0,0 +1,321 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    hash::{BuildHasher, Hash, Hasher},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use ahash::{AHashMap, RandomState};
+use fancy_regex::Regex;
+
+use crate::{compiler::CompileError, Compiler, Sieve};
+
+/// Least recently used cache of regular expressions compiled at runtime,
+/// shared by all contexts created from the same `Runtime`.
+#[derive(Debug)]
+pub struct RegexCache {
+    capacity: usize,
+    entries: Mutex<LruEntries<String, Arc<Regex>>>,
+}
+
+/// Least recently used cache of compiled scripts keyed by a hash of their
+/// source. The cache owns the compiler used for every script it holds, so
+/// entries are never shared between compilers with different settings.
+#[derive(Debug)]
+pub struct ScriptCache {
+    compiler: Compiler,
+    capacity: usize,
+    hasher: RandomState,
+    entries: Mutex<LruEntries<u64, (Box<[u8]>, Arc<Sieve>)>>,
+}
+
+// Entries are kept in a list ordered from the most to the least recently
+// used, linked by their position in `nodes`, so that lookups, insertions and
+// evictions take constant time while the lock is held.
+#[derive(Debug)]
+struct LruEntries<K, V> {
+    entries: AHashMap<K, usize>,
+    nodes: Vec<LruNode<K, V>>,
+    head: usize,
+    tail: usize,
+}
+
+#[derive(Debug)]
+struct LruNode<K, V> {
+    key: K,
+    value: V,
+    prev: usize,
+    next: usize,
+}
+
+const NIL: usize = usize::MAX;
+
+impl RegexCache {
+    pub fn new(capacity: usize) -> Self {
+        RegexCache {
+            capacity,
+            entries: Mutex::new(LruEntries::default()),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn get_or_compile(&self, expr: &str) -> Result<Arc<Regex>, fancy_regex::Error> {
+        if self.capacity == 0 {
+            return Regex::new(expr).map(Arc::new);
+        }
+
+        if let Some(regex) = lock(&self.entries).get(expr) {
+            return Ok(regex.clone());
+        }
+
+        // Compile outside the lock so other contexts are not blocked
+        let regex = Arc::new(Regex::new(expr)?);
+        lock(&self.entries).insert(expr.to_string(), regex.clone(), self.capacity);
+        Ok(regex)
+    }
+}
+
+impl ScriptCache {
+    pub fn new(compiler: Compiler, capacity: usize) -> Self {
+        ScriptCache {
+            compiler,
+            capacity,
+            hasher: RandomState::new(),
+            entries: Mutex::new(LruEntries::default()),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn compiler(&self) -> &Compiler {
+        &self.compiler
+    }
+
+    pub fn len(&self) -> usize {
+        lock(&self.entries).entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        lock(&self.entries).clear();
+    }
+
+    pub fn get_or_compile(&self, script: &[u8]) -> Result<Arc<Sieve>, CompileError> {
+        if self.capacity == 0 {
+            return self.compiler.compile(script).map(Arc::new);
+        }
+
+        let mut hasher = self.hasher.build_hasher();
+        script.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        // The source is compared as well, a hash collision is a cache miss
+        if let Some((_, sieve)) = lock(&self.entries)
+            .get(&hash)
+            .filter(|(source, _)| source.as_ref() == script)
+        {
+            return Ok(sieve.clone());
+        }
+
+        let sieve = Arc::new(self.compiler.compile(script)?);
+        lock(&self.entries).insert(hash, (script.into(), sieve.clone()), self.capacity);
+        Ok(sieve)
+    }
+}
+
+impl<K, V> Default for LruEntries<K, V> {
+    fn default() -> Self {
+        LruEntries {
+            entries: AHashMap::new(),
+            nodes: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> LruEntries<K, V> {
+    fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let pos = *self.entries.get(key)?;
+        self.unlink(pos);
+        self.push_front(pos);
+        Some(&self.nodes[pos].value)
+    }
+
+    fn insert(&mut self, key: K, value: V, capacity: usize) {
+        let pos = if let Some(&pos) = self.entries.get(&key) {
+            self.nodes[pos].value = value;
+            self.unlink(pos);
+            pos
+        } else if self.nodes.len() >= capacity && self.tail != NIL {
+            // Reuse the node of the least recently used entry
+            let pos = self.tail;
+            self.unlink(pos);
+            let node = &mut self.nodes[pos];
+            self.entries.remove(&node.key);
+            node.key = key.clone();
+            node.value = value;
+            self.entries.insert(key, pos);
+            pos
+        } else {
+            let pos = self.nodes.len();
+            self.nodes.push(LruNode {
+                key: key.clone(),
+                value,
+                prev: NIL,
+                next: NIL,
+            });
+            self.entries.insert(key, pos);
+            pos
+        };
+        self.push_front(pos);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.nodes.clear();
+        self.head = NIL;
+        self.tail = NIL;
+    }
+
+    fn unlink(&mut self, pos: usize) {
+        let LruNode { prev, next, .. } = self.nodes[pos];
+        if prev != NIL {
+            self.nodes[prev].next = next;
+        } else {
+            self.head = next;
+        }
+        if next != NIL {
+            self.nodes[next].prev = prev;
+        } else {
+            self.tail = prev;
+        }
+    }
+
+    fn push_front(&mut self, pos: usize) {
+        let node = &mut self.nodes[pos];
+        node.prev = NIL;
+        node.next = self.head;
+        if self.head != NIL {
+            self.nodes[self.head].prev = pos;
+        } else {
+            self.tail = pos;
+        }
+        self.head = pos;
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::Compiler;
+
+    use super::{RegexCache, ScriptCache};
+
+    #[test]
+    fn regex_cache_lru() {
+        let cache = RegexCache::new(2);
+        let a = cache.get_or_compile("a+").unwrap();
+        cache.get_or_compile("b+").unwrap();
+
+        // Reuse "a+" so that "b+" becomes the least recently used entry
+        assert!(Arc::ptr_eq(&a, &cache.get_or_compile("a+").unwrap()));
+        cache.get_or_compile("c+").unwrap();
+
+        let entries = super::lock(&cache.entries);
+        assert_eq!(entries.entries.len(), 2);
+        assert!(entries.entries.contains_key("a+"));
+        assert!(entries.entries.contains_key("c+"));
+        drop(entries);
+
+        assert!(cache.get_or_compile("(").is_err());
+    }
+
+    #[test]
+    fn lru_entries() {
+        let mut entries = super::LruEntries::default();
+        for key in 0..3 {
+            entries.insert(key, key * 10, 3);
+        }
+        assert_eq!(entries.get(&0), Some(&0));
+        entries.insert(1, 100, 3);
+
+        // 2 is now the least recently used entry, then 0 and 1
+        for (key, evicted) in [(3, 2), (4, 0), (5, 1)] {
+            entries.insert(key, key * 10, 3);
+            assert_eq!(entries.get(&evicted), None);
+            assert_eq!(entries.entries.len(), 3);
+        }
+        assert_eq!(entries.get(&3), Some(&30));
+
+        entries.clear();
+        assert_eq!(entries.get(&3), None);
+        entries.insert(6, 60, 3);
+        assert_eq!(entries.get(&6), Some(&60));
+    }
+
+    #[test]
+    fn script_cache() {
+        let cache = ScriptCache::new(Compiler::new(), 1);
+        let a = cache.get_or_compile(b"keep;").unwrap();
+
+        assert!(Arc::ptr_eq(&a, &cache.get_or_compile(b"keep;").unwrap()));
+        cache.get_or_compile(b"discard;").unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(!Arc::ptr_eq(&a, &cache.get_or_compile(b"keep;").unwrap()));
+
+        assert!(cache.get_or_compile(b"if {").is_err());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn script_cache_compiler_settings() {
+        let strict = ScriptCache::new(Compiler::new().with_max_script_size(4), 4);
+        let default = ScriptCache::new(Compiler::new(), 4);
+
+        // Each cache compiles with its own settings
+        assert!(default.get_or_compile(b"discard;").is_ok());
+        assert!(strict.get_or_compile(b"discard;").is_err());
+        assert_eq!(strict.compiler().max_script_size, 4);
+    }
+}
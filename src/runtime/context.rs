@@ -21,25 +21,52 @@
  * for more details.
 */
 
-use std::{borrow::Cow, sync::Arc, time::SystemTime};
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use std::ops::Deref;
 
-use ahash::AHashMap;
-use mail_parser::Message;
+use ahash::{AHashMap, AHashSet};
+use mail_parser::{Message, MessageParser};
 
 use crate::{
-    compiler::grammar::{instruction::Instruction, Capability},
-    Context, Envelope, Event, Input, Metadata, Runtime, Sieve, SpamStatus, VirusStatus,
+    compiler::{
+        grammar::{instruction::Instruction, Capability},
+        Span,
+    },
+    Context, Envelope, Event, EventMetadata, FailurePolicy, Input, Metadata, Modification, Runtime,
+    RuntimeWarning, RuntimeWarningKind, Script, Sieve, SpamStatus, Subaddress, VirusStatus,
     MAX_LOCAL_VARIABLES, MAX_MATCH_VARIABLES,
 };
 
 use super::{
     actions::action_include::IncludeResult,
-    tests::{test_envelope::parse_envelope_address, TestResult},
+    parse_message,
+    platform::unix_timestamp_millis,
+    tests::{
+        test_envelope::{decode_xtext, parse_envelope_address},
+        TestResult,
+    },
     RuntimeError, Variable,
 };
 
+// Runtime of a context, copied when it is first modified
+#[derive(Debug)]
+#[cfg_attr(not(feature = "testsuite"), allow(dead_code))]
+pub(crate) enum RuntimeRef<'x, C> {
+    Borrowed(&'x Runtime<C>),
+    Owned(Box<Runtime<C>>),
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct ScriptStack {
+    pub(crate) name: Script,
     pub(crate) script: Arc<Sieve>,
     pub(crate) prev_pos: usize,
     pub(crate) prev_vars_local: Vec<Variable>,
@@ -47,30 +74,35 @@ pub(crate) struct ScriptStack {
 }
 
 impl<'x, C> Context<'x, C> {
-    #[cfg(not(test))]
     pub(crate) fn new(runtime: &'x Runtime<C>, message: Message<'x>) -> Self {
-        Context {
-            #[cfg(test)]
-            runtime: runtime.clone(),
-            #[cfg(not(test))]
-            runtime,
-            message,
+        let now = unix_timestamp_millis();
+        let mut ctx = Context {
+            runtime: RuntimeRef::Borrowed(runtime),
+            message: Arc::new(message),
             part: 0,
             part_iter: Vec::new().into_iter(),
             part_iter_stack: Vec::new(),
             pos: usize::MAX,
             test_result: false,
             script_cache: AHashMap::new(),
+            scripts_included: AHashSet::new(),
             script_stack: Vec::with_capacity(0),
-            vars_global: AHashMap::new(),
+            vars_global: Arc::default(),
             vars_env: AHashMap::new(),
             vars_local: Vec::with_capacity(0),
             vars_match: Vec::with_capacity(0),
+            vars_size: 0,
             expr_stack: Vec::with_capacity(16),
             expr_pos: 0,
             envelope: Vec::new(),
+            subaddress: AHashMap::new(),
             metadata: Vec::new(),
             message_size: usize::MAX,
+            message_source: None,
+            message_source_error: None,
+            header_index: RefCell::default(),
+            expansion_exceeded: Cell::new(false),
+            metadata_cache: RefCell::default(),
             final_event: Event::Keep {
                 flags: Vec::with_capacity(0),
                 message_id: 0,
@@ -80,28 +112,103 @@ impl<'x, C> Context<'x, C> {
             has_changes: false,
             user_address: "".into(),
             user_full_name: "".into(),
-            current_time: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map(|d| d.as_secs())
-                .unwrap_or(0) as i64,
+            current_time: now.div_euclid(1000),
+            current_time_millis: now.rem_euclid(1000) as u16,
             num_redirects: 0,
             num_instructions: 0,
+            num_part_iterations: 0,
             num_out_messages: 0,
+            num_generated_messages: 0,
+            correlation_id: None,
+            duplicate_id: None,
+            warnings: Vec::new(),
+            vacation_id: None,
+            final_event_origin: None,
+            pending_modification: None,
+            profiler: None,
+            audit_log: None,
+            is_deferred: false,
             last_message_id: 0,
             main_message_id: 0,
             virus_status: VirusStatus::Unknown,
             spam_status: SpamStatus::Unknown,
+        };
+        ctx.apply_mime_leniency();
+        if runtime.received_environment {
+            ctx.set_env_from_received();
         }
+        ctx
     }
 
-    #[allow(clippy::while_let_on_iterator)]
     pub fn run(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
+        let result = self.run_script(input);
+        if let Some(profiler) = &mut self.profiler {
+            profiler.pause();
+        }
+        if let Some(err) = self.message_source_error.take() {
+            // The body could not be read, so the message is not filtered any further
+            let (script, span) = self
+                .event_origin()
+                .map_or((None, None), |(script, span)| (Some(script), Some(span)));
+            self.expansion_exceeded.set(false);
+            self.pending_modification = None;
+            self.finish_loop();
+            self.fail(RuntimeError::Internal {
+                message: format!("Failed to read message: {err}"),
+                script,
+                span,
+            })
+        } else if !self.expansion_exceeded.replace(false) {
+            match result {
+                Some(Ok(event)) => {
+                    if self.audit_log.is_some() {
+                        self.audit(&event);
+                    }
+                    Some(Ok(event))
+                }
+                Some(Err(err)) => self.fail(err),
+                None => None,
+            }
+        } else {
+            // Discard the result produced from the truncated string
+            self.pending_modification = None;
+            self.finish_loop();
+            self.fail(RuntimeError::ExpansionLimitReached)
+        }
+    }
+
+    #[allow(clippy::while_let_on_iterator)]
+    fn run_script(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
         match input {
+            input if self.pending_modification.is_some() => {
+                // Result of a modification preview
+                self.finish_modification(matches!(input, Input::True));
+            }
+            Input::True | Input::False if self.expr_pos > 0 => {
+                // Result of a list lookup from within an expression
+                self.expr_stack
+                    .push(Variable::from(matches!(input, Input::True)));
+            }
             Input::True => self.test_result ^= true,
             Input::False => self.test_result ^= false,
             Input::FncResult(result) => {
                 self.expr_stack.push(result);
             }
+            Input::Variables(variables) => {
+                let vars_global = Arc::make_mut(&mut self.vars_global);
+                for (name, value) in variables {
+                    let new_len = value.len();
+                    let old_len = vars_global
+                        .insert(name.to_ascii_lowercase().into(), value)
+                        .map_or(0, |var| var.len());
+                    self.vars_size = (self.vars_size + new_len).saturating_sub(old_len);
+                }
+                if self.expr_pos > 0 {
+                    self.expr_stack.push(Variable::from(true));
+                } else {
+                    self.test_result ^= true;
+                }
+            }
             Input::Script { name, script } => {
                 let num_vars = script.num_vars;
                 let num_match_vars = script.num_match_vars;
@@ -110,9 +217,14 @@ impl<'x, C> Context<'x, C> {
                     if self.message_size == usize::MAX {
                         self.message_size = self.message.raw_message.len();
                     }
+                    if script.uses_body {
+                        self.load_message();
+                    }
 
-                    self.script_cache.insert(name, script.clone());
+                    self.script_cache.insert(name.clone(), script.clone());
+                    self.scripts_included.insert(name.clone());
                     self.script_stack.push(ScriptStack {
+                        name,
                         script,
                         prev_pos: self.pos,
                         prev_vars_local: std::mem::replace(
@@ -140,39 +252,52 @@ impl<'x, C> Context<'x, C> {
 
         'outer: loop {
             while let Some(instruction) = iter.next() {
+                if self.expansion_exceeded.get() {
+                    // Reported by Context::run
+                    return None;
+                }
                 self.num_instructions += 1;
                 if self.num_instructions > self.runtime.cpu_limit {
                     self.finish_loop();
                     return Some(Err(RuntimeError::CPULimitReached));
                 }
                 self.pos += 1;
+                if let Some(profiler) = &mut self.profiler {
+                    if let Some(stack) = self.script_stack.last() {
+                        profiler.enter(&stack.name, &stack.script, self.pos - 1);
+                    }
+                }
 
                 match instruction {
                     Instruction::Jz(jmp_pos) => {
                         if !self.test_result {
-                            debug_assert!(*jmp_pos > self.pos - 1);
-                            self.pos = *jmp_pos;
+                            debug_assert!(*jmp_pos as usize > self.pos - 1);
+                            self.pos = *jmp_pos as usize;
                             iter = current_script.instructions.get(self.pos..)?.iter();
                             continue;
                         }
                     }
                     Instruction::Jnz(jmp_pos) => {
                         if self.test_result {
-                            debug_assert!(*jmp_pos > self.pos - 1);
-                            self.pos = *jmp_pos;
+                            debug_assert!(*jmp_pos as usize > self.pos - 1);
+                            self.pos = *jmp_pos as usize;
                             iter = current_script.instructions.get(self.pos..)?.iter();
                             continue;
                         }
                     }
                     Instruction::Jmp(jmp_pos) => {
-                        debug_assert_ne!(*jmp_pos, self.pos - 1);
-                        self.pos = *jmp_pos;
+                        debug_assert_ne!(*jmp_pos as usize, self.pos - 1);
+                        self.pos = *jmp_pos as usize;
                         iter = current_script.instructions.get(self.pos..)?.iter();
                         continue;
                     }
                     Instruction::Test(test) => match test.exec(self) {
                         TestResult::Bool(result) => {
                             self.test_result = result;
+                            if self.exceeds_variable_memory() {
+                                self.finish_loop();
+                                return Some(Err(RuntimeError::VariableMemoryLimitReached));
+                            }
                         }
                         TestResult::Event { event, is_not } => {
                             self.test_result = is_not;
@@ -186,6 +311,10 @@ impl<'x, C> Context<'x, C> {
                     Instruction::Eval(expr) => match self.eval_expression(expr) {
                         Ok(result) => {
                             self.test_result = result.to_bool();
+                            if self.exceeds_variable_memory() {
+                                self.finish_loop();
+                                return Some(Err(RuntimeError::VariableMemoryLimitReached));
+                            }
                         }
                         Err(event) => {
                             return Some(Ok(event));
@@ -199,6 +328,8 @@ impl<'x, C> Context<'x, C> {
                             ) {
                                 for local_var in local_vars.iter_mut() {
                                     if !local_var.is_empty() {
+                                        self.vars_size =
+                                            self.vars_size.saturating_sub(local_var.len());
                                         *local_var = Variable::default();
                                     }
                                 }
@@ -217,6 +348,7 @@ impl<'x, C> Context<'x, C> {
                             message_id: self.main_message_id,
                         }
                         .into();
+                        self.final_event_origin = self.event_origin();
                         if let Some(next_event) = next_event {
                             return Some(Ok(next_event));
                         }
@@ -228,13 +360,17 @@ impl<'x, C> Context<'x, C> {
                         }
                     }
                     Instruction::Redirect(redirect) => {
-                        redirect.exec(self);
+                        if let Err(err) = redirect.exec(self) {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::Discard => {
                         self.final_event = Event::Discard.into();
+                        self.final_event_origin = self.event_origin();
                     }
                     Instruction::Stop => {
                         self.script_stack.clear();
@@ -242,20 +378,38 @@ impl<'x, C> Context<'x, C> {
                     }
                     Instruction::Reject(reject) => {
                         self.final_event = None;
+                        let reason = self.eval_value(&reject.reason).to_string().into_owned();
+                        let message = if self.runtime.reject_message {
+                            self.build_reject_message(reject.ereject, &reason)
+                        } else {
+                            None
+                        };
+                        if message.is_some() {
+                            if let Err(err) = self.add_generated_messages(1) {
+                                self.finish_loop();
+                                return Some(Err(err));
+                            }
+                        }
                         return Some(Ok(Event::Reject {
                             extended: reject.ereject,
-                            reason: self.eval_value(&reject.reason).to_string().into_owned(),
+                            message,
+                            reason,
                         }));
                     }
                     Instruction::ForEveryPart(fep) => {
                         if let Some(next_part) = self.part_iter.next() {
+                            self.num_part_iterations += 1;
+                            if self.num_part_iterations > self.runtime.max_part_iterations {
+                                self.finish_loop();
+                                return Some(Err(RuntimeError::PartIterationLimitReached));
+                            }
                             self.part = next_part;
                         } else if let Some((prev_part, prev_part_iter)) = self.part_iter_stack.pop()
                         {
-                            debug_assert!(fep.jz_pos > self.pos - 1);
+                            debug_assert!(fep.jz_pos as usize > self.pos - 1);
                             self.part_iter = prev_part_iter;
                             self.part = prev_part;
-                            self.pos = fep.jz_pos;
+                            self.pos = fep.jz_pos as usize;
                             iter = current_script.instructions.get(self.pos..)?.iter();
                             continue;
                         } else {
@@ -265,6 +419,10 @@ impl<'x, C> Context<'x, C> {
                         }
                     }
                     Instruction::ForEveryPartPush => {
+                        if self.part_iter_stack.len() >= self.runtime.max_nested_foreverypart {
+                            self.finish_loop();
+                            return Some(Err(RuntimeError::TooManyNestedForEveryPart));
+                        }
                         let part_iter = self
                             .find_nested_parts_ids(self.part_iter_stack.is_empty())
                             .into_iter();
@@ -273,7 +431,7 @@ impl<'x, C> Context<'x, C> {
                     }
                     Instruction::ForEveryPartPop(num_pops) => {
                         debug_assert!(
-                            *num_pops > 0 && *num_pops <= self.part_iter_stack.len(),
+                            *num_pops > 0 && *num_pops as usize <= self.part_iter_stack.len(),
                             "Pop out of range: {} with {} items.",
                             num_pops,
                             self.part_iter_stack.len()
@@ -289,9 +447,13 @@ impl<'x, C> Context<'x, C> {
                     }
                     Instruction::While(while_) => match self.eval_expression(&while_.expr) {
                         Ok(result) => {
+                            if self.exceeds_variable_memory() {
+                                self.finish_loop();
+                                return Some(Err(RuntimeError::VariableMemoryLimitReached));
+                            }
                             if !result.to_bool() {
-                                debug_assert!(while_.jz_pos > self.pos - 1);
-                                self.pos = while_.jz_pos;
+                                debug_assert!(while_.jz_pos as usize > self.pos - 1);
+                                self.pos = while_.jz_pos as usize;
                                 iter = current_script.instructions.get(self.pos..)?.iter();
                                 continue;
                             }
@@ -303,44 +465,89 @@ impl<'x, C> Context<'x, C> {
                     Instruction::Let(let_) => match self.eval_expression(&let_.expr) {
                         Ok(result) => {
                             self.set_variable(&let_.name, result);
+                            if self.exceeds_variable_memory() {
+                                self.finish_loop();
+                                return Some(Err(RuntimeError::VariableMemoryLimitReached));
+                            }
                         }
                         Err(event) => {
                             return Some(Ok(event));
                         }
                     },
 
-                    Instruction::Replace(replace) => replace.exec(self),
-                    Instruction::Enclose(enclose) => enclose.exec(self),
+                    Instruction::Replace(replace) => {
+                        if let Some(event) =
+                            self.modify_message(Modification::Replace, |ctx| replace.exec(ctx))
+                        {
+                            return Some(Ok(event));
+                        }
+                    }
+                    Instruction::Enclose(enclose) => {
+                        if let Some(event) =
+                            self.modify_message(Modification::Enclose, |ctx| enclose.exec(ctx))
+                        {
+                            return Some(Ok(event));
+                        }
+                    }
                     Instruction::ExtractText(extract) => {
                         extract.exec(self);
+                        if self.exceeds_variable_memory() {
+                            self.finish_loop();
+                            return Some(Err(RuntimeError::VariableMemoryLimitReached));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::AddHeader(add_header) => add_header.exec(self),
-                    Instruction::DeleteHeader(delete_header) => delete_header.exec(self),
+                    Instruction::DeleteHeader(delete_header) => {
+                        if let Some(event) = self
+                            .modify_message(Modification::DeleteHeader, |ctx| {
+                                delete_header.exec(ctx)
+                            })
+                        {
+                            return Some(Ok(event));
+                        }
+                    }
                     Instruction::Set(set) => {
                         set.exec(self);
+                        if self.exceeds_variable_memory() {
+                            self.finish_loop();
+                            return Some(Err(RuntimeError::VariableMemoryLimitReached));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::Notify(notify) => {
                         notify.exec(self);
+                        if let Err(err) = self.add_queued_generated_messages() {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::Vacation(vacation) => {
                         vacation.exec(self);
+                        if let Err(err) = self.add_queued_generated_messages() {
+                            self.finish_loop();
+                            return Some(Err(err));
+                        }
                         if let Some(event) = self.queued_events.next() {
                             return Some(Ok(event));
                         }
                     }
                     Instruction::EditFlags(flags) => flags.exec(self),
                     Instruction::Include(include) => match include.exec(self) {
-                        IncludeResult::Cached(script) => {
+                        IncludeResult::Cached(name, script) => {
+                            self.scripts_included.insert(name.clone());
+                            if script.uses_body {
+                                self.load_message();
+                            }
                             self.script_stack.push(ScriptStack {
+                                name,
                                 script: script.clone(),
                                 prev_pos: self.pos,
                                 prev_vars_local: std::mem::replace(
@@ -396,7 +603,7 @@ impl<'x, C> Context<'x, C> {
                         self.finish_loop();
                         return Some(Err(RuntimeError::InvalidInstruction(invalid.clone())));
                     }
-                    #[cfg(test)]
+                    #[cfg(any(test, feature = "testsuite"))]
                     Instruction::TestCmd(arguments) => {
                         return Some(Ok(Event::Function {
                             id: u32::MAX,
@@ -410,6 +617,13 @@ impl<'x, C> Context<'x, C> {
             }
 
             if let Some(prev_script) = self.script_stack.pop() {
+                let released = self
+                    .vars_local
+                    .iter()
+                    .chain(self.vars_match.iter())
+                    .map(|var| var.len())
+                    .sum::<usize>();
+                self.vars_size = self.vars_size.saturating_sub(released);
                 self.pos = prev_script.prev_pos;
                 self.vars_local = prev_script.prev_vars_local;
                 self.vars_match = prev_script.prev_vars_match;
@@ -457,6 +671,141 @@ impl<'x, C> Context<'x, C> {
         }
     }
 
+    /// Runs the script to completion, calling `handler` with every event and
+    /// feeding its result back as the input for the next step. Stops at the
+    /// first runtime error or error returned by `handler`.
+    pub fn run_with<E>(
+        &mut self,
+        input: Input,
+        mut handler: impl FnMut(Event) -> Result<Input, E>,
+    ) -> Result<(), E>
+    where
+        E: From<RuntimeError>,
+    {
+        let mut input = input;
+        while let Some(event) = self.run(input) {
+            input = handler(event?)?;
+        }
+        Ok(())
+    }
+
+    /// Asynchronous version of [`Context::run_with`].
+    pub async fn run_with_async<E, F>(
+        &mut self,
+        input: Input,
+        mut handler: impl FnMut(Event) -> F,
+    ) -> Result<(), E>
+    where
+        E: From<RuntimeError>,
+        F: Future<Output = Result<Input, E>>,
+    {
+        let mut input = input;
+        while let Some(event) = self.run(input) {
+            input = handler(event?).await?;
+        }
+        Ok(())
+    }
+
+    /// Same as [`Context::run`] but a panic raised while executing the script,
+    /// for example by a registered function, aborts the script and is returned
+    /// as [`RuntimeError::Internal`] instead of unwinding into the caller. The
+    /// failure policy and redactor are applied as for any other error.
+    pub fn run_catching(&mut self, input: Input) -> Option<Result<Event, RuntimeError>> {
+        match catch_unwind(AssertUnwindSafe(|| self.run(input))) {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                let (script, span) = self
+                    .event_origin()
+                    .map_or((None, None), |(script, span)| (Some(script), Some(span)));
+
+                // Abort the script as if it had exceeded the CPU limit
+                self.pending_modification = None;
+                self.expr_stack.clear();
+                self.expr_pos = 0;
+                self.finish_loop();
+                self.fail(RuntimeError::Internal {
+                    message,
+                    script,
+                    span,
+                })
+            }
+        }
+    }
+
+    // Applies the failure policy and redacts the error before returning it
+    fn fail(&mut self, mut err: RuntimeError) -> Option<Result<Event, RuntimeError>> {
+        self.apply_failure_policy();
+        if let Some(redactor) = self.runtime.redactor {
+            err.redact(redactor);
+        }
+        Some(Err(err))
+    }
+
+    // Counts the replies, notifications and carbon copies queued by an action
+    fn add_queued_generated_messages(&mut self) -> Result<(), RuntimeError> {
+        let num_messages = self
+            .queued_events
+            .as_slice()
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    Event::SendMessage { .. } | Event::Notify { .. } | Event::FileInto { .. }
+                )
+            })
+            .count();
+        self.add_generated_messages(num_messages).map_err(|err| {
+            // The action that went over the limit is not performed
+            self.queued_events = vec![].into_iter();
+            err
+        })
+    }
+
+    fn add_generated_messages(&mut self, num_messages: usize) -> Result<(), RuntimeError> {
+        let max_messages = self.runtime.max_generated_messages;
+        if max_messages > 0 && self.num_generated_messages + num_messages > max_messages {
+            Err(RuntimeError::GeneratedMessageLimitReached)
+        } else {
+            self.num_generated_messages += num_messages;
+            Ok(())
+        }
+    }
+
+    /// Returns the number of messages generated so far, see
+    /// [`Runtime::set_max_generated_messages`].
+    pub fn generated_messages(&self) -> usize {
+        self.num_generated_messages
+    }
+
+    // Replaces the disposition queued by finish_loop after a runtime error
+    fn apply_failure_policy(&mut self) {
+        let event = match &self.runtime.failure_policy {
+            FailurePolicy::Continue => return,
+            FailurePolicy::Keep => Event::Keep {
+                flags: Vec::new(),
+                message_id: 0,
+            },
+            FailurePolicy::KeepWithFlag(flag) => Event::Keep {
+                flags: vec![flag.clone()],
+                message_id: 0,
+            },
+            FailurePolicy::Discard => Event::Discard,
+            FailurePolicy::Defer => {
+                self.is_deferred = true;
+                self.queued_events = vec![].into_iter();
+                return;
+            }
+        };
+        self.queued_events = vec![event].into_iter();
+    }
+
+    /// Returns `true` if the script failed and the message is to be
+    /// delivered again later, see [`FailurePolicy::Defer`].
+    pub fn is_deferred(&self) -> bool {
+        self.is_deferred
+    }
+
     pub(crate) fn finish_loop(&mut self) {
         self.script_stack.clear();
         if let Some(event) = self.final_event.take() {
@@ -528,10 +877,162 @@ impl<'x, C> Context<'x, C> {
         self
     }
 
+    /// Sets the NOTIFY parameter of the recipient, such as `"SUCCESS,FAILURE"`
+    /// or `"NEVER"`. Each value is tested separately by `envelope "notify"`.
+    pub fn set_envelope_notify(&mut self, notify: &str) {
+        for value in notify.split(',') {
+            let value = value.trim();
+            if !value.is_empty() {
+                self.envelope
+                    .push((Envelope::Notify, value.to_uppercase().into()));
+            }
+        }
+    }
+
+    pub fn with_envelope_notify(mut self, notify: &str) -> Self {
+        self.set_envelope_notify(notify);
+        self
+    }
+
+    /// Sets the ORCPT parameter of the recipient, such as
+    /// `"rfc822;john+2Bsieve@example.org"`. The address is xtext-decoded and
+    /// address parts are only applied to the `rfc822` address type.
+    pub fn set_envelope_orcpt(&mut self, orcpt: &str) {
+        let (addr_type, addr) = orcpt.split_once(';').unwrap_or(("rfc822", orcpt));
+        self.envelope.push((
+            Envelope::Orcpt,
+            format!("{};{}", addr_type.trim(), decode_xtext(addr.trim())).into(),
+        ));
+    }
+
+    pub fn with_envelope_orcpt(mut self, orcpt: &str) -> Self {
+        self.set_envelope_orcpt(orcpt);
+        self
+    }
+
+    /// Sets the RET parameter of the transaction, either `"FULL"` or `"HDRS"`.
+    pub fn set_envelope_ret(&mut self, ret: &str) {
+        self.envelope
+            .push((Envelope::Ret, ret.trim().to_uppercase().into()));
+    }
+
+    pub fn with_envelope_ret(mut self, ret: &str) -> Self {
+        self.set_envelope_ret(ret);
+        self
+    }
+
+    /// Sets the xtext-encoded ENVID parameter of the transaction.
+    pub fn set_envelope_envid(&mut self, envid: &str) {
+        self.envelope
+            .push((Envelope::Envid, decode_xtext(envid.trim()).into()));
+    }
+
+    pub fn with_envelope_envid(mut self, envid: &str) -> Self {
+        self.set_envelope_envid(envid);
+        self
+    }
+
     pub fn clear_envelope(&mut self) {
         self.envelope.clear()
     }
 
+    /// Overrides the subaddress separator, `+` by default, used by `:user`
+    /// and `:detail` for the addresses of a domain.
+    pub fn set_subaddress(&mut self, domain: impl AsRef<str>, subaddress: Subaddress) {
+        self.subaddress
+            .insert(domain.as_ref().to_lowercase(), subaddress);
+    }
+
+    pub fn with_subaddress(mut self, domain: impl AsRef<str>, subaddress: Subaddress) -> Self {
+        self.set_subaddress(domain, subaddress);
+        self
+    }
+
+    /// Sets an id that is returned with the metadata of every event, such as
+    /// the id of the delivery attempt.
+    pub fn set_correlation_id(&mut self, id: impl Into<String>) {
+        self.correlation_id = Some(id.into());
+    }
+
+    pub fn with_correlation_id(mut self, id: impl Into<String>) -> Self {
+        self.set_correlation_id(id);
+        self
+    }
+
+    /// Returns the id computed by the last `duplicate` test, which is also
+    /// the id of the last [`Event::DuplicateId`].
+    pub fn duplicate_id(&self) -> Option<&str> {
+        self.duplicate_id.as_deref()
+    }
+
+    /// Returns the warnings raised so far, such as attempts to edit a
+    /// protected header.
+    pub fn warnings(&self) -> &[RuntimeWarning] {
+        &self.warnings
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<RuntimeWarning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    pub(crate) fn warn(&mut self, kind: RuntimeWarningKind) {
+        let (script, span) = self
+            .event_origin()
+            .map_or((None, None), |(script, span)| (Some(script), Some(span)));
+        self.warnings.push(RuntimeWarning { kind, script, span });
+    }
+
+    /// Returns the origin of the event last returned by [`Context::run`].
+    /// Events not caused by a command, such as the implicit keep, have no
+    /// script or span.
+    pub fn event_metadata(&self) -> EventMetadata {
+        let (script, span) = if !self.script_stack.is_empty() {
+            self.event_origin()
+        } else {
+            self.final_event_origin.clone()
+        }
+        .map_or((None, None), |(script, span)| (Some(script), Some(span)));
+        EventMetadata {
+            script,
+            span,
+            correlation_id: self.correlation_id.clone(),
+        }
+    }
+
+    // Bytes held by the variables of the running script and its callers,
+    // recomputed from scratch rather than read from `vars_size`
+    #[cfg(any(test, feature = "testsuite"))]
+    pub(crate) fn variables_size(&self) -> usize {
+        self.vars_global
+            .values()
+            .map(|var| var.len())
+            .sum::<usize>()
+            + self
+                .vars_local
+                .iter()
+                .chain(self.vars_match.iter())
+                .chain(self.script_stack.iter().flat_map(|stack| {
+                    stack
+                        .prev_vars_local
+                        .iter()
+                        .chain(stack.prev_vars_match.iter())
+                }))
+                .map(|var| var.len())
+                .sum::<usize>()
+    }
+
+    #[inline(always)]
+    fn exceeds_variable_memory(&self) -> bool {
+        self.runtime.max_variable_memory > 0 && self.vars_size > self.runtime.max_variable_memory
+    }
+
+    // Script and position of the instruction being executed
+    pub(crate) fn event_origin(&self) -> Option<(Script, Span)> {
+        let stack = self.script_stack.last()?;
+        let span = stack.script.spans.get(self.pos.checked_sub(1)?)?;
+        Some((stack.name.clone(), *span))
+    }
+
     pub fn set_user_address(&mut self, from: impl Into<Cow<'x, str>>) {
         self.user_address = from.into();
     }
@@ -626,8 +1127,105 @@ impl<'x, C> Context<'x, C> {
         self
     }
 
+    /// Prepares the context to filter another message without allocating a
+    /// new one. The user settings, metadata, subaddress rules and compiled
+    /// script cache are kept, while the envelope, environment variables,
+    /// spam and virus status and any state left by a previous run are
+    /// cleared and have to be set again. Metadata provider answers are looked
+    /// up again for the new message.
+    pub fn reset_for(&mut self, raw_message: &'x [u8]) {
+        self.message = Arc::new(parse_message(raw_message));
+        self.message_size = usize::MAX;
+        self.message_source = None;
+        self.message_source_error = None;
+        *self.header_index.get_mut() = Arc::default();
+        self.expansion_exceeded.set(false);
+        *self.metadata_cache.get_mut() = Arc::default();
+        self.envelope.clear();
+        self.vars_env.clear();
+        self.spam_status = SpamStatus::Unknown;
+        self.virus_status = VirusStatus::Unknown;
+
+        self.part = 0;
+        self.part_iter = Vec::new().into_iter();
+        self.part_iter_stack.clear();
+        self.pos = usize::MAX;
+        self.test_result = false;
+        self.script_stack.clear();
+        self.scripts_included.clear();
+        self.vars_global = Arc::default();
+        self.vars_local.clear();
+        self.vars_match.clear();
+        self.vars_size = 0;
+        self.expr_stack.clear();
+        self.expr_pos = 0;
+
+        self.queued_events = vec![].into_iter();
+        self.final_event = Event::Keep {
+            flags: Vec::with_capacity(0),
+            message_id: 0,
+        }
+        .into();
+        self.last_message_id = 0;
+        self.main_message_id = 0;
+        self.has_changes = false;
+        self.num_redirects = 0;
+        self.num_instructions = 0;
+        self.num_part_iterations = 0;
+        self.num_out_messages = 0;
+        self.num_generated_messages = 0;
+        self.correlation_id = None;
+        self.duplicate_id = None;
+        self.vacation_id = None;
+        self.warnings.clear();
+        self.final_event_origin = None;
+        self.pending_modification = None;
+        if let Some(profiler) = &mut self.profiler {
+            **profiler = Default::default();
+        }
+        if let Some(audit_log) = &mut self.audit_log {
+            audit_log.clear();
+        }
+        self.is_deferred = false;
+
+        let now = unix_timestamp_millis();
+        self.current_time = now.div_euclid(1000);
+        self.current_time_millis = now.rem_euclid(1000) as u16;
+
+        self.apply_mime_leniency();
+        if self.runtime.received_environment {
+            self.set_env_from_received();
+        }
+    }
+
     pub fn take_message(&mut self) -> Message<'x> {
-        std::mem::take(&mut self.message)
+        self.load_message();
+        let message = std::mem::take(&mut self.message);
+        Arc::try_unwrap(message).unwrap_or_else(|message| (*message).clone())
+    }
+
+    // Errors reading the source are reported by the next call to Context::run
+    pub(crate) fn load_message(&mut self) {
+        if let Some(source) = self.message_source.take() {
+            let raw_message = match source.raw_message() {
+                Ok(raw_message) => raw_message,
+                Err(err) => {
+                    self.message_source_error = Some(err.to_string());
+                    return;
+                }
+            };
+            if let Some(mut message) = MessageParser::new().parse(raw_message) {
+                // Keep any header modifications made before the body was loaded
+                if let (Some(part), Some(prev_part)) =
+                    (message.parts.first_mut(), self.message.parts.first())
+                {
+                    part.headers = prev_part.headers.clone();
+                }
+                self.message = Arc::new(message);
+                *self.header_index.get_mut() = Arc::default();
+                self.apply_mime_leniency();
+            }
+        }
     }
 
     pub fn has_message_changed(&self) -> bool {
@@ -663,48 +1261,498 @@ impl<'x, C> Context<'x, C> {
     }
 }
 
-#[cfg(test)]
+impl<C> Deref for RuntimeRef<'_, C> {
+    type Target = Runtime<C>;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            RuntimeRef::Borrowed(runtime) => runtime,
+            RuntimeRef::Owned(runtime) => runtime,
+        }
+    }
+}
+
+impl<C: Clone> Clone for RuntimeRef<'_, C> {
+    fn clone(&self) -> Self {
+        match self {
+            RuntimeRef::Borrowed(runtime) => RuntimeRef::Borrowed(runtime),
+            RuntimeRef::Owned(runtime) => RuntimeRef::Owned(runtime.clone()),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "testsuite"))]
+impl<C: Clone> RuntimeRef<'_, C> {
+    pub(crate) fn to_mut(&mut self) -> &mut Runtime<C> {
+        if let RuntimeRef::Borrowed(runtime) = self {
+            *self = RuntimeRef::Owned(Box::new((*runtime).clone()));
+        }
+        match self {
+            RuntimeRef::Owned(runtime) => runtime,
+            RuntimeRef::Borrowed(_) => unreachable!(),
+        }
+    }
+}
+
 impl<'x, C: Clone> Context<'x, C> {
-    pub(crate) fn new(runtime: &'x Runtime<C>, message: Message<'x>) -> Self {
+    /// Creates a context for the same message that is ready to run another
+    /// script, without parsing the message again. The message, variables and
+    /// caches are shared with this context and only copied once either one
+    /// changes them, while the state of the script being run starts from
+    /// scratch. Changes made by either context are not seen by the other.
+    pub fn fork(&self) -> Self {
         Context {
-            runtime: runtime.clone(),
-            message,
+            runtime: self.runtime.clone(),
+            message: self.message.clone(),
             part: 0,
             part_iter: Vec::new().into_iter(),
             part_iter_stack: Vec::new(),
             pos: usize::MAX,
             test_result: false,
-            script_cache: AHashMap::new(),
+            script_cache: self.script_cache.clone(),
+            scripts_included: AHashSet::new(),
             script_stack: Vec::with_capacity(0),
-            vars_global: AHashMap::new(),
-            vars_env: AHashMap::new(),
+            vars_global: self.vars_global.clone(),
+            vars_env: self.vars_env.clone(),
             vars_local: Vec::with_capacity(0),
             vars_match: Vec::with_capacity(0),
+            vars_size: self.vars_global.values().map(|var| var.len()).sum(),
             expr_stack: Vec::with_capacity(16),
             expr_pos: 0,
-            envelope: Vec::new(),
-            metadata: Vec::new(),
-            message_size: usize::MAX,
+            envelope: self.envelope.clone(),
+            subaddress: self.subaddress.clone(),
+            metadata: self.metadata.clone(),
+            message_size: self.message_size,
+            message_source: self.message_source,
+            message_source_error: None,
+            header_index: self.header_index.clone(),
+            expansion_exceeded: Cell::new(false),
+            metadata_cache: self.metadata_cache.clone(),
             final_event: Event::Keep {
                 flags: Vec::with_capacity(0),
-                message_id: 0,
+                message_id: self.main_message_id,
             }
             .into(),
             queued_events: vec![].into_iter(),
-            has_changes: false,
-            user_address: "".into(),
-            user_full_name: "".into(),
-            current_time: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .map(|d| d.as_secs())
-                .unwrap_or(0) as i64,
+            has_changes: self.has_changes,
+            user_address: self.user_address.clone(),
+            user_full_name: self.user_full_name.clone(),
+            current_time: self.current_time,
+            current_time_millis: self.current_time_millis,
             num_redirects: 0,
             num_instructions: 0,
+            num_part_iterations: 0,
             num_out_messages: 0,
-            last_message_id: 0,
-            main_message_id: 0,
-            virus_status: VirusStatus::Unknown,
-            spam_status: SpamStatus::Unknown,
+            num_generated_messages: 0,
+            correlation_id: self.correlation_id.clone(),
+            duplicate_id: None,
+            warnings: Vec::new(),
+            vacation_id: None,
+            final_event_origin: None,
+            pending_modification: None,
+            profiler: self.profiler.as_ref().map(|_| Box::default()),
+            audit_log: self.audit_log.as_ref().map(|_| Vec::new()),
+            is_deferred: false,
+            last_message_id: self.last_message_id,
+            main_message_id: self.main_message_id,
+            virus_status: self.virus_status,
+            spam_status: self.spam_status,
+        }
+    }
+
+    pub(crate) fn runtime_mut(&mut self) -> &mut Runtime<C> {
+        self.runtime.to_mut()
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic while executing script".to_string()
+    }
+}
+
+#[cfg(test)]
+impl<'x, C> Context<'x, C> {
+    // Compiles and runs a script answering true to every event, shared by the
+    // unit tests of the runtime options
+    pub(crate) fn run_test_script(&mut self, script: &[u8]) -> Vec<Result<Event, RuntimeError>> {
+        let mut input = Input::script("", crate::Compiler::new().compile(script).unwrap());
+        let mut events = Vec::new();
+        while let Some(event) = self.run(input) {
+            events.push(event);
+            input = true.into();
+        }
+        events
+    }
+
+    // Runs a script that is expected to succeed and returns its events
+    pub(crate) fn run_test_events(&mut self, script: &[u8]) -> Vec<Event> {
+        self.run_test_script(script)
+            .into_iter()
+            .map(|event| event.unwrap())
+            .collect()
+    }
+
+    // Runs a script that is expected to succeed and returns the folders of
+    // its fileinto actions
+    pub(crate) fn run_test_folders(&mut self, script: &[u8]) -> Vec<String> {
+        self.run_test_events(script)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::FileInto { folder, .. } => Some(folder),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+    };
+
+    use crate::{
+        compiler::grammar::Capability, runtime::RuntimeError, Compiler, Event, FailurePolicy,
+        FunctionMap, Input, Runtime, Script,
+    };
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Error {
+        Runtime,
+        Rejected,
+    }
+
+    impl From<RuntimeError> for Error {
+        fn from(_: RuntimeError) -> Self {
+            Error::Runtime
+        }
+    }
+
+    #[test]
+    fn run_with_handler() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "mailbox"];
+                if mailboxexists "Archive" {
+                    fileinto "Archive";
+                }
+                discard;"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        let mut events = Vec::new();
+        let result = runtime.filter(b"Subject: test\r\n\r\nbody").run_with(
+            Input::script("", script.clone()),
+            |event| {
+                let input = matches!(event, Event::MailboxExists { .. }).into();
+                events.push(event);
+                Ok::<_, Error>(input)
+            },
+        );
+        assert_eq!(result, Ok(()));
+        assert!(matches!(
+            events.as_slice(),
+            [
+                Event::MailboxExists { .. },
+                Event::FileInto { .. },
+                Event::Discard
+            ]
+        ));
+
+        let mut instance = runtime.filter(b"Subject: test\r\n\r\nbody");
+        let fut = pin!(
+            instance.run_with_async(Input::script("", script), |event| async move {
+                match event {
+                    Event::MailboxExists { .. } => Ok(Input::True),
+                    _ => Err(Error::Rejected),
+                }
+            })
+        );
+        assert_eq!(block_on(fut), Err(Error::Rejected));
+    }
+
+    #[test]
+    fn event_metadata() {
+        let script = Compiler::new()
+            .compile(b"require \"fileinto\";\nfileinto \"Archive\";\ndiscard;\n")
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut instance = runtime
+            .filter(b"Subject: test\r\n\r\nbody")
+            .with_correlation_id("delivery-1");
+        let mut input = Input::script("main", script);
+        let mut events = Vec::new();
+        while let Some(event) = instance.run(input) {
+            let metadata = instance.event_metadata();
+            events.push((
+                event.unwrap(),
+                metadata.script,
+                metadata.span.map(|span| span.line_num),
+                metadata.correlation_id,
+            ));
+            input = Input::True;
+        }
+
+        let script = Some(Script::Personal("main".to_string()));
+        let id = Some("delivery-1".to_string());
+        assert!(matches!(
+            events.as_slice(),
+            [
+                (Event::FileInto { .. }, s1, Some(2), i1),
+                (Event::Discard, s2, Some(3), i2),
+            ] if s1 == &script && s2 == &script && i1 == &id && i2 == &id
+        ));
+    }
+
+    #[test]
+    fn run_catching() {
+        let mut fnc_map = FunctionMap::new().with_function("boom", |_, _| panic!("boom"));
+        let script = Compiler::new()
+            .register_functions(&mut fnc_map)
+            .compile(
+                b"require [\"vnd.stalwart.expressions\", \"fileinto\"];\nif eval \"boom('x')\" {\n    fileinto \"Archive\";\n}\n",
+            )
+            .unwrap();
+
+        for (policy, expected) in [
+            (
+                FailurePolicy::Continue,
+                Some(Event::Keep {
+                    flags: vec![],
+                    message_id: 0,
+                }),
+            ),
+            (
+                FailurePolicy::KeepWithFlag("$SieveError".to_string()),
+                Some(Event::Keep {
+                    flags: vec!["$SieveError".to_string()],
+                    message_id: 0,
+                }),
+            ),
+            (FailurePolicy::Discard, Some(Event::Discard)),
+            (FailurePolicy::Defer, None),
+        ] {
+            let is_defer = policy == FailurePolicy::Defer;
+            let runtime = Runtime::new()
+                .with_capability(Capability::Expressions)
+                .with_functions(&mut fnc_map)
+                .with_failure_policy(policy)
+                .with_redactor(|value| value.replace("boom", "****"));
+            let mut instance = runtime.filter(b"Subject: test\r\n\r\nbody");
+
+            let result = instance.run_catching(Input::script("main", script.clone()));
+            assert!(
+                matches!(
+                    &result,
+                    Some(Err(RuntimeError::Internal { message, script: Some(Script::Personal(name)), span: Some(span) }))
+                        if message == "****" && name == "main" && span.line_num == 2
+                ),
+                "{result:?}"
+            );
+            assert_eq!(
+                instance.run_catching(Input::True).map(Result::unwrap),
+                expected
+            );
+            assert!(instance.run_catching(Input::True).is_none());
+            assert_eq!(instance.is_deferred(), is_defer);
+        }
+    }
+
+    fn block_on<F: Future>(mut fut: std::pin::Pin<&mut F>) -> F::Output {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+        let waker = unsafe { Waker::from_raw(clone(std::ptr::null())) };
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn fork() {
+        let compiler = Compiler::new();
+        let runtime = Runtime::new();
+        let run = |ctx: &mut crate::Context<()>, script: &[u8]| {
+            let mut events = Vec::new();
+            let mut input = Input::script("", compiler.compile(script).unwrap());
+            while let Some(event) = ctx.run(input) {
+                events.push(event.unwrap());
+                input = Input::True;
+            }
+            events
+        };
+
+        let mut ctx = runtime
+            .filter(b"From: a@example.org\r\nSubject: Report\r\n\r\nbody")
+            .with_user_address("b@example.org");
+        let mut fork_a = ctx.fork();
+        let mut fork_b = ctx.fork();
+        assert!(Arc::ptr_eq(&ctx.message, &fork_a.message));
+
+        // Changes made by a fork are not seen by the others
+        let events = run(
+            &mut fork_a,
+            b"require [\"editheader\", \"fileinto\"];\naddheader \"X-Test\" \"1\";\nif header :contains \"subject\" \"report\" { fileinto \"Reports\"; }",
+        );
+        assert!(matches!(
+            events.last(),
+            Some(Event::FileInto { folder, .. }) if folder == "Reports"
+        ));
+        assert!(fork_a.has_message_changed());
+
+        for ctx in [&mut fork_b, &mut ctx] {
+            let events = run(ctx, b"if exists \"x-test\" { discard; }");
+            assert!(matches!(events.last(), Some(Event::Keep { .. })));
+            assert!(!ctx.has_message_changed());
+        }
+        assert_eq!(fork_b.user_address, "b@example.org");
+
+        // The message is only copied by the fork that modified it
+        assert!(!Arc::ptr_eq(&ctx.message, &fork_a.message));
+        assert!(Arc::ptr_eq(&ctx.message, &fork_b.message));
+    }
+
+    #[test]
+    fn reset_for() {
+        let compiler = Compiler::new();
+        let runtime = Runtime::new();
+        let script = compiler
+            .compile(
+                b"require [\"fileinto\", \"editheader\"];\nif header :contains \"subject\" \"report\" { addheader \"X-Seen\" \"1\"; fileinto \"Reports\"; }",
+            )
+            .unwrap();
+
+        let messages: [&[u8]; 3] = [
+            b"Subject: Report\r\n\r\nbody",
+            b"Subject: Hello\r\n\r\nbody",
+            b"Subject: Another report\r\n\r\nbody",
+        ];
+        let mut ctx = runtime
+            .filter(messages[0])
+            .with_user_address("a@example.org");
+
+        for (pos, message) in messages.into_iter().enumerate() {
+            if pos > 0 {
+                ctx.reset_for(message);
+            }
+            let mut events = Vec::new();
+            let mut input = Input::script("main", script.clone());
+            while let Some(event) = ctx.run(input) {
+                events.push(event.unwrap());
+                input = Input::True;
+            }
+
+            if pos == 1 {
+                assert!(
+                    matches!(events.as_slice(), [Event::Keep { message_id: 0, .. }]),
+                    "{events:?}"
+                );
+                assert!(!ctx.has_message_changed());
+            } else {
+                assert!(
+                    matches!(
+                        events.as_slice(),
+                        [Event::CreatedMessage { message_id: 1, .. }, Event::FileInto { folder, message_id: 1, .. }] if folder == "Reports"
+                    ),
+                    "{events:?}"
+                );
+                assert!(ctx.has_message_changed());
+            }
+            assert_eq!(ctx.user_address, "a@example.org");
+            assert_eq!(ctx.script_cache.len(), 1);
+        }
+    }
+
+    #[test]
+    fn include_once() {
+        let compiler = Compiler::new();
+        let runtime = Runtime::new();
+        let main = compiler
+            .compile(b"require [\"include\", \"fileinto\"];\ninclude :once \"folder\";\ninclude :once \"folder\";")
+            .unwrap();
+        let included = compiler
+            .compile(b"require \"fileinto\";\nfileinto \"Folder\";")
+            .unwrap();
+        let run = |ctx: &mut crate::Context<()>| {
+            let mut folders = Vec::new();
+            let mut input = Input::script("main", main.clone());
+            while let Some(event) = ctx.run(input) {
+                input = Input::True;
+                match event.unwrap() {
+                    Event::IncludeScript { name, .. } => {
+                        input = Input::script(name, included.clone());
+                    }
+                    Event::FileInto { folder, .. } => folders.push(folder),
+                    _ => (),
+                }
+            }
+            folders
+        };
+
+        // Scripts included by an earlier run are included again
+        let mut ctx = runtime.filter(b"Subject: test\r\n\r\nbody");
+        assert_eq!(run(&mut ctx), ["Folder"]);
+        assert_eq!(run(&mut ctx.fork()), ["Folder"]);
+        ctx.reset_for(b"Subject: another test\r\n\r\nbody");
+        assert_eq!(run(&mut ctx), ["Folder"]);
+    }
+
+    #[test]
+    fn failure_policy() {
+        let script = Compiler::new()
+            .compile(b"require \"fileinto\";\nfileinto \"Work\";\ndiscard;\ndiscard;\ndiscard;")
+            .unwrap();
+
+        for (policy, expected) in [
+            (FailurePolicy::Continue, Some(Event::Discard)),
+            (
+                FailurePolicy::Keep,
+                Some(Event::Keep {
+                    flags: vec![],
+                    message_id: 0,
+                }),
+            ),
+            (
+                FailurePolicy::KeepWithFlag("$SieveError".to_string()),
+                Some(Event::Keep {
+                    flags: vec!["$SieveError".to_string()],
+                    message_id: 0,
+                }),
+            ),
+            (FailurePolicy::Discard, Some(Event::Discard)),
+            (FailurePolicy::Defer, None),
+        ] {
+            let is_defer = policy == FailurePolicy::Defer;
+            let runtime = Runtime::new().with_cpu_limit(3).with_failure_policy(policy);
+            let mut ctx = runtime.filter(b"Subject: test\r\n\r\nbody");
+
+            // Actions returned before the error are not undone
+            assert!(matches!(
+                ctx.run(Input::script("", script.clone())),
+                Some(Ok(Event::FileInto { .. }))
+            ));
+            assert!(matches!(
+                ctx.run(Input::True),
+                Some(Err(RuntimeError::CPULimitReached))
+            ));
+            assert_eq!(ctx.run(Input::True).map(Result::unwrap), expected);
+            assert!(ctx.run(Input::True).is_none());
+            assert_eq!(ctx.is_deferred(), is_defer);
         }
     }
 }
@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use std::cmp::Ordering;
+use std::{borrow::Cow, cmp::Ordering};
 
 use mail_parser::{
     decoders::html::{html_to_text, text_to_html},
@@ -125,13 +125,27 @@ impl<'x, C> Context<'x, C> {
                         Value::Number(n) => {
                             data.push_str(&n.to_string());
                         }
-                        Value::Regex(_) => (),
+                        Value::Regex(_)
+                        | Value::Glob(_)
+                        | Value::Contains(_)
+                        | Value::Header(_) => (),
+                    }
+
+                    // Context::run reports the error once the instruction completes
+                    let max_size = self.runtime.max_expansion_size;
+                    if max_size > 0 && data.len() > max_size {
+                        self.expansion_exceeded.set(true);
+                        data.clear();
+                        break;
                     }
                 }
                 data.into()
             }
             Value::Number(n) => Variable::from(*n),
             Value::Regex(r) => Variable::String(r.expr.clone().into()),
+            Value::Glob(g) => Variable::String(g.expr.clone().into()),
+            Value::Contains(_) => Variable::default(),
+            Value::Header(h) => Variable::String(h.expr.clone().into()),
         }
     }
 
@@ -208,9 +222,34 @@ impl<'x, C> Context<'x, C> {
     pub(crate) fn eval_values_owned(&self, strings: &[Value]) -> Vec<String> {
         strings
             .iter()
-            .map(|s| self.eval_value(s).to_string().into_owned())
+            .map(|s| self.eval_string(s).into_owned())
             .collect()
     }
+
+    // Constants and local variables are borrowed, anything else is evaluated
+    pub(crate) fn eval_string<'z>(&'z self, string: &'z Value) -> Cow<'z, str> {
+        match string {
+            Value::Text(text) => Cow::Borrowed(text.as_str()),
+            Value::Regex(r) => Cow::Borrowed(r.expr.as_str()),
+            Value::Glob(g) => Cow::Borrowed(g.expr.as_str()),
+            Value::Contains(_) => Cow::Borrowed(""),
+            Value::Header(h) => Cow::Borrowed(h.expr.as_str()),
+            Value::Variable(VariableType::Local(var_num)) => self
+                .vars_local
+                .get(*var_num)
+                .map_or(Cow::Borrowed(""), |v| v.to_string()),
+            Value::Variable(VariableType::Match(var_num)) => self
+                .vars_match
+                .get(*var_num)
+                .map_or(Cow::Borrowed(""), |v| v.to_string()),
+            _ => Cow::Owned(self.eval_value(string).to_string().into_owned()),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn eval_strings<'z>(&'z self, strings: &'z [Value]) -> Vec<Cow<'z, str>> {
+        strings.iter().map(|s| self.eval_string(s)).collect()
+    }
 }
 
 impl HeaderVariable {
@@ -496,3 +535,46 @@ fn sanitize_raw_header(bytes: &[u8]) -> String {
 
     result.into_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, sync::Arc};
+
+    use crate::{
+        compiler::{header_key::HeaderKey, Number, Value, VariableType},
+        runtime::Variable,
+        Runtime,
+    };
+
+    #[test]
+    fn eval_string() {
+        let runtime = Runtime::new();
+        let mut ctx = runtime.filter(b"Subject: test\r\n\r\nbody");
+        ctx.vars_local = vec![Variable::from("local")];
+
+        for (value, expected, is_borrowed) in [
+            (Value::Text(Arc::new("text".to_string())), "text", true),
+            (Value::Variable(VariableType::Local(0)), "local", true),
+            (Value::Variable(VariableType::Local(1)), "", true),
+            (Value::Number(Number::Integer(5)), "5", false),
+            (
+                Value::Header(HeaderKey::new("X-Test").unwrap()),
+                "X-Test",
+                true,
+            ),
+            (
+                Value::List(vec![
+                    Value::Text(Arc::new("a ".to_string())),
+                    Value::Variable(VariableType::Local(0)),
+                ]),
+                "a local",
+                false,
+            ),
+        ] {
+            let result = ctx.eval_string(&value);
+            assert_eq!(result, expected);
+            assert_eq!(matches!(result, Cow::Borrowed(_)), is_borrowed, "{value:?}");
+            assert_eq!(ctx.eval_value(&value).to_string(), expected);
+        }
+    }
+}
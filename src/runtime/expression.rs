@@ -24,10 +24,12 @@
 use std::{cmp::Ordering, fmt::Display};
 
 use crate::compiler::grammar::expr::parser::ID_EXTERNAL;
-use crate::Event;
 use crate::{compiler::Number, runtime::Variable, Context};
+use crate::{Event, IntegerDivision, IntegerOverflow, MatchAs};
 
-use crate::compiler::grammar::expr::{BinaryOperator, Constant, Expression, UnaryOperator};
+use crate::compiler::grammar::expr::{
+    functions::BuiltIn, BinaryOperator, Constant, Expression, UnaryOperator,
+};
 
 impl<'x, C> Context<'x, C> {
     pub(crate) fn eval_expression(&mut self, expr: &[Expression]) -> Result<Variable, Event> {
@@ -49,22 +51,46 @@ impl<'x, C> Context<'x, C> {
                     });
                 }
                 Expression::BinaryOperator(op) => {
-                    let right = self.expr_stack.pop().unwrap_or_default();
-                    let left = self.expr_stack.pop().unwrap_or_default();
-                    self.expr_stack.push(match op {
-                        BinaryOperator::Add => left.op_add(right),
-                        BinaryOperator::Subtract => left.op_subtract(right),
-                        BinaryOperator::Multiply => left.op_multiply(right),
-                        BinaryOperator::Divide => left.op_divide(right),
-                        BinaryOperator::And => left.op_and(right),
-                        BinaryOperator::Or => left.op_or(right),
-                        BinaryOperator::Xor => left.op_xor(right),
-                        BinaryOperator::Eq => left.op_eq(right),
-                        BinaryOperator::Ne => left.op_ne(right),
-                        BinaryOperator::Lt => left.op_lt(right),
-                        BinaryOperator::Le => left.op_le(right),
-                        BinaryOperator::Gt => left.op_gt(right),
-                        BinaryOperator::Ge => left.op_ge(right),
+                    let mut right = self.expr_stack.pop().unwrap_or_default();
+                    let mut left = self.expr_stack.pop().unwrap_or_default();
+                    if matches!(
+                        op,
+                        BinaryOperator::Subtract
+                            | BinaryOperator::Multiply
+                            | BinaryOperator::Divide
+                    ) {
+                        // Numeric strings are subject to the same overflow and division policies
+                        left = left.parse_string_number();
+                        right = right.parse_string_number();
+                    }
+                    self.expr_stack.push(match (op, left, right) {
+                        (
+                            BinaryOperator::Add
+                            | BinaryOperator::Subtract
+                            | BinaryOperator::Multiply,
+                            Variable::Integer(a),
+                            Variable::Integer(b),
+                        ) => self.runtime.integer_overflow.eval(op, a, b),
+                        (BinaryOperator::Divide, Variable::Integer(a), Variable::Integer(b))
+                            if self.runtime.integer_division == IntegerDivision::Truncate =>
+                        {
+                            Variable::Integer(a.checked_div(b).unwrap_or(0))
+                        }
+                        (BinaryOperator::In, value, lists) => {
+                            let values = value.into_string_array();
+                            let lists = lists.into_string_array();
+                            if values.is_empty() || lists.is_empty() {
+                                Variable::from(false)
+                            } else {
+                                self.pos -= 1; // Resumes once the host resolves the lists
+                                return Err(Event::ListContains {
+                                    lists,
+                                    values,
+                                    match_as: MatchAs::Lowercase,
+                                });
+                            }
+                        }
+                        (op, left, right) => left.op(op, right),
                     });
                 }
                 Expression::Function { id, num_args } => {
@@ -90,6 +116,21 @@ impl<'x, C> Context<'x, C> {
                         });
                     }
                 }
+                Expression::BuiltIn { function, num_args } => {
+                    let num_args = *num_args as usize;
+                    let mut arguments = vec![Variable::Integer(0); num_args];
+                    for arg_num in 0..num_args {
+                        arguments[num_args - arg_num - 1] =
+                            self.expr_stack.pop().unwrap_or_default();
+                    }
+                    let pattern = (*function == BuiltIn::RegexCapture)
+                        .then(|| arguments.get(1).cloned().unwrap_or_default());
+                    let result = function.eval(self, arguments);
+                    if let Some(pattern) = pattern {
+                        self.set_named_captures(&pattern, &result);
+                    }
+                    self.expr_stack.push(result);
+                }
                 Expression::JmpIf { val, pos } => {
                     if self.expr_stack.last().map_or(false, |v| v.to_bool()) == *val {
                         self.expr_pos += *pos as usize;
@@ -98,6 +139,20 @@ impl<'x, C> Context<'x, C> {
                         }
                     }
                 }
+                Expression::Branch { pos } => {
+                    if !self.expr_stack.pop().map_or(false, |v| v.to_bool()) {
+                        self.expr_pos += *pos as usize;
+                        for _ in 0..*pos {
+                            exprs.next();
+                        }
+                    }
+                }
+                Expression::Jmp { pos } => {
+                    self.expr_pos += *pos as usize;
+                    for _ in 0..*pos {
+                        exprs.next();
+                    }
+                }
                 Expression::ArrayAccess => {
                     let index = self.expr_stack.pop().unwrap_or_default().to_usize();
                     let array = self.expr_stack.pop().unwrap_or_default().into_array();
@@ -122,7 +177,62 @@ impl<'x, C> Context<'x, C> {
     }
 }
 
+impl IntegerOverflow {
+    pub(crate) fn eval(&self, op: &BinaryOperator, a: i64, b: i64) -> Variable {
+        let result = match op {
+            BinaryOperator::Add => a.checked_add(b),
+            BinaryOperator::Subtract => a.checked_sub(b),
+            BinaryOperator::Multiply => a.checked_mul(b),
+            _ => unreachable!("Invalid integer operator {op:?}"),
+        };
+
+        match (result, self) {
+            (Some(n), _) => Variable::Integer(n),
+            (None, IntegerOverflow::Saturate) => Variable::Integer(match op {
+                BinaryOperator::Add => a.saturating_add(b),
+                BinaryOperator::Subtract => a.saturating_sub(b),
+                _ => a.saturating_mul(b),
+            }),
+            (None, IntegerOverflow::Wrap) => Variable::Integer(match op {
+                BinaryOperator::Add => a.wrapping_add(b),
+                BinaryOperator::Subtract => a.wrapping_sub(b),
+                _ => a.wrapping_mul(b),
+            }),
+            (None, IntegerOverflow::Float) => Variable::Float(match op {
+                BinaryOperator::Add => a as f64 + b as f64,
+                BinaryOperator::Subtract => a as f64 - b as f64,
+                _ => a as f64 * b as f64,
+            }),
+        }
+    }
+}
+
 impl Variable {
+    pub(crate) fn op(self, op: &BinaryOperator, other: Variable) -> Variable {
+        match op {
+            BinaryOperator::Add => self.op_add(other),
+            BinaryOperator::Subtract => self.op_subtract(other),
+            BinaryOperator::Multiply => self.op_multiply(other),
+            BinaryOperator::Divide => self.op_divide(other),
+            BinaryOperator::Modulo => self.op_modulo(other),
+            BinaryOperator::And => self.op_and(other),
+            BinaryOperator::Or => self.op_or(other),
+            BinaryOperator::Xor => self.op_xor(other),
+            BinaryOperator::Eq => self.op_eq(other),
+            BinaryOperator::Ne => self.op_ne(other),
+            BinaryOperator::Lt => self.op_lt(other),
+            BinaryOperator::Le => self.op_le(other),
+            BinaryOperator::Gt => self.op_gt(other),
+            BinaryOperator::Ge => self.op_ge(other),
+            BinaryOperator::ShiftLeft => self.op_shift_left(other),
+            BinaryOperator::ShiftRight => self.op_shift_right(other),
+            BinaryOperator::BitwiseAnd => self.op_bitwise_and(other),
+            BinaryOperator::BitwiseOr => self.op_bitwise_or(other),
+            BinaryOperator::BitwiseXor => self.op_bitwise_xor(other),
+            BinaryOperator::In => Variable::from(false),
+        }
+    }
+
     pub fn op_add(self, other: Variable) -> Variable {
         match (self, other) {
             (Variable::Integer(a), Variable::Integer(b)) => Variable::Integer(a.saturating_add(b)),
@@ -200,6 +310,56 @@ impl Variable {
         }
     }
 
+    pub fn op_modulo(self, other: Variable) -> Variable {
+        match (self, other) {
+            (Variable::Integer(a), Variable::Integer(b)) => {
+                Variable::Integer(a.checked_rem_euclid(b).unwrap_or(0))
+            }
+            (Variable::Float(a), Variable::Float(b)) => {
+                Variable::Float(if b != 0.0 { a.rem_euclid(b) } else { 0.0 })
+            }
+            (Variable::Integer(a), Variable::Float(b)) => Variable::Float(if b != 0.0 {
+                (a as f64).rem_euclid(b)
+            } else {
+                0.0
+            }),
+            (Variable::Float(a), Variable::Integer(b)) => {
+                Variable::Float(if b != 0 { a.rem_euclid(b as f64) } else { 0.0 })
+            }
+            (a, b) => a.parse_number().op_modulo(b.parse_number()),
+        }
+    }
+
+    pub fn op_shift_left(self, other: Variable) -> Variable {
+        Variable::Integer(
+            u32::try_from(other.parse_number().to_integer())
+                .ok()
+                .and_then(|shift| self.parse_number().to_integer().checked_shl(shift))
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn op_shift_right(self, other: Variable) -> Variable {
+        Variable::Integer(
+            u32::try_from(other.parse_number().to_integer())
+                .ok()
+                .and_then(|shift| self.parse_number().to_integer().checked_shr(shift))
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn op_bitwise_and(self, other: Variable) -> Variable {
+        Variable::Integer(self.parse_number().to_integer() & other.parse_number().to_integer())
+    }
+
+    pub fn op_bitwise_or(self, other: Variable) -> Variable {
+        Variable::Integer(self.parse_number().to_integer() | other.parse_number().to_integer())
+    }
+
+    pub fn op_bitwise_xor(self, other: Variable) -> Variable {
+        Variable::Integer(self.parse_number().to_integer() ^ other.parse_number().to_integer())
+    }
+
     pub fn op_and(self, other: Variable) -> Variable {
         Variable::Integer(i64::from(self.to_bool() & other.to_bool()))
     }
@@ -266,6 +426,13 @@ impl Variable {
         }
     }
 
+    fn parse_string_number(self) -> Variable {
+        match self {
+            Variable::String(_) => self.parse_number(),
+            _ => self,
+        }
+    }
+
     pub fn to_bool(&self) -> bool {
         match self {
             Variable::Float(f) => *f != 0.0,
@@ -419,8 +586,7 @@ mod test {
     use crate::{
         compiler::{
             grammar::expr::{
-                parser::ExpressionParser, tokenizer::Tokenizer, BinaryOperator, Expression, Token,
-                UnaryOperator,
+                parser::ExpressionParser, tokenizer::Tokenizer, Expression, Token, UnaryOperator,
             },
             VariableType,
         },
@@ -456,21 +622,7 @@ mod test {
                     Expression::BinaryOperator(op) => {
                         let right = stack.pop()?;
                         let left = stack.pop()?;
-                        stack.push(match op {
-                            BinaryOperator::Add => left.op_add(right),
-                            BinaryOperator::Subtract => left.op_subtract(right),
-                            BinaryOperator::Multiply => left.op_multiply(right),
-                            BinaryOperator::Divide => left.op_divide(right),
-                            BinaryOperator::And => left.op_and(right),
-                            BinaryOperator::Or => left.op_or(right),
-                            BinaryOperator::Xor => left.op_xor(right),
-                            BinaryOperator::Eq => left.op_eq(right),
-                            BinaryOperator::Ne => left.op_ne(right),
-                            BinaryOperator::Lt => left.op_lt(right),
-                            BinaryOperator::Le => left.op_le(right),
-                            BinaryOperator::Gt => left.op_gt(right),
-                            BinaryOperator::Ge => left.op_ge(right),
-                        });
+                        stack.push(left.op(op, right));
                     }
                     Expression::JmpIf { val, pos } => {
                         if stack.last()?.to_bool() == *val {
@@ -604,6 +756,34 @@ mod test {
         }
     }
 
+    #[test]
+    fn eval_bitwise_operators() {
+        for (expr, logical, bitwise) in [
+            ("12 & 10", 1, 8),
+            ("12 | 3", 1, 15),
+            ("12 ^ 10", 0, 6),
+            ("12 && 10", 1, 1),
+            ("0 || 3", 1, 1),
+            ("1 | 2 & 4", 1, 1),
+        ] {
+            for (is_bitwise, expected) in [(false, logical), (true, bitwise)] {
+                let result = ExpressionParser::from_tokenizer(
+                    Tokenizer::new(expr, |var_name: &str, _: bool| {
+                        Ok::<_, String>(Token::Variable(VariableType::Global(var_name.to_string())))
+                    })
+                    .with_bitwise_operators(is_bitwise),
+                )
+                .parse()
+                .unwrap()
+                .output
+                .eval(&HashMap::new())
+                .unwrap();
+
+                assert_eq!(result, Variable::Integer(expected), "{expr} ({is_bitwise})");
+            }
+        }
+    }
+
     fn assert_expr(expr: &str, variables: &HashMap<String, Variable>) {
         let e = parse_expression(expr);
 
@@ -0,0 +1,420 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::fmt::Write;
+
+use mail_builder::encoders::base64::base64_encode;
+use mail_parser::{
+    decoders::base64::base64_decode, parsers::MessageStream, DateTime, HeaderName, HeaderValue,
+};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    compiler::{
+        grammar::{
+            actions::action_set::Modifier, expr::functions::BuiltIn, tests::test_date::DATE_PART,
+            AddressPart,
+        },
+        HeaderPart, HeaderVariable, MessagePart, VariableType,
+    },
+    Context, Envelope,
+};
+
+use super::Variable;
+
+impl BuiltIn {
+    pub(crate) fn eval<C>(&self, ctx: &Context<C>, args: Vec<Variable>) -> Variable {
+        let num_args = args.len();
+        let mut args = args.into_iter();
+        let value = args.next().unwrap_or_default();
+        let arg = args.next().unwrap_or_default();
+
+        match self {
+            BuiltIn::Split => {
+                let value = value.to_string();
+                let separator = arg.to_string();
+                let max_len = ctx.runtime.max_variable_size;
+                let mut size = 0;
+                let mut fits = |item: &Variable| {
+                    size += item.len() + 2;
+                    size <= max_len
+                };
+                let items: Vec<Variable> = if separator.is_empty() {
+                    value
+                        .chars()
+                        .map(|ch| Variable::from(ch.to_string()))
+                        .take_while(&mut fits)
+                        .collect()
+                } else {
+                    value
+                        .split(separator.as_ref())
+                        .map(Variable::from)
+                        .take_while(&mut fits)
+                        .collect()
+                };
+                items.into()
+            }
+            BuiltIn::Join => {
+                let items = value.to_string_array();
+                let separator = arg.to_string();
+                let max_len = ctx.runtime.max_variable_size;
+                let mut joined = String::new();
+                for (pos, item) in items.iter().enumerate() {
+                    if (pos > 0 && !push_limited(&mut joined, &separator, max_len))
+                        || !push_limited(&mut joined, item, max_len)
+                    {
+                        break;
+                    }
+                }
+                joined.into()
+            }
+            BuiltIn::Substring => {
+                let value = value.to_string();
+                let len = value.chars().count() as i64;
+                let start = arg.to_integer();
+                let start = if start < 0 {
+                    (len + start).max(0)
+                } else {
+                    start
+                };
+                let count = args.next().map_or(len, |count| count.to_integer().max(0));
+                let substring: String = value
+                    .chars()
+                    .skip(start as usize)
+                    .take(count as usize)
+                    .collect();
+                substring.into()
+            }
+            BuiltIn::Replace => {
+                let from = arg.to_string();
+                if !from.is_empty() {
+                    let to = args.next().unwrap_or_default();
+                    let to = to.to_string();
+                    let value = value.to_string();
+                    let max_len = ctx.runtime.max_variable_size;
+                    let mut replaced = String::with_capacity(value.len().min(max_len));
+                    for (pos, part) in value.split(from.as_ref()).enumerate() {
+                        if (pos > 0 && !push_limited(&mut replaced, &to, max_len))
+                            || !push_limited(&mut replaced, part, max_len)
+                        {
+                            break;
+                        }
+                    }
+                    replaced.into()
+                } else {
+                    value
+                }
+            }
+            BuiltIn::Trim => {
+                let value = value.to_string();
+                value.trim().into()
+            }
+            BuiltIn::Len => match &value {
+                Variable::Array(items) => items.len().into(),
+                _ => value.to_string().chars().count().into(),
+            },
+            BuiltIn::Lower => {
+                let value = value.to_string();
+                let max_len = ctx.runtime.max_variable_size;
+                let mut lower = String::new();
+                push_limited(
+                    &mut lower,
+                    &str_prefix(&value, max_len).to_lowercase(),
+                    max_len,
+                );
+                lower.into()
+            }
+            BuiltIn::Upper => {
+                let value = value.to_string();
+                let max_len = ctx.runtime.max_variable_size;
+                let mut upper = String::new();
+                push_limited(
+                    &mut upper,
+                    &str_prefix(&value, max_len).to_uppercase(),
+                    max_len,
+                );
+                upper.into()
+            }
+            BuiltIn::StartsWith => {
+                let value = value.to_string();
+                let prefix = arg.to_string();
+                value.starts_with(prefix.as_ref()).into()
+            }
+            BuiltIn::EndsWith => {
+                let value = value.to_string();
+                let suffix = arg.to_string();
+                value.ends_with(suffix.as_ref()).into()
+            }
+            BuiltIn::Now => Variable::Integer(ctx.current_time),
+            BuiltIn::ParseDate => match value {
+                Variable::Integer(_) => value,
+                _ => parse_date(value.to_string().trim()).map_or(Variable::default(), |dt| {
+                    Variable::Integer(dt.to_timestamp())
+                }),
+            },
+            BuiltIn::FormatDate => {
+                let part = arg.to_string().to_ascii_lowercase();
+                let dt = DateTime::from_timestamp(value.to_integer());
+                let dt = match args.next().map(|zone| parse_zone(&zone)) {
+                    Some(Some(zone)) => dt.to_timezone(zone),
+                    Some(None) => return Variable::default(),
+                    None => dt,
+                };
+                DATE_PART
+                    .get(part.as_str())
+                    .map_or(Variable::default(), |part| part.eval(&dt).into())
+            }
+            BuiltIn::Any | BuiltIn::All | BuiltIn::Count => {
+                // Without a second argument items are tested for truthiness,
+                // otherwise they are compared to it. Single values are lists
+                // of one item.
+                let items = value.into_array();
+                let compare_to = (num_args > 1).then_some(arg);
+                let is_match = |item: &&Variable| {
+                    compare_to
+                        .as_ref()
+                        .map_or_else(|| item.to_bool(), |value| *item == value)
+                };
+                match self {
+                    BuiltIn::Any => items.iter().any(|item| is_match(&item)).into(),
+                    BuiltIn::All => items.iter().all(|item| is_match(&item)).into(),
+                    _ => items.iter().filter(is_match).count().into(),
+                }
+            }
+            BuiltIn::Header => {
+                parse_header_name(value.to_string().trim()).map_or(Variable::default(), |name| {
+                    ctx.variable(&VariableType::Header(HeaderVariable {
+                        name: vec![name],
+                        part: HeaderPart::Text,
+                        index_hdr: -1,
+                        index_part: -1,
+                    }))
+                    .unwrap_or_default()
+                })
+            }
+            BuiltIn::Address => {
+                let part = if num_args > 1 {
+                    match arg.to_string().to_ascii_lowercase().as_str() {
+                        "all" => AddressPart::All,
+                        "localpart" => AddressPart::LocalPart,
+                        "domain" => AddressPart::Domain,
+                        "user" => AddressPart::User,
+                        "detail" => AddressPart::Detail,
+                        "name" => AddressPart::Name,
+                        _ => return Variable::default(),
+                    }
+                } else {
+                    AddressPart::All
+                };
+
+                // All addresses of all matching headers, a single address is
+                // returned as a string.
+                match parse_header_name(value.to_string().trim()).and_then(|name| {
+                    ctx.variable(&VariableType::Header(HeaderVariable {
+                        name: vec![name],
+                        part: HeaderPart::Address(part),
+                        index_hdr: 0,
+                        index_part: 0,
+                    }))
+                }) {
+                    Some(Variable::Array(items)) if items.len() == 1 => items[0].clone(),
+                    Some(value) => value,
+                    None => Variable::default(),
+                }
+            }
+            BuiltIn::Envelope => {
+                let name = value.to_string().to_ascii_lowercase();
+                Envelope::try_from(name.as_str()).map_or(Variable::default(), |envelope| {
+                    ctx.variable(&VariableType::Envelope(envelope))
+                        .unwrap_or_default()
+                })
+            }
+            BuiltIn::Env => ctx
+                .variable(&VariableType::Environment(
+                    value.to_string().to_ascii_lowercase(),
+                ))
+                .unwrap_or_default(),
+            BuiltIn::BodyText => ctx
+                .variable(&VariableType::Part(MessagePart::TextBody(true)))
+                .unwrap_or_default(),
+            BuiltIn::RegexCapture => {
+                // Returns the whole match followed by each group, named groups
+                // are also stored as global variables once the function returns
+                // (see Context::set_named_captures).
+                let regex = match ctx
+                    .runtime
+                    .regex_cache
+                    .get_or_compile(arg.to_string().as_ref())
+                {
+                    Ok(regex) => regex,
+                    Err(_) => return Variable::default(),
+                };
+                let value = value.to_string();
+                let captures = match regex.captures(value.as_ref()) {
+                    Ok(Some(captures)) => captures,
+                    _ => return Variable::Array(Vec::new().into()),
+                };
+
+                captures
+                    .iter()
+                    .map(|m| m.map_or(Variable::default(), |m| m.as_str().into()))
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+            BuiltIn::Sha256 => to_hex(&Sha256::digest(value.to_string().as_bytes())).into(),
+            BuiltIn::Sha1 => to_hex(&Sha1::digest(value.to_string().as_bytes())).into(),
+            BuiltIn::Md5 => to_hex(&md5::compute(value.to_string().as_bytes()).0).into(),
+            BuiltIn::Crc32 => {
+                Variable::Integer(crc32fast::hash(value.to_string().as_bytes()).into())
+            }
+            BuiltIn::Base64Encode => {
+                // Only the input that fits in the output is encoded
+                let value = value.to_string();
+                let max_len = ctx.runtime.max_variable_size;
+                let bytes = value.as_bytes();
+                let bytes = &bytes[..bytes.len().min(max_len / 4 * 3)];
+                let mut encoded = String::new();
+                if let Some(bytes) = base64_encode(bytes)
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                {
+                    push_limited(&mut encoded, &bytes, max_len);
+                }
+                encoded.into()
+            }
+            BuiltIn::Base64Decode => base64_decode(value.to_string().trim().as_bytes())
+                .map_or(Variable::default(), |bytes| {
+                    String::from_utf8_lossy(&bytes).into_owned().into()
+                }),
+            BuiltIn::Hex => match value {
+                Variable::Integer(n) => format!("{n:x}").into(),
+                _ => {
+                    let value = value.to_string();
+                    let bytes = value.as_bytes();
+                    to_hex(&bytes[..bytes.len().min(ctx.runtime.max_variable_size / 2)]).into()
+                }
+            },
+            // Compiled to the 'in' operator, which is resolved by the host
+            BuiltIn::InList => Variable::default(),
+            BuiltIn::UrlEncode => Modifier::EncodeUrl
+                .apply(value.to_string().as_ref(), ctx)
+                .into(),
+        }
+    }
+}
+
+impl<C> Context<'_, C> {
+    // Stores the named groups of a regex_capture result as global variables
+    pub(crate) fn set_named_captures(&mut self, pattern: &Variable, captures: &Variable) {
+        let (Ok(regex), Variable::Array(captures)) = (
+            self.runtime
+                .regex_cache
+                .get_or_compile(pattern.to_string().as_ref()),
+            captures,
+        ) else {
+            return;
+        };
+        if captures.is_empty() {
+            return;
+        }
+
+        for (pos, name) in regex.capture_names().enumerate() {
+            if let Some(name) = name {
+                self.set_variable(
+                    &VariableType::Global(name.to_ascii_lowercase()),
+                    captures.get(pos).cloned().unwrap_or_default(),
+                );
+            }
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut result = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(result, "{byte:02x}");
+    }
+    result
+}
+
+// Appends as much of `value` as fits in `max_len` bytes, returns false once
+// the limit is reached
+fn push_limited(result: &mut String, value: &str, max_len: usize) -> bool {
+    if result.len() + value.len() <= max_len {
+        result.push_str(value);
+        true
+    } else {
+        for ch in value.chars() {
+            if result.len() + ch.len_utf8() <= max_len {
+                result.push(ch);
+            } else {
+                break;
+            }
+        }
+        false
+    }
+}
+
+// Longest prefix of `value` that is at most `max_len` bytes long
+fn str_prefix(value: &str, max_len: usize) -> &str {
+    let mut end = value.len().min(max_len);
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    &value[..end]
+}
+
+fn parse_header_name(name: &str) -> Option<HeaderName<'static>> {
+    match HeaderName::parse(name)? {
+        HeaderName::Other(_) => HeaderName::Other(name.to_string().into()),
+        name => name.into_owned(),
+    }
+    .into()
+}
+
+// Parses dates in ISO 8601 or RFC 2822 format
+fn parse_date(value: &str) -> Option<DateTime> {
+    DateTime::parse_rfc3339(value)
+        .or_else(|| {
+            let value = format!("{value}\n");
+            match MessageStream::new(value.as_bytes()).parse_date() {
+                HeaderValue::DateTime(dt) => Some(dt),
+                _ => None,
+            }
+        })
+        .filter(|dt| dt.is_valid())
+}
+
+// Time zone offset in seconds from "+hhmm" or "-hhmm"
+fn parse_zone(zone: &Variable) -> Option<i64> {
+    let zone = zone.to_string().trim().parse::<i64>().ok()?;
+    if zone.abs() % 100 >= 60 {
+        return None;
+    }
+    match zone {
+        0..=1400 => Some((zone / 100 * 3600) + (zone % 100 * 60)),
+        -1200..=-1 => Some((zone / 100 * 3600) - (-zone % 100 * 60)),
+        _ => None,
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use mail_parser::{Header, HeaderValue, Message, PartType};
+
+use crate::{Context, MimeLeniency};
+
+impl<'x, C> Context<'x, C> {
+    pub(crate) fn apply_mime_leniency(&mut self) {
+        let leniency = self.runtime.mime_leniency;
+        if leniency != MimeLeniency::Legacy {
+            leniency.apply(Arc::make_mut(&mut self.message));
+        }
+    }
+}
+
+impl MimeLeniency {
+    pub(crate) fn apply(&self, message: &mut Message) {
+        let Message {
+            parts, raw_message, ..
+        } = message;
+
+        for part in parts.iter_mut() {
+            let raw_body = raw_message
+                .get(part.offset_body..part.offset_end)
+                .unwrap_or_default();
+            let missing_boundary = !matches!(&part.body, PartType::Multipart(parts) if !parts.is_empty())
+                && part
+                    .content_type()
+                    .map_or(false, |ct| ct.c_type.eq_ignore_ascii_case("multipart"));
+            let is_text = part.content_type().map_or(true, |ct| {
+                ct.c_type.eq_ignore_ascii_case("text")
+                    || ct.c_type.eq_ignore_ascii_case("multipart")
+            });
+
+            if missing_boundary || part.is_encoding_problem {
+                match self {
+                    MimeLeniency::Strict => {
+                        part.body = PartType::Binary(raw_body.to_vec().into());
+                    }
+                    MimeLeniency::Lenient
+                        if missing_boundary
+                            || (is_text
+                                && matches!(
+                                    part.body,
+                                    PartType::Binary(_) | PartType::InlineBinary(_)
+                                )) =>
+                    {
+                        part.body =
+                            PartType::Text(String::from_utf8_lossy(raw_body).into_owned().into());
+                    }
+                    _ => (),
+                }
+            }
+
+            for header in part.headers.iter_mut() {
+                let raw_value = raw_message
+                    .get(header.offset_start..header.offset_end)
+                    .unwrap_or_default();
+                if !is_8bit(raw_value) {
+                    continue;
+                }
+
+                match self {
+                    MimeLeniency::Strict => {
+                        header.value = HeaderValue::Text(
+                            String::from_utf8_lossy(raw_value).trim().to_string().into(),
+                        );
+                    }
+                    MimeLeniency::Lenient if matches!(header.value, HeaderValue::Text(_)) => {
+                        // Undeclared 8-bit text is most likely ISO-8859-1
+                        header.value = HeaderValue::Text(
+                            raw_value
+                                .iter()
+                                .map(|&ch| ch as char)
+                                .collect::<String>()
+                                .trim()
+                                .to_string()
+                                .into(),
+                        );
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // Whether header tests use the normalized value instead of decoding the raw header
+    pub(crate) fn is_normalized(&self, header: &Header, raw_message: &[u8]) -> bool {
+        match self {
+            MimeLeniency::Legacy => false,
+            _ => {
+                matches!(header.value, HeaderValue::Text(_))
+                    && is_8bit(
+                        raw_message
+                            .get(header.offset_start..header.offset_end)
+                            .unwrap_or_default(),
+                    )
+            }
+        }
+    }
+}
+
+// 8-bit bytes that are not valid UTF-8
+fn is_8bit(bytes: &[u8]) -> bool {
+    !bytes.is_ascii() && std::str::from_utf8(bytes).is_err()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MimeLeniency, Runtime};
+
+    #[test]
+    fn mime_leniency() {
+        let script = "require [\"body\", \"fileinto\"];
+                if header :contains \"subject\" \"Caf\u{e9}\" { fileinto \"subject\"; }
+                if address :is \"from\" \"jorg@example.com\" { fileinto \"address\"; }
+                if body :text :contains \"hello\" { fileinto \"body\"; }";
+        let message = concat!(
+            "Subject: Caf\u{e9}, tr\u{e8}s bien\r\n",
+            "From: J\u{f6}rg <jorg@example.com>\r\n",
+            "Content-Type: multipart/mixed\r\n",
+            "\r\n",
+            "hello world\r\n"
+        )
+        .chars()
+        .map(|ch| ch as u8)
+        .collect::<Vec<_>>();
+
+        for (leniency, expected) in [
+            (MimeLeniency::Strict, vec![]),
+            (MimeLeniency::Lenient, vec!["subject", "address", "body"]),
+        ] {
+            let runtime = Runtime::new().with_mime_leniency(leniency);
+            let folders = runtime.filter(&message).run_test_folders(script.as_bytes());
+            assert_eq!(folders, expected, "{leniency:?}");
+        }
+    }
+}
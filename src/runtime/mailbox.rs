@@ -0,0 +1,158 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::fmt::Debug;
+
+use crate::Context;
+
+/// Translates the mailbox names used by scripts into the naming convention
+/// of the mail store. The runtime calls it for every mailbox name before
+/// emitting `FileInto` and `MailboxExists` events, which allows stores to
+/// apply their own encoding (such as modified UTF-7), hierarchy separator
+/// or namespace without post-processing events.
+pub trait MailboxNormalizer: Debug + Send + Sync {
+    fn normalize(&self, name: &str) -> String;
+}
+
+/// A normalizer covering the most common store conventions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MailboxNames {
+    separator: Option<(char, char)>,
+    prefix: Option<String>,
+    canonical_inbox: bool,
+}
+
+impl MailboxNames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the hierarchy separator `from` used by scripts with `to`.
+    pub fn set_separator(&mut self, from: char, to: char) {
+        self.separator = Some((from, to));
+    }
+
+    pub fn with_separator(mut self, from: char, to: char) -> Self {
+        self.set_separator(from, to);
+        self
+    }
+
+    /// Places all mailboxes other than INBOX under a prefix, such as `"INBOX/"`,
+    /// unless the name already starts with it.
+    pub fn set_prefix(&mut self, prefix: impl Into<String>) {
+        self.prefix = Some(prefix.into());
+    }
+
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.set_prefix(prefix);
+        self
+    }
+
+    /// Matches INBOX case-insensitively and returns it in uppercase.
+    pub fn set_canonical_inbox(&mut self, value: bool) {
+        self.canonical_inbox = value;
+    }
+
+    pub fn with_canonical_inbox(mut self, value: bool) -> Self {
+        self.set_canonical_inbox(value);
+        self
+    }
+}
+
+impl MailboxNormalizer for MailboxNames {
+    fn normalize(&self, name: &str) -> String {
+        let is_inbox =
+            name == "INBOX" || (self.canonical_inbox && name.eq_ignore_ascii_case("inbox"));
+        if is_inbox {
+            return "INBOX".to_string();
+        }
+
+        let name = if let Some((from, to)) = self.separator {
+            name.replace(from, to.encode_utf8(&mut [0; 4]))
+        } else {
+            name.to_string()
+        };
+
+        match &self.prefix {
+            Some(prefix) if !name.starts_with(prefix.as_str()) => format!("{prefix}{name}"),
+            _ => name,
+        }
+    }
+}
+
+impl<'x, C> Context<'x, C> {
+    pub(crate) fn normalize_mailbox(&self, name: String) -> String {
+        if let Some(normalizer) = &self.runtime.mailbox_normalizer {
+            normalizer.normalize(&name)
+        } else {
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Event, Mailbox, Runtime};
+
+    use super::{MailboxNames, MailboxNormalizer};
+
+    #[test]
+    fn normalize_mailbox_names() {
+        let names = MailboxNames::new()
+            .with_separator('/', '.')
+            .with_prefix("INBOX.")
+            .with_canonical_inbox(true);
+        for (name, expected) in [
+            ("inbox", "INBOX"),
+            ("Work/Projects", "INBOX.Work.Projects"),
+            ("INBOX.Archive", "INBOX.Archive"),
+        ] {
+            assert_eq!(names.normalize(name), expected);
+        }
+
+        let runtime = Runtime::new().with_mailbox_normalizer(names);
+        let mut folders = Vec::new();
+        for event in runtime
+            .filter(b"Subject: test\r\n\r\nbody")
+            .run_test_events(
+                br#"require ["fileinto", "mailbox"];
+                if mailboxexists "Lists/Rust" {
+                    fileinto "Lists/Rust";
+                }"#,
+            )
+        {
+            match event {
+                Event::MailboxExists { mailboxes, .. } => {
+                    for mailbox in mailboxes {
+                        if let Mailbox::Name(name) = mailbox {
+                            folders.push(name);
+                        }
+                    }
+                }
+                Event::FileInto { folder, .. } => folders.push(folder),
+                _ => (),
+            }
+        }
+        assert_eq!(folders, ["INBOX.Lists.Rust", "INBOX.Lists.Rust"]);
+    }
+}
@@ -0,0 +1,153 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{borrow::Cow, fmt::Debug, sync::Arc};
+
+use crate::{Context, Metadata};
+
+/// Looks up the server and mailbox annotations that were not preloaded with
+/// `set_medatata`, usually with an IMAP METADATA request. Results, including
+/// missing annotations, are cached for the lifetime of the [`Context`] so a
+/// script testing the same annotation many times causes a single lookup.
+pub trait MetadataProvider: Debug + Send + Sync {
+    fn metadata(&self, metadata: &Metadata<String>) -> Option<String>;
+}
+
+impl<'x, C> Context<'x, C> {
+    pub(crate) fn lookup_metadata(&self, metadata: &Metadata<String>) -> Option<Cow<str>> {
+        if let Some((_, value)) = [&self.metadata, &self.runtime.metadata]
+            .into_iter()
+            .flatten()
+            .find(|(m, _)| match (m, metadata) {
+                (Metadata::Server { annotation: a }, Metadata::Server { annotation: b }) => {
+                    a.eq_ignore_ascii_case(b)
+                }
+                (
+                    Metadata::Mailbox {
+                        name: a,
+                        annotation: c,
+                    },
+                    Metadata::Mailbox {
+                        name: b,
+                        annotation: d,
+                    },
+                ) => a.eq(b) && c.eq_ignore_ascii_case(d),
+                _ => false,
+            })
+        {
+            return Some(Cow::Borrowed(value.as_ref()));
+        }
+
+        let provider = self.runtime.metadata_provider.as_ref()?;
+        let key = match metadata {
+            Metadata::Server { annotation } => Metadata::Server {
+                annotation: annotation.to_ascii_lowercase(),
+            },
+            Metadata::Mailbox { name, annotation } => Metadata::Mailbox {
+                name: name.clone(),
+                annotation: annotation.to_ascii_lowercase(),
+            },
+        };
+        Arc::make_mut(&mut self.metadata_cache.borrow_mut())
+            .entry(key)
+            .or_insert_with(|| provider.metadata(metadata))
+            .clone()
+            .map(Cow::Owned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use crate::{Metadata, Runtime};
+
+    use super::MetadataProvider;
+
+    #[derive(Debug, Default)]
+    struct Annotations {
+        lookups: Arc<AtomicUsize>,
+    }
+
+    impl MetadataProvider for Annotations {
+        fn metadata(&self, metadata: &Metadata<String>) -> Option<String> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            match metadata {
+                Metadata::Mailbox { name, annotation }
+                    if name == "INBOX" && annotation == "/private/comment" =>
+                {
+                    Some("Personal mail".to_string())
+                }
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn metadata_provider_cache() {
+        let script = br#"require ["mboxmetadata", "servermetadata", "fileinto"];
+                if metadata "INBOX" "/private/comment" "Work" {
+                    fileinto "Work";
+                }
+                if metadata :contains "INBOX" "/private/COMMENT" "Personal" {
+                    fileinto "Personal";
+                }
+                if metadataexists "INBOX" "/private/comment" {
+                    fileinto "Exists";
+                }
+                if not servermetadataexists "/shared/admin" {
+                    fileinto "NoAdmin";
+                }
+                if not servermetadata :matches "/shared/admin" "*" {
+                    fileinto "NoAdminValue";
+                }
+                if metadata "INBOX" "/private/vendor" "Preloaded" {
+                    fileinto "Preloaded";
+                }"#;
+        let provider = Annotations::default();
+        let lookups = provider.lookups.clone();
+        let runtime = Runtime::new().with_metadata_provider(provider);
+        let mut ctx = runtime.filter(b"Subject: test\r\n\r\nbody").with_metadata(
+            Metadata::Mailbox {
+                name: "INBOX".to_string(),
+                annotation: "/private/vendor".to_string(),
+            },
+            "Preloaded",
+        );
+
+        for expected_lookups in [2, 4] {
+            if expected_lookups > 2 {
+                // Provider answers are not kept for the next message
+                ctx.reset_for(b"Subject: another test\r\n\r\nbody");
+            }
+            assert_eq!(
+                ctx.run_test_folders(script),
+                ["Personal", "Exists", "NoAdmin", "NoAdminValue", "Preloaded"]
+            );
+            assert_eq!(lookups.load(Ordering::Relaxed), expected_lookups);
+        }
+    }
+}
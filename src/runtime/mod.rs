@@ -22,33 +22,54 @@
 */
 
 pub mod actions;
+pub mod audit;
+pub mod cache;
 pub mod context;
 pub mod eval;
 pub mod expression;
+pub mod functions;
+pub mod leniency;
+pub mod mailbox;
+pub mod metadata;
+pub mod platform;
+pub mod profile;
+pub mod received;
+pub mod redact;
+pub mod runner;
 pub mod serialize;
+pub mod source;
+pub mod tenant;
 pub mod tests;
+pub mod trace;
 pub mod variables;
 
 use std::{borrow::Cow, fmt::Display, hash::Hash, ops::Deref, sync::Arc};
 
 use ahash::{AHashMap, AHashSet};
-#[cfg(not(test))]
-use mail_parser::{Encoding, Message, MessageParser, MessagePart, PartType};
+use mail_parser::{Encoding, HeaderName, Message, MessageParser, MessagePart, PartType};
 
-use mail_parser::HeaderName;
-
-#[cfg(not(test))]
 use crate::Context;
 
+use self::source::MessageSource;
+
 use crate::{
     compiler::{
-        grammar::{expr::parser::ID_EXTERNAL, Capability, Invalid},
-        Number,
+        grammar::{
+            expr::parser::{ID_EXTERNAL, VARIADIC_ARGS},
+            Capability, Invalid,
+        },
+        Number, Span,
     },
-    ExternalId, Function, FunctionMap, Input, Metadata, Runtime, Script, Sieve,
+    AddressOptions, DuplicateIdHasher, DuplicateIdRequest, Event, ExternalId, FailurePolicy,
+    FlagOptions, Function, FunctionMap, FunctionRequest, HeaderPolicy, IncludeScriptRequest, Input,
+    IntegerDivision, IntegerOverflow, ListContainsRequest, LoopOptions, MailboxCreatePolicy,
+    MailboxExistsRequest, Metadata, MimeLeniency, PreviewModificationRequest, Redactor,
+    RedirectPolicy, Request, Runtime, Script, Sieve,
 };
 
-use self::eval::ToString;
+use self::{
+    cache::RegexCache, eval::ToString, mailbox::MailboxNormalizer, metadata::MetadataProvider,
+};
 
 #[derive(Debug, Clone)]
 pub enum Variable {
@@ -60,12 +81,24 @@ pub enum Variable {
 
 #[derive(Debug)]
 pub enum RuntimeError {
-    TooManyIncludes,
+    TooManyIncludes(Vec<Script>),
+    IncludeCycle(Vec<Script>),
     InvalidInstruction(Invalid),
     ScriptErrorMessage(String),
     CapabilityNotAllowed(Capability),
     CapabilityNotSupported(String),
     CPULimitReached,
+    PartIterationLimitReached,
+    TooManyNestedForEveryPart,
+    VariableMemoryLimitReached,
+    ExpansionLimitReached,
+    GeneratedMessageLimitReached,
+    RedirectNotAllowed(String),
+    Internal {
+        message: String,
+        script: Option<Script>,
+        span: Option<Span>,
+    },
 }
 
 impl Default for Variable {
@@ -315,32 +348,58 @@ impl Hash for Variable {
     }
 }
 
-#[cfg(not(test))]
 impl<C> Runtime<C> {
     pub fn filter<'z: 'x, 'x>(&'z self, raw_message: &'x [u8]) -> Context<'x, C> {
-        Context::new(
-            self,
-            MessageParser::new()
-                .parse(raw_message)
-                .unwrap_or_else(|| Message {
-                    parts: vec![MessagePart {
-                        headers: vec![],
-                        is_encoding_problem: false,
-                        body: PartType::Text("".into()),
-                        encoding: Encoding::None,
-                        offset_header: 0,
-                        offset_body: 0,
-                        offset_end: 0,
-                    }],
-                    raw_message: b""[..].into(),
-                    ..Default::default()
-                }),
-        )
+        Context::new(self, parse_message(raw_message))
     }
 
     pub fn filter_parsed<'z: 'x, 'x>(&'z self, message: Message<'x>) -> Context<'x, C> {
         Context::new(self, message)
     }
+
+    /// Parses only the message headers and loads the full message from the
+    /// source once a script requires access to the message body.
+    pub fn filter_source<'z: 'x, 'x>(&'z self, source: &'x dyn MessageSource) -> Context<'x, C> {
+        let mut ctx = self.filter(source.headers());
+        ctx.message_size = source.size();
+        ctx.message_source = Some(source);
+        ctx
+    }
+
+    /// Runs against a message that was already parsed by the caller. The raw
+    /// message is borrowed and only the MIME structure is copied, since
+    /// scripts are allowed to modify it.
+    pub fn filter_message<'z: 'x, 'x>(&'z self, message: &'x Message<'x>) -> Context<'x, C> {
+        Context::new(
+            self,
+            Message {
+                html_body: message.html_body.clone(),
+                text_body: message.text_body.clone(),
+                attachments: message.attachments.clone(),
+                parts: message.parts.clone(),
+                raw_message: message.raw_message.as_ref().into(),
+            },
+        )
+    }
+}
+
+// Messages that cannot be parsed are filtered as an empty message
+pub(crate) fn parse_message(raw_message: &[u8]) -> Message<'_> {
+    MessageParser::new()
+        .parse(raw_message)
+        .unwrap_or_else(|| Message {
+            parts: vec![MessagePart {
+                headers: vec![],
+                is_encoding_problem: false,
+                body: PartType::Text("".into()),
+                encoding: Encoding::None,
+                offset_header: 0,
+                offset_body: 0,
+                offset_end: 0,
+            }],
+            raw_message: b""[..].into(),
+            ..Default::default()
+        })
 }
 
 impl Runtime<()> {
@@ -374,23 +433,60 @@ impl<C> Runtime<C> {
             max_nested_includes: 3,
             cpu_limit: 5000,
             max_variable_size: 4096,
+            max_variable_memory: 1024 * 1024,
+            max_expansion_size: 1024 * 1024,
             max_redirects: 1,
             max_received_headers: 10,
             protected_headers: vec![
-                HeaderName::Other("Original-Subject".into()),
-                HeaderName::Other("Original-From".into()),
+                (
+                    HeaderName::Other("Original-Subject".into()),
+                    HeaderPolicy::Deny,
+                ),
+                (
+                    HeaderName::Other("Original-From".into()),
+                    HeaderPolicy::Deny,
+                ),
+                // RFC 5293 section 6: trace fields must not be removed
+                (HeaderName::Received, HeaderPolicy::DenyDelete),
+                (
+                    HeaderName::Other("Auto-Submitted".into()),
+                    HeaderPolicy::DenyDelete,
+                ),
             ],
             valid_notification_uris: AHashSet::new(),
             valid_ext_lists: AHashSet::new(),
             vacation_use_orig_rcpt: false,
+            vacation_address_wildcards: false,
+            vacation_ignore_subaddress: false,
             vacation_default_subject: "Automated reply".into(),
             vacation_subject_prefix: "Auto: ".into(),
+            redirect_policy: None,
+            mailbox_create_policy: None,
+            duplicate_id_hasher: None,
+            redactor: None,
+            mailbox_normalizer: None,
+            metadata_provider: None,
+            preview_modifications: false,
+            received_environment: false,
+            reject_message: false,
+            failure_policy: FailurePolicy::default(),
+            profiles: AHashMap::new(),
+            address_options: AddressOptions::default(),
+            mime_leniency: MimeLeniency::default(),
+            flag_options: FlagOptions::default(),
+            loop_options: LoopOptions::default(),
             max_header_size: 1024,
             max_out_messages: 3,
+            max_generated_messages: 0,
+            max_part_iterations: 1000,
+            max_nested_foreverypart: 5,
             default_vacation_expiry: 30 * 86400,
             default_duplicate_expiry: 7 * 86400,
             local_hostname: "localhost".into(),
             functions: Vec::new(),
+            regex_cache: Arc::new(RegexCache::new(128)),
+            integer_overflow: IntegerOverflow::default(),
+            integer_division: IntegerDivision::default(),
             context,
         }
     }
@@ -404,6 +500,28 @@ impl<C> Runtime<C> {
         self
     }
 
+    /// Maximum number of MIME parts visited by all `foreverypart` loops
+    /// during a run, including those of included scripts.
+    pub fn set_max_part_iterations(&mut self, size: usize) {
+        self.max_part_iterations = size;
+    }
+
+    pub fn with_max_part_iterations(mut self, size: usize) -> Self {
+        self.max_part_iterations = size;
+        self
+    }
+
+    /// Maximum depth of `foreverypart` loops at runtime, which can exceed
+    /// the compile time limit when loops are entered from included scripts.
+    pub fn set_max_nested_foreverypart(&mut self, size: usize) {
+        self.max_nested_foreverypart = size;
+    }
+
+    pub fn with_max_nested_foreverypart(mut self, size: usize) -> Self {
+        self.max_nested_foreverypart = size;
+        self
+    }
+
     pub fn set_max_nested_includes(&mut self, size: usize) {
         self.max_nested_includes = size;
     }
@@ -422,6 +540,20 @@ impl<C> Runtime<C> {
         self
     }
 
+    /// Limits the messages generated by a run, which are vacation replies,
+    /// notifications, reject bounces and the copies filed with `:fcc`.
+    /// Going over the limit fails the script with
+    /// [`RuntimeError::GeneratedMessageLimitReached`], without performing
+    /// the action that exceeded it. Zero, the default, means no limit.
+    pub fn set_max_generated_messages(&mut self, size: usize) {
+        self.max_generated_messages = size;
+    }
+
+    pub fn with_max_generated_messages(mut self, size: usize) -> Self {
+        self.set_max_generated_messages(size);
+        self
+    }
+
     pub fn set_max_out_messages(&mut self, size: usize) {
         self.max_out_messages = size;
     }
@@ -449,6 +581,28 @@ impl<C> Runtime<C> {
         self
     }
 
+    /// Maximum number of bytes held by all the local, global and match
+    /// variables of a run, zero for no limit.
+    pub fn set_max_variable_memory(&mut self, size: usize) {
+        self.max_variable_memory = size;
+    }
+
+    pub fn with_max_variable_memory(mut self, size: usize) -> Self {
+        self.max_variable_memory = size;
+        self
+    }
+
+    /// Maximum size of a string built by expanding variables, such as
+    /// `"${a}${b}"` or a `text:` block, zero for no limit.
+    pub fn set_max_expansion_size(&mut self, size: usize) {
+        self.max_expansion_size = size;
+    }
+
+    pub fn with_max_expansion_size(mut self, size: usize) -> Self {
+        self.max_expansion_size = size;
+        self
+    }
+
     pub fn set_max_header_size(&mut self, size: usize) {
         self.max_header_size = size;
     }
@@ -458,6 +612,33 @@ impl<C> Runtime<C> {
         self
     }
 
+    pub fn set_regex_cache_size(&mut self, size: usize) {
+        self.regex_cache = Arc::new(RegexCache::new(size));
+    }
+
+    pub fn with_regex_cache_size(mut self, size: usize) -> Self {
+        self.set_regex_cache_size(size);
+        self
+    }
+
+    pub fn set_integer_overflow(&mut self, overflow: IntegerOverflow) {
+        self.integer_overflow = overflow;
+    }
+
+    pub fn with_integer_overflow(mut self, overflow: IntegerOverflow) -> Self {
+        self.integer_overflow = overflow;
+        self
+    }
+
+    pub fn set_integer_division(&mut self, division: IntegerDivision) {
+        self.integer_division = division;
+    }
+
+    pub fn with_integer_division(mut self, division: IntegerDivision) -> Self {
+        self.integer_division = division;
+        self
+    }
+
     pub fn set_default_vacation_expiry(&mut self, expiry: u64) {
         self.default_vacation_expiry = expiry;
     }
@@ -504,10 +685,18 @@ impl<C> Runtime<C> {
         self
     }
 
+    /// Returns the capabilities required by `sieve` that are not enabled in
+    /// this runtime, an empty list means the script can be executed.
+    pub fn unsupported_capabilities<'y>(&self, sieve: &'y Sieve) -> Vec<&'y Capability> {
+        sieve
+            .capabilities()
+            .iter()
+            .filter(|capability| !self.allowed_capabilities.contains(*capability))
+            .collect()
+    }
+
     pub fn set_protected_header(&mut self, header_name: impl Into<Cow<'static, str>>) {
-        if let Some(header_name) = HeaderName::parse(header_name) {
-            self.protected_headers.push(header_name);
-        }
+        self.set_header_policy(header_name, HeaderPolicy::Deny);
     }
 
     pub fn with_protected_header(mut self, header_name: impl Into<Cow<'static, str>>) -> Self {
@@ -522,10 +711,43 @@ impl<C> Runtime<C> {
         self.protected_headers = header_names
             .into_iter()
             .filter_map(HeaderName::parse)
+            .map(|header_name| (header_name, HeaderPolicy::Deny))
             .collect();
         self
     }
 
+    /// Sets which edits `addheader` and `deleteheader` may perform on a
+    /// header, replacing any previous policy for it.
+    pub fn set_header_policy(
+        &mut self,
+        header_name: impl Into<Cow<'static, str>>,
+        policy: HeaderPolicy,
+    ) {
+        if let Some(header_name) = HeaderName::parse(header_name) {
+            self.protected_headers
+                .retain(|(name, _)| name != &header_name);
+            if policy != HeaderPolicy::Allow {
+                self.protected_headers.push((header_name, policy));
+            }
+        }
+    }
+
+    pub fn with_header_policy(
+        mut self,
+        header_name: impl Into<Cow<'static, str>>,
+        policy: HeaderPolicy,
+    ) -> Self {
+        self.set_header_policy(header_name, policy);
+        self
+    }
+
+    pub(crate) fn header_policy(&self, header_name: &HeaderName) -> HeaderPolicy {
+        self.protected_headers
+            .iter()
+            .find(|(name, _)| name == header_name)
+            .map_or(HeaderPolicy::Allow, |(_, policy)| *policy)
+    }
+
     pub fn set_env_variable(
         &mut self,
         name: impl Into<Cow<'static, str>>,
@@ -603,6 +825,32 @@ impl<C> Runtime<C> {
         self
     }
 
+    /// Allows vacation `:addresses` entries such as `"*@example.org"`,
+    /// `"@example.org"` or `"sales-*@example.org"`. Wildcard entries only
+    /// select the messages to reply to, they are not treated as own
+    /// addresses when checking the sender.
+    pub fn set_vacation_address_wildcards(&mut self, value: bool) {
+        self.vacation_address_wildcards = value;
+    }
+
+    pub fn with_vacation_address_wildcards(mut self, value: bool) -> Self {
+        self.set_vacation_address_wildcards(value);
+        self
+    }
+
+    /// Ignores the detail part of addresses, such as `"+sieve"` in
+    /// `"john+sieve@example.org"`, when looking for the user addresses.
+    /// Domains configured with [`Context::set_subaddress`] use their own
+    /// separators, any other domain uses `"+"`.
+    pub fn set_vacation_ignore_subaddress(&mut self, value: bool) {
+        self.vacation_ignore_subaddress = value;
+    }
+
+    pub fn with_vacation_ignore_subaddress(mut self, value: bool) -> Self {
+        self.set_vacation_ignore_subaddress(value);
+        self
+    }
+
     pub fn set_vacation_default_subject(&mut self, value: impl Into<Cow<'static, str>>) {
         self.vacation_default_subject = value.into();
     }
@@ -621,6 +869,149 @@ impl<C> Runtime<C> {
         self
     }
 
+    pub fn set_redirect_policy(&mut self, policy: RedirectPolicy) {
+        self.redirect_policy = Some(policy);
+    }
+
+    pub fn with_redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.set_redirect_policy(policy);
+        self
+    }
+
+    pub fn set_mailbox_create_policy(&mut self, policy: MailboxCreatePolicy) {
+        self.mailbox_create_policy = Some(policy);
+    }
+
+    pub fn with_mailbox_create_policy(mut self, policy: MailboxCreatePolicy) -> Self {
+        self.set_mailbox_create_policy(policy);
+        self
+    }
+
+    /// Replaces the default id of the `duplicate` test, the handle followed by
+    /// the unique id, with the output of `hasher`. This allows sharing the
+    /// duplicate tracking store with systems using a different key scheme.
+    pub fn set_duplicate_id_hasher(&mut self, hasher: DuplicateIdHasher) {
+        self.duplicate_id_hasher = Some(hasher);
+    }
+
+    pub fn with_duplicate_id_hasher(mut self, hasher: DuplicateIdHasher) -> Self {
+        self.set_duplicate_id_hasher(hasher);
+        self
+    }
+
+    /// Applies `redactor` to the text of the events recorded by
+    /// [`Context::run_traced`], the targets of audit records and the
+    /// messages of the errors returned by [`Context::run`]. The events
+    /// returned by `run` are not modified.
+    pub fn set_redactor(&mut self, redactor: Redactor) {
+        self.redactor = Some(redactor);
+    }
+
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.set_redactor(redactor);
+        self
+    }
+
+    pub fn set_mailbox_normalizer(&mut self, normalizer: impl MailboxNormalizer + 'static) {
+        self.mailbox_normalizer = Some(Arc::new(normalizer));
+    }
+
+    pub fn with_mailbox_normalizer(mut self, normalizer: impl MailboxNormalizer + 'static) -> Self {
+        self.set_mailbox_normalizer(normalizer);
+        self
+    }
+
+    pub fn set_metadata_provider(&mut self, provider: impl MetadataProvider + 'static) {
+        self.metadata_provider = Some(Arc::new(provider));
+    }
+
+    pub fn with_metadata_provider(mut self, provider: impl MetadataProvider + 'static) -> Self {
+        self.set_metadata_provider(provider);
+        self
+    }
+
+    /// Emits a [`Event::PreviewModification`] with the message before and
+    /// after every `replace`, `enclose` and `deleteheader`. Answering it with
+    /// `Input::False` undoes the modification.
+    pub fn set_preview_modifications(&mut self, value: bool) {
+        self.preview_modifications = value;
+    }
+
+    pub fn with_preview_modifications(mut self, value: bool) -> Self {
+        self.set_preview_modifications(value);
+        self
+    }
+
+    pub fn set_address_options(&mut self, options: AddressOptions) {
+        self.address_options = options;
+    }
+
+    pub fn with_address_options(mut self, options: AddressOptions) -> Self {
+        self.set_address_options(options);
+        self
+    }
+
+    pub fn set_mime_leniency(&mut self, leniency: MimeLeniency) {
+        self.mime_leniency = leniency;
+    }
+
+    pub fn with_mime_leniency(mut self, leniency: MimeLeniency) -> Self {
+        self.set_mime_leniency(leniency);
+        self
+    }
+
+    pub fn set_flag_options(&mut self, options: FlagOptions) {
+        self.flag_options = options;
+    }
+
+    pub fn with_flag_options(mut self, options: FlagOptions) -> Self {
+        self.set_flag_options(options);
+        self
+    }
+
+    pub fn set_loop_options(&mut self, options: LoopOptions) {
+        self.loop_options = options;
+    }
+
+    pub fn with_loop_options(mut self, options: LoopOptions) -> Self {
+        self.set_loop_options(options);
+        self
+    }
+
+    /// Populate the `remote-host` and `remote-ip` environment items from the
+    /// Received headers of each message, see [`Context::set_env_from_received`].
+    pub fn set_received_environment(&mut self, value: bool) {
+        self.received_environment = value;
+    }
+
+    pub fn with_received_environment(mut self, value: bool) -> Self {
+        self.set_received_environment(value);
+        self
+    }
+
+    /// Attaches a ready to send bounce to every [`Event::Reject`], for hosts
+    /// that cannot refuse the message during the SMTP transaction. See
+    /// [`Context::build_reject_message`].
+    pub fn set_reject_message(&mut self, value: bool) {
+        self.reject_message = value;
+    }
+
+    pub fn with_reject_message(mut self, value: bool) -> Self {
+        self.set_reject_message(value);
+        self
+    }
+
+    /// Decides the disposition of messages whose script fails, so that it
+    /// does not depend on what the script did before the error.
+    pub fn set_failure_policy(&mut self, policy: FailurePolicy) {
+        self.failure_policy = policy;
+    }
+
+    pub fn with_failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.set_failure_policy(policy);
+        self
+    }
+
     pub fn set_local_hostname(&mut self, value: impl Into<Cow<'static, str>>) {
         self.local_hostname = value.into();
     }
@@ -660,6 +1051,11 @@ impl<C> FunctionMap<C> {
         self.with_function_args(name, fnc, 0)
     }
 
+    /// Registers a function that accepts any number of arguments.
+    pub fn with_function_variadic(self, name: impl Into<String>, fnc: Function<C>) -> Self {
+        self.with_function_args(name, fnc, VARIADIC_ARGS)
+    }
+
     pub fn with_function_args(
         mut self,
         name: impl Into<String>,
@@ -690,6 +1086,18 @@ impl<C> FunctionMap<C> {
     ) {
         self.map.insert(name.into(), (ID_EXTERNAL - id, num_args));
     }
+
+    /// Registers an external function that accepts any number of arguments.
+    /// Calls suspend the script with an `Event::Function` which is resumed
+    /// once the result is provided with `Input::FncResult`.
+    pub fn with_external_function_variadic(
+        mut self,
+        name: impl Into<String>,
+        id: ExternalId,
+    ) -> Self {
+        self.set_external_function(name, id, VARIADIC_ARGS);
+        self
+    }
 }
 
 impl Input {
@@ -711,6 +1119,85 @@ impl Input {
     pub fn result(result: Variable) -> Self {
         Input::FncResult(result)
     }
+
+    pub fn variables(
+        variables: impl IntoIterator<Item = (impl Into<String>, impl Into<Variable>)>,
+    ) -> Self {
+        Input::Variables(
+            variables
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+impl Event {
+    /// Returns the request to answer for events that are not actions, or
+    /// `None` for actions.
+    pub fn request(&self) -> Option<Request<'_>> {
+        match self {
+            Event::IncludeScript { name, .. } => {
+                Request::IncludeScript(IncludeScriptRequest { name }).into()
+            }
+            Event::MailboxExists { .. } => Request::MailboxExists(MailboxExistsRequest(())).into(),
+            Event::ListContains { .. } => Request::ListContains(ListContainsRequest(())).into(),
+            Event::DuplicateId { .. } => Request::DuplicateId(DuplicateIdRequest(())).into(),
+            Event::Function { .. } => Request::Function(FunctionRequest(())).into(),
+            Event::PreviewModification { .. } => {
+                Request::PreviewModification(PreviewModificationRequest(())).into()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl IncludeScriptRequest<'_> {
+    /// Answers with the included script, or `None` when it does not exist.
+    pub fn respond(self, script: Option<Arc<Sieve>>) -> Input {
+        match script {
+            Some(script) => Input::Script {
+                name: self.name.clone(),
+                script,
+            },
+            None => Input::False,
+        }
+    }
+}
+
+impl MailboxExistsRequest {
+    /// Answers whether all the mailboxes exist.
+    pub fn respond(self, exists: bool) -> Input {
+        exists.into()
+    }
+}
+
+impl ListContainsRequest {
+    /// Answers whether any of the values is in any of the lists.
+    pub fn respond(self, contains: bool) -> Input {
+        contains.into()
+    }
+}
+
+impl DuplicateIdRequest {
+    /// Answers whether the id has been seen before.
+    pub fn respond(self, is_duplicate: bool) -> Input {
+        is_duplicate.into()
+    }
+}
+
+impl FunctionRequest {
+    /// Answers with the value returned by the function.
+    pub fn respond(self, result: Variable) -> Input {
+        Input::FncResult(result)
+    }
+}
+
+impl PreviewModificationRequest {
+    /// Answers whether the modification is allowed.
+    pub fn respond(self, allow: bool) -> Input {
+        allow.into()
+    }
 }
 
 impl From<bool> for Input {
@@ -830,3 +1317,300 @@ impl From<(&'_ str, &'_ str)> for Metadata<String> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::grammar::Capability, Compiler, Event, Input, Request, Runtime};
+
+    use super::RuntimeError;
+
+    #[test]
+    fn unsupported_capabilities() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "vnd.stalwart.expressions"];
+                if true {
+                    require ["fileinto", "imap4flags"];
+                }"#,
+            )
+            .unwrap();
+        assert_eq!(
+            script.capabilities(),
+            [
+                Capability::FileInto,
+                Capability::Expressions,
+                Capability::Imap4Flags
+            ]
+        );
+
+        let runtime = Runtime::new();
+        assert_eq!(
+            runtime.unsupported_capabilities(&script),
+            [&Capability::Expressions]
+        );
+        assert!(runtime
+            .with_capability(Capability::Expressions)
+            .unsupported_capabilities(&script)
+            .is_empty());
+    }
+
+    #[test]
+    fn typed_responses() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "mailbox", "include"];
+                include :optional "missing";
+                if mailboxexists "Archive" {
+                    fileinto "Archive";
+                }"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut instance = runtime.filter(b"Subject: test\r\n\r\nbody");
+        let mut input = Input::script("", script);
+        let mut actions = Vec::new();
+
+        while let Some(event) = instance.run(input) {
+            let event = event.unwrap();
+            input = match event.request() {
+                Some(Request::IncludeScript(request)) => request.respond(None),
+                Some(Request::MailboxExists(request)) => request.respond(true),
+                Some(request) => panic!("Unexpected request {request:?}"),
+                None => {
+                    actions.push(event);
+                    Input::True
+                }
+            };
+        }
+
+        assert!(matches!(
+            actions.as_slice(),
+            [Event::FileInto { folder, .. }] if folder == "Archive"
+        ));
+    }
+
+    #[test]
+    fn bind_variables() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "mailbox", "include", "variables"];
+                global "folder";
+                if mailboxexists "Lists" {
+                    fileinto "${folder}/${global.list}";
+                }"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+        let mut instance = runtime.filter(b"Subject: test\r\n\r\nbody");
+        let mut input = Input::script("", script);
+        let mut folders = Vec::new();
+
+        while let Some(event) = instance.run(input) {
+            input = match event.unwrap() {
+                Event::MailboxExists { .. } => {
+                    Input::variables([("folder", "Lists"), ("LIST", "rust-users")])
+                }
+                Event::FileInto { folder, .. } => {
+                    folders.push(folder);
+                    Input::True
+                }
+                _ => Input::True,
+            };
+        }
+
+        assert_eq!(folders, ["Lists/rust-users"]);
+    }
+
+    #[test]
+    fn foreverypart_limits() {
+        let compiler = Compiler::new();
+        let single = compiler
+            .compile(b"require \"foreverypart\";\nforeverypart { keep; }")
+            .unwrap();
+        let nested = compiler
+            .compile(b"require \"foreverypart\";\nforeverypart { foreverypart { keep; } }")
+            .unwrap();
+        let message = concat!(
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n",
+            "--b\r\n\r\none\r\n",
+            "--b\r\n\r\ntwo\r\n",
+            "--b\r\n\r\nthree\r\n",
+            "--b--\r\n"
+        );
+
+        for (runtime, script, expected) in [
+            (Runtime::new(), &single, None),
+            (
+                Runtime::new().with_max_part_iterations(2),
+                &single,
+                Some(RuntimeError::PartIterationLimitReached),
+            ),
+            (Runtime::new(), &nested, None),
+            (
+                Runtime::new().with_max_nested_foreverypart(1),
+                &nested,
+                Some(RuntimeError::TooManyNestedForEveryPart),
+            ),
+        ] {
+            let mut instance = runtime.filter(message.as_bytes());
+            let mut input = Input::script("", script.clone());
+            let mut error = None;
+            while let Some(event) = instance.run(input) {
+                if let Err(err) = event {
+                    error = Some(err);
+                }
+                input = Input::True;
+            }
+
+            assert_eq!(
+                error.map(|err| err.to_string()),
+                expected.map(|err| err.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn variable_memory_limit() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["variables", "fileinto"];
+                set "a" "0123456789";
+                set "b" "${a}${a}${a}";
+                fileinto "${b}";
+                set "c" "${b}${b}";
+                fileinto "${c}";"#,
+            )
+            .unwrap();
+
+        for (limit, expected_folders, expect_error) in
+            [(0, 2, false), (100, 2, false), (50, 1, true)]
+        {
+            let runtime = Runtime::new().with_max_variable_memory(limit);
+            let mut instance = runtime.filter(b"Subject: test\r\n\r\nbody");
+            let mut input = Input::script("", script.clone());
+            let mut folders = 0;
+            let mut error = false;
+            while let Some(event) = instance.run(input) {
+                match event {
+                    Ok(Event::FileInto { .. }) => folders += 1,
+                    Err(RuntimeError::VariableMemoryLimitReached) => error = true,
+                    _ => (),
+                }
+                input = Input::True;
+            }
+            assert_eq!(
+                (folders, error),
+                (expected_folders, expect_error),
+                "{limit}"
+            );
+        }
+    }
+
+    #[test]
+    fn variable_size_tracking() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["variables", "fileinto", "include"];
+                global "g";
+                set "a" "0123456789";
+                set "g" "${a}${a}";
+                fileinto "1";
+                set "a" "x";
+                if header :matches "Subject" "*" {
+                    set "b" "${1}${1}";
+                }
+                fileinto "2";
+                set "g" "";
+                fileinto "3";"#,
+            )
+            .unwrap();
+
+        let runtime = Runtime::new();
+        let mut instance = runtime.filter(b"Subject: test\r\n\r\nbody");
+        let mut input = Input::script("", script);
+        let mut sizes = Vec::new();
+        while let Some(event) = instance.run(input) {
+            if let Ok(Event::FileInto { .. }) = event {
+                assert_eq!(instance.vars_size, instance.variables_size());
+                sizes.push(instance.vars_size);
+            }
+            input = Input::True;
+        }
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(sizes[0], 30);
+    }
+
+    #[test]
+    fn expansion_limit() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["variables", "fileinto"];
+                set "a" "0123456789";
+                fileinto "${a}${a}";
+                fileinto text:
+${a}${a}${a}
+.
+;
+                discard;"#,
+            )
+            .unwrap();
+
+        for (limit, expected) in [(0, 3), (40, 3), (25, 1)] {
+            let runtime = Runtime::new().with_max_expansion_size(limit);
+            let mut instance = runtime.filter(b"Subject: test\r\n\r\nbody");
+            let mut input = Input::script("", script.clone());
+            let mut events = 0;
+            let mut error = false;
+            while let Some(event) = instance.run(input) {
+                match event {
+                    Ok(Event::FileInto { .. } | Event::Discard) => events += 1,
+                    Err(RuntimeError::ExpansionLimitReached) => error = true,
+                    _ => (),
+                }
+                input = Input::True;
+            }
+            assert_eq!((events, error), (expected, expected == 1), "{limit}");
+        }
+    }
+
+    #[test]
+    fn include_cycles() {
+        let compiler = Compiler::new();
+        let script_a = compiler
+            .compile(b"require \"include\";\ninclude \"b\";")
+            .unwrap();
+        let script_b = compiler
+            .compile(b"require \"include\";\ninclude \"c\";")
+            .unwrap();
+        let script_c = compiler
+            .compile(b"require \"include\";\ninclude \"a\";")
+            .unwrap();
+
+        for (runtime, expected) in [
+            (Runtime::new(), "Script includes itself: a -> b -> c -> a."),
+            (
+                Runtime::new().with_max_nested_includes(2),
+                "Too many nested includes: a -> b -> c.",
+            ),
+        ] {
+            let mut instance = runtime.filter(b"Subject: test\r\n\r\nbody");
+            let mut input = Input::script("a", script_a.clone());
+            let mut error = None;
+            while let Some(event) = instance.run(input) {
+                input = match event {
+                    Ok(Event::IncludeScript { name, .. }) => match name.as_str() {
+                        "b" => Input::script(name, script_b.clone()),
+                        "c" => Input::script(name, script_c.clone()),
+                        _ => Input::script(name, script_a.clone()),
+                    },
+                    Ok(_) => Input::True,
+                    Err(err) => {
+                        error = Some(err.to_string());
+                        Input::True
+                    }
+                };
+            }
+            assert_eq!(error.as_deref(), Some(expected));
+        }
+    }
+}
@@ -0,0 +1,116 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+// Platform dependent helpers. On wasm32-unknown-unknown the standard library
+// has no clock or process information, so they are provided by the browser.
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+use std::{
+    hash::BuildHasher,
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Seconds since the Unix epoch.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) fn unix_timestamp() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0) as i64
+}
+
+/// Seconds since the Unix epoch.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) fn unix_timestamp() -> i64 {
+    (js_sys::Date::now() / 1000.0) as i64
+}
+
+/// Milliseconds since the Unix epoch.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) fn unix_timestamp_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0) as i64
+}
+
+/// Milliseconds since the Unix epoch.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) fn unix_timestamp_millis() -> i64 {
+    js_sys::Date::now() as i64
+}
+
+/// Measures elapsed wall time.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Stopwatch(std::time::Instant);
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Stopwatch(std::time::Instant::now())
+    }
+
+    pub(crate) fn elapsed(&self) -> std::time::Duration {
+        self.0.elapsed()
+    }
+}
+
+/// Measures elapsed wall time.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Stopwatch(f64);
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Stopwatch {
+    pub(crate) fn start() -> Self {
+        Stopwatch(js_sys::Date::now())
+    }
+
+    pub(crate) fn elapsed(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64((js_sys::Date::now() - self.0).max(0.0) / 1000.0)
+    }
+}
+
+/// Writes a new Message-ID, including the angle brackets.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub(crate) fn write_message_id(buf: &mut Vec<u8>, hostname: &str) {
+    let _ = mail_builder::headers::message_id::generate_message_id_header(buf, hostname);
+}
+
+/// Writes a new Message-ID, including the angle brackets.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub(crate) fn write_message_id(buf: &mut Vec<u8>, hostname: &str) {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let _ = write!(
+        buf,
+        "<{:x}.{:x}.{:x}@{}>",
+        unix_timestamp(),
+        counter,
+        ahash::RandomState::new().hash_one(counter),
+        hostname
+    );
+}
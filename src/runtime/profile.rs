@@ -0,0 +1,243 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+    time::Duration,
+};
+
+use ahash::AHashMap;
+
+use crate::{compiler::Span, Context, Script, Sieve};
+
+use super::platform::Stopwatch;
+
+/// Wall time and number of evaluations of each command executed during a
+/// run, see [`Context::set_profiling`]. Entries are sorted by time spent,
+/// slowest first.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub entries: Vec<ProfileEntry>,
+    pub total_time: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileEntry {
+    pub script: Script,
+    pub span: Span,
+    pub evaluations: u64,
+    pub time: Duration,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Profiler {
+    scripts: Vec<ScriptProfile>,
+    current: usize,
+    running: Option<(usize, usize, Stopwatch)>,
+}
+
+#[derive(Debug)]
+struct ScriptProfile {
+    name: Script,
+    script: Arc<Sieve>,
+    counters: Vec<(u64, Duration)>,
+}
+
+impl Profiler {
+    // Starts timing the instruction at `pos`, stopping the previous one
+    pub(crate) fn enter(&mut self, name: &Script, script: &Arc<Sieve>, pos: usize) {
+        self.pause();
+
+        if !self
+            .scripts
+            .get(self.current)
+            .map_or(false, |profile| Arc::ptr_eq(&profile.script, script))
+        {
+            self.current = if let Some(idx) = self
+                .scripts
+                .iter()
+                .position(|profile| Arc::ptr_eq(&profile.script, script))
+            {
+                idx
+            } else {
+                self.scripts.push(ScriptProfile {
+                    name: name.clone(),
+                    script: script.clone(),
+                    counters: vec![(0, Duration::ZERO); script.instructions.len()],
+                });
+                self.scripts.len() - 1
+            };
+        }
+
+        if let Some((evaluations, _)) = self.scripts[self.current].counters.get_mut(pos) {
+            *evaluations += 1;
+            self.running = Some((self.current, pos, Stopwatch::start()));
+        }
+    }
+
+    // Stops timing, called whenever control returns to the caller
+    pub(crate) fn pause(&mut self) {
+        if let Some((idx, pos, stopwatch)) = self.running.take() {
+            self.scripts[idx].counters[pos].1 += stopwatch.elapsed();
+        }
+    }
+
+    pub(crate) fn report(&self) -> Profile {
+        let mut profile = Profile::default();
+
+        for script in &self.scripts {
+            // Instructions produced by the same command are reported together
+            let mut commands: AHashMap<Span, usize> = AHashMap::new();
+            for (pos, (evaluations, time)) in script.counters.iter().enumerate() {
+                if *evaluations == 0 {
+                    continue;
+                }
+                let span = script.script.spans.get(pos).copied().unwrap_or_default();
+                let idx = *commands.entry(span).or_insert_with(|| {
+                    profile.entries.push(ProfileEntry {
+                        script: script.name.clone(),
+                        span,
+                        evaluations: 0,
+                        time: Duration::ZERO,
+                    });
+                    profile.entries.len() - 1
+                });
+                let entry = &mut profile.entries[idx];
+                entry.evaluations += evaluations;
+                entry.time += *time;
+                profile.total_time += *time;
+            }
+        }
+
+        profile.entries.sort_by(|a, b| {
+            b.time
+                .cmp(&a.time)
+                .then((a.span.line_num, a.span.line_pos).cmp(&(b.span.line_num, b.span.line_pos)))
+        });
+        profile
+    }
+}
+
+impl<'x, C> Context<'x, C> {
+    /// Records the time spent on each command of the scripts being run.
+    /// Profiling adds overhead to every instruction and is disabled by default.
+    pub fn set_profiling(&mut self, enable: bool) {
+        self.profiler = if enable { Some(Box::default()) } else { None };
+    }
+
+    pub fn with_profiling(mut self, enable: bool) -> Self {
+        self.set_profiling(enable);
+        self
+    }
+
+    /// Profile of the run so far, if profiling is enabled.
+    pub fn profile(&self) -> Option<Profile> {
+        self.profiler.as_ref().map(|profiler| profiler.report())
+    }
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            writeln!(
+                f,
+                "{}:{}:{} evaluations={} time={:?}",
+                entry.script.as_str(),
+                entry.span.line_num,
+                entry.span.line_pos,
+                entry.evaluations,
+                entry.time
+            )?;
+        }
+        writeln!(f, "total time={:?}", self.total_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Compiler, Event, Input, Runtime};
+
+    #[test]
+    fn profile() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "variables"];
+set "count" "0";
+if header :contains "subject" "test" {
+    fileinto "Test";
+}
+if header :is "subject" "other" {
+    discard;
+}
+"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        // Disabled by default
+        let ctx = runtime.filter(b"Subject: test\r\n\r\nbody");
+        assert!(ctx.profile().is_none());
+
+        let mut ctx = runtime
+            .filter(b"Subject: test\r\n\r\nbody")
+            .with_profiling(true);
+        let mut input = Input::script("main", script);
+        while let Some(event) = ctx.run(input) {
+            assert!(matches!(
+                event,
+                Ok(Event::FileInto { .. } | Event::Keep { .. })
+            ));
+            input = Input::True;
+        }
+
+        let profile = ctx.profile().unwrap();
+        assert_eq!(
+            profile.entries.iter().map(|e| e.evaluations).sum::<u64>(),
+            ctx.num_instructions as u64
+        );
+        assert_eq!(
+            profile.total_time,
+            profile.entries.iter().map(|e| e.time).sum()
+        );
+        let mut lines = profile
+            .entries
+            .iter()
+            .map(|e| e.span.line_num)
+            .collect::<Vec<_>>();
+        lines.sort_unstable();
+        lines.dedup();
+        assert_eq!(lines, [1, 2, 3, 4, 6]);
+        assert!(profile
+            .entries
+            .iter()
+            .all(|e| e.script.as_str() == "main" && e.evaluations > 0));
+        assert!(profile
+            .entries
+            .windows(2)
+            .all(|pair| pair[0].time >= pair[1].time));
+
+        ctx.set_profiling(false);
+        assert!(ctx.profile().is_none());
+    }
+}
@@ -0,0 +1,179 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use mail_parser::{parsers::MessageStream, DateTime, Header, HeaderName, HeaderValue, Host};
+
+use crate::{Context, ReceivedChain};
+
+impl<'x, C> Context<'x, C> {
+    /// Parses the Received header chain of the message. The remote host and
+    /// address are taken from the topmost header, which is the one added by the
+    /// receiving server, while the earliest date is searched across all hops.
+    pub fn received_chain(&self) -> ReceivedChain {
+        let mut chain = ReceivedChain::default();
+        let headers = self
+            .message
+            .parts
+            .first()
+            .map(|part| part.headers.as_slice())
+            .unwrap_or_default();
+
+        for (hop, header) in headers
+            .iter()
+            .filter(|header| header.name == HeaderName::Received)
+            .enumerate()
+        {
+            if hop == 0 {
+                if let HeaderValue::Received(rcvd) = &header.value {
+                    chain.remote_ip = rcvd.from_ip();
+                    chain.remote_host = rcvd.from_iprev().map(|host| host.to_string());
+                    match rcvd.from() {
+                        Some(Host::Name(name)) if chain.remote_host.is_none() => {
+                            chain.remote_host = name.to_string().into();
+                        }
+                        Some(Host::IpAddr(ip)) if chain.remote_ip.is_none() => {
+                            chain.remote_ip = (*ip).into();
+                        }
+                        _ => (),
+                    }
+                }
+            }
+
+            if let Some(timestamp) = self.received_date(header).map(|dt| dt.to_timestamp()) {
+                if chain
+                    .earliest_date
+                    .map_or(true, |earliest| timestamp < earliest)
+                {
+                    chain.earliest_date = timestamp.into();
+                }
+            }
+        }
+
+        chain
+    }
+
+    /// Sets the `remote-host`, `remote-ip` and `vnd.stalwart.first_received`
+    /// environment items from the Received header chain, keeping any values
+    /// that were already set.
+    pub fn set_env_from_received(&mut self) {
+        let chain = self.received_chain();
+        if let Some(remote_host) = chain.remote_host {
+            self.vars_env
+                .entry("remote-host".into())
+                .or_insert_with(|| remote_host.into());
+        }
+        if let Some(remote_ip) = chain.remote_ip {
+            self.vars_env
+                .entry("remote-ip".into())
+                .or_insert_with(|| remote_ip.to_string().into());
+        }
+        if let Some(earliest_date) = chain.earliest_date {
+            self.vars_env
+                .entry("vnd.stalwart.first_received".into())
+                .or_insert_with(|| earliest_date.into());
+        }
+    }
+
+    pub fn with_env_from_received(mut self) -> Self {
+        self.set_env_from_received();
+        self
+    }
+
+    // The date of a Received header is the one following the last semicolon
+    pub(crate) fn received_date(&self, header: &Header) -> Option<DateTime> {
+        match &header.value {
+            HeaderValue::Received(rcvd) => rcvd.date(),
+            HeaderValue::Text(text) if header.offset_end == 0 => {
+                parse_received_date(text.as_bytes())
+            }
+            _ => None,
+        }
+        .or_else(|| {
+            self.message
+                .raw_message
+                .get(header.offset_start..header.offset_end)
+                .and_then(parse_received_date)
+        })
+        .filter(|dt| dt.is_valid())
+    }
+}
+
+fn parse_received_date(bytes: &[u8]) -> Option<DateTime> {
+    let mut date = bytes[bytes.iter().rposition(|&ch| ch == b';')? + 1..].to_vec();
+    date.push(b'\n');
+    if let HeaderValue::DateTime(dt) = MessageStream::new(&date).parse_date() {
+        Some(dt)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ReceivedChain, Runtime};
+
+    const MESSAGE: &[u8] = concat!(
+        "Received: from mail.example.org (mx.example.org [192.0.2.10])\r\n",
+        "\tby mx.example.net with ESMTPS id abc123; Tue, 1 Aug 2023 10:00:05 +0200\r\n",
+        "Received: from client.example.org ([198.51.100.7])\r\n",
+        "\tby mail.example.org with ESMTPSA id def456; Mon, 31 Jul 2023 23:59:58 -0500\r\n",
+        "Subject: test\r\n",
+        "\r\n",
+        "body"
+    )
+    .as_bytes();
+
+    #[test]
+    fn received_chain() {
+        let runtime = Runtime::new().with_received_environment(true);
+        let instance = runtime.filter(MESSAGE);
+        assert_eq!(
+            instance.received_chain(),
+            ReceivedChain {
+                remote_host: Some("mx.example.org".to_string()),
+                remote_ip: Some("192.0.2.10".parse().unwrap()),
+                earliest_date: Some(1690865998),
+            }
+        );
+
+        let folders = runtime.filter(MESSAGE).run_test_folders(
+            br#"require ["environment", "date", "index", "relational", "fileinto"];
+                if environment "remote-host" "mx.example.org" {
+                    fileinto "remote-host";
+                }
+                if environment "remote-ip" "192.0.2.10" {
+                    fileinto "remote-ip";
+                }
+                if date :zone "+0000" :index 2 "received" "date" "2023-08-01" {
+                    fileinto "first-hop";
+                }
+                if date :value "ge" :zone "+0000" "received" "time" "08:00:00" {
+                    fileinto "received-time";
+                }"#,
+        );
+        assert_eq!(
+            folders,
+            vec!["remote-host", "remote-ip", "first-hop", "received-time"]
+        );
+    }
+}
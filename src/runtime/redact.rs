@@ -0,0 +1,215 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use crate::{Event, Mailbox, Recipient, Redactor};
+
+use super::{RuntimeError, Variable};
+
+impl Event {
+    /// Applies `redactor` to every text of the event that may come from the
+    /// message. Message bodies are redacted as text.
+    pub fn redact(&mut self, redactor: Redactor) {
+        match self {
+            Event::IncludeScript { .. } | Event::Discard => (),
+            Event::MailboxExists {
+                mailboxes,
+                special_use,
+            } => {
+                for mailbox in mailboxes {
+                    match mailbox {
+                        Mailbox::Name(name) | Mailbox::Id(name) => redact(name, redactor),
+                    }
+                }
+                redact_all(special_use, redactor);
+            }
+            Event::ListContains { lists, values, .. } => {
+                redact_all(lists, redactor);
+                redact_all(values, redactor);
+            }
+            Event::DuplicateId { id, .. } => redact(id, redactor),
+            Event::SetEnvelope { value, .. } => redact(value, redactor),
+            Event::Function { arguments, .. } => {
+                for argument in arguments {
+                    argument.redact(redactor);
+                }
+            }
+            Event::PreviewModification { before, after, .. } => {
+                redact_bytes(before, redactor);
+                redact_bytes(after, redactor);
+            }
+            Event::Keep { flags, .. } => redact_all(flags, redactor),
+            Event::Reject {
+                reason, message, ..
+            } => {
+                redact(reason, redactor);
+                if let Some(message) = message {
+                    redact_bytes(message, redactor);
+                }
+            }
+            Event::FileInto {
+                folder,
+                flags,
+                mailbox_id,
+                special_use,
+                ..
+            } => {
+                redact(folder, redactor);
+                redact_all(flags, redactor);
+                for value in [mailbox_id, special_use].into_iter().flatten() {
+                    redact(value, redactor);
+                }
+            }
+            Event::SendMessage { recipient, .. } => match recipient {
+                Recipient::Address(address) | Recipient::List(address) => redact(address, redactor),
+                Recipient::Group(group) => redact_all(group, redactor),
+            },
+            Event::Notify {
+                from,
+                options,
+                message,
+                method,
+                ..
+            } => {
+                if let Some(from) = from {
+                    redact(from, redactor);
+                }
+                redact_all(options, redactor);
+                redact(message, redactor);
+                redact(method, redactor);
+            }
+            Event::CreatedMessage { message, .. } => redact_bytes(message, redactor),
+        }
+    }
+}
+
+impl RuntimeError {
+    /// Applies `redactor` to the parts of the error that may come from the
+    /// message, such as the text of an `error` command or a redirect address.
+    pub fn redact(&mut self, redactor: Redactor) {
+        match self {
+            RuntimeError::ScriptErrorMessage(message)
+            | RuntimeError::RedirectNotAllowed(message)
+            | RuntimeError::Internal { message, .. } => redact(message, redactor),
+            _ => (),
+        }
+    }
+}
+
+impl Variable {
+    pub(crate) fn redact(&mut self, redactor: Redactor) {
+        match self {
+            Variable::String(value) => *value = Arc::new(redactor(value)),
+            Variable::Array(items) => {
+                for item in Arc::make_mut(items) {
+                    item.redact(redactor);
+                }
+            }
+            Variable::Integer(_) | Variable::Float(_) => (),
+        }
+    }
+}
+
+pub(crate) fn redact(value: &mut String, redactor: Redactor) {
+    *value = redactor(value);
+}
+
+fn redact_all(values: &mut [String], redactor: Redactor) {
+    for value in values {
+        redact(value, redactor);
+    }
+}
+
+fn redact_bytes(value: &mut Vec<u8>, redactor: Redactor) {
+    *value = redactor(&String::from_utf8_lossy(value)).into_bytes();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::grammar::actions::action_redirect::{ByTime, Notify, Ret},
+        runtime::RuntimeError,
+        Compiler, Event, Input, Recipient, RedirectAction, Runtime,
+    };
+
+    fn mask(value: &str) -> String {
+        if value.contains('@') {
+            "<address>".to_string()
+        } else {
+            value.to_string()
+        }
+    }
+
+    #[test]
+    fn redactor() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["variables", "fileinto"];
+if address :matches "From" "*" {
+    fileinto "From ${1}";
+    redirect "${1}";
+}
+"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new()
+            .with_redirect_policy(|_| RedirectAction::Deny)
+            .with_redactor(mask);
+
+        // Events returned by run are left as they are
+        let event = runtime
+            .filter(b"From: jdoe@example.org\r\n\r\nbody")
+            .run(Input::script("", script.clone()))
+            .unwrap()
+            .unwrap();
+        assert!(
+            matches!(event, Event::FileInto { folder, .. } if folder == "From jdoe@example.org")
+        );
+
+        let trace = runtime
+            .filter(b"From: jdoe@example.org\r\n\r\nbody")
+            .run_traced(Input::script("", script), |_| Input::False);
+        assert!(matches!(
+            &trace.entries[0].event,
+            Event::FileInto { folder, .. } if folder == "<address>"
+        ));
+        assert!(matches!(
+            &trace.error,
+            Some(RuntimeError::RedirectNotAllowed(address)) if address == "<address>"
+        ));
+
+        let mut event = Event::SendMessage {
+            recipient: Recipient::Group(vec!["a@b".to_string(), "c".to_string()]),
+            notify: Notify::Default,
+            return_of_content: Ret::Default,
+            by_time: ByTime::None,
+            message_id: 0,
+        };
+        event.redact(mask);
+        assert!(matches!(
+            &event,
+            Event::SendMessage { recipient: Recipient::Group(group), .. } if group == &["<address>", "c"]
+        ));
+    }
+}
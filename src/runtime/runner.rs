@@ -0,0 +1,223 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use crate::{Event, Input, Request, Runner, Runtime, Sieve};
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use super::{context::panic_message, trace::Trace, RuntimeError, Variable};
+
+impl<C> Runner<C> {
+    /// Creates a runner that evaluates `script` with the settings of `runtime`.
+    /// Runners are cheap to clone and can be shared between threads.
+    ///
+    /// ```rust
+    ///     use sieve::{Compiler, Event, Runner, Runtime};
+    ///
+    ///     let script = Compiler::new()
+    ///         .compile(b"require \"fileinto\"; fileinto \"Archive\";")
+    ///         .unwrap();
+    ///     let runner = Runner::new(script, Runtime::new());
+    ///
+    ///     let messages = [b"Subject: one\r\n\r\ntest", b"Subject: two\r\n\r\ntest"];
+    ///     for actions in runner.evaluate_many(&messages) {
+    ///         assert!(matches!(
+    ///             actions.unwrap().as_slice(),
+    ///             [Event::FileInto { folder, .. }] if folder == "Archive"
+    ///         ));
+    ///     }
+    /// ```
+    pub fn new(script: impl Into<Arc<Sieve>>, runtime: Runtime<C>) -> Self {
+        Runner {
+            script: script.into(),
+            runtime: Arc::new(runtime),
+        }
+    }
+
+    pub fn script(&self) -> &Arc<Sieve> {
+        &self.script
+    }
+
+    pub fn runtime(&self) -> &Runtime<C> {
+        &self.runtime
+    }
+
+    /// Evaluates a message and returns the actions to take. Includes not
+    /// registered in the runtime are skipped and every other test event,
+    /// such as `MailboxExists` or `DuplicateId`, is answered with `false`.
+    pub fn evaluate(&self, raw_message: &[u8]) -> Result<Vec<Event>, RuntimeError> {
+        self.evaluate_with(raw_message, |event| match event {
+            Event::Function { .. } => Input::FncResult(Variable::default()),
+            Event::PreviewModification { .. } => Input::True,
+            _ => Input::False,
+        })
+    }
+
+    /// Evaluates a message and returns the actions to take, calling `resolver`
+    /// to answer the events that are not actions.
+    pub fn evaluate_with(
+        &self,
+        raw_message: &[u8],
+        mut resolver: impl FnMut(&Event) -> Input,
+    ) -> Result<Vec<Event>, RuntimeError> {
+        let mut instance = self.runtime.filter(raw_message);
+        let mut input = Input::script("", self.script.clone());
+        let mut actions = Vec::new();
+
+        while let Some(event) = instance.run(input) {
+            let event = event?;
+            input = match &event {
+                Event::IncludeScript { .. }
+                | Event::MailboxExists { .. }
+                | Event::ListContains { .. }
+                | Event::DuplicateId { .. }
+                | Event::Function { .. }
+                | Event::PreviewModification { .. } => resolver(&event),
+                _ => {
+                    actions.push(event);
+                    Input::True
+                }
+            };
+        }
+
+        Ok(actions)
+    }
+
+    /// Same as [`Runner::evaluate_with`] with typed answers, `resolver`
+    /// receives each event along with its [`Request`].
+    pub fn evaluate_with_responses(
+        &self,
+        raw_message: &[u8],
+        mut resolver: impl FnMut(&Event, Request<'_>) -> Input,
+    ) -> Result<Vec<Event>, RuntimeError> {
+        self.evaluate_with(raw_message, |event| match event.request() {
+            Some(request) => resolver(event, request),
+            None => Input::True,
+        })
+    }
+
+    /// Evaluates a message and records every event along with the input
+    /// returned by `resolver`, see [`Trace`].
+    pub fn trace(&self, raw_message: &[u8], resolver: impl FnMut(&Event) -> Input) -> Trace {
+        self.runtime
+            .filter(raw_message)
+            .run_traced(Input::script("", self.script.clone()), resolver)
+    }
+
+    /// Evaluates a batch of messages and returns the actions for each message,
+    /// in the same order. With the `parallel` feature the messages are
+    /// evaluated on the rayon thread pool. A panic while evaluating a message
+    /// is returned as a [`RuntimeError::Internal`] for that message only.
+    pub fn evaluate_many<M>(&self, messages: &[M]) -> Vec<Result<Vec<Event>, RuntimeError>>
+    where
+        M: AsRef<[u8]> + Sync,
+        C: Send + Sync,
+    {
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+
+            messages
+                .par_iter()
+                .map(|message| self.evaluate_catching(message.as_ref()))
+                .collect()
+        }
+
+        #[cfg(not(feature = "parallel"))]
+        {
+            messages
+                .iter()
+                .map(|message| self.evaluate_catching(message.as_ref()))
+                .collect()
+        }
+    }
+
+    fn evaluate_catching(&self, raw_message: &[u8]) -> Result<Vec<Event>, RuntimeError> {
+        catch_unwind(AssertUnwindSafe(|| self.evaluate(raw_message))).unwrap_or_else(|payload| {
+            Err(RuntimeError::Internal {
+                message: panic_message(payload.as_ref()),
+                script: None,
+                span: None,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::grammar::Capability, runtime::RuntimeError, Compiler, Event, FunctionMap, Runner,
+        Runtime,
+    };
+
+    #[test]
+    fn evaluate_many() {
+        let mut fnc_map = FunctionMap::new().with_function_no_args("check", |ctx, _| {
+            if ctx.message().subject() == Some("crash") {
+                panic!("boom");
+            }
+            ctx.message()
+                .subject()
+                .map_or(false, |s| s == "archive")
+                .into()
+        });
+        let script = Compiler::new()
+            .register_functions(&mut fnc_map)
+            .compile(
+                b"require [\"vnd.stalwart.expressions\", \"fileinto\"];\nif eval \"check()\" { fileinto \"Archive\"; }",
+            )
+            .unwrap();
+        let runner = Runner::new(
+            script,
+            Runtime::new()
+                .with_capability(Capability::Expressions)
+                .with_functions(&mut fnc_map),
+        );
+
+        let messages = [
+            "Subject: archive\r\n\r\ntest",
+            "Subject: crash\r\n\r\ntest",
+            "Subject: hello\r\n\r\ntest",
+        ];
+        let results = runner.evaluate_many(&messages);
+        assert_eq!(results.len(), messages.len());
+
+        // A panic only fails the message that caused it
+        assert!(matches!(
+            results[0].as_deref(),
+            Ok([Event::FileInto { folder, .. }]) if folder == "Archive"
+        ));
+        assert!(matches!(
+            &results[1],
+            Err(RuntimeError::Internal { message, .. }) if message == "boom"
+        ));
+        assert!(matches!(results[2].as_deref(), Ok([Event::Keep { .. }])));
+        assert!(matches!(
+            runner.evaluate(messages[2].as_bytes()).as_deref(),
+            Ok([Event::Keep { .. }])
+        ));
+        assert!(runner.evaluate_many::<&[u8]>(&[]).is_empty());
+    }
+}
@@ -21,30 +21,163 @@
  * for more details.
 */
 
+use std::fmt::Display;
+
 use crate::{Compiler, Sieve};
 
+// Serialized scripts start with a marker byte followed by the
+// compiler version that produced them.
 const SIEVE_MARKER: u8 = 0xff;
+const HEADER_LEN: usize = 2;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SerializeError {
+    InvalidFormat,
+    IncompatibleVersion { found: u32, expected: u32 },
     Other,
 }
 
 impl Sieve {
-    pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<bincode::ErrorKind>> {
-        if bytes.len() > 2 && bytes[0] == SIEVE_MARKER && bytes[1] == Compiler::VERSION as u8 {
-            bincode::deserialize(&bytes[2..])
+    /// Returns an error if `bytes` is not a serialized script or was produced
+    /// by a different compiler version, in which case the script has to be
+    /// compiled again from its source.
+    pub fn check_compatibility(bytes: &[u8]) -> Result<(), SerializeError> {
+        match Sieve::format_version(bytes) {
+            Some(Compiler::VERSION) => Ok(()),
+            Some(found) => Err(SerializeError::IncompatibleVersion {
+                found,
+                expected: Compiler::VERSION,
+            }),
+            None => Err(SerializeError::InvalidFormat),
+        }
+    }
+
+    pub fn format_version(bytes: &[u8]) -> Option<u32> {
+        if bytes.len() > HEADER_LEN && bytes[0] == SIEVE_MARKER {
+            Some(bytes[1] as u32)
         } else {
-            Err(Box::new(bincode::ErrorKind::Custom(
-                "Incompatible version".to_string(),
-            )))
+            None
         }
     }
 
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, Box<bincode::ErrorKind>> {
+        Sieve::check_compatibility(bytes)
+            .map_err(|err| Box::new(bincode::ErrorKind::Custom(err.to_string())))?;
+        bincode::deserialize(&bytes[HEADER_LEN..])
+    }
+
+    /// Size in bytes of the output of [`Sieve::serialize`].
+    pub fn serialized_size(&self) -> Option<usize> {
+        bincode::serialized_size(self)
+            .ok()
+            .map(|size| size as usize + HEADER_LEN)
+    }
+
+    /// Serializes the compiled script. The output only depends on the script
+    /// source and the compiler configuration, so identical inputs always
+    /// produce byte-identical blobs that can be hashed for caching.
     pub fn serialize(&self) -> Result<Vec<u8>, Box<bincode::ErrorKind>> {
-        let mut buf = Vec::with_capacity(bincode::serialized_size(self)? as usize + 2);
+        let mut buf = Vec::with_capacity(bincode::serialized_size(self)? as usize + HEADER_LEN);
         buf.push(SIEVE_MARKER);
         buf.push(Compiler::VERSION as u8);
         bincode::serialize_into(&mut buf, self)?;
         Ok(buf)
     }
 }
+
+impl Display for SerializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SerializeError::InvalidFormat => write!(f, "Not a serialized Sieve script"),
+            SerializeError::IncompatibleVersion { found, expected } => write!(
+                f,
+                "Incompatible version {found}, expected {expected}: script must be recompiled"
+            ),
+            SerializeError::Other => write!(f, "Serialization error"),
+        }
+    }
+}
+
+impl std::error::Error for SerializeError {}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::{runtime::serialize::SerializeError, Compiler, Sieve};
+
+    #[test]
+    fn check_compatibility() {
+        let mut bytes = Compiler::new()
+            .compile(b"keep;")
+            .unwrap()
+            .serialize()
+            .unwrap();
+        assert_eq!(Sieve::check_compatibility(&bytes), Ok(()));
+        assert!(Sieve::deserialize(&bytes).is_ok());
+
+        bytes[1] = (Compiler::VERSION - 1) as u8;
+        assert_eq!(
+            Sieve::check_compatibility(&bytes),
+            Err(SerializeError::IncompatibleVersion {
+                found: Compiler::VERSION - 1,
+                expected: Compiler::VERSION
+            })
+        );
+        assert!(Sieve::deserialize(&bytes).is_err());
+
+        assert_eq!(
+            Sieve::check_compatibility(b"keep;"),
+            Err(SerializeError::InvalidFormat)
+        );
+    }
+
+    #[test]
+    fn deterministic_output() {
+        let mut dirs = vec![PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests")];
+        let mut num_scripts = 0;
+
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                } else if !path
+                    .extension()
+                    .map_or(false, |e| e == "sieve" || e == "svtest")
+                {
+                    continue;
+                }
+
+                // Each compiler run uses freshly seeded hash maps
+                let script = fs::read(&path).unwrap();
+                let bytes = match Compiler::new().compile(&script) {
+                    Ok(sieve) => sieve.serialize().unwrap(),
+                    Err(_) => continue,
+                };
+                for _ in 0..3 {
+                    assert_eq!(
+                        Compiler::new()
+                            .compile(&script)
+                            .unwrap()
+                            .serialize()
+                            .unwrap(),
+                        bytes,
+                        "{}",
+                        path.display()
+                    );
+                }
+                assert_eq!(
+                    Sieve::deserialize(&bytes).unwrap().serialize().unwrap(),
+                    bytes,
+                    "{}",
+                    path.display()
+                );
+                num_scripts += 1;
+            }
+        }
+
+        assert!(num_scripts > 0);
+    }
+}
@@ -0,0 +1,247 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    cell::{OnceCell, RefCell},
+    fmt::Debug,
+    io::{Read, Seek, SeekFrom},
+};
+
+/// A message that is read lazily by the runtime. The header section is
+/// always parsed up front, while the full message is only requested when a
+/// script accesses the message body or modifies the message.
+pub trait MessageSource: Debug {
+    /// Returns the header section of the message, including the empty line
+    /// that separates it from the body.
+    fn headers(&self) -> &[u8];
+
+    /// Returns the full raw message. The returned bytes must start with the
+    /// same header section returned by `headers`.
+    fn raw_message(&self) -> std::io::Result<&[u8]>;
+
+    /// Returns the size of the full message in bytes.
+    fn size(&self) -> usize;
+}
+
+impl<T: AsRef<[u8]> + Debug> MessageSource for T {
+    fn headers(&self) -> &[u8] {
+        let bytes = self.as_ref();
+        &bytes[..header_end(bytes).unwrap_or(bytes.len())]
+    }
+
+    fn raw_message(&self) -> std::io::Result<&[u8]> {
+        Ok(self.as_ref())
+    }
+
+    fn size(&self) -> usize {
+        self.as_ref().len()
+    }
+}
+
+/// Reads the message headers from a `Read + Seek` source and defers loading
+/// the rest of the message until it is needed.
+pub struct LazyMessage<R: Read + Seek> {
+    reader: RefCell<R>,
+    headers: Vec<u8>,
+    message: OnceCell<Vec<u8>>,
+    size: usize,
+}
+
+impl<R: Read + Seek> LazyMessage<R> {
+    pub fn new(mut reader: R) -> std::io::Result<Self> {
+        let size = reader.seek(SeekFrom::End(0))? as usize;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut headers = Vec::with_capacity(1024);
+        let mut buf = [0u8; 4096];
+        loop {
+            let bytes_read = reader.read(&mut buf)?;
+            if bytes_read == 0 {
+                break;
+            }
+            let scan_from = headers.len().saturating_sub(3);
+            headers.extend_from_slice(&buf[..bytes_read]);
+            if let Some(pos) = header_end(&headers[scan_from..]) {
+                headers.truncate(scan_from + pos);
+                break;
+            }
+        }
+
+        Ok(LazyMessage {
+            reader: RefCell::new(reader),
+            headers,
+            message: OnceCell::new(),
+            size,
+        })
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.message.get().is_some()
+    }
+}
+
+impl<R: Read + Seek> MessageSource for LazyMessage<R> {
+    fn headers(&self) -> &[u8] {
+        &self.headers
+    }
+
+    fn raw_message(&self) -> std::io::Result<&[u8]> {
+        if let Some(message) = self.message.get() {
+            return Ok(message);
+        }
+
+        let mut reader = self.reader.borrow_mut();
+        let mut message = Vec::with_capacity(self.size);
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_to_end(&mut message)?;
+        Ok(self.message.get_or_init(|| message))
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl<R: Read + Seek> Debug for LazyMessage<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LazyMessage")
+            .field("headers", &String::from_utf8_lossy(&self.headers))
+            .field("size", &self.size)
+            .field("is_loaded", &self.is_loaded())
+            .finish()
+    }
+}
+
+fn header_end(bytes: &[u8]) -> Option<usize> {
+    let mut iter = bytes.iter().enumerate().peekable();
+    while let Some((pos, ch)) = iter.next() {
+        if *ch == b'\n' {
+            match iter.peek() {
+                Some((_, b'\n')) => return Some(pos + 2),
+                Some((_, b'\r')) if bytes.get(pos + 2) == Some(&b'\n') => return Some(pos + 3),
+                _ => (),
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+
+    use crate::{runtime::RuntimeError, Compiler, Event, Input, Runtime};
+
+    use super::{LazyMessage, MessageSource};
+
+    const MESSAGE: &[u8] = b"From: a@example.org\r\nSubject: Hello\r\n\r\nsecret body\r\n";
+
+    // Fails every read once the header section was consumed
+    #[derive(Debug)]
+    struct FailingReader {
+        inner: Cursor<&'static [u8]>,
+        reads: usize,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            if self.reads > 1 {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "disk error",
+                ))
+            } else {
+                self.inner.read(buf)
+            }
+        }
+    }
+
+    impl Seek for FailingReader {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn lazy_message() {
+        let message = LazyMessage::new(Cursor::new(MESSAGE)).unwrap();
+        assert_eq!(
+            message.headers(),
+            b"From: a@example.org\r\nSubject: Hello\r\n\r\n"
+        );
+        assert_eq!(message.size(), MESSAGE.len());
+        assert!(!message.is_loaded());
+        assert_eq!(message.raw_message().unwrap(), MESSAGE);
+        assert!(message.is_loaded());
+
+        let message = LazyMessage::new(FailingReader {
+            inner: Cursor::new(MESSAGE),
+            reads: 0,
+        })
+        .unwrap();
+        assert!(message.raw_message().is_err());
+        assert!(!message.is_loaded());
+    }
+
+    #[test]
+    fn filter_source() {
+        let compiler = Compiler::new();
+        let runtime = Runtime::new();
+        let headers = compiler
+            .compile(b"if header :contains \"subject\" \"hello\" { discard; }")
+            .unwrap();
+        let body = compiler
+            .compile(b"require \"body\";\nif body :contains \"secret\" { discard; }")
+            .unwrap();
+
+        // Header tests do not load the message
+        let source = LazyMessage::new(Cursor::new(MESSAGE)).unwrap();
+        let mut ctx = runtime.filter_source(&source);
+        assert!(matches!(
+            ctx.run(Input::script("", headers)),
+            Some(Ok(Event::Discard))
+        ));
+        assert!(!source.is_loaded());
+
+        let source = LazyMessage::new(Cursor::new(MESSAGE)).unwrap();
+        let mut ctx = runtime.filter_source(&source);
+        assert!(matches!(
+            ctx.run(Input::script("", body.clone())),
+            Some(Ok(Event::Discard))
+        ));
+        assert!(source.is_loaded());
+
+        // Read errors are returned instead of testing the header section alone
+        let source = LazyMessage::new(FailingReader {
+            inner: Cursor::new(MESSAGE),
+            reads: 0,
+        })
+        .unwrap();
+        let mut ctx = runtime.filter_source(&source);
+        assert!(matches!(
+            ctx.run(Input::script("", body)),
+            Some(Err(RuntimeError::Internal { message, .. })) if message.contains("disk error")
+        ));
+    }
+}
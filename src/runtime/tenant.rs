@@ -0,0 +1,168 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use super::context::RuntimeRef;
+use crate::{Context, Runtime, RuntimeProfile};
+
+impl<C> RuntimeProfile<C> {
+    /// Creates a profile with the settings of `runtime`. Profiles are usually
+    /// derived from the tenant-wide runtime, changing only what differs:
+    ///
+    /// ```rust
+    ///     use sieve::{Runtime, RuntimeProfile};
+    ///
+    ///     let runtime = Runtime::new();
+    ///     let runtime = runtime.clone().with_profile(
+    ///         "acme",
+    ///         RuntimeProfile::new(runtime.with_max_redirects(5)),
+    ///     );
+    ///     assert!(runtime.has_profile("acme"));
+    /// ```
+    pub fn new(runtime: Runtime<C>) -> Self {
+        RuntimeProfile { runtime }
+    }
+
+    pub fn runtime(&self) -> &Runtime<C> {
+        &self.runtime
+    }
+
+    pub fn runtime_mut(&mut self) -> &mut Runtime<C> {
+        &mut self.runtime
+    }
+}
+
+impl<C> From<Runtime<C>> for RuntimeProfile<C> {
+    fn from(runtime: Runtime<C>) -> Self {
+        RuntimeProfile::new(runtime)
+    }
+}
+
+impl<C> Runtime<C> {
+    /// Registers the profile of a tenant, replacing any previous one. The
+    /// compiled regular expressions cache is shared with this runtime.
+    pub fn set_profile(&mut self, tenant_id: impl Into<String>, profile: RuntimeProfile<C>) {
+        let mut profile = profile;
+        profile.runtime.regex_cache = self.regex_cache.clone();
+        profile.runtime.profiles.clear();
+        self.profiles.insert(tenant_id.into(), Arc::new(profile));
+    }
+
+    pub fn with_profile(
+        mut self,
+        tenant_id: impl Into<String>,
+        profile: RuntimeProfile<C>,
+    ) -> Self {
+        self.set_profile(tenant_id, profile);
+        self
+    }
+
+    pub fn remove_profile(&mut self, tenant_id: &str) -> Option<Arc<RuntimeProfile<C>>> {
+        self.profiles.remove(tenant_id)
+    }
+
+    pub fn has_profile(&self, tenant_id: &str) -> bool {
+        self.profiles.contains_key(tenant_id)
+    }
+
+    /// Returns the runtime of a tenant, or this runtime if the tenant has no
+    /// profile, so that `runtime.tenant(id).filter(message)` creates a
+    /// context with the settings of the tenant.
+    pub fn tenant(&self, tenant_id: &str) -> &Runtime<C> {
+        self.profiles
+            .get(tenant_id)
+            .map_or(self, |profile| &profile.runtime)
+    }
+}
+
+impl<'x, C: Clone> Context<'x, C> {
+    /// Switches the context to the profile of a tenant registered in the
+    /// runtime the context was created with. Returns `false`, leaving the
+    /// context unchanged, if the tenant has no profile. The message is
+    /// interpreted again using the MIME leniency of the profile, so this is
+    /// meant to be called before running any script.
+    pub fn set_tenant(&mut self, tenant_id: &str) -> bool {
+        let runtime = match &self.runtime {
+            RuntimeRef::Borrowed(runtime) => {
+                let runtime: &'x Runtime<C> = runtime;
+                runtime
+                    .profiles
+                    .get(tenant_id)
+                    .map(|profile| RuntimeRef::Borrowed(&profile.runtime))
+            }
+            RuntimeRef::Owned(runtime) => runtime
+                .profiles
+                .get(tenant_id)
+                .map(|profile| RuntimeRef::Owned(Box::new(profile.runtime.clone()))),
+        };
+
+        if let Some(runtime) = runtime {
+            self.runtime = runtime;
+            *self.header_index.get_mut() = Arc::default();
+            self.apply_mime_leniency();
+            if self.runtime.received_environment {
+                self.set_env_from_received();
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn with_tenant(mut self, tenant_id: &str) -> Self {
+        self.set_tenant(tenant_id);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{compiler::grammar::Capability, Runtime, RuntimeProfile};
+
+    #[test]
+    fn tenant_profiles() {
+        let restricted = Runtime::new()
+            .with_max_redirects(0)
+            .without_capability(Capability::Vacation);
+        let runtime = Runtime::new()
+            .with_max_redirects(3)
+            .with_profile("free", RuntimeProfile::new(restricted));
+
+        assert_eq!(runtime.tenant("free").max_redirects, 0);
+        assert_eq!(runtime.tenant("unknown").max_redirects, 3);
+
+        let mut ctx = runtime.filter(b"Subject: test\r\n\r\nbody");
+        assert!(!ctx.set_tenant("unknown"));
+        assert_eq!(ctx.runtime.max_redirects, 3);
+        assert!(ctx.set_tenant("free"));
+        assert_eq!(ctx.runtime.max_redirects, 0);
+        assert!(!ctx
+            .runtime
+            .allowed_capabilities
+            .contains(&Capability::Vacation));
+        assert!(Arc::ptr_eq(&ctx.runtime.regex_cache, &runtime.regex_cache));
+    }
+}
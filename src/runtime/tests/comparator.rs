@@ -28,7 +28,7 @@ use crate::{
         grammar::{Comparator, RelationalMatch},
         Number, Value,
     },
-    runtime::Variable,
+    runtime::{cache::RegexCache, Variable},
     MatchAs,
 };
 
@@ -56,6 +56,22 @@ impl Comparator {
             }
     }
 
+    pub(crate) fn contains_value(
+        &self,
+        haystack: &str,
+        pattern: &Value,
+        pattern_expr: &Variable,
+    ) -> bool {
+        if let Value::Contains(keys) = pattern {
+            match self {
+                Comparator::Octet => keys.octet.is_match(haystack),
+                _ => keys.casemap.is_match(&haystack.to_lowercase()),
+            }
+        } else {
+            self.contains(haystack, pattern_expr.to_string().as_ref())
+        }
+    }
+
     pub(crate) fn relational(
         &self,
         relation: &RelationalMatch,
@@ -85,19 +101,55 @@ impl Comparator {
         }
     }
 
+    pub(crate) fn matches_value(
+        &self,
+        value: &str,
+        pattern: &Value,
+        pattern_expr: &Variable,
+        capture_positions: u64,
+        captured_values: &mut Vec<(usize, String)>,
+    ) -> bool {
+        if let Value::Glob(glob) = pattern {
+            let pattern = if matches!(self, Comparator::AsciiCaseMap) {
+                &glob.casemap
+            } else {
+                &glob.octet
+            };
+            if capture_positions == 0 {
+                pattern.matches(value)
+            } else {
+                pattern
+                    .clone()
+                    .capture(value, capture_positions, captured_values)
+            }
+        } else {
+            self.matches(
+                value,
+                pattern_expr.to_string().as_ref(),
+                capture_positions,
+                captured_values,
+            )
+        }
+    }
+
     pub(crate) fn regex(
         &self,
+        cache: &RegexCache,
         pattern: &Value,
         pattern_expr: &Variable,
         value: &str,
         mut capture_positions: u64,
         captured_values: &mut Vec<(usize, String)>,
     ) -> bool {
+        let cached_regex;
         let regex = if let Value::Regex(regex) = pattern {
-            Cow::Borrowed(&regex.regex)
+            &regex.regex
         } else {
-            match fancy_regex::Regex::new(pattern_expr.to_string().as_ref()) {
-                Ok(regex) => Cow::Owned(regex),
+            match cache.get_or_compile(pattern_expr.to_string().as_ref()) {
+                Ok(regex) => {
+                    cached_regex = regex;
+                    cached_regex.as_ref()
+                }
                 Err(err) => {
                     debug_assert!(false, "Failed to compile regex: {err:?}");
                     return false;
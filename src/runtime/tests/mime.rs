@@ -28,9 +28,10 @@ use mail_parser::{Message, MessagePart, MimeHeaders, PartType};
 use crate::Context;
 
 #[derive(Debug)]
-pub(crate) enum ContentTypeFilter {
-    Type(String),
-    TypeSubtype((String, String)),
+pub(crate) struct ContentTypeFilter {
+    c_type: Option<String>,
+    c_subtype: Option<String>,
+    params: Vec<(String, String)>,
 }
 
 pub(crate) struct SubpartIterator<'x, C> {
@@ -101,21 +102,9 @@ impl<'x, C> Context<'x, C> {
                         };
 
                         for ctf in ct_filter {
-                            match ctf {
-                                ContentTypeFilter::Type(name) => {
-                                    if name.eq_ignore_ascii_case(ct) {
-                                        process_part = true;
-                                        break;
-                                    }
-                                }
-                                ContentTypeFilter::TypeSubtype((name, subname)) => {
-                                    if name.eq_ignore_ascii_case(ct)
-                                        && subname.eq_ignore_ascii_case(cst)
-                                    {
-                                        process_part = true;
-                                        break;
-                                    }
-                                }
+                            if ctf.matches(ct, cst, subpart) {
+                                process_part = true;
+                                break;
                             }
                         }
 
@@ -200,22 +189,57 @@ impl<'x, C> Context<'x, C> {
 }
 
 impl ContentTypeFilter {
+    // Parses "type", "type/subtype" and "*", where the subtype may be "*",
+    // optionally followed by the parameters the part must have
     pub(crate) fn parse(ct: &str) -> Option<ContentTypeFilter> {
-        let mut iter = ct.split('/');
-        let name = iter.next()?;
-        if let Some(sub_name) = iter.next() {
-            if !name.is_empty() && !sub_name.is_empty() && iter.next().is_none() {
-                Some(ContentTypeFilter::TypeSubtype((
-                    name.to_string(),
-                    sub_name.to_string(),
-                )))
-            } else {
-                None
+        let mut iter = ct.split(';');
+        let media_type = iter.next()?.trim();
+        let (c_type, c_subtype) = match media_type.split_once('/') {
+            Some((c_type, c_subtype)) => (c_type.trim(), c_subtype.trim()),
+            None => (media_type, "*"),
+        };
+        if c_type.is_empty() || c_subtype.is_empty() || c_subtype.contains('/') {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        for param in iter {
+            let param = param.trim();
+            if param.is_empty() {
+                continue;
             }
-        } else if !name.is_empty() {
-            Some(ContentTypeFilter::Type(name.to_string()))
-        } else {
-            None
+            let (name, value) = param.split_once('=')?;
+            let name = name.trim();
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            if name.is_empty() {
+                return None;
+            }
+            params.push((name.to_string(), value.to_string()));
         }
+
+        Some(ContentTypeFilter {
+            c_type: (c_type != "*").then(|| c_type.to_string()),
+            c_subtype: (c_subtype != "*").then(|| c_subtype.to_string()),
+            params,
+        })
+    }
+
+    pub(crate) fn matches(&self, ct: &str, cst: &str, part: &MessagePart) -> bool {
+        self.c_type
+            .as_ref()
+            .map_or(true, |c_type| c_type.eq_ignore_ascii_case(ct))
+            && self
+                .c_subtype
+                .as_ref()
+                .map_or(true, |c_subtype| c_subtype.eq_ignore_ascii_case(cst))
+            && self.params.iter().all(|(name, value)| {
+                part.content_type()
+                    .and_then(|ct| ct.attribute(name))
+                    .map_or(false, |v| v.eq_ignore_ascii_case(value))
+            })
     }
 }
@@ -81,7 +81,11 @@ impl Test {
                     mailboxes: test
                         .mailbox_names
                         .iter()
-                        .map(|m| Mailbox::Name(ctx.eval_value(m).to_string().into_owned()))
+                        .map(|m| {
+                            Mailbox::Name(
+                                ctx.normalize_mailbox(ctx.eval_value(m).to_string().into_owned()),
+                            )
+                        })
                         .collect(),
                     special_use: Vec::new(),
                 },
@@ -106,9 +110,9 @@ impl Test {
             Test::SpecialUseExists(test) => TestResult::Event {
                 event: Event::MailboxExists {
                     mailboxes: if let Some(mailbox) = &test.mailbox {
-                        vec![Mailbox::Name(
+                        vec![Mailbox::Name(ctx.normalize_mailbox(
                             ctx.eval_value(mailbox).to_string().into_owned(),
-                        )]
+                        ))]
                     } else {
                         Vec::new()
                     },
@@ -122,7 +126,7 @@ impl Test {
             Test::Invalid(invalid) => {
                 TestResult::Error(RuntimeError::InvalidInstruction(invalid.clone()))
             }
-            #[cfg(test)]
+            #[cfg(any(test, feature = "testsuite"))]
             Test::TestCmd { arguments, is_not } => TestResult::Event {
                 event: Event::Function {
                     id: u32::MAX,
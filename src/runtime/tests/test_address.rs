@@ -21,6 +21,8 @@
  * for more details.
 */
 
+use std::borrow::Cow;
+
 use mail_parser::{
     parsers::{
         fields::address::{
@@ -37,7 +39,7 @@ use crate::{
         grammar::{tests::test_address::TestAddress, AddressPart, MatchType},
         Number,
     },
-    Context, Event,
+    AddressFallback, AddressOptions, Context, Event, Subaddress,
 };
 
 use super::TestResult;
@@ -53,6 +55,7 @@ impl TestAddress {
                 ctx.find_headers(
                     &header_list,
                     self.index,
+                    self.mime,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_addresses(header, &self.address_part, |value| {
@@ -74,6 +77,7 @@ impl TestAddress {
             MatchType::Value(rel_match) => ctx.find_headers(
                 &header_list,
                 self.index,
+                self.mime,
                 self.mime_anychild,
                 |header, _, _| {
                     ctx.find_addresses(header, &self.address_part, |value| {
@@ -92,21 +96,24 @@ impl TestAddress {
                 let result = ctx.find_headers(
                     &header_list,
                     self.index,
+                    self.mime,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_addresses(header, &self.address_part, |value| {
                             for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter())
                             {
                                 if is_matches {
-                                    if self.comparator.matches(
+                                    if self.comparator.matches_value(
                                         value,
-                                        pattern_expr.to_string().as_ref(),
+                                        pattern,
+                                        pattern_expr,
                                         *capture_positions,
                                         &mut captured_positions,
                                     ) {
                                         return true;
                                     }
                                 } else if self.comparator.regex(
+                                    &ctx.runtime.regex_cache,
                                     pattern,
                                     pattern_expr,
                                     value,
@@ -130,6 +137,7 @@ impl TestAddress {
                 ctx.find_headers(
                     &header_list,
                     self.index,
+                    self.mime,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_addresses(header, &self.address_part, |value| {
@@ -156,6 +164,7 @@ impl TestAddress {
                 ctx.find_headers(
                     &header_list,
                     self.index,
+                    self.mime,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_addresses(header, &self.address_part, |value| {
@@ -187,79 +196,217 @@ impl TestAddress {
 }
 
 impl<'x, C> Context<'x, C> {
-    #[allow(unused_assignments)]
     pub(crate) fn find_addresses(
         &self,
         header: &Header,
         part: &AddressPart,
         mut visitor_fnc: impl FnMut(&str) -> bool,
     ) -> bool {
-        match &header.value {
-            HeaderValue::Address(Address::List(addr_list)) => {
-                for addr in addr_list {
-                    if let Some(addr) = part.eval(addr) {
-                        if visitor_fnc(addr) {
-                            return true;
-                        }
+        let options = &self.runtime.address_options;
+        let raw_header = if header.offset_end > 0 {
+            Cow::Borrowed(
+                self.message
+                    .raw_message
+                    .get(header.offset_start..header.offset_end)
+                    .unwrap_or(b""),
+            )
+        } else if let HeaderValue::Text(text) = &header.value {
+            // Inserted header
+            Cow::Owned(format!("{text}\n").into_bytes())
+        } else {
+            Cow::Borrowed(&b""[..])
+        };
+        let strip_comments = !options.comments_as_name && raw_header.contains(&b'(');
+
+        let stripped_header;
+        let parsed_header;
+        let address = match &header.value {
+            HeaderValue::Address(address) if !strip_comments => address,
+            _ => {
+                let bytes = if strip_comments {
+                    stripped_header = remove_comments(&raw_header);
+                    stripped_header.as_slice()
+                } else {
+                    raw_header.as_ref()
+                };
+                parsed_header = MessageStream::new(bytes).parse_address();
+                match &parsed_header {
+                    HeaderValue::Address(address) => address,
+                    _ => {
+                        return options.invalid_addresses == AddressFallback::MatchRaw
+                            && visitor_fnc("")
                     }
                 }
-                false
             }
-            HeaderValue::Address(Address::Group(group_list)) => {
+        };
+
+        match address {
+            Address::List(addr_list) => addr_list
+                .iter()
+                .any(|addr| self.visit_address(addr, part, &mut visitor_fnc)),
+            Address::Group(group_list) => {
                 for group in group_list {
-                    for addr in &group.addresses {
-                        if let Some(addr) = part.eval(addr) {
-                            if visitor_fnc(addr) {
-                                return true;
-                            }
+                    if group.addresses.is_empty() {
+                        if options.empty_groups == AddressFallback::MatchRaw
+                            && visitor_fnc(group.name.as_deref().unwrap_or(""))
+                        {
+                            return true;
                         }
+                    } else if group.name.is_some() && !options.group_members {
+                        continue;
+                    } else if group
+                        .addresses
+                        .iter()
+                        .any(|addr| self.visit_address(addr, part, &mut visitor_fnc))
+                    {
+                        return true;
                     }
                 }
                 false
             }
-            _ => {
-                let mut raw_header = None;
-                let bytes = if header.offset_end > 0 {
-                    self.message
-                        .raw_message
-                        .get(header.offset_start..header.offset_end)
-                        .unwrap_or(b"")
-                } else if let HeaderValue::Text(text) = &header.value {
-                    // Inserted header
-                    raw_header = format!("{text}\n").into_bytes().into();
-                    raw_header.as_deref().unwrap()
-                } else {
-                    b""
-                };
+        }
+    }
 
-                match MessageStream::new(bytes).parse_address() {
-                    HeaderValue::Address(Address::List(addr_list)) => {
-                        for addr in &addr_list {
-                            if let Some(addr) = part.eval(addr) {
-                                if visitor_fnc(addr) {
-                                    return true;
-                                }
-                            }
-                        }
-                        false
-                    }
-                    HeaderValue::Address(Address::Group(group_list)) => {
-                        for group in group_list {
-                            for addr in &group.addresses {
-                                if let Some(addr) = part.eval(addr) {
-                                    if visitor_fnc(addr) {
-                                        return true;
-                                    }
-                                }
-                            }
-                        }
-                        false
-                    }
-                    _ => visitor_fnc(""),
-                }
-            }
+    fn visit_address(
+        &self,
+        addr: &Addr,
+        part: &AddressPart,
+        visitor_fnc: &mut impl FnMut(&str) -> bool,
+    ) -> bool {
+        if self.runtime.address_options.invalid_addresses == AddressFallback::Skip
+            && !addr.address.as_deref().map_or(false, |a| a.contains('@'))
+        {
+            false
+        } else if let Some(value) = addr
+            .address
+            .as_deref()
+            .and_then(|email| self.eval_subaddress(part, email))
+        {
+            value.map_or(false, visitor_fnc)
+        } else if let Some(addr) = part.eval(addr) {
+            visitor_fnc(addr)
+        } else {
+            false
+        }
+    }
+
+    // Splits :user and :detail using the separators configured for the domain,
+    // returns None when the domain uses the default separator
+    pub(crate) fn eval_subaddress<'y>(
+        &self,
+        part: &AddressPart,
+        email: &'y str,
+    ) -> Option<Option<&'y str>> {
+        if self.subaddress.is_empty() || !matches!(part, AddressPart::User | AddressPart::Detail) {
+            return None;
+        }
+        let (local_part, domain) = email.rsplit_once('@')?;
+        let split = match self.subaddress.get(&domain.to_lowercase())? {
+            Subaddress::Separators(separators) => local_part.split_once(separators.as_slice()),
+            Subaddress::None => None,
+        };
+
+        Some(match (part, split) {
+            (AddressPart::User, Some((user, _))) => Some(user),
+            (AddressPart::User, None) => Some(local_part),
+            (_, Some((_, detail))) => Some(detail),
+            (_, None) => None,
+        })
+    }
+}
+
+impl AddressOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tests the members of named groups. When disabled, only the addresses
+    /// outside of a group are tested.
+    pub fn set_group_members(&mut self, value: bool) {
+        self.group_members = value;
+    }
+
+    pub fn with_group_members(mut self, value: bool) -> Self {
+        self.set_group_members(value);
+        self
+    }
+
+    /// Decides whether a group without members, such as
+    /// `undisclosed-recipients:;`, matches against its display name.
+    pub fn set_empty_groups(&mut self, fallback: AddressFallback) {
+        self.empty_groups = fallback;
+    }
+
+    pub fn with_empty_groups(mut self, fallback: AddressFallback) -> Self {
+        self.set_empty_groups(fallback);
+        self
+    }
+
+    /// Uses a comment as the display name of an address that has none, as
+    /// in `jdoe@example.org (John Doe)`. When disabled, comments are removed
+    /// before the header is parsed.
+    pub fn set_comments_as_name(&mut self, value: bool) {
+        self.comments_as_name = value;
+    }
+
+    pub fn with_comments_as_name(mut self, value: bool) -> Self {
+        self.set_comments_as_name(value);
+        self
+    }
+
+    /// Decides whether addresses without a valid addr-spec, and headers that
+    /// cannot be parsed as addresses, match against their raw text.
+    pub fn set_invalid_addresses(&mut self, fallback: AddressFallback) {
+        self.invalid_addresses = fallback;
+    }
+
+    pub fn with_invalid_addresses(mut self, fallback: AddressFallback) -> Self {
+        self.set_invalid_addresses(fallback);
+        self
+    }
+}
+
+impl Default for AddressOptions {
+    fn default() -> Self {
+        Self {
+            group_members: true,
+            empty_groups: AddressFallback::Skip,
+            comments_as_name: true,
+            invalid_addresses: AddressFallback::MatchRaw,
+        }
+    }
+}
+
+// Removes RFC 5322 comments, keeping quoted strings and escaped characters intact
+fn remove_comments(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut depth = 0;
+    let mut in_quote = false;
+    let mut is_escaped = false;
+
+    for &ch in bytes {
+        if is_escaped {
+            is_escaped = false;
+        } else if ch == b'\\' {
+            is_escaped = true;
+        } else if in_quote {
+            in_quote = ch != b'"';
+        } else if ch == b'(' {
+            depth += 1;
+            continue;
+        } else if ch == b')' && depth > 0 {
+            depth -= 1;
+            continue;
+        } else if ch == b'"' && depth == 0 {
+            in_quote = true;
+        }
+
+        if depth == 0 {
+            result.push(ch);
         }
     }
+
+    result
 }
 
 impl AddressPart {
@@ -311,3 +458,116 @@ impl AddressPart {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{AddressFallback, AddressOptions, Envelope, Runtime, Subaddress};
+
+    fn matches(options: AddressOptions, test: &str, message: &str) -> bool {
+        let runtime = Runtime::new().with_address_options(options);
+        !runtime
+            .filter(message.as_bytes())
+            .run_test_folders(
+                format!("require \"fileinto\";\nif {test} {{ fileinto \"match\"; }}").as_bytes(),
+            )
+            .is_empty()
+    }
+
+    #[test]
+    fn address_options() {
+        let group =
+            "To: undisclosed-recipients:;\r\nCc: Team: a@example.org;, b@example.org\r\n\r\nbody";
+        let comment = "From: jdoe@example.org (John Doe)\r\n\r\nbody";
+        let invalid = "From: John Doe\r\n\r\nbody";
+        let defaults = AddressOptions::new();
+
+        for (options, test, message, expected) in [
+            (defaults, r#"address :is "cc" "a@example.org""#, group, true),
+            (
+                defaults.with_group_members(false),
+                r#"address :is "cc" "a@example.org""#,
+                group,
+                false,
+            ),
+            (
+                defaults.with_group_members(false),
+                r#"address :is "cc" "b@example.org""#,
+                group,
+                true,
+            ),
+            (
+                defaults,
+                r#"address :is "to" "undisclosed-recipients""#,
+                group,
+                false,
+            ),
+            (
+                defaults.with_empty_groups(AddressFallback::MatchRaw),
+                r#"address :is "to" "undisclosed-recipients""#,
+                group,
+                true,
+            ),
+            (
+                defaults
+                    .with_group_members(false)
+                    .with_empty_groups(AddressFallback::MatchRaw),
+                r#"address :is "to" "undisclosed-recipients""#,
+                group,
+                true,
+            ),
+            (
+                defaults,
+                r#"address :name :is "from" "John Doe""#,
+                comment,
+                true,
+            ),
+            (
+                defaults.with_comments_as_name(false),
+                r#"address :name :is "from" "John Doe""#,
+                comment,
+                false,
+            ),
+            (
+                defaults.with_comments_as_name(false),
+                r#"address :is "from" "jdoe@example.org""#,
+                comment,
+                true,
+            ),
+            (defaults, r#"address :is "from" "John Doe""#, invalid, true),
+            (
+                defaults.with_invalid_addresses(AddressFallback::Skip),
+                r#"address :is "from" "John Doe""#,
+                invalid,
+                false,
+            ),
+        ] {
+            assert_eq!(
+                matches(options, test, message),
+                expected,
+                "{test} with {options:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn subaddress_per_domain() {
+        let script = br#"require ["envelope", "fileinto", "subaddress", "variables"];
+                if address :user :matches "to" "*" { set "to_user" "${1}"; }
+                if address :detail :matches "to" "*" { set "to_detail" "${1}"; }
+                if address :detail :matches "cc" "*" { set "cc_detail" "${1}"; }
+                if envelope :detail :matches "to" "*" { set "rcpt_detail" "${1}"; }
+                if address :detail :matches "from" "*" { set "from_detail" "${1}"; }
+                fileinto "${to_user}|${to_detail}|${cc_detail}|${rcpt_detail}|${from_detail}";
+                "#;
+        let runtime = Runtime::new();
+        let folders = runtime
+            .filter(
+                b"From: jane+news@example.com\r\nTo: john-lists@example.org\r\nCc: jane+work@example.net\r\n\r\nbody",
+            )
+            .with_envelope(Envelope::To, "john-alerts@example.org")
+            .with_subaddress("Example.org", Subaddress::Separators(vec!['-', '+']))
+            .with_subaddress("example.net", Subaddress::None)
+            .run_test_folders(script);
+        assert_eq!(folders, ["john|lists||alerts|news"]);
+    }
+}
@@ -21,7 +21,10 @@
  * for more details.
 */
 
-use mail_parser::{decoders::html::html_to_text, MimeHeaders, PartType};
+use mail_parser::{
+    decoders::{charsets::map::charset_decoder, html::html_to_text},
+    MimeHeaders, PartType,
+};
 
 use crate::{
     compiler::{
@@ -50,22 +53,22 @@ impl TestBody {
             for (key, pattern) in key_list.iter().zip(self.key_list.iter()) {
                 let result = match &self.match_type {
                     MatchType::Is => self.comparator.is(&subject, key),
-                    MatchType::Contains => {
-                        self.comparator.contains(subject, key.to_string().as_ref())
-                    }
+                    MatchType::Contains => self.comparator.contains_value(subject, pattern, key),
                     MatchType::Value(rel_match) => {
                         self.comparator.relational(rel_match, &subject, key)
                     }
-                    MatchType::Matches(_) => self.comparator.matches(
+                    MatchType::Matches(_) => {
+                        self.comparator
+                            .matches_value(subject, pattern, key, 0, &mut Vec::new())
+                    }
+                    MatchType::Regex(_) => self.comparator.regex(
+                        &ctx.runtime.regex_cache,
+                        pattern,
+                        key,
                         subject,
-                        key.to_string().as_ref(),
                         0,
                         &mut Vec::new(),
                     ),
-                    MatchType::Regex(_) => {
-                        self.comparator
-                            .regex(pattern, key, subject, 0, &mut Vec::new())
-                    }
                     _ => break,
                 };
 
@@ -186,7 +189,14 @@ impl TestBody {
                     (
                         BodyTransform::Content(_),
                         PartType::Binary(bytes) | PartType::InlineBinary(bytes),
-                    ) => String::from_utf8_lossy(bytes.as_ref()),
+                    ) => match part
+                        .content_type()
+                        .and_then(|ct| ct.attribute("charset"))
+                        .and_then(|charset| charset_decoder(charset.as_bytes()))
+                    {
+                        Some(decoder) => decoder(bytes.as_ref()).into(),
+                        None => String::from_utf8_lossy(bytes.as_ref()),
+                    },
                     _ => {
                         return false;
                     }
@@ -196,22 +206,27 @@ impl TestBody {
                 for (key, pattern) in key_list.iter().zip(self.key_list.iter()) {
                     result = match &self.match_type {
                         MatchType::Is => self.comparator.is(&text.as_ref(), key),
-                        MatchType::Contains => self
-                            .comparator
-                            .contains(text.as_ref(), key.to_string().as_ref()),
+                        MatchType::Contains => {
+                            self.comparator.contains_value(text.as_ref(), pattern, key)
+                        }
                         MatchType::Value(rel_match) => {
                             self.comparator.relational(rel_match, &text.as_ref(), key)
                         }
-                        MatchType::Matches(_) => self.comparator.matches(
+                        MatchType::Matches(_) => self.comparator.matches_value(
+                            text.as_ref(),
+                            pattern,
+                            key,
+                            0,
+                            &mut Vec::new(),
+                        ),
+                        MatchType::Regex(_) => self.comparator.regex(
+                            &ctx.runtime.regex_cache,
+                            pattern,
+                            key,
                             text.as_ref(),
-                            key.to_string().as_ref(),
                             0,
                             &mut Vec::new(),
                         ),
-                        MatchType::Regex(_) => {
-                            self.comparator
-                                .regex(pattern, key, text.as_ref(), 0, &mut Vec::new())
-                        }
                         _ => false,
                     };
 
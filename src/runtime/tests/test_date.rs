@@ -23,7 +23,7 @@
 
 use std::borrow::Cow;
 
-use mail_parser::{parsers::MessageStream, DateTime, Header, HeaderValue};
+use mail_parser::{parsers::MessageStream, DateTime, Header, HeaderName, HeaderValue};
 
 use crate::{
     compiler::{
@@ -52,6 +52,7 @@ impl TestDate {
                 ctx.find_headers(
                     &[header_name],
                     self.index,
+                    true,
                     self.mime_anychild,
                     |header, _, _| {
                         if ctx.find_dates(header).is_some() {
@@ -75,6 +76,7 @@ impl TestDate {
                 ctx.find_headers(
                     &[header_name],
                     self.index,
+                    true,
                     self.mime_anychild,
                     |header, _, _| {
                         if let Some(dt) = ctx.find_dates(header) {
@@ -106,6 +108,7 @@ impl TestDate {
                 let result = ctx.find_headers(
                     &[header_name],
                     self.index,
+                    true,
                     self.mime_anychild,
                     |header, _, _| {
                         if let Some(dt) = ctx.find_dates(header) {
@@ -171,13 +174,7 @@ impl TestCurrentDate {
                 }
             }
             MatchType::List => {
-                let value = self.date_part.eval(
-                    &(if let Some(zone) = self.zone {
-                        DateTime::from_timestamp(ctx.current_time).to_timezone(zone)
-                    } else {
-                        DateTime::from_timestamp(ctx.current_time)
-                    }),
-                );
+                let value = self.eval_date_part(ctx);
                 if !value.is_empty() {
                     return TestResult::Event {
                         event: Event::ListContains {
@@ -191,13 +188,7 @@ impl TestCurrentDate {
             }
             _ => {
                 let mut captured_values = Vec::new();
-                let date_part = self.date_part.eval(
-                    &(if let Some(zone) = self.zone {
-                        DateTime::from_timestamp(ctx.current_time).to_timezone(zone)
-                    } else {
-                        DateTime::from_timestamp(ctx.current_time)
-                    }),
-                );
+                let date_part = self.eval_date_part(ctx);
 
                 for key in &self.key_list {
                     let key = ctx.eval_value(key);
@@ -238,11 +229,29 @@ impl TestCurrentDate {
 
         TestResult::Bool(result ^ self.is_not)
     }
+
+    fn eval_date_part<C>(&self, ctx: &Context<C>) -> String {
+        if let DatePart::Millisecond = self.date_part {
+            format!("{:03}", ctx.current_time_millis)
+        } else {
+            self.date_part.eval(
+                &(if let Some(zone) = self.zone {
+                    DateTime::from_timestamp(ctx.current_time).to_timezone(zone)
+                } else {
+                    DateTime::from_timestamp(ctx.current_time)
+                }),
+            )
+        }
+    }
 }
 
 impl<'x, C> Context<'x, C> {
     #[allow(unused_assignments)]
     pub(crate) fn find_dates(&self, header: &'x Header) -> Option<Cow<'x, DateTime>> {
+        if header.name == HeaderName::Received {
+            return self.received_date(header).map(Cow::Owned);
+        }
+
         if let HeaderValue::DateTime(dt) = &header.value {
             if dt.is_valid() {
                 return Some(Cow::Borrowed(dt));
@@ -271,7 +280,7 @@ impl<'x, C> Context<'x, C> {
 }
 
 impl DatePart {
-    fn eval(&self, dt: &DateTime) -> String {
+    pub(crate) fn eval(&self, dt: &DateTime) -> String {
         match self {
             DatePart::Year => format!("{:04}", dt.year),
             DatePart::Month => format!("{:02}", dt.month),
@@ -295,10 +304,48 @@ impl DatePart {
                 dt.tz_minute
             ),
             DatePart::Weekday => dt.day_of_week().to_string(),
+            DatePart::Epoch => dt.to_timestamp().to_string(),
+            DatePart::IsoWeek => {
+                let (year, week) = iso_week(dt);
+                format!("{year:04}-W{week:02}")
+            }
+            DatePart::Millisecond => "000".to_string(),
         }
     }
 }
 
+// Returns the ISO 8601 week-numbering year and week of a date
+fn iso_week(dt: &DateTime) -> (i32, u32) {
+    fn is_leap(year: i32) -> bool {
+        (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+    }
+    fn weeks_in_year(year: i32) -> u32 {
+        let p =
+            |y: i32| (y + y.div_euclid(4) - y.div_euclid(100) + y.div_euclid(400)).rem_euclid(7);
+        if p(year) == 4 || p(year - 1) == 3 {
+            53
+        } else {
+            52
+        }
+    }
+
+    const DAYS_BEFORE_MONTH: [u32; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let year = dt.year as i32;
+    let month = (dt.month as usize).clamp(1, 12);
+    let ordinal =
+        DAYS_BEFORE_MONTH[month - 1] + dt.day as u32 + u32::from(month > 2 && is_leap(year));
+    let weekday = (dt.day_of_week() as u32 + 6) % 7 + 1;
+    let week = (ordinal + 10 - weekday) / 7;
+
+    if week < 1 {
+        (year - 1, weeks_in_year(year - 1))
+    } else if week > weeks_in_year(year) {
+        (year + 1, 1)
+    } else {
+        (year, week)
+    }
+}
+
 impl Zone {
     pub(crate) fn eval<'x>(&self, dt: &'x DateTime) -> Cow<'x, DateTime> {
         match self {
@@ -38,7 +38,7 @@ impl TestDuplicate {
             DupMatch::Header(header_name) => {
                 let mut value = String::new();
                 if let Some(header_name) = ctx.parse_header_name(header_name) {
-                    ctx.find_headers(&[header_name], None, true, |header, _, _| {
+                    ctx.find_headers(&[header_name], None, true, true, |header, _, _| {
                         if header.offset_end > 0 {
                             if let Some(bytes) = ctx
                                 .message
@@ -72,15 +72,25 @@ impl TestDuplicate {
             DupMatch::Default => ctx.message.message_id().unwrap_or("").into(),
         };
 
+        if id.is_empty() {
+            ctx.duplicate_id = None;
+            return TestResult::Bool(false ^ self.is_not);
+        }
+        let handle = self
+            .handle
+            .as_ref()
+            .map(|handle| ctx.eval_value(handle).to_string().into_owned())
+            .unwrap_or_default();
+        let id = if let Some(hasher) = ctx.runtime.duplicate_id_hasher {
+            hasher(&handle, &id)
+        } else {
+            format!("{handle}{id}")
+        };
+        ctx.duplicate_id = Some(id.clone());
+
         TestResult::Event {
             event: Event::DuplicateId {
-                id: if id.is_empty() {
-                    return TestResult::Bool(false ^ self.is_not);
-                } else if let Some(handle) = &self.handle {
-                    format!("{}{}", ctx.eval_value(handle).to_string(), id)
-                } else {
-                    id.into_owned()
-                },
+                id,
                 expiry: self.seconds.unwrap_or(ctx.runtime.default_duplicate_expiry),
                 last: self.last,
             },
@@ -88,3 +98,56 @@ impl TestDuplicate {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Compiler, Event, Input, Runtime};
+
+    fn duplicate_ids(runtime: &Runtime<()>, script: &[u8]) -> Vec<(String, Option<String>)> {
+        let script = Compiler::new().compile(script).unwrap();
+        let mut instance =
+            runtime.filter(b"Message-ID: <abc@example.org>\r\nSubject: test\r\n\r\nbody");
+        let mut input = Input::script("", script);
+        let mut ids = Vec::new();
+        while let Some(event) = instance.run(input) {
+            if let Event::DuplicateId { id, .. } = event.unwrap() {
+                ids.push((id, instance.duplicate_id().map(|id| id.to_string())));
+                input = false.into();
+            } else {
+                input = true.into();
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn duplicate_id_hasher() {
+        let script = br#"require "duplicate";
+            if duplicate { keep; }
+            if duplicate :handle "list" :uniqueid "1234" { keep; }"#;
+
+        assert_eq!(
+            duplicate_ids(&Runtime::new(), script),
+            [
+                (
+                    "abc@example.org".to_string(),
+                    Some("abc@example.org".to_string())
+                ),
+                ("list1234".to_string(), Some("list1234".to_string())),
+            ]
+        );
+        assert_eq!(
+            duplicate_ids(
+                &Runtime::new().with_duplicate_id_hasher(|handle, id| format!("{handle}:{id}")),
+                script
+            ),
+            [
+                (
+                    ":abc@example.org".to_string(),
+                    Some(":abc@example.org".to_string())
+                ),
+                ("list:1234".to_string(), Some("list:1234".to_string())),
+            ]
+        );
+    }
+}
@@ -25,7 +25,7 @@ use mail_parser::DateTime;
 
 use crate::{
     compiler::{
-        grammar::{tests::test_envelope::TestEnvelope, MatchType},
+        grammar::{tests::test_envelope::TestEnvelope, AddressPart, MatchType},
         Number,
     },
     Context, Envelope, Event,
@@ -71,15 +71,17 @@ impl TestEnvelope {
                 let result = ctx.find_envelopes(self, |value| {
                     for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter()) {
                         if is_matches {
-                            if self.comparator.matches(
+                            if self.comparator.matches_value(
                                 value,
-                                pattern_expr.to_string().as_ref(),
+                                pattern,
+                                pattern_expr,
                                 *capture_positions,
                                 &mut captured_positions,
                             ) {
                                 return true;
                             }
                         } else if self.comparator.regex(
+                            &ctx.runtime.regex_cache,
                             pattern,
                             pattern_expr,
                             value,
@@ -159,14 +161,19 @@ impl<'x, C> Context<'x, C> {
             if test_envelope.envelope_list.contains(name)
                 && match name {
                     Envelope::From | Envelope::To | Envelope::Orcpt => {
-                        if let Some(value) = test_envelope
-                            .address_part
-                            .eval_string(value.to_string().as_ref())
-                        {
-                            cb(value)
+                        let value = value.to_string();
+                        let part = &test_envelope.address_part;
+                        if *name == Envelope::Orcpt && part != &AddressPart::All {
+                            orcpt_address(value.as_ref())
                         } else {
-                            false
+                            Some(value.as_ref())
                         }
+                        .map_or(false, |value| {
+                            match self.eval_subaddress(part, value) {
+                                Some(value) => value.map_or(false, &mut cb),
+                                None => part.eval_string(value).map_or(false, &mut cb),
+                            }
+                        })
                     }
                     Envelope::ByTimeAbsolute if test_envelope.zone.is_some() => {
                         if let Some(dt) = DateTime::parse_rfc3339(value.to_string().as_ref()) {
@@ -185,6 +192,40 @@ impl<'x, C> Context<'x, C> {
     }
 }
 
+// Returns the address of an ORCPT value of type rfc822
+pub(crate) fn orcpt_address(value: &str) -> Option<&str> {
+    match value.split_once(';') {
+        Some((addr_type, addr)) if addr_type.eq_ignore_ascii_case("rfc822") => Some(addr),
+        Some(_) => None,
+        None => Some(value),
+    }
+}
+
+// Decodes the "+XX" hexadecimal escapes of RFC 3461 xtext
+pub(crate) fn decode_xtext(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        match bytes.get(pos..pos + 3) {
+            Some([b'+', hi, lo]) if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                result.push(
+                    u8::from_str_radix(std::str::from_utf8(&[*hi, *lo]).unwrap(), 16).unwrap(),
+                );
+                pos += 3;
+            }
+            _ => {
+                result.push(bytes[pos]);
+                pos += 1;
+            }
+        }
+    }
+
+    String::from_utf8(result)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
 pub fn parse_envelope_address(addr: &str) -> Option<&str> {
     let addr = addr.as_bytes();
     let mut addr_start_pos = 0;
@@ -273,3 +314,42 @@ pub fn parse_envelope_address(addr: &str) -> Option<&str> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::Runtime;
+
+    #[test]
+    fn envelope_dsn() {
+        let script = br#"require ["envelope", "envelope-dsn", "fileinto", "relational",
+                             "comparator-i;ascii-numeric"];
+                if allof(envelope "notify" "failure",
+                         envelope :count "eq" :comparator "i;ascii-numeric" "notify" "2") {
+                    fileinto "notify";
+                }
+                if envelope :matches "orcpt" "rfc822;*@example.com" {
+                    fileinto "orcpt";
+                }
+                if envelope :localpart "orcpt" "john+sieve" {
+                    fileinto "orcpt-localpart";
+                }
+                if envelope "ret" "hdrs" {
+                    fileinto "ret";
+                }
+                if envelope "envid" "QQ314159=" {
+                    fileinto "envid";
+                }"#;
+        let runtime = Runtime::new();
+        let folders = runtime
+            .filter(b"Subject: test\r\n\r\nbody")
+            .with_envelope_notify("SUCCESS, FAILURE")
+            .with_envelope_orcpt("rfc822;john+2Bsieve@example.com")
+            .with_envelope_ret("HDRS")
+            .with_envelope_envid("QQ314159+3D")
+            .run_test_folders(script);
+        assert_eq!(
+            folders,
+            ["notify", "orcpt", "orcpt-localpart", "ret", "envid"]
+        );
+    }
+}
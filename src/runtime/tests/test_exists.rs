@@ -29,14 +29,20 @@ impl TestExists {
     pub(crate) fn exec<C>(&self, ctx: &mut Context<C>) -> TestResult {
         let header_names = ctx.parse_header_names(&self.header_names);
         let mut header_exists = vec![false; header_names.len()];
-        let parts = [ctx.part];
+        let parts = [if self.mime { ctx.part } else { 0 }];
         let mut part_iter = SubpartIterator::new(ctx, &parts, self.mime_anychild);
         let mut result = false;
 
-        while let Some((_, message_part)) = part_iter.next() {
+        while let Some((part_id, message_part)) = part_iter.next() {
+            let positions = ctx.header_positions(part_id, message_part, &header_names);
             for (pos, header_name) in header_names.iter().enumerate() {
                 if !header_exists[pos]
-                    && message_part.headers.iter().any(|h| &h.name == header_name)
+                    && positions.get(pos).iter().any(|&header_pos| {
+                        message_part
+                            .headers
+                            .get(header_pos)
+                            .map_or(false, |h| h.name == header_name.name)
+                    })
                 {
                     header_exists[pos] = true;
                 }
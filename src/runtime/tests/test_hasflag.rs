@@ -64,7 +64,7 @@ impl TestHasFlag {
             result
         } else {
             let mut captured_values = Vec::new();
-            let result = ctx.tokenize_flags(&self.flags, |check_flag| {
+            let result = ctx.tokenize_flags(&self.flags, false, |check_flag| {
                 for variable in variable_list {
                     match ctx.get_variable(variable) {
                         Some(flags) if !flags.is_empty() => {
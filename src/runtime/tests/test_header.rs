@@ -21,11 +21,15 @@
  * for more details.
 */
 
-use mail_parser::{parsers::MessageStream, Header, HeaderName, HeaderValue};
+use std::{borrow::Cow, sync::Arc};
+
+use ahash::AHashMap;
+use mail_parser::{parsers::MessageStream, Header, HeaderName, HeaderValue, MessagePart};
 
 use crate::{
     compiler::{
         grammar::{actions::action_mime::MimeOpts, tests::test_header::TestHeader, MatchType},
+        header_key::{HeaderKey, LowercaseName},
         Number, Value,
     },
     runtime::Variable,
@@ -52,16 +56,16 @@ impl TestHeader {
                 ctx.find_headers(
                     &header_list,
                     self.index,
+                    self.mime,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_header_values(header, &mime_opts, |value| {
-                            for key in &key_list {
+                            for (key, pattern) in key_list.iter().zip(self.key_list.iter()) {
                                 if is_is {
                                     if self.comparator.is(&value, key) {
                                         return true;
                                     }
-                                } else if self.comparator.contains(value, key.to_string().as_ref())
-                                {
+                                } else if self.comparator.contains_value(value, pattern, key) {
                                     return true;
                                 }
                             }
@@ -73,6 +77,7 @@ impl TestHeader {
             MatchType::Value(rel_match) => ctx.find_headers(
                 &header_list,
                 self.index,
+                self.mime,
                 self.mime_anychild,
                 |header, _, _| {
                     ctx.find_header_values(header, &mime_opts, |value| {
@@ -91,21 +96,24 @@ impl TestHeader {
                 let result = ctx.find_headers(
                     &header_list,
                     self.index,
+                    self.mime,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_header_values(header, &mime_opts, |value| {
                             for (pattern_expr, pattern) in key_list.iter().zip(self.key_list.iter())
                             {
                                 if is_matches {
-                                    if self.comparator.matches(
+                                    if self.comparator.matches_value(
                                         value,
-                                        pattern_expr.to_string().as_ref(),
+                                        pattern,
+                                        pattern_expr,
                                         *capture_positions,
                                         &mut captured_values,
                                     ) {
                                         return true;
                                     }
                                 } else if self.comparator.regex(
+                                    &ctx.runtime.regex_cache,
                                     pattern,
                                     pattern_expr,
                                     value,
@@ -129,30 +137,16 @@ impl TestHeader {
                 ctx.find_headers(
                     &header_list,
                     self.index,
+                    self.mime,
                     self.mime_anychild,
                     |header, _, _| {
-                        match &mime_opts {
-                            MimeOpts::None => {
+                        if let MimeOpts::None = &mime_opts {
+                            count += 1;
+                        } else {
+                            ctx.find_header_values(header, &mime_opts, |_| {
                                 count += 1;
-                            }
-                            MimeOpts::Type | MimeOpts::Subtype | MimeOpts::ContentType => {
-                                if let HeaderValue::ContentType(_) = &header.value {
-                                    count += 1;
-                                }
-                            }
-                            MimeOpts::Param(params) => {
-                                if let HeaderValue::ContentType(ct) = &header.value {
-                                    if let Some(attributes) = &ct.attributes {
-                                        for (attr_name, _) in attributes {
-                                            if params.iter().any(|p| {
-                                                p.to_string().eq_ignore_ascii_case(attr_name)
-                                            }) {
-                                                count += 1;
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+                                false
+                            });
                         }
 
                         false
@@ -173,6 +167,7 @@ impl TestHeader {
                 ctx.find_headers(
                     &header_list,
                     self.index,
+                    self.mime,
                     self.mime_anychild,
                     |header, _, _| {
                         ctx.find_header_values(header, &mime_opts, |value| {
@@ -203,11 +198,30 @@ impl TestHeader {
     }
 }
 
+// Header positions for a list of header names, stored back to back
+pub(crate) struct HeaderPositions {
+    positions: Vec<usize>,
+    ends: Vec<usize>,
+}
+
+impl HeaderPositions {
+    // Positions of the header name at `name_pos` in the requested list
+    pub(crate) fn get(&self, name_pos: usize) -> &[usize] {
+        let start = name_pos
+            .checked_sub(1)
+            .and_then(|pos| self.ends.get(pos))
+            .copied()
+            .unwrap_or(0);
+        let end = self.ends.get(name_pos).copied().unwrap_or(start);
+        &self.positions[start..end]
+    }
+}
+
 impl<'x, C> Context<'x, C> {
-    pub(crate) fn parse_header_names<'z: 'y, 'y>(
-        &'z self,
+    pub(crate) fn parse_header_names<'y>(
+        &self,
         header_names: &'y [Value],
-    ) -> Vec<HeaderName<'y>> {
+    ) -> Vec<Cow<'y, HeaderKey>> {
         let mut result = Vec::with_capacity(header_names.len());
         for header_name in header_names {
             if let Some(header_name) = self.parse_header_name(header_name) {
@@ -218,70 +232,60 @@ impl<'x, C> Context<'x, C> {
     }
 
     #[inline(always)]
-    pub(crate) fn parse_header_name(&self, header_name: &Value) -> Option<HeaderName<'static>> {
-        let h_ = self.eval_value(header_name);
-        let h = h_.to_string();
-
-        match HeaderName::parse(h.as_ref())? {
-            HeaderName::Other(_) => HeaderName::Other(h.into_owned().into()),
-            hn => hn.into_owned(),
+    pub(crate) fn parse_header_name<'y>(
+        &self,
+        header_name: &'y Value,
+    ) -> Option<Cow<'y, HeaderKey>> {
+        if let Value::Header(key) = header_name {
+            Some(Cow::Borrowed(key))
+        } else {
+            HeaderKey::new(self.eval_string(header_name).into_owned()).map(Cow::Owned)
         }
-        .into()
     }
 
     pub(crate) fn find_headers(
         &self,
-        header_names: &[HeaderName],
+        header_names: &[Cow<HeaderKey>],
         index: Option<i32>,
+        mime: bool,
         any_child: bool,
         mut visitor_fnc: impl FnMut(&Header, usize, usize) -> bool,
     ) -> bool {
-        let parts = [self.part];
+        // Without :mime, tests apply to the message headers even inside foreverypart
+        let parts = [if mime { self.part } else { 0 }];
         let mut part_iter = SubpartIterator::new(self, &parts, any_child);
 
         while let Some((part_id, message_part)) = part_iter.next() {
-            'outer: for header_name in header_names {
+            let positions = self.header_positions(part_id, message_part, header_names);
+            'outer: for (name_pos, header_name) in header_names.iter().enumerate() {
+                let mut headers = positions.get(name_pos).iter().filter_map(|&pos| {
+                    message_part
+                        .headers
+                        .get(pos)
+                        .filter(|h| h.name == header_name.name)
+                        .map(|h| (pos, h))
+                });
+
                 match index {
                     None => {
-                        for (pos, header) in message_part
-                            .headers
-                            .iter()
-                            .enumerate()
-                            .filter(|(_, h)| &h.name == header_name)
-                        {
+                        for (pos, header) in headers {
                             if visitor_fnc(header, part_id, pos) {
                                 return true;
                             }
                         }
                     }
                     Some(index) if index >= 0 => {
-                        let mut header_count = 0;
-
-                        for (pos, header) in message_part.headers.iter().enumerate() {
-                            if &header.name == header_name {
-                                header_count += 1;
-                                if header_count == index {
-                                    if visitor_fnc(header, part_id, pos) {
-                                        return true;
-                                    }
-                                    continue 'outer;
-                                }
+                        if let Some((pos, header)) = headers.nth((index as usize).wrapping_sub(1)) {
+                            if visitor_fnc(header, part_id, pos) {
+                                return true;
                             }
+                            continue 'outer;
                         }
                     }
                     Some(index) => {
-                        let index = -index;
-                        let mut header_count = 0;
-
-                        for (pos, header) in message_part.headers.iter().enumerate().rev() {
-                            if &header.name == header_name {
-                                header_count += 1;
-                                if header_count == index {
-                                    if visitor_fnc(header, part_id, pos) {
-                                        return true;
-                                    }
-                                    break;
-                                }
+                        if let Some((pos, header)) = headers.rev().nth((-index) as usize - 1) {
+                            if visitor_fnc(header, part_id, pos) {
+                                return true;
                             }
                         }
                     }
@@ -291,6 +295,43 @@ impl<'x, C> Context<'x, C> {
         false
     }
 
+    // Positions of all the requested headers of a part, retrieved with a single
+    // borrow of the index. The index itself is built on first access by walking
+    // the part's headers once.
+    pub(crate) fn header_positions(
+        &self,
+        part_id: usize,
+        part: &MessagePart,
+        header_names: &[Cow<HeaderKey>],
+    ) -> HeaderPositions {
+        let mut header_index = self.header_index.borrow_mut();
+        let index = Arc::make_mut(&mut header_index)
+            .entry(part_id)
+            .or_insert_with(|| {
+                let mut index: AHashMap<LowercaseName, Vec<usize>> =
+                    AHashMap::with_capacity(part.headers.len());
+                for (pos, header) in part.headers.iter().enumerate() {
+                    index
+                        .entry(LowercaseName::new(header.name.as_str()))
+                        .or_default()
+                        .push(pos);
+                }
+                index
+            });
+
+        let mut positions = HeaderPositions {
+            positions: Vec::new(),
+            ends: Vec::with_capacity(header_names.len()),
+        };
+        for header_name in header_names {
+            if let Some(found) = index.get(header_name.key.as_str()) {
+                positions.positions.extend_from_slice(found);
+            }
+            positions.ends.push(positions.positions.len());
+        }
+        positions
+    }
+
     #[allow(unused_assignments)]
     pub(crate) fn find_header_values(
         &self,
@@ -335,6 +376,14 @@ impl<'x, C> Context<'x, C> {
             {
                 visitor_fnc(text.as_ref())
             }
+            (MimeOpts::None, HeaderValue::Text(text))
+                if self
+                    .runtime
+                    .mime_leniency
+                    .is_normalized(header, &self.message.raw_message) =>
+            {
+                visitor_fnc(text.as_ref())
+            }
             (MimeOpts::None, _) => {
                 if let HeaderValue::Text(text) = MessageStream::new(
                     self.message
@@ -362,19 +411,44 @@ impl<'x, C> Context<'x, C> {
             }
             (MimeOpts::Param(params), HeaderValue::ContentType(ct)) => {
                 if let Some(attributes) = &ct.attributes {
-                    for param in params {
-                        for (attr_name, attr_value) in attributes {
-                            if param.to_string().eq_ignore_ascii_case(attr_name)
-                                && visitor_fnc(attr_value.as_ref())
-                            {
-                                return true;
-                            }
+                    for (attr_name, attr_value) in attributes {
+                        if params
+                            .iter()
+                            .any(|p| p.to_string().eq_ignore_ascii_case(attr_name))
+                            && visitor_fnc(attr_value.as_ref())
+                        {
+                            return true;
                         }
                     }
                 }
-                visitor_fnc("")
+                false
             }
-            _ => visitor_fnc(""),
+            // Headers without a content type or missing parameters have no value to test
+            _ => false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::{compiler::header_key::HeaderKey, Runtime};
+
+    #[test]
+    fn header_positions() {
+        let runtime = Runtime::new();
+        let ctx = runtime.filter(b"X-A: 1\r\nx-b: 2\r\nX-A: 3\r\nSubject: test\r\n\r\nbody");
+        let header_names = ["x-a", "X-C", "subject", "X-B"]
+            .into_iter()
+            .map(|name| Cow::Owned(HeaderKey::new(name).unwrap()))
+            .collect::<Vec<_>>();
+
+        let positions = ctx.header_positions(0, &ctx.message.parts[0], &header_names);
+        assert_eq!(positions.get(0), &[0, 2]);
+        assert_eq!(positions.get(1), &[] as &[usize]);
+        assert_eq!(positions.get(2), &[3]);
+        assert_eq!(positions.get(3), &[1]);
+        assert_eq!(positions.get(4), &[] as &[usize]);
+    }
+}
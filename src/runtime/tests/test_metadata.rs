@@ -46,29 +46,12 @@ impl TestMetadata {
             },
         };
 
-        let value = if let Some((_, value)) = [&ctx.metadata, &ctx.runtime.metadata]
-            .into_iter()
-            .flatten()
-            .find(|(m, _)| match (m, &metadata) {
-                (Metadata::Server { annotation: a }, Metadata::Server { annotation: b }) => {
-                    a.eq_ignore_ascii_case(b)
-                }
-                (
-                    Metadata::Mailbox {
-                        name: a,
-                        annotation: c,
-                    },
-                    Metadata::Mailbox {
-                        name: b,
-                        annotation: d,
-                    },
-                ) => a.eq(b) && c.eq_ignore_ascii_case(d),
-                _ => false,
-            }) {
-            value.as_ref()
+        let value_ = if let Some(value) = ctx.lookup_metadata(&metadata) {
+            value.into_owned()
         } else {
             return TestResult::Bool(false ^ self.is_not);
         };
+        let value = value_.as_str();
 
         let mut result = false;
         if let MatchType::Count(match_type) = &self.match_type {
@@ -98,6 +81,7 @@ impl TestMetadata {
                         &mut captured_values,
                     ),
                     MatchType::Regex(capture_positions) => self.comparator.regex(
+                        &ctx.runtime.regex_cache,
                         pattern,
                         &key,
                         value,
@@ -127,23 +111,22 @@ impl TestMetadataExists {
             .mailbox
             .as_ref()
             .map(|s| ctx.eval_value(s).to_string().into_owned());
-        let mut annotations = ctx.eval_values(&self.annotation_names);
-
-        for (metadata, _) in [&ctx.metadata, &ctx.runtime.metadata].into_iter().flatten() {
-            match (metadata, mailbox.as_ref()) {
-                (Metadata::Server { annotation }, None) => {
-                    annotations.retain(|a| !a.to_string().eq_ignore_ascii_case(annotation))
-                }
-                (Metadata::Mailbox { name, annotation }, Some(mailbox)) if name.eq(mailbox) => {
-                    annotations.retain(|a| !a.to_string().eq_ignore_ascii_case(annotation));
-                }
-                _ => (),
-            }
-            if annotations.is_empty() {
-                return TestResult::Bool(true ^ self.is_not);
-            }
-        }
+        let annotations = ctx.eval_values(&self.annotation_names);
 
-        TestResult::Bool(false ^ self.is_not)
+        TestResult::Bool(
+            !annotations.is_empty()
+                && annotations.iter().all(|annotation| {
+                    let annotation = annotation.to_string().into_owned();
+                    ctx.lookup_metadata(&if let Some(name) = &mailbox {
+                        Metadata::Mailbox {
+                            name: name.clone(),
+                            annotation,
+                        }
+                    } else {
+                        Metadata::Server { annotation }
+                    })
+                    .is_some()
+                }) ^ self.is_not,
+        )
     }
 }
@@ -101,10 +101,14 @@ impl TestNotifyMethodCapability {
                         0,
                         &mut Vec::new(),
                     ),
-                    MatchType::Regex(_) => {
-                        self.comparator
-                            .regex(pattern, &key, "maybe", 0, &mut Vec::new())
-                    }
+                    MatchType::Regex(_) => self.comparator.regex(
+                        &ctx.runtime.regex_cache,
+                        pattern,
+                        &key,
+                        "maybe",
+                        0,
+                        &mut Vec::new(),
+                    ),
                     _ => false,
                 } {
                     return TestResult::Bool(true ^ self.is_not);
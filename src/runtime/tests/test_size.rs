@@ -21,18 +21,66 @@
  * for more details.
 */
 
-use crate::{compiler::grammar::tests::test_size::TestSize, Context};
+use mail_parser::{HeaderValue, PartType};
+
+use crate::{
+    compiler::grammar::tests::test_size::{SizeMode, TestSize},
+    Context,
+};
 
 use super::TestResult;
 
 impl TestSize {
     pub(crate) fn exec<C>(&self, ctx: &Context<C>) -> TestResult {
+        let size = match self.mode {
+            SizeMode::Raw => ctx.message_size,
+            SizeMode::Decoded => ctx.decoded_size(),
+            SizeMode::Body => ctx.body_size(),
+        };
+
         TestResult::Bool(
             (if self.over {
-                ctx.message_size > self.limit
+                size > self.limit
             } else {
-                ctx.message_size < self.limit
+                size < self.limit
             }) ^ self.is_not,
         )
     }
 }
+
+impl<'x, C> Context<'x, C> {
+    // Size of the message with the bodies of all parts decoded from their
+    // transfer encoding
+    pub(crate) fn decoded_size(&self) -> usize {
+        let mut size = self.message_size as i64;
+        for part in &self.message.parts {
+            if part.offset_body != 0 && !matches!(part.body, PartType::Multipart(_)) {
+                size += part.body.len() as i64 - (part.offset_end - part.offset_body) as i64;
+            }
+        }
+        size.max(0) as usize
+    }
+
+    // Size of the message excluding its top-level headers
+    pub(crate) fn body_size(&self) -> usize {
+        let header_size = self.message.parts.first().map_or(0, |part| {
+            part.headers
+                .iter()
+                .map(|header| {
+                    if header.offset_end != 0 {
+                        header.offset_end - header.offset_field
+                    } else {
+                        // Inserted header
+                        header.name.as_str().len()
+                            + match &header.value {
+                                HeaderValue::Text(text) => text.len(),
+                                _ => 0,
+                            }
+                            + 4
+                    }
+                })
+                .sum::<usize>()
+        });
+        self.message_size.saturating_sub(header_size + 2)
+    }
+}
@@ -58,6 +58,7 @@ impl TestSpamTest {
                 &mut captured_values,
             ),
             MatchType::Regex(capture_positions) => self.comparator.regex(
+                &ctx.runtime.regex_cache,
                 &self.value,
                 &value,
                 status.to_string().as_ref(),
@@ -102,6 +103,7 @@ impl TestVirusTest {
                 &mut captured_values,
             ),
             MatchType::Regex(capture_positions) => self.comparator.regex(
+                &ctx.runtime.regex_cache,
                 &self.value,
                 &value,
                 status.to_string().as_ref(),
@@ -56,9 +56,9 @@ impl TestString {
             MatchType::List => {
                 let mut values = Vec::with_capacity(self.source.len());
                 for source in &self.source {
-                    let value = ctx.eval_value(source).to_string().into_owned();
-                    if !value.is_empty() && !values.iter().any(|v: &String| v.eq(&value)) {
-                        values.push(value);
+                    let value = ctx.eval_string(source);
+                    if !value.is_empty() && !values.iter().any(|v: &String| v == value.as_ref()) {
+                        values.push(value.into_owned());
                     }
                 }
                 if !values.is_empty() {
@@ -74,28 +74,42 @@ impl TestString {
             }
             _ => {
                 let mut captured_values = Vec::new();
-                let sources = ctx.eval_values(&self.source);
+                // Most tests have a single source, which needs no list
+                let source_;
+                let sources_;
+                let sources = if let [source] = self.source.as_slice() {
+                    source_ = ctx.eval_value(source);
+                    std::slice::from_ref(&source_)
+                } else {
+                    sources_ = ctx.eval_values(&self.source);
+                    sources_.as_slice()
+                };
 
                 for pattern in &self.key_list {
                     let key = ctx.eval_value(pattern);
-                    for source in &sources {
+                    for source in sources {
                         if !empty_is_null || !source.is_empty() {
                             result = match &self.match_type {
                                 MatchType::Is => self.comparator.is(source, &key),
-                                MatchType::Contains => self.comparator.contains(
+                                MatchType::Contains => self.comparator.contains_value(
                                     source.to_string().as_ref(),
-                                    key.to_string().as_ref(),
+                                    pattern,
+                                    &key,
                                 ),
                                 MatchType::Value(relation) => {
                                     self.comparator.relational(relation, source, &key)
                                 }
-                                MatchType::Matches(capture_positions) => self.comparator.matches(
-                                    source.to_string().as_ref(),
-                                    key.to_string().as_ref(),
-                                    *capture_positions,
-                                    &mut captured_values,
-                                ),
+                                MatchType::Matches(capture_positions) => {
+                                    self.comparator.matches_value(
+                                        source.to_string().as_ref(),
+                                        pattern,
+                                        &key,
+                                        *capture_positions,
+                                        &mut captured_values,
+                                    )
+                                }
                                 MatchType::Regex(capture_positions) => self.comparator.regex(
+                                    &ctx.runtime.regex_cache,
                                     pattern,
                                     &key,
                                     source.to_string().as_ref(),
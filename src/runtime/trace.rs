@@ -0,0 +1,397 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::fmt::{self, Display, Write};
+
+use crate::{Context, Event, Input, Mailbox, Recipient};
+
+use super::{RuntimeError, Variable};
+
+/// Events produced by a script run, in order, along with the input that
+/// answered each of them. Its `Display` output is a stable, line based
+/// format meant to be stored and compared against later runs.
+#[derive(Debug, Default)]
+pub struct Trace {
+    pub entries: Vec<TraceEntry>,
+    pub error: Option<RuntimeError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub event: Event,
+    pub input: Input,
+}
+
+/// First line where a trace differs from a golden trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceMismatch {
+    pub line_num: usize,
+    pub expected: Option<String>,
+    pub found: Option<String>,
+}
+
+impl<'x, C> Context<'x, C> {
+    /// Runs the context to completion and records every event. Actions are
+    /// answered with `Input::True` and any other event is passed to `resolver`.
+    pub fn run_traced(&mut self, input: Input, mut resolver: impl FnMut(&Event) -> Input) -> Trace {
+        let mut trace = Trace::default();
+        let mut input = input;
+
+        while let Some(event) = self.run(input) {
+            match event {
+                Ok(mut event) => {
+                    input = if is_resolved(&event) {
+                        resolver(&event)
+                    } else {
+                        Input::True
+                    };
+                    if let Some(redactor) = self.runtime.redactor {
+                        event.redact(redactor);
+                    }
+                    trace.entries.push(TraceEntry {
+                        event,
+                        input: input.clone(),
+                    });
+                }
+                Err(err) => {
+                    trace.error = Some(err);
+                    break;
+                }
+            }
+        }
+
+        trace
+    }
+}
+
+impl Trace {
+    /// Compares the trace with a previously stored one, ignoring line endings.
+    pub fn compare(&self, golden: &str) -> Result<(), TraceMismatch> {
+        let trace = self.to_string();
+        let mut expected = golden.lines();
+        let mut found = trace.lines();
+        let mut line_num = 1;
+
+        loop {
+            match (expected.next(), found.next()) {
+                (None, None) => return Ok(()),
+                (Some(a), Some(b)) if a.trim_end_matches('\r') == b => {}
+                (expected, found) => {
+                    return Err(TraceMismatch {
+                        line_num,
+                        expected: expected.map(|s| s.trim_end_matches('\r').to_string()),
+                        found: found.map(String::from),
+                    })
+                }
+            }
+            line_num += 1;
+        }
+    }
+}
+
+impl Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in &self.entries {
+            write!(f, "{entry}")?;
+        }
+        if let Some(err) = &self.error {
+            writeln!(f, "error {err:?}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut line = String::new();
+        match &self.event {
+            Event::IncludeScript { name, optional } => {
+                write!(line, "include name={:?} optional={optional}", name.as_str())
+            }
+            Event::MailboxExists {
+                mailboxes,
+                special_use,
+            } => {
+                line.push_str("mailbox_exists mailboxes=");
+                list(
+                    &mut line,
+                    mailboxes.iter().map(|mailbox| match mailbox {
+                        Mailbox::Name(name) => format!("name:{name:?}"),
+                        Mailbox::Id(id) => format!("id:{id:?}"),
+                    }),
+                );
+                line.push_str(" special_use=");
+                strings(&mut line, special_use);
+                Ok(())
+            }
+            Event::ListContains {
+                lists,
+                values,
+                match_as,
+            } => {
+                line.push_str("list_contains lists=");
+                strings(&mut line, lists);
+                line.push_str(" values=");
+                strings(&mut line, values);
+                write!(line, " match_as={match_as:?}")
+            }
+            Event::DuplicateId { id, expiry, last } => {
+                write!(line, "duplicate_id id={id:?} expiry={expiry} last={last}")
+            }
+            Event::SetEnvelope { envelope, value } => {
+                write!(line, "set_envelope envelope={envelope:?} value={value:?}")
+            }
+            Event::Function { id, arguments } => {
+                write!(line, "function id={id} arguments=")?;
+                list(&mut line, arguments.iter().map(variable));
+                Ok(())
+            }
+            Event::Keep { flags, message_id } => {
+                line.push_str("keep flags=");
+                strings(&mut line, flags);
+                write!(line, " message_id={message_id}")
+            }
+            Event::Discard => {
+                line.push_str("discard");
+                Ok(())
+            }
+            Event::Reject {
+                extended, reason, ..
+            } => {
+                write!(line, "reject extended={extended} reason={reason:?}")
+            }
+            Event::FileInto {
+                folder,
+                flags,
+                mailbox_id,
+                special_use,
+                create,
+                message_id,
+            } => {
+                write!(line, "fileinto folder={folder:?} flags=")?;
+                strings(&mut line, flags);
+                write!(
+                    line,
+                    " mailbox_id={mailbox_id:?} special_use={special_use:?} create={create} message_id={message_id}"
+                )
+            }
+            Event::SendMessage {
+                recipient,
+                notify,
+                return_of_content,
+                by_time,
+                message_id,
+            } => {
+                line.push_str("send_message recipient=");
+                match recipient {
+                    Recipient::Address(address) => write!(line, "address:{address:?}")?,
+                    Recipient::List(list) => write!(line, "list:{list:?}")?,
+                    Recipient::Group(group) => {
+                        line.push_str("group:");
+                        strings(&mut line, group);
+                    }
+                }
+                write!(
+                    line,
+                    " notify={notify:?} ret={return_of_content:?} by_time={by_time:?} message_id={message_id}"
+                )
+            }
+            Event::Notify {
+                from,
+                importance,
+                importance_level,
+                options,
+                message,
+                method,
+            } => {
+                write!(
+                    line,
+                    "notify method={method:?} from={from:?} importance={importance:?} importance_level={importance_level} options="
+                )?;
+                strings(&mut line, options);
+                write!(line, " message={message:?}")
+            }
+            Event::PreviewModification {
+                modification,
+                before,
+                after,
+            } => {
+                write!(line, "preview_modification modification={modification:?}")?;
+                for (prefix, message) in [("\n  - ", before), ("\n  + ", after)] {
+                    for message_line in String::from_utf8_lossy(message).lines() {
+                        line.push_str(prefix);
+                        line.push_str(message_line.trim_end_matches('\r'));
+                    }
+                }
+                Ok(())
+            }
+            Event::CreatedMessage {
+                message_id,
+                message,
+            } => {
+                write!(line, "created_message message_id={message_id}")?;
+                // The message is written on its own lines so that changes
+                // to its contents show up as line diffs
+                for message_line in String::from_utf8_lossy(message).lines() {
+                    line.push_str("\n  | ");
+                    line.push_str(message_line.trim_end_matches('\r'));
+                }
+                Ok(())
+            }
+        }?;
+
+        // Answers go at the end of the first line
+        let (line, body) = match line.find('\n') {
+            Some(pos) => line.split_at(pos),
+            None => (line.as_str(), ""),
+        };
+        match &self.input {
+            _ if !is_resolved(&self.event) => writeln!(f, "{line}{body}"),
+            Input::True => writeln!(f, "{line} -> true{body}"),
+            Input::False => writeln!(f, "{line} -> false{body}"),
+            Input::FncResult(result) => writeln!(f, "{line} -> {}{body}", variable(result)),
+            Input::Script { name, .. } => {
+                writeln!(f, "{line} -> script {:?}{body}", name.as_str())
+            }
+            Input::Variables(variables) => {
+                write!(f, "{line} -> variables")?;
+                for (name, value) in variables {
+                    write!(f, " {name}={}", variable(value))?;
+                }
+                writeln!(f, "{body}")
+            }
+        }
+    }
+}
+
+impl Display for TraceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Trace differs at line {}: expected {:?}, found {:?}",
+            self.line_num,
+            self.expected.as_deref().unwrap_or("end of trace"),
+            self.found.as_deref().unwrap_or("end of trace")
+        )
+    }
+}
+
+fn is_resolved(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::IncludeScript { .. }
+            | Event::MailboxExists { .. }
+            | Event::ListContains { .. }
+            | Event::DuplicateId { .. }
+            | Event::Function { .. }
+            | Event::PreviewModification { .. }
+    )
+}
+
+fn variable(value: &Variable) -> String {
+    match value {
+        Variable::String(s) => format!("{s:?}"),
+        Variable::Integer(n) => n.to_string(),
+        Variable::Float(n) => format!("{n:?}"),
+        Variable::Array(items) => {
+            let mut result = String::new();
+            list(&mut result, items.iter().map(variable));
+            result
+        }
+    }
+}
+
+fn strings(line: &mut String, items: &[String]) {
+    list(line, items.iter().map(|item| format!("{item:?}")));
+}
+
+fn list(line: &mut String, items: impl Iterator<Item = String>) {
+    line.push('[');
+    for (pos, item) in items.enumerate() {
+        if pos > 0 {
+            line.push_str(", ");
+        }
+        line.push_str(&item);
+    }
+    line.push(']');
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Compiler, Event, Input, Runtime};
+
+    use super::TraceMismatch;
+
+    #[test]
+    fn golden_trace() {
+        let script = Compiler::new()
+            .compile(
+                br#"require ["fileinto", "mailbox", "imap4flags"];
+                if mailboxexists "Archive" {
+                    fileinto :flags "\\Seen" "Archive";
+                } else {
+                    keep;
+                }"#,
+            )
+            .unwrap();
+        let runtime = Runtime::new();
+
+        for (exists, golden) in [
+            (
+                true,
+                concat!(
+                    "mailbox_exists mailboxes=[name:\"Archive\"] special_use=[] -> true\n",
+                    "fileinto folder=\"Archive\" flags=[\"\\\\Seen\"] mailbox_id=None ",
+                    "special_use=None create=false message_id=0\n"
+                ),
+            ),
+            (
+                false,
+                concat!(
+                    "mailbox_exists mailboxes=[name:\"Archive\"] special_use=[] -> false\n",
+                    "keep flags=[] message_id=0\n"
+                ),
+            ),
+        ] {
+            let trace = runtime
+                .filter(b"Subject: test\r\n\r\nbody")
+                .run_traced(Input::script("", script.clone()), |event| {
+                    (matches!(event, Event::MailboxExists { .. }) && exists).into()
+                });
+            assert_eq!(trace.to_string(), golden);
+            assert_eq!(trace.compare(&golden.replace('\n', "\r\n")), Ok(()));
+        }
+
+        let trace = runtime
+            .filter(b"Subject: test\r\n\r\nbody")
+            .run_traced(Input::script("", script), |_| Input::False);
+        assert_eq!(
+            trace.compare("mailbox_exists mailboxes=[name:\"Archive\"] special_use=[] -> false\n"),
+            Err(TraceMismatch {
+                line_num: 2,
+                expected: None,
+                found: Some("keep flags=[] message_id=0".to_string()),
+            })
+        );
+    }
+}
@@ -29,7 +29,9 @@ impl<'x, C> Context<'x, C> {
     pub(crate) fn set_match_variables(&mut self, set_vars: Vec<(usize, String)>) {
         for (var_num, value) in set_vars {
             if let Some(var) = self.vars_match.get_mut(var_num) {
+                let old_len = var.len();
                 *var = value.into();
+                self.vars_size = (self.vars_size + var.len()).saturating_sub(old_len);
             } else {
                 debug_assert!(false, "Invalid match variable {var_num}");
             }
@@ -42,6 +44,7 @@ impl<'x, C> Context<'x, C> {
             positions ^= 1 << index;
             if let Some(match_var) = self.vars_match.get_mut(index as usize) {
                 if !match_var.is_empty() {
+                    self.vars_size = self.vars_size.saturating_sub(match_var.len());
                     *match_var = Variable::default();
                 }
             } else {
@@ -0,0 +1,792 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! Runner for test scripts in the `.svtest` format used by the Pigeonhole
+//! test suite. Test scripts require `vnd.stalwart.testsuite` and use the
+//! `test`, `test_set`, `test_result_*`, `test_message`, `test_config_set`
+//! and related commands to run and verify scripts against messages.
+
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use ahash::{AHashMap, AHashSet};
+use mail_parser::{
+    parsers::MessageStream, Encoding, HeaderName, HeaderValue, Message, MessageParser, MessagePart,
+    PartType,
+};
+
+use crate::{
+    compiler::grammar::Capability,
+    runtime::{context::ScriptStack, Variable},
+    Compiler, Context, Envelope, Event, HeaderPolicy, Input, IntegerDivision, IntegerOverflow,
+    Mailbox, Recipient, Runtime, Script, Sieve, SpamStatus, VirusStatus,
+};
+
+pub type ExternalFunction = fn(u32, Vec<Variable>) -> Variable;
+
+#[derive(Debug, Clone)]
+pub struct TestSuite {
+    pub(crate) compiler: Compiler,
+    pub(crate) runtime: Runtime<()>,
+    pub(crate) external_functions: Option<ExternalFunction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestError {
+    pub test: String,
+    pub message: String,
+}
+
+// State kept when the context is recreated for a new message
+type ContextState = (
+    usize,
+    AHashMap<Script, Arc<Sieve>>,
+    AHashSet<Script>,
+    Vec<ScriptStack>,
+    Arc<AHashMap<Cow<'static, str>, Variable>>,
+    Vec<Variable>,
+    Vec<Variable>,
+);
+
+impl TestSuite {
+    pub fn new() -> Self {
+        TestSuite {
+            compiler: Compiler::new(),
+            runtime: Runtime::new(),
+            external_functions: None,
+        }
+    }
+
+    pub fn set_compiler(&mut self, compiler: Compiler) {
+        self.compiler = compiler;
+    }
+
+    pub fn with_compiler(mut self, compiler: Compiler) -> Self {
+        self.compiler = compiler;
+        self
+    }
+
+    pub fn set_runtime(&mut self, runtime: Runtime<()>) {
+        self.runtime = runtime;
+    }
+
+    pub fn with_runtime(mut self, runtime: Runtime<()>) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Handler for the external functions registered with the compiler,
+    /// called with the function id and its arguments.
+    pub fn set_external_functions(&mut self, handler: ExternalFunction) {
+        self.external_functions = Some(handler);
+    }
+
+    pub fn with_external_functions(mut self, handler: ExternalFunction) -> Self {
+        self.external_functions = Some(handler);
+        self
+    }
+
+    /// Runs a test script. Included scripts and scripts compiled with
+    /// `test_script_compile` are loaded relative to the script's directory.
+    pub fn run_file(&self, path: impl AsRef<Path>) -> Result<(), TestError> {
+        let path = path.as_ref();
+        let script = fs::read(path)
+            .map_err(|err| error("", format!("Failed to read {}: {err}", path.display())))?;
+        self.run(&script, path.parent().unwrap_or_else(|| Path::new(".")))
+    }
+
+    pub fn run(&self, script: &[u8], base_path: &Path) -> Result<(), TestError> {
+        let mut compiler = self.compiler.clone().with_test_commands(true);
+        let script = compiler
+            .compile(&add_crlf(script))
+            .map_err(|err| error("", format!("Failed to compile test script: {err}")))?;
+
+        let mut input = Input::script("", script);
+        let mut current_test = String::new();
+        let mut raw_message_: Option<Vec<u8>> = None;
+        let mut prev_state: Option<ContextState> = None;
+        let mut mailboxes = Vec::new();
+        let mut lists: AHashMap<String, AHashSet<String>> = AHashMap::new();
+        let mut duplicated_ids = AHashSet::new();
+        let mut actions = Vec::new();
+
+        'outer: loop {
+            let runtime = self
+                .runtime
+                .clone()
+                .with_capability(Capability::Other("vnd.stalwart.testsuite".to_string()));
+            let mut instance = Context::new(
+                &runtime,
+                Message {
+                    parts: vec![MessagePart {
+                        headers: vec![],
+                        is_encoding_problem: false,
+                        body: PartType::Text("".into()),
+                        encoding: Encoding::None,
+                        offset_header: 0,
+                        offset_body: 0,
+                        offset_end: 0,
+                    }],
+                    raw_message: b""[..].into(),
+                    ..Default::default()
+                },
+            );
+            let raw_message = raw_message_.take().unwrap_or_default();
+            instance.message = MessageParser::new()
+                .parse(&raw_message)
+                .unwrap_or_else(|| Message {
+                    html_body: vec![],
+                    text_body: vec![],
+                    attachments: vec![],
+                    parts: vec![MessagePart {
+                        headers: vec![],
+                        is_encoding_problem: false,
+                        body: PartType::Text("".into()),
+                        encoding: Encoding::None,
+                        offset_header: 0,
+                        offset_body: 0,
+                        offset_end: 0,
+                    }],
+                    raw_message: b""[..].into(),
+                })
+                .into();
+            instance.message_size = raw_message.len();
+            if let Some((
+                pos,
+                script_cache,
+                scripts_included,
+                script_stack,
+                vars_global,
+                vars_local,
+                vars_match,
+            )) = prev_state.take()
+            {
+                instance.pos = pos;
+                instance.script_cache = script_cache;
+                instance.scripts_included = scripts_included;
+                instance.script_stack = script_stack;
+                instance.vars_global = vars_global;
+                instance.vars_local = vars_local;
+                instance.vars_match = vars_match;
+                instance.vars_size = instance.variables_size();
+            }
+            instance.set_env_variable("vnd.stalwart.default_mailbox", "INBOX");
+            instance.set_env_variable("vnd.stalwart.username", "john.doe");
+            instance.set_user_address("MAILER-DAEMON");
+            if let Some(addr) = instance
+                .message
+                .from()
+                .and_then(|a| a.first())
+                .and_then(|a| a.address.as_ref())
+            {
+                instance.set_envelope(Envelope::From, addr.to_string());
+            }
+            if let Some(addr) = instance
+                .message
+                .to()
+                .and_then(|a| a.first())
+                .and_then(|a| a.address.as_ref())
+            {
+                instance.set_envelope(Envelope::To, addr.to_string());
+            }
+
+            while let Some(event) = instance.run(input) {
+                let event = event.map_err(|err| error(&current_test, format!("{err:?}")))?;
+                match event {
+                    Event::IncludeScript { name, optional } => {
+                        let mut include_path = PathBuf::from(base_path);
+                        include_path.push(if matches!(name, Script::Personal(_)) {
+                            "included"
+                        } else {
+                            "included-global"
+                        });
+                        include_path.push(format!("{name}.sieve"));
+
+                        if let Ok(bytes) = fs::read(include_path.as_path()) {
+                            let script = compiler.compile(&add_crlf(&bytes)).map_err(|err| {
+                                error(
+                                    &current_test,
+                                    format!("Failed to compile {}: {err}", include_path.display()),
+                                )
+                            })?;
+                            input = Input::script(name, script);
+                        } else if optional {
+                            input = Input::False;
+                        } else {
+                            return Err(error(
+                                &current_test,
+                                format!("Script {} not found", include_path.display()),
+                            ));
+                        }
+                    }
+                    Event::MailboxExists {
+                        mailboxes: mailboxes_,
+                        special_use,
+                    } => {
+                        for action in &actions {
+                            if let Event::FileInto { folder, create, .. } = action {
+                                if *create && !mailboxes.contains(folder) {
+                                    mailboxes.push(folder.to_string());
+                                }
+                            }
+                        }
+                        input = (special_use.is_empty()
+                            && mailboxes_.iter().all(|n| {
+                                if let Mailbox::Name(n) = n {
+                                    mailboxes.contains(n)
+                                } else {
+                                    false
+                                }
+                            }))
+                        .into();
+                    }
+                    Event::ListContains {
+                        lists: lists_,
+                        values,
+                        ..
+                    } => {
+                        let mut result = false;
+                        'list: for list in &lists_ {
+                            if let Some(list) = lists.get(list) {
+                                for value in &values {
+                                    if list.contains(value) {
+                                        result = true;
+                                        break 'list;
+                                    }
+                                }
+                            }
+                        }
+
+                        input = result.into();
+                    }
+                    Event::DuplicateId { id, .. } => {
+                        input = duplicated_ids.contains(&id).into();
+                    }
+                    Event::Function { id, arguments } if id == u32::MAX => {
+                        // Test commands
+                        input = Input::True;
+                        let mut arguments = arguments.into_iter();
+                        let command = arguments
+                            .next()
+                            .map(|arg| arg.to_string().into_owned())
+                            .unwrap_or_default();
+                        let mut params = arguments
+                            .map(|arg| arg.to_string().into_owned())
+                            .collect::<Vec<_>>()
+                            .into_iter();
+
+                        match command.as_str() {
+                            "test" => {
+                                current_test = next_param(&mut params, &current_test, "test name")?;
+                            }
+                            "test_set" => {
+                                let target =
+                                    next_param(&mut params, &current_test, "test_set target")?;
+                                if target == "message" {
+                                    let value =
+                                        next_param(&mut params, &current_test, "test_set message")?;
+                                    raw_message_ = if value.eq_ignore_ascii_case(":smtp") {
+                                        let mut message = None;
+                                        for action in actions.iter().rev() {
+                                            if let Event::SendMessage { message_id, .. } = action {
+                                                message = actions.iter().find_map(|item| {
+                                                    if let Event::CreatedMessage {
+                                                        message_id: message_id_,
+                                                        message,
+                                                    } = item
+                                                    {
+                                                        if message_id == message_id_ {
+                                                            return Some(message);
+                                                        }
+                                                    }
+                                                    None
+                                                });
+                                                break;
+                                            }
+                                        }
+                                        message
+                                            .ok_or_else(|| {
+                                                error(&current_test, "No SMTP message found")
+                                            })?
+                                            .to_vec()
+                                            .into()
+                                    } else {
+                                        value.into_bytes().into()
+                                    };
+                                    prev_state = (
+                                        instance.pos,
+                                        instance.script_cache,
+                                        instance.scripts_included,
+                                        instance.script_stack,
+                                        instance.vars_global,
+                                        instance.vars_local,
+                                        instance.vars_match,
+                                    )
+                                        .into();
+
+                                    continue 'outer;
+                                } else if let Some(envelope) = target.strip_prefix("envelope.") {
+                                    let envelope = Envelope::try_from(envelope.to_string())
+                                        .map_err(|_| {
+                                            error(
+                                                &current_test,
+                                                format!("Invalid envelope {envelope:?}"),
+                                            )
+                                        })?;
+                                    let value =
+                                        next_param(&mut params, &current_test, "envelope value")?;
+                                    instance.envelope.retain(|(e, _)| e != &envelope);
+                                    instance.set_envelope(envelope, value);
+                                } else if target == "currentdate" {
+                                    let bytes =
+                                        next_param(&mut params, &current_test, "currentdate")?
+                                            .into_bytes();
+                                    if let HeaderValue::DateTime(dt) =
+                                        MessageStream::new(&bytes).parse_date()
+                                    {
+                                        instance.current_time = dt.to_timestamp();
+                                        instance.current_time_millis = 0;
+                                    } else {
+                                        return Err(error(&current_test, "Invalid currentdate"));
+                                    }
+                                } else {
+                                    return Err(error(
+                                        &current_test,
+                                        format!("test_set {target} is not supported"),
+                                    ));
+                                }
+                            }
+                            "test_message" => {
+                                input = match next_param(&mut params, &current_test, "test_message type")?.as_str() {
+                                    ":folder" => {
+                                        let folder_name = next_param(&mut params, &current_test, "test_message folder name")?;
+                                        matches!(&instance.final_event, Some(Event::Keep { .. }))
+                                            || actions.iter().any(|a| {
+                                                if !folder_name.eq_ignore_ascii_case("INBOX") {
+                                                    matches!(a, Event::FileInto { folder, .. } if folder == &folder_name)
+                                                } else {
+                                                    matches!(a, Event::Keep { .. })
+                                                }
+                                            })
+                                    }
+                                    ":smtp" => actions
+                                        .iter()
+                                        .any(|a| matches!(a, Event::SendMessage { .. })),
+                                    param => {
+                                        return Err(error(
+                                            &current_test,
+                                            format!("Invalid test_message parameter {param:?}"),
+                                        ))
+                                    }
+                                }
+                                .into();
+                            }
+                            "test_assert_message" => {
+                                let expected_message =
+                                    next_param(&mut params, &current_test, "expected message")?;
+                                let built_message = instance.build_message();
+                                if expected_message.as_bytes() != built_message {
+                                    return Err(error(
+                                        &current_test,
+                                        format!(
+                                            "Message built incorrectly: <[{}]>",
+                                            String::from_utf8_lossy(&built_message)
+                                        ),
+                                    ));
+                                }
+                            }
+                            "test_config_set" => {
+                                let name =
+                                    next_param(&mut params, &current_test, "test_config_set name")?;
+                                let value = next_param(
+                                    &mut params,
+                                    &current_test,
+                                    "test_config_set value",
+                                )?;
+
+                                match name.as_str() {
+                                    "sieve_editheader_protected"
+                                    | "sieve_editheader_forbid_add"
+                                    | "sieve_editheader_forbid_delete" => {
+                                        if !value.is_empty() {
+                                            let runtime = instance.runtime_mut();
+                                            for header_name in value.split(' ') {
+                                                let current = HeaderName::parse(header_name)
+                                                    .map_or(HeaderPolicy::Allow, |h| {
+                                                        runtime.header_policy(&h)
+                                                    });
+                                                let policy = match name.as_str() {
+                                                    "sieve_editheader_forbid_add" => {
+                                                        HeaderPolicy::from_permissions(
+                                                            false,
+                                                            current.allows_delete(),
+                                                        )
+                                                    }
+                                                    "sieve_editheader_forbid_delete" => {
+                                                        HeaderPolicy::from_permissions(
+                                                            current.allows_add(),
+                                                            false,
+                                                        )
+                                                    }
+                                                    _ => HeaderPolicy::Deny,
+                                                };
+                                                runtime.set_header_policy(
+                                                    header_name.to_string(),
+                                                    policy,
+                                                );
+                                            }
+                                        } else {
+                                            instance.runtime_mut().protected_headers.clear();
+                                        }
+                                    }
+                                    "sieve_variables_max_variable_size" => {
+                                        instance
+                                            .runtime_mut()
+                                            .set_max_variable_size(number(&current_test, &value)?);
+                                    }
+                                    "sieve_expressions_integer_overflow" => {
+                                        instance.runtime_mut().set_integer_overflow(
+                                            match value.as_str() {
+                                                "wrap" => IntegerOverflow::Wrap,
+                                                "float" => IntegerOverflow::Float,
+                                                _ => IntegerOverflow::Saturate,
+                                            },
+                                        );
+                                    }
+                                    "sieve_expressions_integer_division" => {
+                                        instance.runtime_mut().set_integer_division(
+                                            if value.eq_ignore_ascii_case("truncate") {
+                                                IntegerDivision::Truncate
+                                            } else {
+                                                IntegerDivision::Float
+                                            },
+                                        );
+                                    }
+                                    "sieve_valid_ext_list" => {
+                                        instance.runtime_mut().set_valid_ext_list(value);
+                                    }
+                                    "sieve_ext_list_item" => {
+                                        let item = next_param(
+                                            &mut params,
+                                            &current_test,
+                                            "list item value",
+                                        )?;
+                                        lists.entry(value).or_default().insert(item);
+                                    }
+                                    "sieve_duplicated_id" => {
+                                        duplicated_ids.insert(value);
+                                    }
+                                    "sieve_user_email" => {
+                                        instance.set_user_address(value);
+                                    }
+                                    "sieve_vacation_use_original_recipient" => {
+                                        instance.runtime_mut().set_vacation_use_orig_rcpt(
+                                            value.eq_ignore_ascii_case("yes"),
+                                        );
+                                    }
+                                    "sieve_vacation_address_wildcards" => {
+                                        instance.runtime_mut().set_vacation_address_wildcards(
+                                            value.eq_ignore_ascii_case("yes"),
+                                        );
+                                    }
+                                    "sieve_vacation_ignore_subaddress" => {
+                                        instance.runtime_mut().set_vacation_ignore_subaddress(
+                                            value.eq_ignore_ascii_case("yes"),
+                                        );
+                                    }
+                                    "sieve_vacation_default_subject" => {
+                                        instance.runtime_mut().set_vacation_default_subject(value);
+                                    }
+                                    "sieve_vacation_default_subject_template" => {
+                                        instance.runtime_mut().set_vacation_subject_prefix(value);
+                                    }
+                                    "sieve_spam_status" => {
+                                        instance.set_spam_status(SpamStatus::from_number(number(
+                                            &current_test,
+                                            &value,
+                                        )?));
+                                    }
+                                    "sieve_spam_status_plus" => {
+                                        instance.set_spam_status(
+                                            match number::<u32>(&current_test, &value)? {
+                                                0 => SpamStatus::Unknown,
+                                                100.. => SpamStatus::Spam,
+                                                n => SpamStatus::MaybeSpam((n as f64) / 100.0),
+                                            },
+                                        );
+                                    }
+                                    "sieve_virus_status" => {
+                                        instance.set_virus_status(VirusStatus::from_number(
+                                            number(&current_test, &value)?,
+                                        ));
+                                    }
+                                    "sieve_editheader_max_header_size" => {
+                                        let mhs = if !value.is_empty() {
+                                            number(&current_test, &value)?
+                                        } else {
+                                            1024
+                                        };
+                                        instance.runtime_mut().set_max_header_size(mhs);
+                                        compiler.set_max_header_size(mhs);
+                                    }
+                                    "sieve_include_max_includes" => {
+                                        compiler.set_max_includes(if !value.is_empty() {
+                                            number(&current_test, &value)?
+                                        } else {
+                                            3
+                                        });
+                                    }
+                                    "sieve_include_max_nesting_depth" => {
+                                        compiler.set_max_nested_blocks(if !value.is_empty() {
+                                            number(&current_test, &value)?
+                                        } else {
+                                            3
+                                        });
+                                    }
+                                    name => {
+                                        return Err(error(
+                                            &current_test,
+                                            format!("test_config_set {name} is not supported"),
+                                        ))
+                                    }
+                                }
+                            }
+                            "test_result_execute" => {
+                                input = (matches!(&instance.final_event, Some(Event::Keep { .. }))
+                                    || actions.iter().any(|a| {
+                                        matches!(
+                                            a,
+                                            Event::Keep { .. }
+                                                | Event::FileInto { .. }
+                                                | Event::SendMessage { .. }
+                                        )
+                                    }))
+                                .into();
+                            }
+                            "test_result_action" => {
+                                let action = next_param(
+                                    &mut params,
+                                    &current_test,
+                                    "test_result_action parameter",
+                                )?;
+                                input = match action.as_str() {
+                                    "reject" => actions
+                                        .iter()
+                                        .any(|a| matches!(a, Event::Reject { .. })),
+                                    "redirect" => {
+                                        let address = params.last().ok_or_else(|| {
+                                            error(
+                                                &current_test,
+                                                "Missing test_result_action redirect address",
+                                            )
+                                        })?;
+                                        actions.iter().any(|a| {
+                                            matches!(a, Event::SendMessage { recipient: Recipient::Address(recipient), .. } if recipient == &address)
+                                        })
+                                    }
+                                    "keep" => {
+                                        matches!(&instance.final_event, Some(Event::Keep { .. }))
+                                            || actions
+                                                .iter()
+                                                .any(|a| matches!(a, Event::Keep { .. }))
+                                    }
+                                    "send_message" => actions
+                                        .iter()
+                                        .any(|a| matches!(a, Event::SendMessage { .. })),
+                                    action => {
+                                        return Err(error(
+                                            &current_test,
+                                            format!("test_result_action {action} is not supported"),
+                                        ))
+                                    }
+                                }
+                                .into();
+                            }
+                            "test_result_action_count" => {
+                                let count = next_param(&mut params, &current_test, "action count")?;
+                                input = (actions.len() == number::<usize>(&current_test, &count)?)
+                                    .into();
+                            }
+                            "test_imap_metadata_set" => {
+                                let first =
+                                    next_param(&mut params, &current_test, "metadata parameter")?;
+                                let (mailbox, annotation) = if first == ":mailbox" {
+                                    (
+                                        Some(next_param(
+                                            &mut params,
+                                            &current_test,
+                                            "metadata mailbox name",
+                                        )?),
+                                        next_param(
+                                            &mut params,
+                                            &current_test,
+                                            "metadata annotation name",
+                                        )?,
+                                    )
+                                } else {
+                                    (None, first)
+                                };
+                                let value =
+                                    next_param(&mut params, &current_test, "metadata value")?;
+                                if let Some(mailbox) = mailbox {
+                                    instance.set_medatata((mailbox, annotation), value);
+                                } else {
+                                    instance.set_medatata(annotation, value);
+                                }
+                            }
+                            "test_mailbox_create" => {
+                                let mailbox = params.last().ok_or_else(|| {
+                                    error(&current_test, "Missing mailbox to create")
+                                })?;
+                                mailboxes.push(mailbox);
+                            }
+                            "test_result_reset" => {
+                                actions.clear();
+                                instance.final_event = Event::Keep {
+                                    flags: vec![],
+                                    message_id: 0,
+                                }
+                                .into();
+                                instance.metadata.clear();
+                                instance.has_changes = false;
+                                instance.num_redirects = 0;
+                                if instance.runtime.vacation_use_orig_rcpt {
+                                    instance.runtime_mut().vacation_use_orig_rcpt = false;
+                                }
+                                mailboxes.clear();
+                                lists.clear();
+                                #[cfg(test)]
+                                crate::runtime::actions::action_mime::reset_test_boundary();
+                            }
+                            "test_script_compile" => {
+                                let mut include_path = PathBuf::from(base_path);
+                                include_path.push(next_param(
+                                    &mut params,
+                                    &current_test,
+                                    "script name",
+                                )?);
+
+                                if let Ok(bytes) = fs::read(include_path.as_path()) {
+                                    input = compiler.compile(&add_crlf(&bytes)).is_ok().into();
+                                } else {
+                                    return Err(error(
+                                        &current_test,
+                                        format!("Script {} not found", include_path.display()),
+                                    ));
+                                }
+                            }
+                            "test_config_reload" => (),
+                            "test_fail" => {
+                                return Err(error(
+                                    &current_test,
+                                    params.last().unwrap_or_default(),
+                                ));
+                            }
+                            _ => {
+                                return Err(error(
+                                    &current_test,
+                                    format!("Test command {command} is not supported"),
+                                ))
+                            }
+                        }
+                    }
+                    Event::Function { id, arguments } => {
+                        input = match self.external_functions {
+                            Some(handler) => handler(id, arguments).into(),
+                            None => {
+                                return Err(error(
+                                    &current_test,
+                                    format!("Unknown external function {id}"),
+                                ))
+                            }
+                        };
+                    }
+                    action => {
+                        actions.push(action);
+                        input = true.into();
+                    }
+                }
+            }
+
+            return Ok(());
+        }
+    }
+}
+
+impl Default for TestSuite {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for TestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.test.is_empty() {
+            write!(f, "Test '{}' failed: {}", self.test, self.message)
+        } else {
+            f.write_str(&self.message)
+        }
+    }
+}
+
+fn error(test: &str, message: impl Into<String>) -> TestError {
+    TestError {
+        test: test.to_string(),
+        message: message.into(),
+    }
+}
+
+fn next_param(
+    params: &mut impl Iterator<Item = String>,
+    test: &str,
+    name: &str,
+) -> Result<String, TestError> {
+    params
+        .next()
+        .ok_or_else(|| error(test, format!("Missing {name}")))
+}
+
+fn number<T: std::str::FromStr>(test: &str, value: &str) -> Result<T, TestError> {
+    value
+        .parse()
+        .map_err(|_| error(test, format!("Invalid number {value:?}")))
+}
+
+// Test scripts are stored with LF line endings
+fn add_crlf(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut last_ch = 0;
+    for &ch in bytes {
+        if ch == b'\n' && last_ch != b'\r' {
+            result.push(b'\r');
+        }
+        result.push(ch);
+        last_ch = ch;
+    }
+    result
+}
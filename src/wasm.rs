@@ -0,0 +1,299 @@
+/*
+ * Copyright (c) 2020-2023, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart Sieve Interpreter.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+//! JavaScript bindings for validating and previewing scripts in the browser.
+//!
+//! Enabled with the `wasm` feature. Results are returned as JSON strings.
+
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::{Compiler, Event, Input, Recipient, Runtime};
+
+/// Compiles a script and returns its diagnostics:
+/// `{"valid": bool, "errors": [{"line", "column", "message"}]}`.
+#[wasm_bindgen]
+pub fn compile(source: &str) -> String {
+    match Compiler::new().compile(source.as_bytes()) {
+        Ok(_) => json!({ "valid": true, "errors": [] }),
+        Err(err) => json!({
+            "valid": false,
+            "errors": [{
+                "line": err.line_num(),
+                "column": err.line_pos(),
+                "message": err.to_string(),
+            }],
+        }),
+    }
+    .to_string()
+}
+
+/// Runs a script against a raw message without side effects and returns
+/// the actions it would take: `{"actions": [...], "errors": [...]}`.
+///
+/// External lookups are answered with fixed values: mailboxes exist, lists
+/// and duplicate tracking contain nothing and included scripts are missing.
+#[wasm_bindgen(js_name = dryRun)]
+pub fn dry_run(source: &str, raw_message: &str) -> String {
+    let script = match Compiler::new().compile(source.as_bytes()) {
+        Ok(script) => script,
+        Err(err) => {
+            return json!({ "actions": [], "errors": [err.to_string()] }).to_string();
+        }
+    };
+
+    let runtime = Runtime::new();
+    let mut instance = runtime.filter(raw_message.as_bytes());
+    let mut input = Input::script("script", script);
+    let mut actions = Vec::new();
+    let mut errors = Vec::new();
+
+    while let Some(result) = instance.run(input) {
+        input = Input::True;
+        match result {
+            Ok(event) => match event {
+                Event::IncludeScript { name, optional } => {
+                    if !optional {
+                        errors.push(Value::from(format!("Script {name} not found")));
+                    }
+                    input = Input::False;
+                }
+                Event::MailboxExists { .. } => {}
+                Event::ListContains { .. } | Event::DuplicateId { .. } => {
+                    input = Input::False;
+                }
+                Event::Function { .. } => {
+                    input = Input::FncResult(Default::default());
+                }
+                event => actions.push(event_to_json(event)),
+            },
+            Err(err) => {
+                errors.push(Value::from(err.to_string()));
+                break;
+            }
+        }
+    }
+
+    json!({ "actions": actions, "errors": errors }).to_string()
+}
+
+fn event_to_json(event: Event) -> Value {
+    match event {
+        Event::SetEnvelope { envelope, value } => json!({
+            "type": "setEnvelope",
+            "envelope": envelope,
+            "value": value,
+        }),
+        Event::Keep { flags, message_id } => json!({
+            "type": "keep",
+            "flags": flags,
+            "messageId": message_id,
+        }),
+        Event::Discard => json!({ "type": "discard" }),
+        Event::Reject {
+            extended, reason, ..
+        } => json!({
+            "type": "reject",
+            "extended": extended,
+            "reason": reason,
+        }),
+        Event::FileInto {
+            folder,
+            flags,
+            mailbox_id,
+            special_use,
+            create,
+            message_id,
+        } => json!({
+            "type": "fileInto",
+            "folder": folder,
+            "flags": flags,
+            "mailboxId": mailbox_id,
+            "specialUse": special_use,
+            "create": create,
+            "messageId": message_id,
+        }),
+        Event::SendMessage {
+            recipient,
+            notify,
+            return_of_content,
+            by_time,
+            message_id,
+        } => json!({
+            "type": "sendMessage",
+            "recipient": match recipient {
+                Recipient::Address(address) => json!({ "address": address }),
+                Recipient::List(list) => json!({ "list": list }),
+                Recipient::Group(group) => json!({ "group": group }),
+            },
+            "notify": notify,
+            "returnOfContent": return_of_content,
+            "byTime": by_time,
+            "messageId": message_id,
+        }),
+        Event::Notify {
+            from,
+            importance,
+            importance_level,
+            options,
+            message,
+            method,
+        } => json!({
+            "type": "notify",
+            "from": from,
+            "importance": format!("{importance:?}").to_lowercase(),
+            "importanceLevel": importance_level,
+            "options": options,
+            "message": message,
+            "method": method,
+        }),
+        Event::CreatedMessage {
+            message_id,
+            message,
+        } => json!({
+            "type": "createdMessage",
+            "messageId": message_id,
+            "message": String::from_utf8_lossy(&message),
+        }),
+        event => json!({ "type": "other", "event": format!("{event:?}") }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{compile, dry_run, event_to_json};
+    use crate::{Envelope, Event};
+
+    const MESSAGE: &str = "From: john@example.org\r\nSubject: test\r\n\r\nbody";
+
+    fn dry_run_json(script: &str) -> Value {
+        serde_json::from_str(&dry_run(script, MESSAGE)).unwrap()
+    }
+
+    #[test]
+    fn compile_diagnostics() {
+        let result: Value = serde_json::from_str(&compile("keep;")).unwrap();
+        assert_eq!(result, json!({ "valid": true, "errors": [] }));
+
+        let result: Value = serde_json::from_str(&compile("keep")).unwrap();
+        assert_eq!(result["valid"], json!(false));
+        assert_eq!(result["errors"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn event_json() {
+        for (event, expected) in [
+            (Event::Discard, json!({ "type": "discard" })),
+            (
+                Event::Keep {
+                    flags: vec!["\\Seen".to_string()],
+                    message_id: 1,
+                },
+                json!({ "type": "keep", "flags": ["\\Seen"], "messageId": 1 }),
+            ),
+            (
+                Event::SetEnvelope {
+                    envelope: Envelope::From,
+                    value: "jane@example.org".to_string(),
+                },
+                json!({
+                    "type": "setEnvelope",
+                    "envelope": "From",
+                    "value": "jane@example.org",
+                }),
+            ),
+            (
+                Event::FileInto {
+                    folder: "Junk".to_string(),
+                    flags: vec![],
+                    mailbox_id: None,
+                    special_use: None,
+                    create: true,
+                    message_id: 0,
+                },
+                json!({
+                    "type": "fileInto",
+                    "folder": "Junk",
+                    "flags": [],
+                    "mailboxId": null,
+                    "specialUse": null,
+                    "create": true,
+                    "messageId": 0,
+                }),
+            ),
+            (
+                Event::CreatedMessage {
+                    message_id: 2,
+                    message: b"Subject: hi\r\n\r\n".to_vec(),
+                },
+                json!({
+                    "type": "createdMessage",
+                    "messageId": 2,
+                    "message": "Subject: hi\r\n\r\n",
+                }),
+            ),
+        ] {
+            assert_eq!(event_to_json(event), expected);
+        }
+    }
+
+    #[test]
+    fn dry_run_actions() {
+        let result = dry_run_json("require \"fileinto\"; fileinto \"Junk\";");
+        assert_eq!(result["errors"], json!([]));
+        let actions = result["actions"].as_array().unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0]["type"], json!("fileInto"));
+        assert_eq!(actions[0]["folder"], json!("Junk"));
+
+        // Mailbox lookups succeed, list and duplicate lookups fail
+        let result = dry_run_json(concat!(
+            "require [\"fileinto\", \"mailbox\", \"duplicate\"];\n",
+            "if mailboxexists \"Work\" { fileinto \"Work\"; }\n",
+            "if duplicate { fileinto \"Duplicate\"; }\n",
+        ));
+        let folders = result["actions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|action| action["type"] == json!("fileInto"))
+            .map(|action| action["folder"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(folders, ["Work"]);
+
+        // Included scripts are missing
+        let result = dry_run_json(concat!(
+            "require \"include\";\n",
+            "include :optional \"optional\";\n",
+            "include \"required\";\n",
+        ));
+        assert_eq!(result["errors"].as_array().unwrap().len(), 1);
+
+        // Compilation errors are reported without running
+        let result = dry_run_json("keep");
+        assert_eq!(result["actions"], json!([]));
+        assert_eq!(result["errors"].as_array().unwrap().len(), 1);
+    }
+}